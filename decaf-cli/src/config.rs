@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use decaf::{ArchiveOptions, ExtractOptions};
+use toml_edit::Document;
+
+use crate::{parse_size, CliError};
+
+/// Defaults for command-line options, read from configuration files
+///
+/// The user configuration is `$XDG_CONFIG_HOME/decaf/config.toml` (`~/.config/decaf/config.toml`
+/// by default) and the project configuration is `.decafrc` in the current directory. Both are TOML
+/// documents of top-level keys; every key set by the project configuration overrides the user
+/// configuration, and flags override both.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub level: Option<i32>,
+    pub bundle_size: Option<usize>,
+    pub exclude: Option<Vec<String>>,
+    pub threads: Option<usize>,
+}
+
+impl Config {
+    /// Loads the user and then the project configuration, ignoring either if it doesn't exist
+    pub fn load() -> Result<Config, CliError> {
+        let mut config = Config::default();
+        let user_config = env::var_os("XDG_CONFIG_HOME")
+            .filter(|directory| !directory.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|directory| directory.join("decaf/config.toml"));
+        for path in user_config.into_iter().chain([PathBuf::from(".decafrc")]) {
+            match fs::read_to_string(&path) {
+                Ok(text) => config.merge(Config::parse(&text).map_err(|message| {
+                    CliError::Usage(format!("{}: {}", path.display(), message))
+                })?),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(io::Error::new(
+                        error.kind(),
+                        format!("could not read {}: {}", path.display(), error),
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides every key of `self` that is set in `other`
+    fn merge(&mut self, other: Config) {
+        self.level = other.level.or(self.level);
+        self.bundle_size = other.bundle_size.or(self.bundle_size);
+        self.exclude = other.exclude.or(self.exclude.take());
+        self.threads = other.threads.or(self.threads);
+    }
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let document =
+            Document::parse(text).map_err(|error| error.to_string().trim_end().to_string())?;
+        let mut config = Config::default();
+        for (key, item) in document.iter() {
+            let line = item
+                .span()
+                .map_or(0, |span| text[..span.start].matches('\n').count() + 1);
+            let error = |message: &str| format!("line {}: {}", line, message);
+            let wrong_type = || error(&format!("wrong type of value for {}", key));
+            match key {
+                "level" => {
+                    let level = item.as_integer().ok_or_else(wrong_type)?;
+                    config.level = Some(
+                        level
+                            .try_into()
+                            .map_err(|_| error("level is out of range"))?,
+                    )
+                }
+                "bundle-size" => {
+                    config.bundle_size = Some(match (item.as_integer(), item.as_str()) {
+                        (Some(size), _) => size
+                            .try_into()
+                            .map_err(|_| error("bundle-size must be positive"))?,
+                        (_, Some(size)) => parse_size(size)
+                            .ok_or_else(|| error("bundle-size must be a size such as 64M"))?,
+                        _ => return Err(wrong_type()),
+                    })
+                }
+                "exclude" => {
+                    config.exclude = Some(match (item.as_array(), item.as_str()) {
+                        (Some(patterns), _) => patterns
+                            .iter()
+                            .map(|pattern| pattern.as_str().map(String::from))
+                            .collect::<Option<_>>()
+                            .ok_or_else(wrong_type)?,
+                        (_, Some(pattern)) => vec![pattern.to_string()],
+                        _ => return Err(wrong_type()),
+                    })
+                }
+                "threads" => {
+                    let threads = item.as_integer().ok_or_else(wrong_type)?;
+                    config.threads = Some(
+                        threads
+                            .try_into()
+                            .ok()
+                            .filter(|&threads| threads > 0)
+                            .ok_or_else(|| error("threads must be at least 1"))?,
+                    )
+                }
+                key => return Err(error(&format!("unknown key {}", key))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the default archive options with the configured settings applied
+    pub fn archive_options(&self) -> ArchiveOptions {
+        let mut options = ArchiveOptions::default();
+        if let Some(level) = self.level {
+            options.compression_level = level;
+        }
+        if let Some(bundle_size) = self.bundle_size {
+            options.bundle_size = bundle_size;
+        }
+        options.threads = self.threads;
+        options
+    }
+
+    /// Returns the default extract options with the configured settings applied
+    pub fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions {
+            threads: self.threads,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(
+            "# defaults for this project\n\
+             level = 19\n\
+             bundle-size = 1_048_576\n\
+             exclude = [\n  \"target\",\n  \"a]b\", # trailing comma\n]\n\
+             threads = 4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                level: Some(19),
+                bundle_size: Some(1 << 20),
+                exclude: Some(vec!["target".to_string(), "a]b".to_string()]),
+                threads: Some(4),
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        // sizes can be written like on the command line, and a single pattern without an array
+        let config = Config::parse("bundle-size = \"64M\"\nexclude = \"say \\\"hi\\\"\"").unwrap();
+        assert_eq!(config.bundle_size, Some(64 << 20));
+        assert_eq!(config.exclude, Some(vec!["say \"hi\"".to_string()]));
+
+        for (text, error) in [
+            ("colour = 1", "line 1: unknown key colour"),
+            ("\n\n[section]\nlevel = 1", "line 3: unknown key section"),
+            ("level = \"19\"", "line 1: wrong type of value for level"),
+            ("level = 4294967296", "line 1: level is out of range"),
+            ("bundle-size = -1", "line 1: bundle-size must be positive"),
+            (
+                "bundle-size = \"lots\"",
+                "line 1: bundle-size must be a size such as 64M",
+            ),
+            (
+                "exclude = [1, 2]",
+                "line 1: wrong type of value for exclude",
+            ),
+            (
+                "level = 4\n\nthreads = 0",
+                "line 3: threads must be at least 1",
+            ),
+        ] {
+            assert_eq!(Config::parse(text).unwrap_err(), error, "{}", text);
+        }
+        // TOML syntax errors name their line themselves
+        let error = Config::parse("level = 1\nlevel =").unwrap_err();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn merge() {
+        let mut config = Config::parse("level = 3\nexclude = [\"target\"]\nthreads = 2").unwrap();
+        config.merge(Config::parse("level = 19\nbundle-size = \"1M\"").unwrap());
+        assert_eq!(
+            config,
+            Config {
+                level: Some(19),
+                bundle_size: Some(1 << 20),
+                exclude: Some(vec!["target".to_string()]),
+                threads: Some(2),
+            }
+        );
+
+        // an empty array still overrides, unlike a key that isn't set
+        config.merge(Config::parse("exclude = []").unwrap());
+        assert_eq!(config.exclude, Some(Vec::new()));
+        assert_eq!(config.level, Some(19));
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::{fs, io, mem, process::exit, ptr, thread};
+
+/// The exit code of a process stopped by ctrl-c, like a shell reports it
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// What the process is doing, so that an interrupt can clean up after it and say how far it got
+pub enum Activity {
+    /// writing an archive to `output` through `partial`, which is removed when interrupted
+    Writing { output: String, partial: String },
+    /// extracting `input` into `output`, which is left as far as it got when interrupted
+    Extracting { input: String, output: String },
+}
+
+impl Activity {
+    /// Writing to `output`, through `OUTPUT.tmp` if `atomic`
+    pub fn writing(output: &str, atomic: bool) -> Activity {
+        Activity::Writing {
+            output: output.to_string(),
+            partial: match atomic {
+                true => format!("{}.tmp", output),
+                false => output.to_string(),
+            },
+        }
+    }
+
+    pub fn extracting(input: &str, output: &str) -> Activity {
+        Activity::Extracting {
+            input: input.to_string(),
+            output: output.to_string(),
+        }
+    }
+}
+
+static ACTIVITY: Mutex<Option<(Activity, Instant)>> = Mutex::new(None);
+
+/// Records `activity` as what the process is doing until the returned guard is dropped
+#[must_use]
+pub fn during(activity: Activity) -> ActivityGuard {
+    *ACTIVITY.lock().unwrap() = Some((activity, Instant::now()));
+    ActivityGuard
+}
+
+pub struct ActivityGuard;
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        *ACTIVITY.lock().unwrap() = None;
+    }
+}
+
+/// Handles SIGINT on a thread of its own, which cleans up after the current activity and exits
+/// with `EXIT_INTERRUPTED`
+///
+/// SIGINT is blocked for every thread and waited for with `sigwait` instead of being caught by a
+/// signal handler, so that cleaning up isn't restricted to async-signal-safe functions. It has to
+/// be called before any other thread is started, as they inherit the blocked signal.
+pub fn install() -> io::Result<()> {
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) {
+            0 => signals,
+            error => return Err(io::Error::from_raw_os_error(error)),
+        }
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        while unsafe { libc::sigwait(&signals, &mut signal) } != 0 {}
+        interrupted()
+    });
+    Ok(())
+}
+
+fn interrupted() -> ! {
+    // the lock is held until the process exits, so the activity can't finish in the meantime
+    let activity = ACTIVITY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match &*activity {
+        None => eprintln!("\ndecaf: interrupted"),
+        Some((Activity::Writing { output, partial }, started)) => {
+            let removed = match fs::remove_file(partial) {
+                Ok(()) => format!("removed the partial archive {}", partial),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    "no partial archive was written".to_string()
+                }
+                Err(error) => format!(
+                    "could not remove the partial archive {}: {}",
+                    partial, error
+                ),
+            };
+            eprintln!(
+                "\ndecaf: interrupted after {:.2} sec while writing {}; {}",
+                started.elapsed().as_secs_f32(),
+                output,
+                removed
+            );
+            if partial != output && Path::new(output).exists() {
+                eprintln!("decaf: the existing {} was left as it was", output);
+            }
+        }
+        Some((Activity::Extracting { input, output }, started)) => {
+            eprintln!(
+                "\ndecaf: interrupted after {:.2} sec while extracting {} into {}",
+                started.elapsed().as_secs_f32(),
+                input,
+                output
+            );
+            eprintln!(
+                "decaf: {} holds a partial extraction; files in it may be missing or incomplete",
+                output
+            );
+        }
+    }
+    exit(EXIT_INTERRUPTED)
+}
@@ -1,82 +1,2341 @@
-use std::time::Instant;
-use std::{env, fs::File, path::Path, process::exit};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::{mpsc, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, fs, fs::File, mem, path::Path, process::exit};
 
 use decaf::*;
+use notify::Watcher;
+use sha2::{Digest, Sha256};
+
+mod config;
+use config::Config;
+mod interrupt;
+use interrupt::Activity;
+
+/// An error which ends the process with a dedicated exit code
+enum CliError {
+    /// the command line could not be understood
+    Usage(String),
+    /// an operation failed; see `exit_code` for how it is classified
+    Io(io::Error),
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            CliError::Io(error) => match error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<ArchiveError>())
+            {
+                Some(ArchiveError::Corrupt(_)) => 4,
+                Some(ArchiveError::Verification(_)) => 5,
+                Some(ArchiveError::LimitExceeded(_)) => 6,
+                None => 3,
+            },
+        }
+    }
+}
+
+/// Prefixes an io error with what was being done, keeping its kind
+fn context<S: AsRef<str>>(message: S) -> impl FnOnce(io::Error) -> io::Error {
+    move |error| io::Error::new(error.kind(), format!("{}: {}", message.as_ref(), error))
+}
+
+/// How much is printed besides errors and what a command is asked to print, as set by `-q` and
+/// `-v` anywhere on the command line
+#[derive(Clone, Copy, PartialEq)]
+enum Verbosity {
+    /// nothing but errors
+    Quiet,
+    /// status lines and warnings
+    Normal,
+    /// also every archived or extracted listing, like `tar -v`
+    Verbose,
+}
+
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// whether `--progress` was given anywhere on the command line
+static SHOW_PROGRESS: OnceLock<bool> = OnceLock::new();
+
+fn verbosity() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or(Verbosity::Normal)
+}
+
+/// Sets the verbosity from the `-q` and `-v` flags among `args`, the last of which wins, and
+/// whether progress is shown from `--progress`, and returns the other arguments
+fn take_verbosity(args: &[String]) -> Vec<String> {
+    let mut verbosity = Verbosity::Normal;
+    let mut show_progress = false;
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = Verbosity::Verbose,
+            "--progress" => show_progress = true,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    let _ = VERBOSITY.set(verbosity);
+    let _ = SHOW_PROGRESS.set(show_progress);
+    rest
+}
+
+/// Prints a status line like `println!` unless quiet, but to stderr if `$to_stderr` is set because
+/// stdout carries a stream
+macro_rules! status {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if verbosity() == Verbosity::Quiet {
+        } else if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Returns a callback printing warnings as they happen, unless quiet
+fn warning_callback() -> Option<WarningCallback> {
+    (verbosity() != Verbosity::Quiet)
+        .then(|| WarningCallback::new(|warning| eprintln!("decaf: warning: {}", warning)))
+}
+
+/// Returns a callback printing the path of every archived or extracted listing if verbose, to
+/// stderr if `to_stderr` is set because stdout carries a stream, or else with `--progress` one
+/// line on stderr updated with the share of the content done and the time left
+fn progress_callback(to_stderr: bool) -> Option<ProgressCallback> {
+    match verbosity() {
+        Verbosity::Verbose => Some(ProgressCallback::new(move |progress| {
+            let slash = match progress.permissions & 0o040000 == 0o040000 {
+                true => "/",
+                false => "",
+            };
+            status!(to_stderr, "{}{}", progress.path, slash)
+        })),
+        Verbosity::Normal if SHOW_PROGRESS.get() == Some(&true) => {
+            let start = Instant::now();
+            // the line is only redrawn when it changes, as there may be many small listings
+            let last_line = std::sync::Mutex::new(String::new());
+            Some(ProgressCallback::new(move |progress| {
+                let line = progress_line(progress, start.elapsed());
+                let mut last_line = last_line.lock().unwrap();
+                if *last_line != line {
+                    let finished = progress.total == Some(progress.done);
+                    eprint!("\r\x1b[2K{}{}", line, if finished { "\n" } else { "" });
+                    *last_line = line;
+                }
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the progress line for `progress` after `elapsed`, e.g. `decaf: 42% of 1.50 GiB, 12s
+/// left`, estimating the time left from the rate so far
+fn progress_line(progress: &Progress, elapsed: Duration) -> String {
+    let Some(total) = progress.total.filter(|&total| total > 0) else {
+        return format!("decaf: {} done", SizeFormat::Human.format(progress.done));
+    };
+    let percent = progress.done.saturating_mul(100) / total;
+    let mut line = format!("decaf: {}% of {}", percent, SizeFormat::Human.format(total));
+    // the rate of the first second says little about the rest
+    if progress.done > 0 && progress.done < total && elapsed >= Duration::from_secs(1) {
+        let left = elapsed.as_secs_f64() * (total - progress.done) as f64 / progress.done as f64;
+        line += &format!(", {}s left", left.ceil() as u64);
+    }
+    line
+}
+
+/// How byte sizes are printed
+#[derive(Clone, Copy, Default)]
+enum SizeFormat {
+    /// binary units with two decimals, e.g. `1.50 MiB`
+    #[default]
+    Human,
+    /// the exact number of bytes, for scripts
+    Bytes,
+}
+
+impl SizeFormat {
+    fn format(self, bytes: u64) -> String {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+        if matches!(self, SizeFormat::Bytes) || bytes < 1024 {
+            return format!("{} B", bytes);
+        }
+        let mut size = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+/// What happens when the output of a command already exists
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Clobber {
+    /// ask on the terminal, and refuse if there is none
+    #[default]
+    Ask,
+    /// overwrite the file or write into the directory (`--force`)
+    Force,
+    /// leave the output alone and skip the command (`--no-clobber`)
+    Never,
+}
+
+impl Clobber {
+    /// Returns whether `output` may be written, which is always the case if it doesn't exist or
+    /// is an empty directory
+    fn allows(self, output: &str) -> Result<bool, CliError> {
+        let exists = match fs::read_dir(output) {
+            Ok(mut entries) => entries.next().is_some(),
+            Err(_) => Path::new(output).exists(),
+        };
+        if !exists {
+            return Ok(true);
+        }
+        match self {
+            Clobber::Force => Ok(true),
+            Clobber::Never => {
+                status!(
+                    false,
+                    "decaf: {} already exists; not overwriting it",
+                    output
+                );
+                Ok(false)
+            }
+            Clobber::Ask if io::stdin().is_terminal() => {
+                eprint!("decaf: {} already exists; overwrite it? [y/N] ", output);
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                if matches!(answer.trim(), "y" | "Y" | "yes") {
+                    return Ok(true);
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", output),
+                )
+                .into())
+            }
+            Clobber::Ask => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} already exists; pass --force to overwrite it or --no-clobber to skip",
+                    output
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Loads the configuration files unless `--no-config` is among `args`
+fn load_config(args: &[String]) -> Result<Config, CliError> {
+    if args.iter().any(|arg| arg == "--no-config") {
+        return Ok(Config::default());
+    }
+    Config::load()
+}
+
+/// Takes the value following `flag`, failing with a usage error if there is none
+fn flag_value<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<&'a str, CliError> {
+    flags
+        .next()
+        .map(String::as_str)
+        .ok_or_else(|| CliError::Usage(format!("{} requires a value", flag)))
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 || args.len() > 3 {
-        usage();
-        exit(1)
+    if let Err(error) = interrupt::install() {
+        eprintln!("decaf: warning: ctrl-c will not clean up: {}", error);
+    }
+    if let Err(error) = run(&args[1..]) {
+        match &error {
+            CliError::Usage(message) => {
+                eprintln!("decaf: {}\n\nRun `decaf --help` for usage.", message)
+            }
+            CliError::Io(error) => eprintln!("decaf: error: {}", error),
+        }
+        exit(error.exit_code())
+    }
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let args = &take_verbosity(args)[..];
+    match args.first().map(String::as_str) {
+        None => {
+            eprint!("{}", usage());
+            exit(2)
+        }
+        Some("-h" | "--help") => {
+            print!("{}", usage());
+            Ok(())
+        }
+        Some("analyze") => analyze(&args[1..]),
+        Some("cat") => cat(&args[1..]),
+        Some("convert") => convert(&args[1..]),
+        Some("create") => create(&args[1..]),
+        Some("grep") => grep(&args[1..]),
+        Some("grep-path") => grep_path(&args[1..]),
+        Some("index") => index(&args[1..]),
+        Some("manifest") => manifest(&args[1..]),
+        Some("repack") => repack(&args[1..]),
+        Some("status") => status(&args[1..]),
+        Some("tree") => tree(&args[1..]),
+        Some("upgrade") => upgrade(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        Some("watch") => watch(&args[1..]),
+        Some("spec") => spec(&args[1..]),
+        Some(_) => archive_or_extract(args),
     }
+}
 
-    let input = args[1].as_str();
-    let output = if args.len() == 3 {
-        args[2].to_string()
-    } else {
-        if let Some(stripped) = input.strip_suffix(".df") {
-            stripped.to_string()
-        } else {
-            let input_filename = Path::new(input).file_name().unwrap().to_str().unwrap();
-            format!("{}.df", input_filename)
+fn archive_or_extract(args: &[String]) -> Result<(), CliError> {
+    let config = load_config(args)?;
+    let mut archive_options = config.archive_options();
+    let mut extract_options = config.extract_options();
+    archive_options.excludes = config.exclude.unwrap_or_default();
+    let mut files_from: Option<&str> = None;
+    let mut null_delimited = false;
+    let mut top_level_directory = false;
+    let mut show_stats = false;
+    let mut preflight = false;
+    let mut placeholders = false;
+    let mut checksum_file = false;
+    let mut clobber = Clobber::default();
+    let mut sizes = SizeFormat::default();
+    let mut output_flag: Option<&str> = None;
+    let mut recursive = false;
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--strip-components" => {
+                extract_options.strip_components =
+                    flag_value(&mut flags, arg)?.parse().map_err(|_| {
+                        CliError::Usage(
+                            "--strip-components requires a number of components".to_string(),
+                        )
+                    })?;
+            }
+            "--transform" => {
+                let (from, to) = flag_value(&mut flags, arg)?
+                    .split_once('=')
+                    .ok_or_else(|| {
+                        CliError::Usage("--transform requires a rule such as FROM=TO".to_string())
+                    })?;
+                extract_options
+                    .path_remaps
+                    .push((from.to_string(), to.to_string()));
+            }
+            "--permissions" => {
+                let permissions =
+                    parse_permission_mode(flag_value(&mut flags, arg)?).ok_or_else(|| {
+                        CliError::Usage(
+                            "--permissions requires preserve, exec, or FILE:DIR octal modes"
+                                .to_string(),
+                        )
+                    })?;
+                archive_options.permissions = permissions;
+                extract_options.permissions = permissions;
+            }
+            "--file-mode" => extract_options.file_mode = Some(parse_mode(&mut flags, arg)?),
+            "--dir-mode" => extract_options.dir_mode = Some(parse_mode(&mut flags, arg)?),
+            "--mode-mask" => extract_options.mode_mask = parse_mode(&mut flags, arg)?,
+            "--preserve-special-bits" => extract_options.preserve_special_bits = true,
+            "--skip-existing" => extract_options.overwrite = OverwritePolicy::Skip,
+            "--max-memory" => {
+                extract_options.max_memory =
+                    Some(parse_size(flag_value(&mut flags, arg)?).ok_or_else(|| {
+                        CliError::Usage("--max-memory requires a size such as 512M".to_string())
+                    })? as u64)
+            }
+            "--sort" => {
+                archive_options.sort = match flag_value(&mut flags, arg)? {
+                    "size" => SortStrategy::Size,
+                    "path" => SortStrategy::Path,
+                    "extension" => SortStrategy::Extension,
+                    "walk" => SortStrategy::WalkOrder,
+                    _ => {
+                        return Err(CliError::Usage(
+                            "--sort requires one of size, path, extension, or walk".to_string(),
+                        ))
+                    }
+                }
+            }
+            "--limit-rate" => {
+                let rate = parse_size(flag_value(&mut flags, arg)?).ok_or_else(|| {
+                    CliError::Usage(
+                        "--limit-rate requires a size per second such as 50M".to_string(),
+                    )
+                })?;
+                archive_options.rate_limit = Some(rate as u64);
+                extract_options.rate_limit = Some(rate as u64);
+            }
+            "--duplicates" => {
+                let policy = match flag_value(&mut flags, arg)? {
+                    "error" => DuplicatePathPolicy::Error,
+                    "dedupe" => DuplicatePathPolicy::Dedupe,
+                    "rename" => DuplicatePathPolicy::Rename,
+                    _ => {
+                        return Err(CliError::Usage(
+                            "--duplicates requires one of error, dedupe, or rename".to_string(),
+                        ))
+                    }
+                };
+                archive_options.duplicate_paths = policy;
+                extract_options.duplicate_paths = policy;
+            }
+            "--case-collisions" => {
+                extract_options.case_collisions = match flag_value(&mut flags, arg)? {
+                    "warn" => CaseCollisionPolicy::Warn,
+                    "error" => CaseCollisionPolicy::Error,
+                    "rename" => CaseCollisionPolicy::Rename,
+                    "ignore" => CaseCollisionPolicy::Ignore,
+                    _ => {
+                        return Err(CliError::Usage(
+                            "--case-collisions requires one of warn, error, rename, or ignore"
+                                .to_string(),
+                        ))
+                    }
+                }
+            }
+            "--assume-case-insensitive" => extract_options.assume_case_insensitive = true,
+            "--max-size" => {
+                let size = parse_size(flag_value(&mut flags, arg)?).ok_or_else(|| {
+                    CliError::Usage("--max-size requires a size such as 2G".to_string())
+                })?;
+                archive_options.limits.max_uncompressed_size = Some(size as u64);
+            }
+            "--level" => archive_options.compression_level = parse_level(&mut flags, arg)?,
+            "--bundle-size" => archive_options.bundle_size = parse_bundle_size(&mut flags, arg)?,
+            "--exclude" => archive_options
+                .excludes
+                .push(flag_value(&mut flags, arg)?.to_string()),
+            "--threads" | "-j" => {
+                let threads = flag_value(&mut flags, arg)?
+                    .parse()
+                    .ok()
+                    .filter(|&threads| threads > 0)
+                    .ok_or_else(|| {
+                        CliError::Usage("--threads requires a number of threads".to_string())
+                    })?;
+                archive_options.threads = Some(threads);
+                extract_options.threads = Some(threads);
+            }
+            "--no-config" => {}
+            "--skip-unreadable" => archive_options.read_errors = ReadErrorPolicy::Skip,
+            "--compress-listings" => archive_options.compress_listings = true,
+            "--section-checksums" => archive_options.section_checksums = true,
+            "--header-checksum" => archive_options.header_checksum = true,
+            "--split-checksum" => archive_options.split_checksum = true,
+            "--footer" => archive_options.footer = true,
+            "--seekable-frames" => {
+                archive_options.seekable_frame_size = Some(parse_frame_size(&mut flags, arg)?)
+            }
+            "--zstd-checksums" => archive_options.zstd_frame_checksums = true,
+            "--hash" => archive_options.hash = parse_hash(&mut flags, arg)?,
+            "--structure-only" => archive_options.structure_only = true,
+            "--low-memory" => archive_options.low_memory = true,
+            "--tempdir" => {
+                let directory = Path::new(flag_value(&mut flags, arg)?);
+                archive_options.temp_dir = Some(directory.to_path_buf());
+                extract_options.temp_dir = Some(directory.to_path_buf());
+            }
+            "--file-flags" => {
+                archive_options.file_flags = true;
+                extract_options.file_flags = true;
+            }
+            "--checksum-file" => checksum_file = true,
+            "--no-temp-file" => archive_options.atomic_write = false,
+            "--no-verify" => extract_options.verify = VerifyLevel::None,
+            "--paranoid" => extract_options.verify = VerifyLevel::Paranoid,
+            "--no-preserve-permissions" => {
+                extract_options.permission_policy = PermissionPolicy::Skip
+            }
+            "--lenient-permissions" => {
+                extract_options.permission_policy = PermissionPolicy::Lenient
+            }
+            "--top-level-dir" => top_level_directory = true,
+            "--stats" => show_stats = true,
+            "--preflight" => preflight = true,
+            "--placeholders" => placeholders = true,
+            "--recursive-extract" => recursive = true,
+            "--force" | "-f" => clobber = Clobber::Force,
+            "--no-clobber" | "-n" => clobber = Clobber::Never,
+            "--bytes" => sizes = SizeFormat::Bytes,
+            "--prefix" => {
+                archive_options.path_prefix = Some(flag_value(&mut flags, arg)?.to_string())
+            }
+            "--files-from" | "-T" => files_from = Some(flag_value(&mut flags, arg)?),
+            "-0" | "--null" => null_delimited = true,
+            "--output" | "-o" => output_flag = Some(flag_value(&mut flags, arg)?),
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+
+    // with --output, every path is an input, and several of them are archived side by side under
+    // their own names
+    let joined_inputs;
+    let mut inputs: Vec<&str> = Vec::new();
+    if let Some(output) = output_flag {
+        inputs = mem::take(&mut paths);
+        if inputs.is_empty() {
+            return Err(CliError::Usage("--output requires an input".to_string()));
+        }
+        if inputs.len() > 1 {
+            if inputs
+                .iter()
+                .any(|input| *input == "-" || input.ends_with(".df"))
+            {
+                return Err(CliError::Usage(
+                    "only directories and files can be archived together".to_string(),
+                ));
+            }
+            if files_from.is_some() || top_level_directory {
+                return Err(CliError::Usage(
+                    "--files-from and --top-level-dir take a single DIRECTORY".to_string(),
+                ));
+            }
+        }
+        joined_inputs = inputs.join(" ");
+        paths = vec![&joined_inputs, output];
+    }
+    let (input, output) = match paths[..] {
+        [input, output] => (input, output.to_string()),
+        // like `tar -x`, a stream from stdin is extracted into the current directory
+        ["-"] => ("-", ".".to_string()),
+        [input] => match input.strip_suffix(".df") {
+            Some(stripped) => (input, stripped.to_string()),
+            None => {
+                let input_filename = Path::new(input)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| {
+                        CliError::Usage(format!(
+                            "can not derive an output name from {}; pass an OUTPUT",
+                            input
+                        ))
+                    })?;
+                (input, format!("{}.df", input_filename))
+            }
+        },
+        _ => {
+            return Err(CliError::Usage(
+                "expected an input and an optional output".to_string(),
+            ))
         }
     };
 
-    if !input.ends_with(".df") {
+    // a preflight writes nothing, and neither stdout nor the implicit current directory are
+    // clobbered, nor is anything when existing files are skipped
+    let to_stdout = output == "-";
+    archive_options.on_warning = warning_callback();
+    archive_options.on_progress = progress_callback(to_stdout);
+    extract_options.on_warning = warning_callback();
+    extract_options.on_progress = progress_callback(false);
+    extract_options.on_nested_archive = Some(nested_archive_callback(
+        recursive,
+        &extract_options,
+        &archive_options.limits,
+    ));
+    let archiving = input != "-" && !input.ends_with(".df");
+    let skipping = !archiving && extract_options.overwrite == OverwritePolicy::Skip;
+    if !preflight && !to_stdout && paths != ["-"] && !skipping && !clobber.allows(&output)? {
+        return Ok(());
+    }
+    if to_stdout && show_stats {
+        return Err(CliError::Usage(
+            "--stats can not be used when writing a stream to stdout".to_string(),
+        ));
+    }
+    if archiving {
+        archive_options
+            .validate()
+            .map_err(|e| CliError::Usage(e.to_string()))?;
+    } else {
+        extract_options
+            .validate()
+            .map_err(|e| CliError::Usage(e.to_string()))?;
+    }
+    if (checksum_file || output.contains("{hash}")) && (to_stdout || !archiving) {
+        return Err(CliError::Usage(
+            "--checksum-file and {hash} require writing an archive to a file".to_string(),
+        ));
+    }
+    if preflight && archive_options.low_memory {
+        return Err(CliError::Usage(
+            "--preflight can not be used with --low-memory".to_string(),
+        ));
+    }
+
+    if input == "-" {
+        if preflight || placeholders {
+            return Err(CliError::Usage(
+                "--preflight and --placeholders require an archive, not a stream".to_string(),
+            ));
+        }
+        let timer_overall = Instant::now();
+        status!(false, "decaf: extracting stream from stdin");
+        let extracting = interrupt::during(Activity::extracting("stdin", &output));
+        let stats = decaf::extract_stream(
+            &mut io::stdin().lock(),
+            &output,
+            &archive_options.limits,
+            &extract_options,
+        )?;
+        drop(extracting);
+        status!(
+            false,
+            "decaf: unarchived stream to {} (wrote {}) in {:.2} sec",
+            output,
+            sizes.format(stats.uncompressed_bytes),
+            timer_overall.elapsed().as_secs_f32()
+        );
+        if show_stats {
+            print_stats(&stats, sizes);
+        }
+    } else if archiving {
         let timer_overall = Instant::now();
         // todo: spinners
-        println!("decaf: indexing files in {}", input);
-        let pre_archive = decaf::create_archive_from_directory(Path::new(input)).unwrap();
+        status!(to_stdout, "decaf: indexing files in {}", input);
+        let mut pre_archive = match files_from {
+            _ if inputs.len() > 1 => decaf::create_archive_from_inputs(&inputs)
+                .map_err(context(format!("could not index {}", input)))?,
+            Some(list_path) => {
+                let list = read_file_list(list_path, null_delimited)
+                    .map_err(context(format!("could not read file list {}", list_path)))?;
+                decaf::create_archive_from_paths(list, Path::new(input))?
+            }
+            None => decaf::create_archive_from_directory(Path::new(input))
+                .map_err(context(format!("could not index {}", input)))?,
+        };
+        pre_archive.listings.retain(|listing| {
+            !archive_options
+                .excludes
+                .iter()
+                .any(|pattern| is_excluded(&listing.relative_path, pattern))
+        });
 
-        println!(
+        status!(
+            to_stdout,
             "decaf: indexed {} files in {:.2} sec",
             pre_archive.listings.len(),
             timer_overall.elapsed().as_secs_f32()
         );
 
-        println!("decaf: creating archive for {}", input);
-        let mut outfile = File::create(output.clone()).unwrap();
-        let bytes = pre_archive.archive_to_writer(&mut outfile).unwrap();
+        if top_level_directory {
+            archive_options.path_prefix = Some(top_level_directory_name(input)?);
+        }
 
-        println!(
-            "decaf: archived {} as {} (wrote {:.2} mb) in {:.2} sec",
+        status!(to_stdout, "decaf: creating archive for {}", input);
+        let written = match to_stdout {
+            // an archive with a footer is written in a single pass like a stream
+            true if archive_options.footer => pre_archive
+                .archive_to_writer_with_options(&mut io::stdout().lock(), &archive_options)
+                .map_err(context("could not write archive to stdout"))?,
+            true => pre_archive
+                .archive_to_stream(&mut io::stdout().lock(), &archive_options)
+                .map_err(context("could not write stream to stdout"))?,
+            false => {
+                let _writing =
+                    interrupt::during(Activity::writing(&output, archive_options.atomic_write));
+                pre_archive
+                    .archive_to_file_with_options(&output, &archive_options)
+                    .map_err(context(format!("could not write {}", output)))?
+            }
+        };
+        let output = match to_stdout {
+            true => output,
+            false => publish(&output, checksum_file)
+                .map_err(context(format!("could not publish {}", output)))?,
+        };
+
+        status!(
+            to_stdout,
+            "decaf: archived {} as {} (wrote {}) in {:.2} sec",
             input,
             output,
-            bytes as f32 / 1024.0 / 1024.0,
+            sizes.format(written.bytes_written as u64),
             timer_overall.elapsed().as_secs_f32()
         );
+        if show_stats {
+            print_stats(&written.stats, sizes);
+        }
+    } else if placeholders {
+        let timer_overall = Instant::now();
+        let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+        let layout = ArchiveLayout::read_seekable(&mut io::BufReader::new(infile))?;
+        let extracting = interrupt::during(Activity::extracting(input, &output));
+        let stats = layout.create_placeholders(&output, &extract_options)?;
+        drop(extracting);
+        status!(
+            false,
+            "decaf: created placeholders for {} in {} in {:.2} sec",
+            input,
+            output,
+            timer_overall.elapsed().as_secs_f32()
+        );
+        if show_stats {
+            print_stats(&stats, sizes);
+        }
+    } else if archive_options.low_memory {
+        let timer_overall = Instant::now();
+        let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+        status!(false, "decaf: extracting files from archive {}", input);
+        let extracting = interrupt::during(Activity::extracting(input, &output));
+        let stats = decaf::extract_sequentially(
+            &mut io::BufReader::new(infile),
+            &output,
+            &archive_options.limits,
+            &extract_options,
+        )?;
+        drop(extracting);
+        status!(
+            false,
+            "decaf: unarchived {} to {} (wrote {}) in {:.2} sec",
+            input,
+            output,
+            sizes.format(stats.uncompressed_bytes),
+            timer_overall.elapsed().as_secs_f32()
+        );
+        if show_stats {
+            print_stats(&stats, sizes);
+        }
     } else {
         let timer_overall = Instant::now();
-        let mut infile = File::open(input).unwrap();
-        println!("decaf: extracting files from archive {}", input);
-        let ex_archive = extract_from_reader(&mut infile).unwrap();
-        println!(
+        let mut infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+        status!(false, "decaf: extracting files from archive {}", input);
+        let ex_archive = ExtractedArchive::from_reader_with_options(
+            &mut infile,
+            &archive_options.limits,
+            &extract_options,
+        )?;
+        status!(
+            false,
             "decaf: extracted {} files in {:.2} sec",
             ex_archive.listings.len(),
             timer_overall.elapsed().as_secs_f32()
         );
-        ex_archive.create_all_files(output.clone()).unwrap();
+        if preflight {
+            let report = ex_archive.preflight(&output, &extract_options)?;
+            print_preflight(&report, sizes);
+            if !report.is_ok() {
+                return Err(io::Error::other(format!(
+                    "preflight of extracting {} to {} failed",
+                    input, output
+                ))
+                .into());
+            }
+            status!(
+                false,
+                "decaf: preflight of extracting {} to {} passed",
+                input,
+                output
+            );
+            return Ok(());
+        }
+        let extracting = interrupt::during(Activity::extracting(input, &output));
+        let stats = ex_archive.create_all_files_with_options(&output, &extract_options)?;
+        drop(extracting);
+        status!(
+            false,
+            "decaf: unarchived {} to {} (wrote {}) in {:.2} sec",
+            input,
+            output,
+            sizes.format(stats.uncompressed_bytes),
+            timer_overall.elapsed().as_secs_f32()
+        );
+        if show_stats {
+            print_stats(&stats, sizes);
+        }
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &ArchiveStats, sizes: SizeFormat) {
+    println!("  files              {:>14}", stats.file_count);
+    println!("  bare directories   {:>14}", stats.dir_count);
+    println!("  symlinks           {:>14}", stats.symlink_count);
+    println!("  bundles            {:>14}", stats.bundle_count);
+    println!(
+        "  uncompressed size  {:>14}",
+        sizes.format(stats.uncompressed_bytes)
+    );
+    println!(
+        "  compressed size    {:>14}",
+        sizes.format(stats.compressed_bytes)
+    );
+    println!("  ratio              {:>14.2}", stats.ratio());
+    println!(
+        "  duration           {:>10.2} sec",
+        stats.duration.as_secs_f32()
+    );
+}
+
+fn print_preflight(report: &PreflightReport, sizes: SizeFormat) {
+    for path in &report.collisions {
+        println!("  collision          {}", path.display());
+    }
+    for path in &report.unwritable {
+        println!("  unwritable         {}", path.display());
+    }
+    println!(
+        "  required space     {:>14}",
+        sizes.format(report.required_bytes)
+    );
+    println!(
+        "  available space    {:>14}",
+        sizes.format(report.available_bytes)
+    );
+}
+
+/// Writes `content` to `output`, through `OUTPUT.tmp` renamed on success if `atomic`, so that an
+/// interrupted write never truncates an existing archive
+fn write_output(output: &str, content: &[u8], atomic: bool) -> io::Result<()> {
+    let _writing = interrupt::during(Activity::writing(output, atomic));
+    if !atomic {
+        return fs::write(output, content);
+    }
+    let temporary_path = format!("{}.tmp", output);
+    let mut temporary_file = File::create(&temporary_path)?;
+    let written = temporary_file
+        .write_all(content)
+        .and_then(|()| temporary_file.sync_all())
+        .and_then(|()| fs::rename(&temporary_path, output));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    written
+}
+
+/// Puts the finishing touches on the archive written to `output`: replaces `{hash}` in its name
+/// with the start of its SHA-256 digest and, if `checksum_file` is set, writes the digest next to it
+/// as `<name>.sha256` in the format of `sha256sum`, so that downloads can be verified without
+/// decaf; returns the final path
+fn publish(output: &str, checksum_file: bool) -> io::Result<String> {
+    if !checksum_file && !output.contains("{hash}") {
+        return Ok(output.to_string());
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(output)?, &mut hasher)?;
+    let digest = format!("{:x}", hasher.finalize());
+
+    let published = output.replace("{hash}", &digest[..16]);
+    if published != output {
+        fs::rename(output, &published)?;
+    }
+    if checksum_file {
+        let file_name = Path::new(&published)
+            .file_name()
+            .map_or_else(|| published.clone().into(), |name| name.to_string_lossy());
+        let line = format!("{}  {}\n", digest, file_name);
+        write_output(&format!("{}.sha256", published), line.as_bytes(), true)?;
+    }
+    Ok(published)
+}
+
+/// Reads the newline (or NUL, if `null_delimited`) separated paths in `list_path`, where `-`
+/// reads the list from stdin
+fn read_file_list(list_path: &str, null_delimited: bool) -> io::Result<Vec<String>> {
+    let mut list = String::new();
+    if list_path == "-" {
+        io::stdin().read_to_string(&mut list)?;
+    } else {
+        File::open(list_path)?.read_to_string(&mut list)?;
+    }
+
+    let delimiter = if null_delimited { '\0' } else { '\n' };
+    Ok(list
+        .split(delimiter)
+        .map(|path| path.strip_suffix('\r').unwrap_or(path))
+        .filter(|path| !path.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Prints how well the content of an archive compressed, read from its tables alone
+fn analyze(args: &[String]) -> Result<(), CliError> {
+    let mut top = 10;
+    let mut sizes = SizeFormat::default();
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--top" => {
+                top = flag_value(&mut flags, arg)?.parse().map_err(|_| {
+                    CliError::Usage("--top requires a number of listings".to_string())
+                })?
+            }
+            "--bytes" => sizes = SizeFormat::Bytes,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let input = match paths[..] {
+        [input] => input,
+        _ => return Err(CliError::Usage("analyze expects an archive".to_string())),
+    };
+
+    let mut infile = io::BufReader::new(
+        File::open(input).map_err(context(format!("could not open {}", input)))?,
+    );
+    let report = ArchiveLayout::read_seekable(&mut infile)
+        .map_err(context(format!("could not read {}", input)))?
+        .compression_report(top);
+
+    let row = |name: &str, totals: &CompressionTotals| {
         println!(
-            "decaf: unarchived {} to {} in {:.2} sec",
+            "  {:<30} {:>14} {:>14} {:>8.2}",
+            name,
+            sizes.format(totals.uncompressed_bytes),
+            sizes.format(totals.compressed_bytes),
+            totals.ratio()
+        )
+    };
+    let heading = |name: &str| {
+        println!(
+            "\n  {:<30} {:>14} {:>14} {:>8}",
+            name, "uncompressed", "compressed", "ratio"
+        )
+    };
+
+    println!("decaf: compression of {}", input);
+    heading("bundle");
+    for (index, bundle) in report.bundles.iter().enumerate() {
+        row(&index.to_string(), bundle);
+    }
+    heading("extension");
+    for (extension, totals) in &report.extensions {
+        row(
+            if extension.is_empty() {
+                "(none)"
+            } else {
+                extension
+            },
+            totals,
+        );
+    }
+    // listings share the compression of their bundle, so their compressed sizes are estimates
+    heading("largest listings (estimated)");
+    for (path, totals) in &report.largest {
+        row(path, totals);
+    }
+    Ok(())
+}
+
+/// Prints the lines of the files of an archive containing a fixed string as `PATH:LINE:TEXT`, like
+/// `grep -n`, decompressing one bundle at a time
+fn grep(args: &[String]) -> Result<(), CliError> {
+    let mut ignore_case = false;
+    let mut files_with_matches = false;
+    let mut paths: Vec<&str> = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--ignore-case" | "-i" => ignore_case = true,
+            "--files-with-matches" | "-l" => files_with_matches = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let (input, pattern) = match paths[..] {
+        [input, pattern] if !pattern.is_empty() => (input, pattern),
+        _ => {
+            return Err(CliError::Usage(
+                "grep expects an archive and a pattern".to_string(),
+            ))
+        }
+    };
+
+    let fold = |bytes: &[u8]| -> Vec<u8> {
+        if ignore_case {
+            bytes.to_ascii_lowercase()
+        } else {
+            bytes.to_vec()
+        }
+    };
+    let pattern = fold(pattern.as_bytes());
+    let contains = |line: &[u8]| {
+        fold(line)
+            .windows(pattern.len())
+            .any(|window| window == pattern)
+    };
+
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let mut stdout = io::stdout().lock();
+    decaf::for_each_file(
+        &mut io::BufReader::new(infile),
+        &Limits::default(),
+        |listing, content| {
+            // symbolic links hold a copy of the content of the file they point at, which is
+            // searched as that file, so skip them rather than report the same matches twice
+            if listing.permissions & 0o170000 == 0o120000 {
+                return Ok(());
+            }
+            let content = content.strip_suffix(b"\n").unwrap_or(content);
+            let mut lines = content
+                .split(|&byte| byte == b'\n')
+                .enumerate()
+                .filter(|(_, line)| contains(line))
+                .peekable();
+            if lines.peek().is_none() {
+                return Ok(());
+            }
+            if files_with_matches {
+                return writeln!(stdout, "{}", listing.path);
+            }
+            if content.contains(&0) {
+                return writeln!(stdout, "Binary file {} matches", listing.path);
+            }
+            for (number, line) in lines {
+                writeln!(
+                    stdout,
+                    "{}:{}:{}",
+                    listing.path,
+                    number + 1,
+                    String::from_utf8_lossy(line)
+                )?;
+            }
+            Ok(())
+        },
+    )
+    .map_err(context(format!("could not search {}", input)))?;
+    Ok(())
+}
+
+/// Writes the content of a single file of an archive to stdout, reading only the bundle holding it
+fn cat(args: &[String]) -> Result<(), CliError> {
+    let [input, path] = args else {
+        return Err(CliError::Usage(
+            "cat expects an archive and the path of a file in it".to_string(),
+        ));
+    };
+    let path = path.trim_start_matches("./");
+
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let mut reader = SeekableArchiveReader::new(io::BufReader::new(infile))
+        .map_err(context(format!("could not read {}", input)))?;
+    if reader
+        .find(path)
+        .is_some_and(|listing| listing.permissions & 0o170000 == 0o040000)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is a directory in {}", path, input),
+        )
+        .into());
+    }
+    let content = reader
+        .read_file(path)
+        .map_err(context(format!("could not read {} from {}", path, input)))?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no file {}", input, path),
+            )
+        })?;
+    // a pipeline that stops reading early, like `| head`, is not an error
+    match io::stdout().lock().write_all(&content) {
+        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Prints the listings of an archive whose path matches a glob, and the bundle holding each, read
+/// from its tables alone
+fn grep_path(args: &[String]) -> Result<(), CliError> {
+    let (input, pattern) = match args {
+        [input, pattern] if !input.starts_with('-') => (input, pattern),
+        _ => {
+            return Err(CliError::Usage(
+                "grep-path expects an archive and a pattern".to_string(),
+            ))
+        }
+    };
+
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let layout = ArchiveLayout::read_seekable(&mut io::BufReader::new(infile))
+        .map_err(context(format!("could not read {}", input)))?;
+    let matches = layout.find_matching(pattern);
+    for (listing, bundle) in &matches {
+        let bundle = bundle.map_or("-".to_string(), |index| format!("bundle {}", index));
+        println!("  {:<18} {}", bundle, listing.path);
+    }
+    println!("decaf: {} listings match {}", matches.len(), pattern);
+    Ok(())
+}
+
+/// A file or directory printed by `decaf tree`
+#[derive(Default)]
+struct TreeNode<'a> {
+    /// the size of the file, or of every file below the directory
+    size: u64,
+    directory: bool,
+    children: BTreeMap<&'a str, TreeNode<'a>>,
+}
+
+impl TreeNode<'_> {
+    /// Prints the children of the node one per line below `prefix`, connected like `tree` does,
+    /// and returns how many directories and files were printed
+    fn print_children(&self, prefix: &str, sizes: SizeFormat) -> (usize, usize) {
+        let (mut directories, mut files) = (0, 0);
+        for (i, (name, child)) in self.children.iter().enumerate() {
+            let last = i + 1 == self.children.len();
+            println!(
+                "{}{}{}{} ({})",
+                prefix,
+                if last { "└── " } else { "├── " },
+                name,
+                if child.directory { "/" } else { "" },
+                sizes.format(child.size)
+            );
+            if child.directory {
+                directories += 1;
+            } else {
+                files += 1;
+            }
+            let indent = if last { "    " } else { "│   " };
+            let (below_directories, below_files) =
+                child.print_children(&format!("{}{}", prefix, indent), sizes);
+            directories += below_directories;
+            files += below_files;
+        }
+        (directories, files)
+    }
+}
+
+/// Prints the listings of an archive as an indented tree with their sizes, read from its tables
+/// alone
+fn tree(args: &[String]) -> Result<(), CliError> {
+    let mut sizes = SizeFormat::default();
+    let mut paths: Vec<&str> = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--bytes" => sizes = SizeFormat::Bytes,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let input = match paths[..] {
+        [input] => input,
+        _ => return Err(CliError::Usage("tree expects an archive".to_string())),
+    };
+
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let layout = ArchiveLayout::read_seekable(&mut io::BufReader::new(infile))
+        .map_err(context(format!("could not read {}", input)))?;
+
+    // directories missing from the archive are implied by the paths below them
+    let mut root = TreeNode {
+        directory: true,
+        ..Default::default()
+    };
+    for listing in &layout.listings {
+        let mut node = &mut root;
+        node.size += listing.filesize;
+        for component in listing
+            .path
+            .split('/')
+            .filter(|component| !component.is_empty())
+        {
+            node = node.children.entry(component).or_default();
+            node.directory = true;
+            node.size += listing.filesize;
+        }
+        node.directory = listing.permissions & 0o170000 == 0o040000;
+    }
+
+    println!("{} ({})", input, sizes.format(root.size));
+    let (directories, files) = root.print_children("", sizes);
+    println!(
+        "\n{} director{}, {} file{}",
+        directories,
+        if directories == 1 { "y" } else { "ies" },
+        files,
+        if files == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
+
+/// Returns a callback which, if `recursive`, extracts every nested archive with `options` but
+/// for the path transforms meant for the outer archive, and otherwise points them out
+fn nested_archive_callback(
+    recursive: bool,
+    options: &ExtractOptions,
+    limits: &Limits,
+) -> NestedArchiveCallback {
+    let options = ExtractOptions {
+        strip_components: 0,
+        path_remaps: Vec::new(),
+        ..options.clone()
+    };
+    let limits = *limits;
+    NestedArchiveCallback::new(move |nested| {
+        if !recursive {
+            status!(
+                false,
+                "decaf: {} is itself an archive; --recursive-extract extracts it as well",
+                nested.path
+            );
+            return Ok(());
+        }
+        let mut options = options.clone();
+        options.on_nested_archive = Some(nested_archive_callback(true, &options, &limits));
+        extract_nested_archive(nested, &options, &limits).map_err(context(format!(
+            "could not extract nested archive {}",
+            nested.path
+        )))
+    })
+}
+
+/// Extracts a nested archive next to it, into a directory named like it without its extension,
+/// and removes it; it is left as it is if that directory already exists
+fn extract_nested_archive(
+    nested: &NestedArchive,
+    options: &ExtractOptions,
+    limits: &Limits,
+) -> io::Result<()> {
+    let archive_path = nested.extracted_path;
+    let name = archive_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let output = match [".df", ".dfs", ".tar.gz", ".tgz", ".tar"]
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension))
+        .filter(|stem| !stem.is_empty())
+    {
+        Some(stem) => archive_path.with_file_name(stem),
+        None => archive_path.with_file_name(format!("{}.extracted", name)),
+    };
+    if output.exists() {
+        eprintln!(
+            "decaf: warning: {} already exists, so the nested archive {} was not extracted",
+            output.display(),
+            nested.path
+        );
+        return Ok(());
+    }
+    status!(
+        false,
+        "decaf: extracting nested archive {} into {}",
+        nested.path,
+        output.display()
+    );
+
+    let mut reader = io::BufReader::new(File::open(archive_path)?);
+    let extracted = match nested.kind {
+        NestedArchiveKind::DecafStream => {
+            decaf::extract_stream(&mut reader, &output, limits, options)?;
+            None
+        }
+        NestedArchiveKind::Tar | NestedArchiveKind::TarGz => {
+            let mut converted = Vec::new();
+            let archive_options = ArchiveOptions {
+                limits: *limits,
+                ..Default::default()
+            };
+            decaf::from_tar(reader, &mut converted, &archive_options)?;
+            Some(ExtractedArchive::from_reader_with_options(
+                &mut converted.as_slice(),
+                limits,
+                options,
+            )?)
+        }
+        _ => Some(ExtractedArchive::from_reader_with_options(
+            &mut reader,
+            limits,
+            options,
+        )?),
+    };
+    if let Some(extracted) = extracted {
+        extracted.create_all_files_with_options(&output, options)?;
+    }
+    fs::remove_file(archive_path)
+}
+
+/// Creates a DeCAF archive or, through dtar, a deterministic tarball of a directory
+fn create(args: &[String]) -> Result<(), CliError> {
+    let mut format = "df";
+    let mut checksum_file = false;
+    let mut sizes = SizeFormat::default();
+    let mut clobber = Clobber::default();
+    let mut forwarded: Vec<String> = Vec::new();
+    let mut output_flag: Option<&str> = None;
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--format" => format = flag_value(&mut flags, arg)?,
+            "--output" | "-o" => {
+                let output = flag_value(&mut flags, arg)?;
+                output_flag = Some(output);
+                forwarded.extend([arg.clone(), output.to_string()]);
+            }
+            "--checksum-file" => {
+                checksum_file = true;
+                forwarded.push(arg.clone());
+            }
+            "--bytes" => {
+                sizes = SizeFormat::Bytes;
+                forwarded.push(arg.clone());
+            }
+            "--force" | "-f" => {
+                clobber = Clobber::Force;
+                forwarded.push(arg.clone());
+            }
+            "--no-clobber" | "-n" => {
+                clobber = Clobber::Never;
+                forwarded.push(arg.clone());
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => forwarded.push(arg.clone()),
+            path => {
+                paths.push(path);
+                forwarded.push(arg.clone());
+            }
+        }
+    }
+
+    let extension = match format {
+        "df" => return archive_or_extract(&forwarded),
+        "stream" if paths.len() > 1 || output_flag.is_some() => {
+            return Err(CliError::Usage(
+                "--format stream writes to stdout and takes no OUTPUT".to_string(),
+            ))
+        }
+        "stream" => {
+            forwarded.push("-".to_string());
+            return archive_or_extract(&forwarded);
+        }
+        "tar" | "tar.gz" => format,
+        _ => {
+            return Err(CliError::Usage(
+                "--format requires one of df, stream, tar, or tar.gz".to_string(),
+            ))
+        }
+    };
+    if let Some(flag) = forwarded.iter().find(|arg| {
+        arg.starts_with('-')
+            && !matches!(
+                arg.as_str(),
+                "--bytes"
+                    | "--no-config"
+                    | "--force"
+                    | "-f"
+                    | "--no-clobber"
+                    | "-n"
+                    | "--checksum-file"
+                    | "--output"
+                    | "-o"
+            )
+    }) {
+        return Err(CliError::Usage(format!(
+            "{} is not supported with --format {}",
+            flag, format
+        )));
+    }
+
+    let (input, output) = match (&paths[..], output_flag) {
+        (&[input], Some(output)) | (&[input, output], None) => (input, output.to_string()),
+        (&[input], None) => (
+            input,
+            format!("{}.{}", top_level_directory_name(input)?, extension),
+        ),
+        _ => {
+            return Err(CliError::Usage(format!(
+                "--format {} expects a directory and an optional output",
+                format
+            )))
+        }
+    };
+
+    if !clobber.allows(&output)? {
+        return Ok(());
+    }
+
+    let timer_overall = Instant::now();
+    status!(false, "decaf: creating {} for {}", extension, input);
+    let writing = interrupt::during(Activity::writing(&output, false));
+    let mut outfile = io::BufWriter::new(
+        File::create(&output).map_err(context(format!("could not create {}", output)))?,
+    );
+    let options = dtar::TarOptions::default();
+    match extension {
+        "tar" => dtar::create_tar_with_options(input, &mut outfile, &options),
+        _ => dtar::create_tar_gz_with_options(input, &mut outfile, &options),
+    }
+    .and_then(|()| outfile.flush())
+    .map_err(context(format!("could not archive {}", input)))?;
+    drop(writing);
+    let bytes = fs::metadata(&output)
+        .map_err(context(format!("could not read {}", output)))?
+        .len();
+    let output = publish(&output, checksum_file)
+        .map_err(context(format!("could not publish {}", output)))?;
+
+    status!(
+        false,
+        "decaf: archived {} as {} (wrote {}) in {:.2} sec",
+        input,
+        output,
+        sizes.format(bytes),
+        timer_overall.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
+
+fn repack(args: &[String]) -> Result<(), CliError> {
+    let mut options = load_config(args)?.archive_options();
+    let mut sizes = SizeFormat::default();
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--level" => options.compression_level = parse_level(&mut flags, arg)?,
+            "--bytes" => sizes = SizeFormat::Bytes,
+            "--bundle-size" => options.bundle_size = parse_bundle_size(&mut flags, arg)?,
+            "--compress-listings" => options.compress_listings = true,
+            "--section-checksums" => options.section_checksums = true,
+            "--header-checksum" => options.header_checksum = true,
+            "--split-checksum" => options.split_checksum = true,
+            "--footer" => options.footer = true,
+            "--seekable-frames" => {
+                options.seekable_frame_size = Some(parse_frame_size(&mut flags, arg)?)
+            }
+            "--zstd-checksums" => options.zstd_frame_checksums = true,
+            "--hash" => options.hash = parse_hash(&mut flags, arg)?,
+            "--no-temp-file" => options.atomic_write = false,
+            "--no-config" => {}
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+
+    let (input, output) = match paths[..] {
+        [input] => (input, input),
+        [input, output] => (input, output),
+        _ => {
+            return Err(CliError::Usage(
+                "repack expects an archive and an optional output".to_string(),
+            ))
+        }
+    };
+    options
+        .validate()
+        .map_err(|e| CliError::Usage(e.to_string()))?;
+    options.on_warning = warning_callback();
+    options.on_progress = progress_callback(false);
+
+    let timer_overall = Instant::now();
+    status!(false, "decaf: repacking archive {}", input);
+    let mut infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let mut repacked = Vec::new();
+    let bytes = decaf::repack(&mut infile, &mut repacked, &options)?.bytes_written;
+    write_output(output, &repacked, options.atomic_write)
+        .map_err(context(format!("could not write {}", output)))?;
+
+    status!(
+        false,
+        "decaf: repacked {} as {} (wrote {}) in {:.2} sec",
+        input,
+        output,
+        sizes.format(bytes as u64),
+        timer_overall.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
+
+fn manifest(args: &[String]) -> Result<(), CliError> {
+    let mut check = false;
+    let mut paths: Vec<&str> = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let read_manifest = |input: &str| -> Result<(HashAlgorithm, Vec<String>), CliError> {
+        let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+        let layout = ArchiveLayout::read_seekable(&mut io::BufReader::new(infile))
+            .map_err(context(format!("could not read {}", input)))?;
+        Ok((layout.hash, manifest_lines(&layout.listings, layout.hash)))
+    };
+
+    match (check, &paths[..]) {
+        (false, [input]) => {
+            println!("#mtree v2.0");
+            for line in read_manifest(input)?.1 {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        (true, [directory, input]) => {
+            let (hash, archived) = read_manifest(input)?;
+            let archived: BTreeSet<String> = archived.into_iter().collect();
+            let live = decaf::create_archive_from_directory(Path::new(directory))
+                .and_then(|archivable| archivable.checksummed_listings(hash))
+                .map_err(context(format!("could not index {}", directory)))?;
+            let live: BTreeSet<String> = manifest_lines(&live, hash).into_iter().collect();
+            if archived == live {
+                println!("decaf: {} matches {}", directory, input);
+                return Ok(());
+            }
+            for line in archived.difference(&live) {
+                println!("- {}", line);
+            }
+            for line in live.difference(&archived) {
+                println!("+ {}", line);
+            }
+            Err(io::Error::from(ArchiveError::Verification(format!(
+                "{} does not match {}",
+                directory, input
+            )))
+            .into())
+        }
+        _ => Err(CliError::Usage(
+            "manifest expects an archive, or with --check a directory and an archive".to_string(),
+        )),
+    }
+}
+
+/// Returns a line of an mtree manifest for every listing, sorted by path
+///
+/// Files and links have their size and the checksum of their content, named after the hash
+/// algorithm of the archive like `xxh3digest`, and directories neither.
+fn manifest_lines(listings: &[ExtractedListing], hash: HashAlgorithm) -> Vec<String> {
+    let mut lines: Vec<(&str, String)> = listings
+        .iter()
+        .map(|listing| {
+            let kind = match listing.permissions & 0o170000 {
+                0o040000 => "dir",
+                0o120000 => "link",
+                _ => "file",
+            };
+            let mut line = format!(
+                "./{} type={} mode={:04o}",
+                mtree_escape(&listing.path),
+                kind,
+                listing.permissions & 0o7777
+            );
+            if kind != "dir" {
+                line += &format!(
+                    " size={} {}digest={:016x}",
+                    listing.filesize,
+                    hash.name(),
+                    listing.content_checksum
+                );
+            }
+            (&*listing.path, line)
+        })
+        .collect();
+    lines.sort();
+    lines.into_iter().map(|(_, line)| line).collect()
+}
+
+/// Escapes a path for an mtree manifest, where whitespace, backslashes, `#` and non-ASCII bytes
+/// are written as backslash-prefixed octal
+fn mtree_escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' && byte != b'#' {
+            escaped.push(byte as char);
+        } else {
+            escaped += &format!("\\{:03o}", byte);
+        }
+    }
+    escaped
+}
+
+fn status(args: &[String]) -> Result<(), CliError> {
+    let (input, directory) = match args {
+        [input, directory] if !input.starts_with('-') && !directory.starts_with('-') => {
+            (input, directory)
+        }
+        _ => {
+            return Err(CliError::Usage(
+                "status expects an archive and a directory".to_string(),
+            ))
+        }
+    };
+
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let layout = ArchiveLayout::read_seekable(&mut io::BufReader::new(infile))
+        .map_err(context(format!("could not read {}", input)))?;
+    let comparison = decaf::compare(&layout, directory)
+        .map_err(context(format!("could not compare {}", directory)))?;
+    if comparison.is_clean() {
+        println!(
+            "decaf: {} matches {} ({} listings)",
+            directory,
+            input,
+            comparison.matched.len()
+        );
+        return Ok(());
+    }
+
+    for path in &comparison.modified {
+        println!("  modified           {}", path);
+    }
+    for path in &comparison.missing {
+        println!("  missing            {}", path);
+    }
+    for path in &comparison.extra {
+        println!("  extra              {}", path);
+    }
+    Err(io::Error::from(ArchiveError::Verification(format!(
+        "{} differs from {}",
+        directory, input
+    )))
+    .into())
+}
+
+fn watch(args: &[String]) -> Result<(), CliError> {
+    let config = load_config(args)?;
+    let mut options = config.archive_options();
+    options.excludes = config.exclude.unwrap_or_default();
+    let mut debounce = Duration::from_secs(2);
+    let mut sizes = SizeFormat::default();
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--level" => options.compression_level = parse_level(&mut flags, arg)?,
+            "--bundle-size" => options.bundle_size = parse_bundle_size(&mut flags, arg)?,
+            "--exclude" => options
+                .excludes
+                .push(flag_value(&mut flags, arg)?.to_string()),
+            "--debounce" => {
+                let seconds: f64 = flag_value(&mut flags, arg)?
+                    .parse()
+                    .ok()
+                    .filter(|seconds: &f64| seconds.is_finite() && *seconds >= 0.0)
+                    .ok_or_else(|| {
+                        CliError::Usage("--debounce requires a number of seconds".to_string())
+                    })?;
+                debounce = Duration::from_secs_f64(seconds);
+            }
+            "--bytes" => sizes = SizeFormat::Bytes,
+            "--no-config" => {}
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let (input, output) = match paths[..] {
+        [input, output] => (input, output),
+        _ => {
+            return Err(CliError::Usage(
+                "watch expects a directory and an output archive".to_string(),
+            ))
+        }
+    };
+    options
+        .validate()
+        .map_err(|e| CliError::Usage(e.to_string()))?;
+    options.on_warning = warning_callback();
+    options.on_progress = progress_callback(false);
+
+    // event paths are under the canonical root; the archive may be inside of the tree, so it and
+    // its temporary file are neither archived nor treated as changes
+    let root = fs::canonicalize(input).map_err(context(format!("could not open {}", input)))?;
+    let output_path = Path::new(output);
+    let output_directory = match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output_path = fs::canonicalize(output_directory)
+        .map_err(context(format!(
+            "could not open {}",
+            output_directory.display()
+        )))?
+        .join(output_path.file_name().unwrap_or_default());
+    let mut temporary_path = output_path.clone().into_os_string();
+    temporary_path.push(".tmp");
+    let ignored = [output_path, temporary_path.into()];
+    let is_ignored = |path: &Path| {
+        ignored.iter().any(|ignored| ignored == path)
+            || path
+                .strip_prefix(&root)
+                .ok()
+                .and_then(Path::to_str)
+                .is_some_and(|path| {
+                    options
+                        .excludes
+                        .iter()
+                        .any(|pattern| is_excluded(path, pattern))
+                })
+    };
+    let filter = |archivable: &mut ArchivableArchive| {
+        archivable
+            .listings
+            .retain(|listing| !is_ignored(&root.join(&*listing.relative_path)));
+    };
+    let archive = |archivable: &ArchivableArchive| -> io::Result<()> {
+        let timer_overall = Instant::now();
+        let writing = interrupt::during(Activity::writing(output, options.atomic_write));
+        let written = archivable.archive_to_file_with_options(output, &options)?;
+        drop(writing);
+        status!(
+            false,
+            "decaf: archived {} as {} (wrote {}) in {:.2} sec",
             input,
             output,
+            sizes.format(written.bytes_written as u64),
+            timer_overall.elapsed().as_secs_f32()
+        );
+        Ok(())
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+    watcher
+        .watch(&root, notify::RecursiveMode::Recursive)
+        .map_err(|e| io::Error::other(format!("could not watch {}: {}", input, e)))?;
+    let mut archivable = decaf::create_archive_from_directory(&root)
+        .map_err(context(format!("could not index {}", input)))?;
+    filter(&mut archivable);
+    archive(&archivable).map_err(context(format!("could not write {}", output)))?;
+    status!(false, "decaf: watching {} for changes", input);
+
+    loop {
+        // wait for a change, and then for the tree to be quiet for the debounce period
+        let mut changed = BTreeSet::new();
+        let mut next = receiver.recv();
+        loop {
+            match next {
+                // archiving reads the tree, which must not count as a change
+                Ok(Ok(event)) if event.kind.is_access() => {}
+                Ok(Ok(event)) => {
+                    changed.extend(event.paths.into_iter().filter(|path| !is_ignored(path)))
+                }
+                Ok(Err(error)) => eprintln!("decaf: warning: {}", error),
+                Err(_) => return Err(io::Error::other("the watcher stopped unexpectedly").into()),
+            }
+            next = match receiver.recv_timeout(debounce) {
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                next => next.map_err(|_| mpsc::RecvError),
+            };
+        }
+        if changed.is_empty() {
+            continue;
+        }
+
+        // files vanishing while they are indexed or archived are picked up with the next change
+        let changed_count = changed.len();
+        match archivable.refresh(&root, changed) {
+            Ok(true) => filter(&mut archivable),
+            Ok(false) => {}
+            Err(error) => {
+                eprintln!("decaf: warning: could not index {}: {}", input, error);
+                continue;
+            }
+        }
+        status!(false, "decaf: changed paths: {}", changed_count);
+        if let Err(error) = archive(&archivable) {
+            eprintln!("decaf: warning: could not write {}: {}", output, error);
+        }
+    }
+}
+
+fn verify(args: &[String]) -> Result<(), CliError> {
+    let mut threads = None;
+    let mut paths: Vec<&str> = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--threads" | "-j" => {
+                threads = Some(
+                    flag_value(&mut flags, arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&threads| threads > 0)
+                        .ok_or_else(|| {
+                            CliError::Usage("--threads requires a number of threads".to_string())
+                        })?,
+                )
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let input = match paths[..] {
+        [input] => input,
+        _ => return Err(CliError::Usage("verify expects an archive".to_string())),
+    };
+
+    let timer_overall = Instant::now();
+    let infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let report = verify_archive(&mut io::BufReader::new(infile), threads)?;
+    if report.is_ok() {
+        status!(
+            false,
+            "decaf: verified {} in {:.2} sec",
+            input,
             timer_overall.elapsed().as_secs_f32()
         );
+        return Ok(());
+    }
+
+    if !report.archive_checksum_matches {
+        println!("  archive checksum   mismatch");
+    }
+    for bundle in &report.corrupt_bundles {
+        println!("  corrupt bundle     {}", bundle);
+    }
+    for path in &report.corrupt_listings {
+        println!("  corrupt listing    {}", path);
     }
+    Err(io::Error::from(ArchiveError::Verification(format!("{} is corrupt", input))).into())
 }
 
-fn usage() {
-    print!("decaf {}: {}", env! {"CARGO_PKG_VERSION"}, USAGE,);
+fn index(args: &[String]) -> Result<(), CliError> {
+    let (input, output) = match args {
+        [input] => (input, format!("{}.idx", input)),
+        [input, output] => (input, output.clone()),
+        _ => {
+            return Err(CliError::Usage(
+                "index expects an archive and an optional output".to_string(),
+            ))
+        }
+    };
+
+    let mut infile = io::BufReader::new(
+        File::open(input).map_err(context(format!("could not open {}", input)))?,
+    );
+    let index = ArchiveLayout::read_seekable(&mut infile)
+        .map_err(context(format!("could not read {}", input)))?
+        .index();
+    write_output(&output, &index.encode(), true)
+        .map_err(context(format!("could not write {}", output)))?;
+    status!(
+        false,
+        "decaf: indexed {} listings of {} in {}",
+        index.listings.len(),
+        input,
+        output
+    );
+    Ok(())
+}
+
+/// Converts between DeCAF archives and tarballs or zip archives, as told by the extensions
+fn convert(args: &[String]) -> Result<(), CliError> {
+    let mut options = ArchiveOptions::default();
+    let mut paths: Vec<&str> = Vec::new();
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            // tarballs and zip archives are held in memory until they are written, so both bound
+            // the content read from them
+            "--max-size" | "--max-memory" => {
+                let size = parse_size(flag_value(&mut flags, arg)?)
+                    .ok_or_else(|| CliError::Usage(format!("{} requires a size such as 2G", arg)))?
+                    as u64;
+                let limit = &mut options.limits.max_uncompressed_size;
+                *limit = Some(limit.map_or(size, |limit| limit.min(size)));
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Usage(format!("unknown option {}", flag)))
+            }
+            path => paths.push(path),
+        }
+    }
+    let [input, output] = paths[..] else {
+        return Err(CliError::Usage(
+            "convert expects an input and an output".to_string(),
+        ));
+    };
+    let is_tarball = |path: &str| {
+        [".tar", ".tar.gz", ".tgz"]
+            .iter()
+            .any(|e| path.ends_with(e))
+    };
+
+    let mut converted = io::Cursor::new(Vec::new());
+    // errors of the archive itself keep their exit code, e.g. 6 for exceeding --max-size
+    let read_context = |error: io::Error| match error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<ArchiveError>())
+    {
+        Some(_) => error,
+        None => context(format!("could not convert {}", input))(error),
+    };
+    // a tarball piped in, e.g. from a download, is converted as it arrives
+    if input == "-" && (output.ends_with(".df") || output.ends_with(".dfs")) {
+        let stdin = io::stdin().lock();
+        match output.ends_with(".dfs") {
+            true => decaf::from_tar_to_stream(stdin, &mut converted, &options),
+            false => decaf::from_tar(stdin, &mut converted, &options),
+        }
+        .map_err(read_context)?;
+        write_output(output, converted.get_ref(), true)
+            .map_err(context(format!("could not write {}", output)))?;
+        status!(false, "decaf: converted stdin to {}", output);
+        return Ok(());
+    }
+
+    let mut infile = io::BufReader::new(
+        File::open(input).map_err(context(format!("could not open {}", input)))?,
+    );
+    match (input.ends_with(".df"), output.ends_with(".df")) {
+        (false, true) if is_tarball(input) => {
+            decaf::from_tar(infile, &mut converted, &options).map_err(read_context)?;
+        }
+        (false, false) if is_tarball(input) && output.ends_with(".dfs") => {
+            decaf::from_tar_to_stream(infile, &mut converted, &options).map_err(read_context)?;
+        }
+        (false, true) if input.ends_with(".zip") => {
+            decaf::from_zip(infile, &mut converted, &options).map_err(read_context)?;
+        }
+        (true, false) if output.ends_with(".tar") => {
+            dtar::decaf_to_tar(&mut infile, &mut converted).map_err(read_context)?
+        }
+        (true, false) if output.ends_with(".zip") => {
+            decaf::to_zip(&mut infile, &mut converted).map_err(read_context)?
+        }
+        _ => return Err(CliError::Usage(
+            "convert turns a .tar, .tar.gz, .tgz or .zip into a .df, a tarball into a .dfs, or a \
+                 .df into a .tar or .zip"
+                .to_string(),
+        )),
+    }
+    write_output(output, converted.get_ref(), true)
+        .map_err(context(format!("could not write {}", output)))?;
+    status!(false, "decaf: converted {} to {}", input, output);
+    Ok(())
+}
+
+fn upgrade(args: &[String]) -> Result<(), CliError> {
+    let (input, output) = match args {
+        [input] => (input, input),
+        [input, output] => (input, output),
+        _ => {
+            return Err(CliError::Usage(
+                "upgrade expects an archive and an optional output".to_string(),
+            ))
+        }
+    };
+
+    let mut infile = File::open(input).map_err(context(format!("could not open {}", input)))?;
+    let archive = extract_from_reader(&mut infile)?;
+    if archive.revision() == FormatRevision::Current && input == output {
+        status!(false, "decaf: {} is already a current archive", input);
+        return Ok(());
+    }
+
+    let mut upgraded = Vec::new();
+    archive.repack_to_writer(&mut upgraded, &ArchiveOptions::default())?;
+    write_output(output, &upgraded, true)
+        .map_err(context(format!("could not write {}", output)))?;
+    status!(
+        false,
+        "decaf: upgraded {} ({:?}) to {}",
+        input,
+        archive.revision(),
+        output
+    );
+    Ok(())
+}
+
+fn spec(args: &[String]) -> Result<(), CliError> {
+    if !args.is_empty() {
+        return Err(CliError::Usage("spec takes no arguments".to_string()));
+    }
+
+    let structures: [(&str, usize, &[format::Field]); 7] = [
+        ("header", format::Header::SIZE, &format::Header::FIELDS),
+        (
+            "section checksums (following the header if it says so)",
+            format::SectionChecksums::SIZE,
+            &format::SectionChecksums::FIELDS,
+        ),
+        (
+            "header checksum (following the header and its section checksums if it says so)",
+            format::HeaderChecksum::SIZE,
+            &format::HeaderChecksum::FIELDS,
+        ),
+        (
+            "listing entry (followed by its path)",
+            format::ListingEntry::SIZE,
+            &format::ListingEntry::FIELDS,
+        ),
+        (
+            "bundle entry",
+            format::BundleEntry::SIZE,
+            &format::BundleEntry::FIELDS,
+        ),
+        (
+            "stream frame header (followed by its listing entries and bundle)",
+            format::FrameHeader::SIZE,
+            &format::FrameHeader::FIELDS,
+        ),
+        (
+            "path index header (followed by its listing entries and bundle entries)",
+            format::IndexHeader::SIZE,
+            &format::IndexHeader::FIELDS,
+        ),
+    ];
+    println!("all integers are little-endian");
+    for (name, size, fields) in structures {
+        println!("\n{} ({} bytes)", name, size);
+        println!("  offset  size  field                 description");
+        for field in fields {
+            println!(
+                "  {:>6}  {:>4}  {:<20}  {}",
+                field.offset, field.size, field.name, field.description
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parses a permission mode given as `preserve`, `exec`, or octal `FILE:DIR` modes like `644:755`
+fn parse_permission_mode(mode: &str) -> Option<PermissionMode> {
+    match mode {
+        "preserve" => Some(PermissionMode::Preserve),
+        "exec" => Some(PermissionMode::ExecutableOnly),
+        _ => {
+            let (file, directory) = mode.split_once(':')?;
+            Some(PermissionMode::Fixed {
+                file: u32::from_str_radix(file, 8).ok()?,
+                directory: u32::from_str_radix(directory, 8).ok()?,
+            })
+        }
+    }
+}
+
+fn parse_frame_size<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<usize, CliError> {
+    parse_size(flag_value(flags, flag)?)
+        .filter(|&size| size > 0)
+        .ok_or_else(|| {
+            CliError::Usage("--seekable-frames requires a size such as 256K".to_string())
+        })
+}
+
+/// Parses the octal permission bits following `flag`, such as `644` or `7022`
+fn parse_mode<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<u32, CliError> {
+    u32::from_str_radix(flag_value(flags, flag)?, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| CliError::Usage(format!("{} requires octal permission bits", flag)))
+}
+
+fn parse_level<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<i32, CliError> {
+    flag_value(flags, flag)?
+        .parse()
+        .map_err(|_| CliError::Usage("--level requires an integer compression level".to_string()))
+}
+
+fn parse_hash<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<HashAlgorithm, CliError> {
+    match flag_value(flags, flag)? {
+        "xxh3" => Ok(HashAlgorithm::Xxh3),
+        "crc32" => Ok(HashAlgorithm::Crc32),
+        "blake3" => Ok(HashAlgorithm::Blake3),
+        _ => Err(CliError::Usage(
+            "--hash requires one of xxh3, crc32, or blake3".to_string(),
+        )),
+    }
+}
+
+fn parse_bundle_size<'a, I: Iterator<Item = &'a String>>(
+    flags: &mut I,
+    flag: &str,
+) -> Result<usize, CliError> {
+    parse_size(flag_value(flags, flag)?)
+        .ok_or_else(|| CliError::Usage("--bundle-size requires a size such as 64M".to_string()))
+}
+
+/// Parses a byte size with an optional binary unit suffix, e.g. `512K`, `64M` or `1G`
+pub(crate) fn parse_size(size: &str) -> Option<usize> {
+    let (digits, multiplier) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 1024),
+        (i, 'M' | 'm') => (&size[..i], 1024 * 1024),
+        (i, 'G' | 'g') => (&size[..i], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+fn usage() -> String {
+    format!("decaf {}: {}", env! {"CARGO_PKG_VERSION"}, USAGE)
 }
 
 static USAGE: &str = "manipulate DeCAF archives
 
 Usage: decaf <ARCHIVE | DIRECTORY> [OUTPUT]
+       decaf <DIRECTORY | FILE>... -o OUTPUT
+       decaf analyze <ARCHIVE> [--top N]
+       decaf cat <ARCHIVE> <PATH>
+       decaf convert <INPUT> <OUTPUT> [--max-size SIZE] [--max-memory SIZE]
+       decaf create <DIRECTORY> [OUTPUT] [--format df|stream|tar|tar.gz]
+       decaf create <DIRECTORY | FILE>... -o OUTPUT
+       decaf grep <ARCHIVE> <TEXT> [-i, --ignore-case] [-l, --files-with-matches]
+       decaf grep-path <ARCHIVE> <PATTERN>
+       decaf index <ARCHIVE> [OUTPUT]
+       decaf manifest [--check DIRECTORY] <ARCHIVE>
+       decaf repack <ARCHIVE> [OUTPUT] [--level LEVEL] [--bundle-size SIZE] [--compress-listings]
+                    [--section-checksums] [--header-checksum] [--split-checksum] [--footer]
+                    [--seekable-frames SIZE] [--zstd-checksums] [--hash ALGORITHM]
+       decaf status <ARCHIVE> <DIRECTORY>
+       decaf tree <ARCHIVE> [--bytes]
+       decaf upgrade <ARCHIVE> [OUTPUT]
+       decaf verify <ARCHIVE> [-j, --threads N]
+       decaf watch <DIRECTORY> <OUTPUT> [--debounce SECONDS]
+       decaf spec
 
 Arguments:
-    <ARCHIVE | DIRECTORY>  Path to the input archive (.df) or directory
-    [OUTPUT]               Optional path for output file or directory
+    <ARCHIVE | DIRECTORY>  Path to the input archive (.df) or directory, or - to extract a stream
+                           from stdin into OUTPUT (default: the current directory)
+    [OUTPUT]               Optional path for output file or directory, or - to write a DIRECTORY
+                           to stdout as a stream, e.g. `decaf DIR - | ssh HOST decaf - DIR`;
+                           {hash} in the name of an archive is replaced with the first 16 hex
+                           digits of its SHA-256 digest, e.g. site-{hash}.df
+    <TEXT>                 Fixed string searched for by grep in every line of every file, which
+                           is printed as PATH:LINE:TEXT, or once as PATH with -l
+    <PATTERN>              Glob matched by grep-path against whole listing paths, where * and ?
+                           match within a path component and ** across them, e.g. '**/*.so'
+
+Options:
+    --permissions MODE     Normalize listing permissions when archiving or unarchiving; MODE is
+                           preserve (default), exec (only keep the execute bit, like git), or
+                           octal FILE:DIR modes such as 644:755
+    --stats                Print a summary table of the archived or unarchived listings
+    --bytes                Print sizes as exact numbers of bytes instead of KiB, MiB, GiB
+    --no-config            Ignore the configuration files
+    -q, --quiet            Print nothing but errors and what the command is asked for, such as
+                           the --stats table or the output of tree and grep
+    -v, --verbose          Also print every listing as it is archived or extracted, like tar -v
+    --progress             Show how much of the content is archived or extracted so far, and an
+                           estimate of the time left, on a line of stderr updated as it goes
+    --limit-rate SIZE      Limit reading source files or writing extracted files to SIZE bytes
+                           per second, e.g. 50M, so that disks aren't saturated
+    --duplicates POLICY    What happens to listings whose path is already taken, e.g. after
+                           --strip-components: error (default), dedupe (keep the first), or
+                           rename (to NAME~1.EXT and so on)
+    -f, --force            Overwrite an existing OUTPUT file or extract into a non-empty OUTPUT
+                           directory without asking
+    -n, --no-clobber       Skip the command if OUTPUT already exists instead of asking
+    --max-size SIZE        Fail if the archived or unarchived files add up to more than SIZE
+                           bytes, e.g. 2G, to guard against decompression bombs
+    -j, --threads N        Compress, decompress or verify bundles and write extracted files
+                           with N threads (default: one per core, up to 8; verify uses every
+                           core)
+    --low-memory           Keep memory use bounded for large archives, e.g. in containers with
+                           256M of memory: read one file and compress one bundle at a time,
+                           spilling compressed bundles to a temporary file, and extract in one
+                           pass holding only one bundle at a time
+    --tempdir DIR          Create temporary files, like those spilled by --low-memory, in DIR
+                           instead of $TMPDIR or /tmp, e.g. when /tmp is a small tmpfs
+    --file-flags           Archive whether files are immutable or append-only (chattr +i and +a
+                           on Linux, chflags uchg and uappnd on the BSDs and macOS), and set
+                           those flags again when unarchiving, which usually takes root
+
+Archiving options:
+    -o, --output OUTPUT    Write the archive to OUTPUT; several paths given with it are archived
+                           side by side, each under its own name like tar does, e.g.
+                           `decaf create src docs README.md -o project.df`
+    -T, --files-from LIST  Archive only the paths listed in LIST (- for stdin), relative to DIRECTORY
+    -0, --null             Paths in the --files-from list are NUL-delimited, as from `find -print0`
+    --sort ORDER           Order listings by size (default, as in the specification), path,
+                           extension (groups similar files for better compression), or walk
+                           (the order of the --files-from list)
+    --top-level-dir        Store listings under the name of DIRECTORY, so unarchiving into the
+                           current directory recreates it instead of spreading its contents
+    --prefix NAME          Store listings under the directory NAME
+    --exclude PATTERN      Leave out paths matching PATTERN, where * and ? match within a path
+                           component; patterns without a / match any component, e.g. *.o
+    --level LEVEL          zstd compression level (default: 3)
+    --bundle-size SIZE     Target uncompressed bundle size of at least 64K, e.g. 512K, 64M
+                           (default: 10M)
+    --skip-unreadable      Leave files that can't be read out of the archive with a warning
+                           instead of failing
+    --compress-listings    Compress the listing block, which saves space in archives with many
+                           files
+    --section-checksums    Store checksums of the listing block and bundle table after the
+                           header, so that readers fetching only parts of the archive can verify
+                           the tables they use
+    --header-checksum      Store a checksum of the header and bundle table after the header, so
+                           that tools listing many archives can trust their counts and sizes
+                           without reading them whole
+    --split-checksum       End the archive checksum with the tables, leaving the bundles to their
+                           own checksums, so that readers fetching only parts of the archive can
+                           verify it
+    --footer               Write the tables after the bundles, followed by a footer, so that the
+                           archive is written in a single pass; with - as OUTPUT, the archive is
+                           written to stdout instead of a stream
+    --seekable-frames SIZE Compress bundles as independent zstd frames of SIZE bytes, e.g. 256K,
+                           with a seek table, so that reading a single file from a large bundle
+                           decompresses only the frames holding it
+    --zstd-checksums       Store a checksum and the uncompressed size in every zstd frame, so
+                           that zstd tools can validate bundles without decaf
+    --hash ALGORITHM       Checksum the archive with xxh3 (default), crc32, which CPUs with
+                           CRC instructions compute fastest, or blake3, which is stronger and
+                           hashed on every core; streams are always checksummed with xxh3
+    --no-temp-file         Write the archive directly to OUTPUT instead of to OUTPUT.tmp, which
+                           is renamed to OUTPUT once the archive is complete
+    --structure-only       Archive only directories, paths and permissions, storing files as
+                           empty, e.g. to recreate a skeleton of the tree or audit its layout
+    --checksum-file        Write the SHA-256 digest of the archive to OUTPUT.sha256, which
+                           `sha256sum -c` verifies without decaf; also taken by create
+
+Extraction options:
+    --strip-components N   Remove N leading path components from every extracted listing
+    --transform FROM=TO    Rewrite listing paths starting with FROM to start with TO instead
+    --no-verify            Skip all checksum verification for speed; corruption goes unnoticed
+    --paranoid             Also read every file back after writing it and verify it again
+    --case-collisions POLICY
+                           What happens to listings whose path differs only by case from an
+                           earlier one when extracting to a case-insensitive filesystem, as on
+                           macOS and Windows: warn (default), error, rename (to NAME~1.EXT and
+                           so on), or ignore
+    --assume-case-insensitive
+                           Look for case collisions even on a case-sensitive filesystem, e.g.
+                           when the tree will be copied to macOS later
+    --no-preserve-permissions
+                           Leave extracted listings with the default permissions of new files
+    --lenient-permissions  Warn instead of failing where permissions can't be set, e.g. on FAT
+    --file-mode MODE       Give every extracted file the octal permissions MODE, e.g. 644
+    --dir-mode MODE        Give every extracted directory the octal permissions MODE, e.g. 755
+    --mode-mask MASK       Clear the octal permission bits MASK from every extracted listing, like
+                           a umask, e.g. 7022 so that nothing setuid, setgid, sticky or writable
+                           by others than its owner is created, whatever the archive holds
+    --preserve-special-bits
+                           Keep the setuid, setgid and sticky bits of extracted listings, which
+                           are cleared by default as archives can carry setuid binaries
+    --skip-existing        Keep files that already exist in OUTPUT, with a warning, instead of
+                           overwriting them; OUTPUT may then be a non-empty directory
+    --max-memory SIZE      Fail instead of holding more than SIZE bytes of decompressed bundles
+                           in memory, e.g. 512M; --low-memory holds one bundle at a time
+    --placeholders         Create every file empty, with its permissions, from the listings
+                           alone without decompressing any bundle, e.g. to check a layout
+    --recursive-extract    Also extract every archive found among the extracted files, DeCAF
+                           archives and streams as well as tarballs, into a directory named like
+                           it without its extension, and remove it; nested archives are only
+                           pointed out otherwise
+
+Analyze options:
+    --top N                List the N listings taking the most compressed space (default: 10),
+                           next to the compression ratio of every bundle and file extension
+
+Create options:
+    --format FORMAT        Write a DeCAF archive (df, the default, which takes the archiving
+                           options), a DeCAF stream to stdout (stream, like an OUTPUT of -),
+                           which is written and read in one pass, or a deterministic tarball
+                           (tar or tar.gz) using dtar, whose modification times are taken from
+                           $SOURCE_DATE_EPOCH or zero
+
+Manifest options:
+    --check DIRECTORY      Compare DIRECTORY against the manifest of ARCHIVE, printing the lines
+                           only in the archive with - and those only in DIRECTORY with +
+
+Watch options:
+    --debounce SECONDS     Wait until DIRECTORY has not changed for SECONDS (default: 2) before
+                           archiving it again; only the changed, new or removed paths are
+                           indexed again rather than the whole tree. Also takes --level,
+                           --bundle-size and --exclude
+
+Repack options:
+    --level LEVEL          zstd compression level for the rewritten bundles (default: 3)
+    --bundle-size SIZE     Target uncompressed bundle size of at least 64K, e.g. 512K, 64M
+                           (default: 10M)
+    --compress-listings    Compress the listing block of the rewritten archive
+    --section-checksums    Store checksums of the tables of the rewritten archive
+    --header-checksum      Store a checksum of the header and bundle table of the rewritten archive
+    --split-checksum       End the archive checksum of the rewritten archive with its tables
+    --footer               Write the tables of the rewritten archive after its bundles
+    --seekable-frames SIZE Compress the rewritten bundles as seekable zstd frames of SIZE bytes
+    --zstd-checksums       Store a checksum and the uncompressed size in every rewritten zstd frame
+    --hash ALGORITHM       Checksum the rewritten archive with xxh3, crc32 or blake3
+    --no-temp-file         Write the archive directly to OUTPUT instead of through OUTPUT.tmp
+
+Convert options:
+    --max-size SIZE        Fail if the files of a tarball or zip archive add up to more than SIZE
+                           bytes, e.g. 2G; they are held in memory until the DeCAF archive is
+                           written, so this bounds the memory a tarball piped to stdin takes
+    --max-memory SIZE      The same as --max-size, as the files are held in memory
+
+Convert:
+    convert turns a tarball (.tar, .tar.gz or .tgz) or a zip archive (.zip) into a DeCAF archive,
+    keeping permissions and links to files inside of it, or a DeCAF archive into a tarball (.tar)
+    or a zip archive (.zip); the format of OUTPUT is chosen by its extension. A tarball can also
+    become a DeCAF stream (.dfs), and is read from stdin if INPUT is -, gzipped or not, so that an
+    upstream release can be mirrored as it downloads: curl -L URL | decaf convert - release.df
+
+Index:
+    index writes the listings of ARCHIVE ordered by path, with its bundle table, to OUTPUT
+    (default: ARCHIVE.idx), so that package managers and the like can look files up and fetch only
+    the bundles holding them without reading the listing block of the archive again
+
+Configuration:
+    Defaults for --level, --bundle-size, --exclude and --threads are read from
+    ~/.config/decaf/config.toml (or $XDG_CONFIG_HOME/decaf/config.toml) and then from .decafrc in
+    the current directory, each overriding the keys set before it; flags override both, and
+    --exclude adds to the configured patterns. For example:
+        level = 19
+        bundle-size = \"64M\"
+        exclude = [\"target\", \"*.o\"]
+        threads = 4
+
+Exit codes:
+    0    success
+    2    the command line could not be understood
+    3    reading or writing files failed
+    4    the archive is corrupt
+    5    the archive failed integrity verification
+    6    the archive exceeds --max-size
+    130  interrupted with ctrl-c; a partial archive is removed, while a partial extraction is
+         left in OUTPUT
 
 Examples:
     Archiving:
@@ -88,6 +2347,10 @@ Examples:
             $ decaf my-folder/ output.df
         This will create an archive from `my-folder` as `output.df`.
 
+        Creating an archive from a list of files:
+            $ find . -name '*.rs' -print0 | decaf --files-from - -0 . sources.df
+        This will create an archive `sources.df` containing only the listed files.
+
     Unarchiving:
         Unarchiving to a directory:
             $ decaf photos.df
@@ -97,5 +2360,29 @@ Examples:
             $ decaf photos.df pictures/
         This will create a directory `pictures/` from the archive `photos.df` in the current directory.
 
+        Unarchiving a subtree straight into a deployment root:
+            $ decaf artifacts.df /srv/www --transform build/output=
+        This will extract `build/output/index.html` as `/srv/www/index.html`.
+
+    Repacking:
+        Recompressing an existing archive in place:
+            $ decaf repack photos.df --level 19 --bundle-size 64M
+        This will rewrite `photos.df` with new compression settings without its source directory.
+
+    Upgrading:
+        Rewriting an archive from a pre-release format revision:
+            $ decaf upgrade old.df
+        This will rewrite `old.df` in place as a current archive.
+
+    Reading a single file:
+        Printing a file of an archive:
+            $ decaf cat release.df config/app.json | jq .version
+        This will decompress only the bundle holding `config/app.json` and write it to stdout.
+
+    Inspecting the format:
+        Printing the binary layout of archives:
+            $ decaf spec
+        This will print the offset and size of every field of the archive structures.
+
 Copyright (c) The DeCAF Project Developers, 2024. Licensed MIT OR Apache-2.0 OR BSD-2-Clause.
 ";
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use std::{env, process::exit};
+
+use decaf::{
+    ArchiveError, ArchiveHandle, SeekableArchiveReader, DEFAULT_CACHED_BUNDLES, DEFAULT_CACHE_BYTES,
+};
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, Generation, INodeNo, LockOwner,
+    MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+
+const USAGE: &str = "\
+Usage: decaf-fuse [OPTIONS] <ARCHIVE> <MOUNTPOINT>
+
+Mounts ARCHIVE read-only at MOUNTPOINT until it is unmounted, e.g. with `fusermount -u`.
+Bundles are decompressed on demand as files are read.
+
+Options:
+  --cache-bundles N  Keep up to N decompressed bundles in memory (default: 16)
+  --cache-bytes N    Keep up to N bytes of decompressed bundles in memory (default: 256 MiB)
+  --auto-unmount     Unmount when the process exits
+  -h, --help         Print this help
+";
+
+// the archive never changes while mounted
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A file or directory of the mounted tree; the inode number of a node is its index plus one
+struct Node {
+    parent: INodeNo,
+    /// index of the listing this node was read from; directories only implied by the paths of
+    /// other listings have none
+    listing: Option<usize>,
+    is_dir: bool,
+    children: Vec<(String, INodeNo)>,
+}
+
+struct DecafFs {
+    nodes: Vec<Node>,
+    archive: Mutex<ArchiveHandle<File>>,
+    uid: u32,
+    gid: u32,
+    mtime: SystemTime,
+}
+
+impl DecafFs {
+    /// Builds the tree of the listings of `reader`, failing if a path is both a file and a
+    /// directory, e.g. a file with other listings under it, as it could be neither read nor listed
+    fn new(
+        reader: SeekableArchiveReader<File>,
+        cache: (usize, u64),
+        metadata: &std::fs::Metadata,
+    ) -> Result<DecafFs, io::Error> {
+        let mut nodes = vec![Node {
+            parent: INodeNo::ROOT,
+            listing: None,
+            is_dir: true,
+            children: Vec::new(),
+        }];
+        let mut lookup: HashMap<(u64, &str), INodeNo> = HashMap::new();
+
+        for (i, listing) in reader.listings().iter().enumerate() {
+            let is_dir = listing.permissions & 0o040000 == 0o040000;
+            let mut parent = INodeNo::ROOT;
+            let mut components = listing.path.split('/').filter(|c| !c.is_empty()).peekable();
+            let mut path = Vec::new();
+            while let Some(component) = components.next() {
+                let last = components.peek().is_none();
+                path.push(component);
+                let ino = match lookup.get(&(parent.0, component)) {
+                    // listings are ordered by size first, so the file may come before or after
+                    // the listings under it
+                    Some(ino) if nodes[ino.0 as usize - 1].is_dir != (!last || is_dir) => {
+                        return Err(ArchiveError::Corrupt(format!(
+                            "{} is listed both as a file and as a directory",
+                            path.join("/")
+                        ))
+                        .into());
+                    }
+                    Some(ino) => *ino,
+                    None => {
+                        let ino = INodeNo(nodes.len() as u64 + 1);
+                        nodes.push(Node {
+                            parent,
+                            listing: None,
+                            is_dir: !last || is_dir,
+                            children: Vec::new(),
+                        });
+                        nodes[parent.0 as usize - 1]
+                            .children
+                            .push((component.to_string(), ino));
+                        lookup.insert((parent.0, component), ino);
+                        ino
+                    }
+                };
+                if last {
+                    nodes[ino.0 as usize - 1].listing = Some(i);
+                }
+                parent = ino;
+            }
+        }
+
+        Ok(DecafFs {
+            nodes,
+            archive: Mutex::new(ArchiveHandle::with_cache(reader, cache.0.max(1), cache.1)),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        })
+    }
+
+    fn node(&self, ino: INodeNo) -> Option<&Node> {
+        self.nodes.get((ino.0 as usize).checked_sub(1)?)
+    }
+
+    fn attr(&self, ino: INodeNo, node: &Node) -> FileAttr {
+        let archive = self.archive.lock().unwrap();
+        let listing = node.listing.map(|i| &archive.listings()[i]);
+        let size = match listing {
+            Some(listing) if !node.is_dir => listing.filesize,
+            _ => 0,
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            // symbolic links are archived with the content of the file they point at rather than
+            // with their target, so they are read as regular files holding that content
+            kind: if node.is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            },
+            perm: listing.map_or(0o755, |listing| listing.permissions & 0o7777) as u16,
+            nlink: if node.is_dir { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+}
+
+impl Filesystem for DecafFs {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let Some(parent) = self.node(parent) else {
+            return reply.error(Errno::ENOENT);
+        };
+        match parent
+            .children
+            .iter()
+            .find(|(child, _)| OsStr::new(child) == name)
+        {
+            Some((_, ino)) => {
+                let attr = self.attr(*ino, self.node(*ino).unwrap());
+                reply.entry(&TTL, &attr, Generation(0))
+            }
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let Some(node) = self.node(ino) else {
+            return reply.error(Errno::ENOENT);
+        };
+        let Some(listing_index) = node.listing.filter(|_| !node.is_dir) else {
+            return reply.error(Errno::EISDIR);
+        };
+
+        let mut archive = self.archive.lock().unwrap();
+        let listing = archive.listings()[listing_index].clone();
+        // bundles are verified against their checksums as they are decompressed
+        match archive.read_range(&listing, offset..offset.saturating_add(size as u64)) {
+            Ok(content) => reply.data(&content),
+            Err(error) => {
+                eprintln!("decaf-fuse: error: {}: {}", listing.path, error);
+                reply.error(Errno::EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.node(ino).filter(|node| node.is_dir) else {
+            return reply.error(Errno::ENOTDIR);
+        };
+
+        let mut entries = vec![(ino, "."), (node.parent, "..")];
+        entries.extend(
+            node.children
+                .iter()
+                .map(|(name, child)| (*child, name.as_str())),
+        );
+        for (i, (child, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let kind = if self.node(child).unwrap().is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            // the offset given is that of the next entry
+            if reply.add(child, i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut positional = Vec::new();
+    let mut cache_bundles = DEFAULT_CACHED_BUNDLES;
+    let mut cache_bytes = DEFAULT_CACHE_BYTES;
+    let mut auto_unmount = false;
+    let mut flags = args.iter();
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
+            }
+            "--cache-bundles" => {
+                cache_bundles = match flags.next().map(|value| value.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => usage_error("--cache-bundles requires a number"),
+                }
+            }
+            "--cache-bytes" => {
+                cache_bytes = match flags.next().map(|value| value.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => usage_error("--cache-bytes requires a number"),
+                }
+            }
+            "--auto-unmount" => auto_unmount = true,
+            flag if flag.starts_with('-') => usage_error(&format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    let [archive_path, mountpoint] = positional[..] else {
+        usage_error("expected an archive and a mountpoint")
+    };
+
+    let cache = (cache_bundles, cache_bytes);
+    if let Err(error) = mount(archive_path, mountpoint, cache, auto_unmount) {
+        eprintln!("decaf-fuse: error: {}", error);
+        exit(3)
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("decaf-fuse: {}\n\n{}", message, USAGE);
+    exit(2)
+}
+
+fn mount(
+    archive_path: &str,
+    mountpoint: &str,
+    cache: (usize, u64),
+    auto_unmount: bool,
+) -> Result<(), io::Error> {
+    let archive_file = File::open(archive_path)?;
+    let metadata = archive_file.metadata()?;
+    let reader = SeekableArchiveReader::new(archive_file)?;
+    let fs = DecafFs::new(reader, cache, &metadata)?;
+
+    let mut config = Config::default();
+    config.mount_options.extend([
+        MountOption::RO,
+        MountOption::FSName(archive_path.to_string()),
+        MountOption::Subtype("decaf".to_string()),
+    ]);
+    if auto_unmount {
+        config.mount_options.push(MountOption::AutoUnmount);
+    }
+    fuser::mount(fs, mountpoint, &config)
+}
@@ -1,642 +1,7719 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error;
+use std::ffi::CString;
+use std::fmt;
 use std::fs::{self, OpenOptions, Permissions};
 use std::fs::{read_link, File};
 use std::io::BufWriter;
-use std::io::{self, Read, Write};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::*;
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+pub use tempfile::TempDir;
 use xxhash_rust::xxh3::xxh3_64 as xxh3;
 use zstd::stream as zstd;
-use zstd_safe::zstd_sys::{ZSTD_dParameter, ZSTD_MAGIC_SKIPPABLE_START};
 
-static MAGIC_NUMBER: u64 = u64::from_le_bytes(*b"iamdecaf");
+pub mod format;
 
-// TODO: use .map_err() for all the ?s
+/// Enters a `tracing` span for the rest of the enclosing block; a no-op without the `tracing`
+/// feature
+macro_rules! trace_span {
+    ($($span:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($span)*).entered();
+    };
+}
 
-// TODO: remove excessive buffering while writing archives; we can stitch data in whenever we want
-// by using Trait std::io::Seek
+/// Emits a `tracing` event at the given level, e.g. `trace_event!(debug, "...")`; a no-op without
+/// the `tracing` feature
+macro_rules! trace_event {
+    ($level:ident, $($event:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($event)*);
+    };
+}
 
-// in general, we need to do way more pre-computation of buffer and file sizes etc etc
+// upper bound on the threads writing files during extraction; beyond this, extraction is bound
+// by the filesystem rather than by the number of writers
+const MAX_EXTRACTION_WORKERS: usize = 8;
 
-fn relative_path_from<P: AsRef<Path>, B: AsRef<Path>>(path: P, base: B) -> Option<PathBuf> {
-    let path = path.as_ref();
-    let base = base.as_ref();
+// upper bound on the threads compressing bundles by default
+const MAX_COMPRESSION_WORKERS: usize = 8;
 
-    if path.is_absolute() != base.is_absolute() {
-        if path.is_absolute() {
-            Some(PathBuf::from(path))
-        } else {
-            None
-        }
-    } else {
-        let mut ita = path.components();
-        let mut itb = base.components();
-        let mut comps: Vec<Component> = Vec::new();
-        loop {
-            match (ita.next(), itb.next()) {
-                (None, None) => break,
-                (Some(a), None) => {
-                    comps.push(a);
-                    comps.extend(ita.by_ref());
-                    break;
-                }
-                (None, _) => comps.push(Component::ParentDir),
-                (Some(a), Some(b)) if comps.is_empty() && a == b => (),
-                (Some(a), Some(b)) if b == Component::CurDir => comps.push(a),
-                (Some(_), Some(b)) if b == Component::ParentDir => return None,
-                (Some(a), Some(_)) => {
-                    comps.push(Component::ParentDir);
-                    for _ in itb {
-                        comps.push(Component::ParentDir);
-                    }
-                    comps.push(a);
-                    comps.extend(ita.by_ref());
-                    break;
-                }
-            }
-        }
-        Some(comps.iter().map(|c| c.as_os_str()).collect())
-    }
+// upper bound on the threads decompressing bundles by default; decompression is fast enough that
+// more threads mostly contend for memory bandwidth
+const MAX_DECOMPRESSION_WORKERS: usize = 8;
+
+// number of listings whose content is read ahead of bundle assembly while archiving
+const READ_AHEAD_LISTINGS: usize = 64;
+
+/// zstd compression level of bundles by default
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Target uncompressed bundle size by default
+pub const DEFAULT_BUNDLE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Number of decompressed bundles an `ArchiveHandle` keeps by default
+pub const DEFAULT_CACHED_BUNDLES: usize = 16;
+
+/// Bytes of decompressed bundles an `ArchiveHandle` keeps by default
+pub const DEFAULT_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Smallest bundle size `ArchiveOptions::validate` accepts; smaller bundles compress poorly and
+/// bloat the bundle table
+pub const MIN_BUNDLE_SIZE: usize = 64 * 1024;
+
+// the magic numbers of the skippable zstd frame holding the seek table of a bundle in the zstd
+// seekable format, and of the footer ending it
+const SEEK_TABLE_FRAME_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC_NUMBER: u32 = 0x8F92_EAB1;
+
+// the largest frame of a seekable bundle, as in the zstd seekable format
+const MAX_SEEKABLE_FRAME_SIZE: usize = 1 << 30;
+
+/// A revision of the archive format which can be read by this crate
+///
+/// Pre-release revisions share the current layout after the archive checksum; they only differ in
+/// their magic number and in how the rest of the archive is checksummed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatRevision {
+    /// pre-release archives with the magic number `notdecaf` and a CRC-32 archive checksum
+    LegacyCrc32,
+    /// pre-release archives with the magic number `notdecaf` and an xxh3 archive checksum
+    LegacyXxh3,
+    /// archives with the magic number `iamdecaf`, as written by this crate
+    Current,
 }
 
-#[derive(Debug)]
-pub struct ArchivableListing {
-    pub relative_path: Box<str>, // relative file or directory path
-    pub permissions: u32,
-    pub file_size: u64,
-    pub literal_path: PathBuf,
+/// A checksum computed over bytes passed in any number of pieces, as by a `HashAlgorithm`
+pub trait Hasher: Send {
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Returns the checksum of everything passed to `update` so far
+    fn digest(&self) -> u64;
 }
 
-impl Ord for ArchivableListing {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // compare by content length
-        self.file_size
-            .cmp(&other.file_size)
-            // compare by path length
-            .then(self.relative_path.len().cmp(&other.relative_path.len()))
-            // compare by permissions
-            .then(self.permissions.cmp(&other.permissions))
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, bytes: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, bytes);
+    }
+
+    fn digest(&self) -> u64 {
+        xxhash_rust::xxh3::Xxh3::digest(self)
     }
 }
 
-impl Eq for ArchivableListing {}
+impl Hasher for crc32fast::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        crc32fast::Hasher::update(self, bytes);
+    }
 
-impl PartialEq for ArchivableListing {
-    fn eq(&self, other: &Self) -> bool {
-        self.file_size == other.file_size
-            && self.relative_path.len() == other.relative_path.len()
-            && self.permissions == other.permissions
+    fn digest(&self) -> u64 {
+        self.clone().finalize() as u64
     }
 }
 
-impl PartialOrd for ArchivableListing {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+#[cfg(feature = "blake3")]
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        // below this, spreading the input over threads costs more than it saves
+        const PARALLEL_FROM: usize = 128 * 1024;
+        match bytes.len() >= PARALLEL_FROM {
+            true => self.update_rayon(bytes),
+            false => blake3::Hasher::update(self, bytes),
+        };
+    }
+
+    fn digest(&self) -> u64 {
+        let mut digest = [0u8; 8];
+        digest.copy_from_slice(&self.finalize().as_bytes()[..8]);
+        u64::from_le_bytes(digest)
     }
 }
 
-pub struct ArchivableArchive {
-    pub listings: Vec<ArchivableListing>,
+/// The hash algorithm every checksum of an archive is computed with: the checksums of the listing
+/// contents, of the bundles, of the sections and of the archive as a whole
+///
+/// The algorithm is recorded in the archive header, so readers always verify an archive with the
+/// algorithm it was written with. Streams and the checksums of path indexes always use xxh3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    /// XXH3-64, the fastest on most CPUs
+    #[default]
+    Xxh3,
+    /// CRC-32, for CPUs computing it in hardware (SSE4.2 and PCLMULQDQ on x86, the CRC extension
+    /// on ARM) that are too weak to run xxh3 fast; weaker at catching corruption than the others
+    Crc32,
+    /// BLAKE3 truncated to 64 bits, hashed on every core for large inputs, for archives with
+    /// strong integrity requirements; only available with the `blake3` feature
+    #[cfg(feature = "blake3")]
+    Blake3,
 }
 
-impl ArchivableArchive {
-    fn create_archive<W: Write>(&self, writer: &mut W) -> Result<usize, io::Error> {
-        let target_bundle_size = 10 * (1024 * 1024); // 10mb target bundle size
+impl HashAlgorithm {
+    /// Returns the identifier of the algorithm in the archive header
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlgorithm::Xxh3 => 0,
+            HashAlgorithm::Crc32 => 1,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => 2,
+        }
+    }
 
-        let mut binary_listings: Vec<Vec<u8>> = Vec::new();
-        let mut binary_bundles: Vec<Vec<u8>> = Vec::new();
+    /// Returns the name of the algorithm, e.g. for `--hash` on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Crc32 => "crc32",
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
 
-        let mut listing_idx = 0;
-        binary_bundles.push(Vec::new());
-        let mut bundle_idx = 0;
-        let mut current_bundle_offset = 0;
-        loop {
-            if binary_bundles[bundle_idx].len() > target_bundle_size {
-                binary_bundles.push(Vec::new());
-                current_bundle_offset = 0;
-                bundle_idx += 1;
-            }
-
-            // get file content for listing if necessary
-            let mut listing_content =
-                Vec::with_capacity(self.listings[listing_idx].file_size as usize);
-            let mut content_checksum = 0;
-
-            if self.listings[listing_idx].literal_path.to_str().unwrap() != "" {
-                listing_content = fs::read(&self.listings[listing_idx].literal_path)?;
-                content_checksum = xxh3(&listing_content);
-            }
-
-            let listing_path: &[u8] = self.listings[listing_idx].relative_path.as_bytes();
-            let listing_permissions: u32 = self.listings[listing_idx].permissions;
-            let listing_bundle_index: u64 = bundle_idx as u64;
-            let listing_offset_in_bundle: u64 = current_bundle_offset as u64;
-            let listing_file_size: u64 = listing_content.len() as u64;
-            let listing_checksum: u64 = content_checksum;
-            let listing_total_length: u64 = (listing_path.len() + 44) as u64;
-
-            let mut listing_constructed: Vec<u8> =
-                Vec::with_capacity(listing_total_length as usize);
-            listing_constructed.extend_from_slice(&listing_total_length.to_le_bytes());
-            listing_constructed.extend_from_slice(&listing_bundle_index.to_le_bytes());
-            listing_constructed.extend_from_slice(&listing_offset_in_bundle.to_le_bytes());
-            listing_constructed.extend_from_slice(&listing_file_size.to_le_bytes());
-            listing_constructed.extend_from_slice(&listing_permissions.to_le_bytes());
-            listing_constructed.extend_from_slice(&listing_checksum.to_le_bytes());
-            listing_constructed.extend_from_slice(listing_path);
-
-            binary_listings.push(listing_constructed);
-
-            current_bundle_offset += listing_content.len();
-            binary_bundles[bundle_idx].append(&mut listing_content);
-
-            listing_idx += 1;
-            // check for listing exhaustion
-            if listing_idx == self.listings.len() {
-                break;
+    /// Returns a hasher computing checksums with the algorithm
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+            HashAlgorithm::Crc32 => Box::new(crc32fast::Hasher::new()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+
+    /// Returns the checksum of `bytes`
+    pub fn hash(self, bytes: &[u8]) -> u64 {
+        match self {
+            HashAlgorithm::Xxh3 => xxh3(bytes),
+            _ => {
+                let mut hasher = self.hasher();
+                hasher.update(bytes);
+                hasher.digest()
             }
         }
+    }
+
+    /// Returns whether this CPU computes the algorithm with instructions made for it rather than
+    /// with portable code: SIMD for xxh3 and BLAKE3, carry-less multiplication or CRC instructions
+    /// for CRC-32
+    pub fn is_accelerated(self) -> bool {
+        match self {
+            // xxh3 is vectorized with the instructions the crate is compiled for
+            HashAlgorithm::Xxh3 => cfg!(any(target_feature = "sse2", target_feature = "neon")),
+            HashAlgorithm::Crc32 => crc32_is_accelerated(),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => cfg!(any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "aarch64"
+            )),
+        }
+    }
+
+    /// Returns the algorithm the archive with `header` is checksummed with; pre-release archives
+    /// only differ from current ones in their archive checksum, so their other checksums are xxh3
+    fn of(header: &format::Header) -> Result<HashAlgorithm, io::Error> {
+        if header.magic == format::LEGACY_MAGIC_NUMBER {
+            return Ok(HashAlgorithm::Xxh3);
+        }
+        HashAlgorithm::from_id(header.hash_algorithm_id())
+    }
 
-        // --------------------------------------------
-        // generating the archive header data
-        // --------------------------------------------
+    /// Returns the algorithm with the identifier `id` from an archive header
+    fn from_id(id: u8) -> Result<HashAlgorithm, io::Error> {
+        match id {
+            0 => Ok(HashAlgorithm::Xxh3),
+            1 => Ok(HashAlgorithm::Crc32),
+            #[cfg(feature = "blake3")]
+            2 => Ok(HashAlgorithm::Blake3),
+            #[cfg(not(feature = "blake3"))]
+            2 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "archive is checksummed with BLAKE3, which requires the blake3 feature",
+            )),
+            id => Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing_block_length names unknown hash algorithm {}",
+                id
+            ))
+            .into()),
+        }
+    }
+}
 
-        let listing_section_total_length: usize = binary_listings.iter().map(|v| v.len()).sum();
+/// Returns whether crc32fast computes CRC-32 with carry-less multiplication on this CPU
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn crc32_is_accelerated() -> bool {
+    is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.2")
+}
 
-        // generate header info for bundles and compress bundles
-        let mut bundle_section: Vec<u8> = Vec::with_capacity(binary_bundles.len());
-        let mut compressed_bundles: Vec<Vec<u8>> =
-            Vec::with_capacity(binary_bundles.len() * (8 + 4));
-        let mut compressed_bundle_current_offset: u64 =
-            (listing_section_total_length + 40 + (binary_bundles.len() * 8 * 3)) as u64;
+/// Returns whether crc32fast computes CRC-32 with the CRC instructions on this CPU
+#[cfg(target_arch = "aarch64")]
+fn crc32_is_accelerated() -> bool {
+    std::arch::is_aarch64_feature_detected!("crc")
+}
 
-        let mut i = 0;
-        for bundle in binary_bundles {
-            let compressed_bundle_offset = compressed_bundle_current_offset;
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn crc32_is_accelerated() -> bool {
+    false
+}
 
-            let bundle_checksum = xxh3(&bundle);
+/// Options controlling how an archive is written
+#[derive(Debug, Clone)]
+pub struct ArchiveOptions {
+    /// zstd compression level used for every bundle
+    pub compression_level: i32,
+    /// uncompressed size in bytes after which a new bundle is started
+    pub bundle_size: usize,
+    /// normalization applied to listing permissions before they are written
+    pub permissions: PermissionMode,
+    /// directory name prepended to every listing path, e.g. the name of the archived directory so
+    /// that extracting into the current directory creates it, like dtar does for tar entries
+    pub path_prefix: Option<String>,
+    /// order in which listings are written, and so how their content is grouped into bundles
+    pub sort: SortStrategy,
+    /// maximum number of bytes per second read from source files, or `None` for no limit
+    pub rate_limit: Option<u64>,
+    /// what happens when the content of a listing can't be read
+    pub read_errors: ReadErrorPolicy,
+    /// bounds on the archive that is written
+    pub limits: Limits,
+    /// store the listing block as a zstd frame, which saves space in archives with many listings
+    pub compress_listings: bool,
+    /// number of threads compressing bundles, or `None` for one per available core, up to 8
+    pub threads: Option<usize>,
+    /// what happens to a listing whose path is already taken by an earlier listing
+    pub duplicate_paths: DuplicatePathPolicy,
+    /// patterns of listing paths left out of the archive, as matched by `is_excluded`
+    pub excludes: Vec<String>,
+    /// write archive files to `<name>.tmp` first and rename them once they are complete, so that
+    /// a crash never leaves a truncated archive under the final name
+    pub atomic_write: bool,
+    /// archive only the directory structure and metadata, storing regular files as empty so that
+    /// extracting the archive recreates a skeleton of the tree; symlinks keep their targets
+    pub structure_only: bool,
+    /// keep memory use bounded however large the archive is: files are read one at a time,
+    /// bundles are compressed on a single thread unless `threads` is set, and compressed bundles
+    /// are spilled to a temporary file until the archive is written; a single file is still held
+    /// in memory while it is bundled
+    pub low_memory: bool,
+    /// follow the header with checksums of the listing block and the bundle table, so that
+    /// readers which never see the whole archive, like `SeekableArchiveReader`, can verify them
+    pub section_checksums: bool,
+    /// follow the header with a checksum of itself and the bundle table, so that
+    /// `ArchiveSummary::peek` can trust the counts and sizes it reads without the listing block
+    pub header_checksum: bool,
+    /// end the archive checksum with the bundle table, leaving the bundles to their own
+    /// checksums, so that readers which never read the whole archive, like
+    /// `SeekableArchiveReader`, can verify it
+    pub split_checksum: bool,
+    /// write the bundles as they are compressed and the tables after them, followed by a footer
+    /// completing the header, so that the archive is written in a single pass without holding its
+    /// bundles, e.g. to a pipe; such archives have no section, header or split checksums, and are
+    /// only read as a whole or by seeking
+    pub footer: bool,
+    /// compress every bundle as independent zstd frames of this many uncompressed bytes followed
+    /// by a seek table in the zstd seekable format, so that `SeekableArchiveReader::read_file`
+    /// decompresses only the frames holding a file rather than its whole bundle; every zstd
+    /// decoder still reads such bundles, at a small cost in compression ratio
+    pub seekable_frame_size: Option<usize>,
+    /// store the zstd checksum and the uncompressed size in the header of every zstd frame of a
+    /// bundle, so that third-party zstd tools like `zstd -t` can validate bundles on their own; the
+    /// checksums of the archive are verified either way
+    pub zstd_frame_checksums: bool,
+    /// hash algorithm every checksum of the archive is computed with; streams are always
+    /// checksummed with xxh3
+    pub hash: HashAlgorithm,
+    /// directory temporary files are created in instead of the system's, such as the bundles
+    /// spilled by `low_memory`; archive files written with `atomic_write` are still written next
+    /// to their final name, as they are renamed into place
+    pub temp_dir: Option<PathBuf>,
+    /// store the `FileFlags` of every file in its listing, such as whether it is immutable, where
+    /// the platform and filesystem have them; off by default, as the same tree would otherwise be
+    /// archived differently depending on where it is read from
+    pub file_flags: bool,
+    /// called with every warning as soon as it happens, besides collecting it in the stats
+    pub on_warning: Option<WarningCallback>,
+    /// called with every listing once it is archived or extracted
+    pub on_progress: Option<ProgressCallback>,
+}
 
-            // compress with zstd
-            let mut compressed_bundle = Vec::new();
-            zstd::copy_encode(bundle.as_slice(), &mut compressed_bundle, 3)?;
-            let compressed_bundle_size = compressed_bundle.len() as u64;
-            compressed_bundles.push(compressed_bundle);
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        ArchiveOptions {
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            bundle_size: DEFAULT_BUNDLE_SIZE,
+            permissions: PermissionMode::default(),
+            path_prefix: None,
+            sort: SortStrategy::default(),
+            rate_limit: None,
+            read_errors: ReadErrorPolicy::default(),
+            limits: Limits::default(),
+            compress_listings: false,
+            threads: None,
+            duplicate_paths: DuplicatePathPolicy::default(),
+            excludes: Vec::new(),
+            atomic_write: true,
+            structure_only: false,
+            low_memory: false,
+            section_checksums: false,
+            header_checksum: false,
+            split_checksum: false,
+            footer: false,
+            seekable_frame_size: None,
+            zstd_frame_checksums: false,
+            hash: HashAlgorithm::default(),
+            temp_dir: None,
+            file_flags: false,
+            on_warning: None,
+            on_progress: None,
+        }
+    }
+}
 
-            println!("{}, {} {}", i, bundle.len(), compressed_bundle_size);
+impl ArchiveOptions {
+    /// Returns a builder starting from the default options
+    pub fn builder() -> ArchiveOptionsBuilder {
+        ArchiveOptionsBuilder::default()
+    }
 
-            // increment offset
-            compressed_bundle_current_offset += compressed_bundle_size;
+    /// Checks that the options make sense together, returning an `InvalidInput` error naming the
+    /// first option that doesn't
+    pub fn validate(&self) -> Result<(), io::Error> {
+        self.check()?;
+        if self.bundle_size < MIN_BUNDLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "bundle size {} is smaller than {} bytes",
+                    self.bundle_size, MIN_BUNDLE_SIZE
+                ),
+            ));
+        }
+        Ok(())
+    }
 
-            bundle_section.write_all(&compressed_bundle_offset.to_le_bytes())?;
-            bundle_section.write_all(&compressed_bundle_size.to_le_bytes())?;
-            bundle_section.write_all(&bundle_checksum.to_le_bytes())?;
-            i += 1;
+    /// Checks the options like `validate`, but without its floor on the bundle size, as smaller
+    /// bundles only compress worse; every function writing an archive or a stream checks its
+    /// options this way before writing anything
+    fn check(&self) -> Result<(), io::Error> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        let levels = zstd_safe::min_c_level()..=zstd_safe::max_c_level();
+        if !levels.contains(&self.compression_level) {
+            return invalid(format!(
+                "compression level {} is not between {} and {}",
+                self.compression_level,
+                levels.start(),
+                levels.end()
+            ));
+        }
+        if self.threads == Some(0) {
+            return invalid("threads must be at least 1".to_string());
+        }
+        if self.rate_limit == Some(0) {
+            return invalid("rate limit must be at least 1 byte per second".to_string());
+        }
+        if self.seekable_frame_size == Some(0) {
+            return invalid("seekable frame size must be at least 1 byte".to_string());
+        }
+        // these checksums are found through the header, which says nothing until the footer
+        if self.footer && (self.section_checksums || self.header_checksum || self.split_checksum) {
+            return invalid(
+                "archives with a footer cannot have section, header or split checksums".to_string(),
+            );
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if prefix
+                .split('/')
+                .all(|component| component.is_empty() || component == ".")
+                || prefix.split('/').any(|component| component == "..")
+            {
+                return invalid(format!(
+                    "path prefix {:?} is not a relative directory",
+                    prefix
+                ));
+            }
         }
+        Ok(())
+    }
 
-        // --------------------------------------------
-        // writing the archive buffer
-        // --------------------------------------------
+    /// Returns the codec bundles are compressed with
+    fn codec(&self) -> Codec {
+        match self.seekable_frame_size {
+            Some(frame_size) => Codec::SeekableZstd { frame_size },
+            None => Codec::Zstd,
+        }
+    }
+}
 
-        let mut archive_buffer: Vec<u8> = Vec::new();
+/// Builds `ArchiveOptions` one option at a time from the defaults, validating them once they are
+/// all set
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptionsBuilder {
+    options: ArchiveOptions,
+}
 
-        // write listing block length
-        archive_buffer.write_all(&(listing_section_total_length as u64).to_le_bytes())?;
+impl ArchiveOptionsBuilder {
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.options.compression_level = level;
+        self
+    }
 
-        // write listing count
-        archive_buffer.write_all(&(self.listings.len() as u64).to_le_bytes())?;
+    pub fn bundle_size(mut self, size: usize) -> Self {
+        self.options.bundle_size = size;
+        self
+    }
 
-        // write bundle count
-        archive_buffer.write_all(&(compressed_bundles.len() as u64).to_le_bytes())?;
+    pub fn permissions(mut self, permissions: PermissionMode) -> Self {
+        self.options.permissions = permissions;
+        self
+    }
 
-        // write listing block
-        for bl in binary_listings.drain(..) {
-            archive_buffer.write_all(&bl)?;
-        }
+    pub fn path_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.options.path_prefix = Some(prefix.into());
+        self
+    }
 
-        // write the bundle block
-        archive_buffer.append(&mut bundle_section);
+    pub fn sort(mut self, sort: SortStrategy) -> Self {
+        self.options.sort = sort;
+        self
+    }
 
-        // write compressed block
-        for compressed_bundle in compressed_bundles.drain(..) {
-            archive_buffer.write_all(&compressed_bundle)?;
-        }
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.rate_limit = Some(bytes_per_second);
+        self
+    }
 
-        // --------------------------------------------
-        // writing the actual archive
-        // --------------------------------------------
+    pub fn read_errors(mut self, policy: ReadErrorPolicy) -> Self {
+        self.options.read_errors = policy;
+        self
+    }
 
-        // write magic number
-        writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
 
-        // write checksum
-        let archive_checksum: u64 = xxh3(archive_buffer.as_slice());
-        writer.write_all(&archive_checksum.to_le_bytes())?;
+    pub fn compress_listings(mut self, compress: bool) -> Self {
+        self.options.compress_listings = compress;
+        self
+    }
 
-        // write archive
-        writer.write_all(&archive_buffer)?;
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = Some(threads);
+        self
+    }
 
-        Ok(16 + archive_buffer.len()) // 8 bytes for the magic number, 8 bytes for the checksum
+    pub fn duplicate_paths(mut self, policy: DuplicatePathPolicy) -> Self {
+        self.options.duplicate_paths = policy;
+        self
     }
 
-    pub fn archive_to_file<P: AsRef<Path>>(
-        &self,
-        output_archive_path: P,
-    ) -> Result<usize, io::Error> {
-        let output_file = File::create(output_archive_path)?;
-        let mut writer = BufWriter::new(output_file);
-        self.create_archive(&mut writer)
+    /// Adds a pattern of listing paths to leave out, as matched by `is_excluded`
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.options.excludes.push(pattern.into());
+        self
     }
 
-    pub fn archive_to_writer<W: Write>(&self, writer: &mut W) -> Result<usize, io::Error> {
-        let mut writer = BufWriter::new(writer);
-        self.create_archive(&mut writer)
+    pub fn atomic_write(mut self, atomic: bool) -> Self {
+        self.options.atomic_write = atomic;
+        self
     }
-}
 
-pub fn create_archive_from_directory<P: AsRef<Path>>(
-    directory_path: P,
-) -> Result<ArchivableArchive, io::Error> {
-    create_archive_recursive(directory_path.as_ref(), directory_path.as_ref())
-}
+    pub fn structure_only(mut self, structure_only: bool) -> Self {
+        self.options.structure_only = structure_only;
+        self
+    }
 
-fn resolve_link<P: AsRef<Path>, B: AsRef<Path>>(
-    path: P,
-    parent_path: B,
-) -> Result<bool, io::Error> {
-    let resolved = read_link(path)?;
-    if !resolved.starts_with(&parent_path) {
-        return Ok(false);
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.options.low_memory = low_memory;
+        self
     }
-    if !resolved.metadata()?.is_symlink() {
-        return Ok(true);
+
+    pub fn section_checksums(mut self, section_checksums: bool) -> Self {
+        self.options.section_checksums = section_checksums;
+        self
     }
-    resolve_link(resolved, parent_path)
-}
 
-fn create_archive_recursive<P: AsRef<Path>, B: AsRef<Path>>(
-    directory_path: P,
-    parent_path: B,
-) -> Result<ArchivableArchive, io::Error> {
-    let mut local_listings = Vec::new();
-    let entries = fs::read_dir(directory_path)?;
+    pub fn header_checksum(mut self, header_checksum: bool) -> Self {
+        self.options.header_checksum = header_checksum;
+        self
+    }
 
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        let metadata = entry.metadata()?;
+    pub fn split_checksum(mut self, split_checksum: bool) -> Self {
+        self.options.split_checksum = split_checksum;
+        self
+    }
 
-        if metadata.is_symlink() {
-            if !resolve_link(&path, &parent_path)? {
-                continue;
-            } else {
-                let can_path = path.canonicalize()?;
-                let relative_path = relative_path_from(path, &parent_path).unwrap();
-                let path_str = relative_path
-                    .to_str()
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid path"))?;
-                let perms = metadata.permissions().mode();
-                local_listings.push(ArchivableListing {
-                    permissions: perms,
-                    relative_path: path_str.into(),
-                    file_size: 0,
-                    literal_path: can_path.clone(),
-                });
-                continue;
-            }
-        }
+    pub fn footer(mut self, footer: bool) -> Self {
+        self.options.footer = footer;
+        self
+    }
 
-        // directory handling
-        if metadata.is_dir() {
-            let sub_entries = fs::read_dir(&path)?;
-            if sub_entries.count() == 0 {
-                // bare directory
-                let relative_path = relative_path_from(path, &parent_path).unwrap();
-                let path_str = relative_path
-                    .to_str()
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid path"))?;
-                local_listings.push(ArchivableListing {
-                    permissions: metadata.permissions().mode(),
-                    relative_path: path_str.into(),
-                    file_size: 0,
-                    literal_path: "".into(),
-                });
-            } else {
-                // recurse
-                let mut sub_listings = create_archive_recursive(&path, parent_path.as_ref())?;
-                local_listings.append(&mut sub_listings.listings);
-            }
-            continue;
-        }
+    pub fn seekable_frame_size(mut self, size: usize) -> Self {
+        self.options.seekable_frame_size = Some(size);
+        self
+    }
 
-        // file handling
-        let perms = metadata.permissions().mode();
-        let relative_path = relative_path_from(&path, parent_path.as_ref()).unwrap();
-        let path_str = relative_path
-            .to_str()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid path"))?;
+    pub fn zstd_frame_checksums(mut self, checksums: bool) -> Self {
+        self.options.zstd_frame_checksums = checksums;
+        self
+    }
 
-        let can_path = &path.canonicalize()?;
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.options.hash = hash;
+        self
+    }
 
-        let file_size = fs::metadata(can_path)?.size();
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.options.temp_dir = Some(directory.into());
+        self
+    }
 
-        local_listings.push(ArchivableListing {
-            permissions: perms,
-            relative_path: path_str.into(),
-            file_size,
-            literal_path: can_path.clone(),
-        });
+    pub fn file_flags(mut self, file_flags: bool) -> Self {
+        self.options.file_flags = file_flags;
+        self
     }
 
-    local_listings.sort();
-    Ok(ArchivableArchive {
-        listings: local_listings,
-    })
-}
+    pub fn on_warning<F: Fn(&Warning) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.options.on_warning = Some(WarningCallback::new(callback));
+        self
+    }
 
-#[derive(Debug)]
-pub struct ExtractedListing {
-    pub path: Box<str>, // relative file or directory path
-    pub permissions: u32,
-    pub content_checksum: u64, // checksum of `content`
-    pub filesize: u64,
-    pub bundle_idx: usize,
-    pub bundle_offset: usize, // binary content of file or empty if directory
-}
+    pub fn on_progress<F: Fn(&Progress) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.options.on_progress = Some(ProgressCallback::new(callback));
+        self
+    }
 
-#[derive(Debug)]
-pub struct ExtractedArchive {
-    pub listings: Vec<ExtractedListing>,
-    bundles: Vec<Vec<u8>>,
+    /// Returns the options, or the error of `ArchiveOptions::validate` if they don't make sense
+    pub fn build(self) -> Result<ArchiveOptions, io::Error> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
 }
 
-pub fn extract_from_file<P: AsRef<Path>>(archive_path: P) -> Result<ExtractedArchive, io::Error> {
-    let mut archive_file = File::open(archive_path)?;
-    extract_from_reader(&mut archive_file)
+/// What happens when the content of a listing can't be read while archiving, e.g. because a file
+/// is not readable by the current user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadErrorPolicy {
+    /// stop archiving and return the error
+    #[default]
+    FailFast,
+    /// leave the listing out of the archive and record a warning
+    Skip,
+    /// archive the listing with empty content and record a warning
+    ReplaceWithEmpty,
 }
 
-pub fn extract_from_reader<R: Read>(reader: &mut R) -> Result<ExtractedArchive, io::Error> {
-    ExtractedArchive::from_reader(reader)
+/// Upper bounds on the size of an archive, enforced both while archiving and while reading, so
+/// that services accepting archives from untrusted sources can't be made to exhaust memory or disk
+/// by a decompression bomb; `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// maximum total size of the content of every listing, and so of the decompressed bundles
+    pub max_uncompressed_size: Option<u64>,
+    pub max_listing_count: Option<u64>,
+    pub max_bundle_count: Option<u64>,
+    /// maximum length of a listing path in bytes
+    pub max_path_length: Option<u64>,
 }
 
-impl ExtractedArchive {
-    pub fn from_reader<R: Read>(reader: &mut R) -> Result<ExtractedArchive, io::Error> {
-        let mut input_buffer: Vec<u8> = Vec::new();
-        reader.read_to_end(&mut input_buffer)?;
-
-        if input_buffer.len() < 64 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "invalid archive: archive too small with size {} bytes",
-                    input_buffer.len()
-                ),
-            ));
-        };
-
-        // verify magic number
-        if input_buffer[0..8] != MAGIC_NUMBER.to_le_bytes() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid archive: does not contain magic number",
-            ));
+impl Limits {
+    fn check(limit: Option<u64>, value: u64, name: &str) -> Result<(), ArchiveError> {
+        match limit {
+            Some(limit) if value > limit => Err(ArchiveError::LimitExceeded(format!(
+                "archive exceeds limits: {} of {} is more than the limit of {}",
+                name, value, limit
+            ))),
+            _ => Ok(()),
         }
+    }
 
-        // verify archive checksum
-        if u64::from_le_bytes(input_buffer[8..16].try_into().unwrap()) != xxh3(&input_buffer[16..])
-        {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid archive: could not verify archive integrity",
-            ));
-        }
+    fn check_listing_count(&self, listing_count: u64) -> Result<(), ArchiveError> {
+        Limits::check(self.max_listing_count, listing_count, "listing count")
+    }
 
-        let listing_block_length = u64::from_le_bytes(input_buffer[16..24].try_into().unwrap());
-        let listing_count = u64::from_le_bytes(input_buffer[24..32].try_into().unwrap());
-        let bundle_count = u64::from_le_bytes(input_buffer[32..40].try_into().unwrap());
+    fn check_bundle_count(&self, bundle_count: u64) -> Result<(), ArchiveError> {
+        Limits::check(self.max_bundle_count, bundle_count, "bundle count")
+    }
 
-        let mut bundles_uncompressed: Vec<Vec<u8>> = Vec::new();
-        let mut current_offset: usize = listing_block_length as usize + 40;
-        for i in 0..bundle_count {
-            let compressed_bundle_offset = u64::from_le_bytes(
-                input_buffer[current_offset..current_offset + 8]
-                    .try_into()
-                    .unwrap(),
-            );
+    fn check_path(&self, path: &str) -> Result<(), ArchiveError> {
+        Limits::check(self.max_path_length, path.len() as u64, "path length")
+            .map_err(|error| ArchiveError::LimitExceeded(format!("{} for {}", error, path)))
+    }
 
-            let compressed_bundle_size = u64::from_le_bytes(
-                input_buffer[current_offset + 8..current_offset + 16]
-                    .try_into()
-                    .unwrap(),
-            );
+    fn check_uncompressed_size(&self, uncompressed_size: u64) -> Result<(), ArchiveError> {
+        Limits::check(
+            self.max_uncompressed_size,
+            uncompressed_size,
+            "uncompressed size",
+        )
+    }
+}
 
-            let uncompressed_bundle_checksum = u64::from_le_bytes(
-                input_buffer[current_offset + 16..current_offset + 24]
-                    .try_into()
-                    .unwrap(),
-            );
+/// The order in which listings are written to an archive
+///
+/// Every strategy except `WalkOrder` only depends on the listings themselves, so the same set of
+/// files always produces the same archive regardless of how they were collected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortStrategy {
+    /// file size, then path length, then path bytes, as described by the specification
+    #[default]
+    Size,
+    /// path bytes, lexicographically, which keeps files from the same directory together
+    Path,
+    /// file extension, then file size, then path, which groups similar content into the same
+    /// bundles for better compression
+    Extension,
+    /// the order of `ArchivableArchive::listings`, e.g. the order of an explicit file list; only
+    /// deterministic if that order is
+    WalkOrder,
+}
+
+impl SortStrategy {
+    fn compare(
+        &self,
+        a: &ArchivableListing,
+        a_permissions: u32,
+        b: &ArchivableListing,
+        b_permissions: u32,
+    ) -> Ordering {
+        match self {
+            SortStrategy::Size => a
+                .file_size
+                .cmp(&b.file_size)
+                .then(a.relative_path.len().cmp(&b.relative_path.len()))
+                .then(a.relative_path.cmp(&b.relative_path))
+                .then(a_permissions.cmp(&b_permissions)),
+            SortStrategy::Path => a.relative_path.cmp(&b.relative_path),
+            SortStrategy::Extension => extension_of(&a.relative_path)
+                .cmp(extension_of(&b.relative_path))
+                .then(a.file_size.cmp(&b.file_size))
+                .then(a.relative_path.cmp(&b.relative_path)),
+            SortStrategy::WalkOrder => Ordering::Equal,
+        }
+    }
+}
+
+/// Returns whether the full `mode` of a listing is that of a regular file
+fn is_regular_file(mode: u32) -> bool {
+    mode & 0o170000 == 0o100000
+}
+
+/// Returns whether the listing path `path` is `directory` or a path under it
+fn is_within(path: &str, directory: &str) -> bool {
+    path.strip_prefix(directory)
+        .is_some_and(|remainder| remainder.is_empty() || remainder.starts_with('/'))
+}
+
+/// Returns the extension of the final component of `path`, or an empty string if it has none
+fn extension_of(path: &str) -> &str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    match file_name.rfind('.') {
+        Some(0) | None => "",
+        Some(i) => &file_name[i + 1..],
+    }
+}
+
+/// How listing permissions are normalized, so that differences in umask between machines don't
+/// change archive bytes or extracted trees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionMode {
+    /// keep permissions exactly as they are
+    #[default]
+    Preserve,
+    /// only keep whether a file is executable; files become 0644 or 0755 and directories 0755
+    ExecutableOnly,
+    /// force the permission bits of every file and directory to the given values
+    Fixed { file: u32, directory: u32 },
+}
+
+impl PermissionMode {
+    /// Applies the normalization to a full mode, keeping its file type bits
+    pub fn apply(&self, mode: u32) -> u32 {
+        let file_type = mode & 0o170000;
+        let is_directory = file_type == 0o040000;
+        match *self {
+            PermissionMode::Preserve => mode,
+            PermissionMode::ExecutableOnly if is_directory || mode & 0o111 != 0 => {
+                file_type | 0o755
+            }
+            PermissionMode::ExecutableOnly => file_type | 0o644,
+            PermissionMode::Fixed { directory, .. } if is_directory => {
+                file_type | (directory & 0o7777)
+            }
+            PermissionMode::Fixed { file, .. } => file_type | (file & 0o7777),
+        }
+    }
+}
+
+/// Flags of a file restricting changes to it, like those set by `chattr` on Linux and `chflags`
+/// on the BSDs and macOS, which are stored above the mode in the permissions of a listing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileFlags {
+    /// the file can't be changed, renamed or removed, nor linked to
+    pub immutable: bool,
+    /// the file can only be opened for appending
+    pub append_only: bool,
+}
+
+impl FileFlags {
+    /// Returns the flags stored in the permissions of a listing
+    pub fn from_permissions(permissions: u32) -> FileFlags {
+        FileFlags {
+            immutable: permissions & format::ListingEntry::IMMUTABLE != 0,
+            append_only: permissions & format::ListingEntry::APPEND_ONLY != 0,
+        }
+    }
+
+    /// Returns the flags as they are stored in the permissions of a listing
+    pub fn to_permissions(self) -> u32 {
+        let mut permissions = 0;
+        if self.immutable {
+            permissions |= format::ListingEntry::IMMUTABLE;
+        }
+        if self.append_only {
+            permissions |= format::ListingEntry::APPEND_ONLY;
+        }
+        permissions
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == FileFlags::default()
+    }
+
+    /// Reads the flags of the file at `path`, following symbolic links; files on platforms and
+    /// filesystems without flags have none
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<FileFlags, io::Error> {
+        read_file_flags(path.as_ref())
+    }
+
+    /// Sets or clears these flags on the file at `path`, keeping any other flags it has; fails on
+    /// platforms and filesystems without flags
+    pub fn set<P: AsRef<Path>>(self, path: P) -> Result<(), io::Error> {
+        set_file_flags(path.as_ref(), self)
+    }
+}
+
+/// Options controlling how listings are written to disk during extraction
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// number of leading path components removed from every listing path; listings with no
+    /// components left are skipped, like `tar --strip-components`
+    pub strip_components: usize,
+    /// `(from, to)` path prefix rewrites applied after stripping; the first matching rule wins
+    pub path_remaps: Vec<(String, String)>,
+    /// normalization applied to listing permissions when files are created
+    pub permissions: PermissionMode,
+    /// permission bits given to every extracted listing other than a directory, replacing those
+    /// left by `permissions`, e.g. `0o644`
+    pub file_mode: Option<u32>,
+    /// permission bits given to every extracted directory, e.g. `0o755`
+    pub dir_mode: Option<u32>,
+    /// permission bits cleared from every extracted listing after `file_mode` and `dir_mode`,
+    /// like a umask, so that e.g. `0o7002` never creates world-writable or setuid files whatever
+    /// the archive holds
+    pub mode_mask: u32,
+    /// keep the setuid, setgid and sticky bits of extracted listings, which are cleared by default
+    /// since archives from untrusted sources can carry setuid binaries
+    pub preserve_special_bits: bool,
+    /// maximum number of bytes per second written to extracted files, or `None` for no limit
+    pub rate_limit: Option<u64>,
+    /// number of threads writing files, and decompressing bundles when the archive is read with
+    /// `ExtractedArchive::from_reader_with_options`, or `None` for one per available core, up to 8
+    pub threads: Option<usize>,
+    /// which checksums are verified while files are written, and while the archive is read with
+    /// `ExtractedArchive::from_reader_with_options`
+    pub verify: VerifyLevel,
+    /// whether listing permissions are set, and what happens when that fails
+    pub permission_policy: PermissionPolicy,
+    /// what happens to a listing whose path, after the path transforms, is already taken by an
+    /// earlier listing
+    pub duplicate_paths: DuplicatePathPolicy,
+    /// what happens to a listing whose path differs only by case from an earlier listing when
+    /// extracting to a case-insensitive filesystem
+    pub case_collisions: CaseCollisionPolicy,
+    /// check for case collisions even if the filesystem extracted to is case-sensitive, e.g. when
+    /// the tree will be copied to a case-insensitive one later
+    pub assume_case_insensitive: bool,
+    /// what happens to a file that already exists where a listing is extracted to
+    pub overwrite: OverwritePolicy,
+    /// maximum number of bytes of decompressed bundles held in memory at once by
+    /// `ExtractedArchive::from_reader_with_options`, which holds every bundle it needs, and by
+    /// `extract_sequentially`, which holds one at a time; archives needing more fail with
+    /// `ArchiveError::LimitExceeded`
+    pub max_memory: Option<u64>,
+    /// directory temporary files and directories are created in instead of the system's, such
+    /// as the one `ExtractedArchive::extract_to_temp` extracts into
+    pub temp_dir: Option<PathBuf>,
+    /// set the `FileFlags` stored in the listings on the extracted files and bare directories,
+    /// after their permissions, as the permission policy dictates; setting them usually takes
+    /// root, e.g. `CAP_LINUX_IMMUTABLE` for immutable files on Linux
+    pub file_flags: bool,
+    /// called with every warning as soon as it happens, besides collecting it in the stats
+    pub on_warning: Option<WarningCallback>,
+    /// called with every listing once it is archived or extracted
+    pub on_progress: Option<ProgressCallback>,
+    /// called with every extracted file which is itself an archive, e.g. to extract it as well
+    pub on_nested_archive: Option<NestedArchiveCallback>,
+}
+
+/// What happens to a file that already exists where a listing is extracted to; directories are
+/// always reused
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// replace the file with the listing
+    #[default]
+    Overwrite,
+    /// keep the file and skip the listing, recording a warning
+    Skip,
+    /// stop and return an `AlreadyExists` error
+    Error,
+}
+
+/// Builds `ExtractOptions` one option at a time from the defaults, validating them once they are
+/// all set
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptionsBuilder {
+    options: ExtractOptions,
+}
+
+impl ExtractOptions {
+    /// Returns a builder starting from the default options
+    pub fn builder() -> ExtractOptionsBuilder {
+        ExtractOptionsBuilder::default()
+    }
+}
+
+impl ExtractOptionsBuilder {
+    pub fn strip_components(mut self, count: usize) -> Self {
+        self.options.strip_components = count;
+        self
+    }
+
+    /// Adds a rewrite of listing paths starting with `from` to start with `to` instead
+    pub fn path_remap<F: Into<String>, T: Into<String>>(mut self, from: F, to: T) -> Self {
+        self.options.path_remaps.push((from.into(), to.into()));
+        self
+    }
+
+    pub fn permissions(mut self, permissions: PermissionMode) -> Self {
+        self.options.permissions = permissions;
+        self
+    }
+
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.options.file_mode = Some(mode);
+        self
+    }
+
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.options.dir_mode = Some(mode);
+        self
+    }
+
+    pub fn mode_mask(mut self, mask: u32) -> Self {
+        self.options.mode_mask = mask;
+        self
+    }
+
+    pub fn preserve_special_bits(mut self, preserve: bool) -> Self {
+        self.options.preserve_special_bits = preserve;
+        self
+    }
+
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.options.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = Some(threads);
+        self
+    }
+
+    pub fn verify(mut self, verify: VerifyLevel) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    pub fn permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.options.permission_policy = policy;
+        self
+    }
+
+    pub fn duplicate_paths(mut self, policy: DuplicatePathPolicy) -> Self {
+        self.options.duplicate_paths = policy;
+        self
+    }
+
+    pub fn case_collisions(mut self, policy: CaseCollisionPolicy) -> Self {
+        self.options.case_collisions = policy;
+        self
+    }
+
+    pub fn assume_case_insensitive(mut self, assume: bool) -> Self {
+        self.options.assume_case_insensitive = assume;
+        self
+    }
+
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.options.overwrite = policy;
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.options.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.options.temp_dir = Some(directory.into());
+        self
+    }
+
+    pub fn file_flags(mut self, file_flags: bool) -> Self {
+        self.options.file_flags = file_flags;
+        self
+    }
+
+    pub fn on_warning<F: Fn(&Warning) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.options.on_warning = Some(WarningCallback::new(callback));
+        self
+    }
+
+    pub fn on_progress<F: Fn(&Progress) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.options.on_progress = Some(ProgressCallback::new(callback));
+        self
+    }
+
+    pub fn on_nested_archive<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&NestedArchive) -> Result<(), io::Error> + Send + Sync + 'static,
+    {
+        self.options.on_nested_archive = Some(NestedArchiveCallback::new(callback));
+        self
+    }
+
+    /// Returns the options, or the error of `ExtractOptions::validate` if they don't make sense
+    pub fn build(self) -> Result<ExtractOptions, io::Error> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// What happens to a listing whose path differs only by case from an earlier listing, which it
+/// would overwrite on case-insensitive filesystems such as the defaults of macOS and Windows
+///
+/// Collisions are only looked for if the filesystem extracted to is case-insensitive, or
+/// `ExtractOptions::assume_case_insensitive` is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaseCollisionPolicy {
+    /// extract the listing anyway, recording a warning
+    #[default]
+    Warn,
+    /// stop and return an error
+    Error,
+    /// extract the listing to the first free path with `~1`, `~2`, ... appended to its name
+    /// before the extension, recording a warning
+    Rename,
+    /// don't look for collisions
+    Ignore,
+}
+
+/// What happens to a listing whose path is already taken by an earlier listing, as in listings
+/// constructed by hand or collected from case-insensitive filesystems, which would otherwise
+/// overwrite each other when extracted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePathPolicy {
+    /// stop and return an error
+    #[default]
+    Error,
+    /// keep only the first listing with the path, recording a warning for the others
+    Dedupe,
+    /// move the listing to the first free path with `~1`, `~2`, ... appended to its name before
+    /// the extension, recording a warning
+    Rename,
+}
+
+/// Returns `path` with `~n` appended to its name before the extension, e.g. `dir/name~1.txt`
+fn numbered_path(path: &str, n: usize) -> String {
+    let extension = extension_of(path);
+    let stem_end = match extension {
+        "" => path.len(),
+        _ => path.len() - extension.len() - 1,
+    };
+    format!("{}~{}{}", &path[..stem_end], n, &path[stem_end..])
+}
+
+/// Decides where listings are extracted to, applying the path transforms and then the duplicate
+/// path and case collision policies to the listings in order
+struct ExtractionPaths {
+    seen_paths: HashSet<String>,
+    /// the lowercased paths taken so far, if case collisions are looked for
+    lowercase_paths: Option<HashSet<String>>,
+}
+
+impl ExtractionPaths {
+    /// Prepares to extract to the existing `output_directory_path`, probing whether it is on a
+    /// case-insensitive filesystem unless that doesn't matter
+    fn new(
+        output_directory_path: &Path,
+        options: &ExtractOptions,
+    ) -> Result<ExtractionPaths, io::Error> {
+        let check_case = options.case_collisions != CaseCollisionPolicy::Ignore
+            && (options.assume_case_insensitive || is_case_insensitive(output_directory_path)?);
+        Ok(ExtractionPaths {
+            seen_paths: HashSet::new(),
+            lowercase_paths: check_case.then(HashSet::new),
+        })
+    }
+
+    /// Returns the path `listing` is extracted to relative to the output directory, or `None` if
+    /// it is left out
+    fn resolve(
+        &mut self,
+        listing: &ExtractedListing,
+        options: &ExtractOptions,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Option<String>, io::Error> {
+        let Some(path) = options.transform_path(&listing.path)? else {
+            return Ok(None);
+        };
+        let Some(path) = resolve_duplicate_path(
+            path,
+            &mut self.seen_paths,
+            options.duplicate_paths,
+            warnings,
+            options.on_warning.as_ref(),
+        )?
+        else {
+            return Ok(None);
+        };
+        let Some(lowercase_paths) = &mut self.lowercase_paths else {
+            return Ok(Some(path));
+        };
+        if lowercase_paths.insert(path.to_lowercase()) {
+            return Ok(Some(path));
+        }
+
+        let (path, warning) = match options.case_collisions {
+            CaseCollisionPolicy::Error => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} differs only by case from an earlier path, which it would overwrite",
+                        path
+                    ),
+                ))
+            }
+            CaseCollisionPolicy::Rename => {
+                let renamed = (1..)
+                    .map(|n| numbered_path(&path, n))
+                    .find(|renamed| !lowercase_paths.contains(&renamed.to_lowercase()))
+                    .unwrap();
+                lowercase_paths.insert(renamed.to_lowercase());
+                let warning = Warning::CaseCollision {
+                    path,
+                    renamed: Some(renamed.clone()),
+                };
+                (renamed, warning)
+            }
+            CaseCollisionPolicy::Warn | CaseCollisionPolicy::Ignore => {
+                let warning = Warning::CaseCollision {
+                    path: path.clone(),
+                    renamed: None,
+                };
+                (path, warning)
+            }
+        };
+        report_warning(warnings, options.on_warning.as_ref(), warning);
+        Ok(Some(path))
+    }
+}
+
+/// Returns a function naming `directory` in the error of creating a temporary file in it
+fn temp_dir_error(directory: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |error| {
+        io::Error::new(
+            error.kind(),
+            format!(
+                "could not create a temporary file in {}: {}",
+                directory.display(),
+                error
+            ),
+        )
+    }
+}
+
+/// Returns whether the filesystem holding `directory` treats names differing only by case as the
+/// same, by creating a file there and looking it up by its uppercased name
+fn is_case_insensitive(directory: &Path) -> Result<bool, io::Error> {
+    let probe = tempfile::Builder::new()
+        .prefix(".decaf-case-probe-")
+        .tempfile_in(directory)?;
+    let uppercase_name = probe
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_uppercase)
+        .unwrap_or_default();
+    Ok(fs::symlink_metadata(directory.join(uppercase_name)).is_ok())
+}
+
+/// Applies `policy` to `path` given the paths taken before it, returning the path to use or
+/// `None` if the listing is left out
+fn resolve_duplicate_path(
+    path: String,
+    seen_paths: &mut HashSet<String>,
+    policy: DuplicatePathPolicy,
+    warnings: &mut Vec<Warning>,
+    on_warning: Option<&WarningCallback>,
+) -> Result<Option<String>, io::Error> {
+    if !seen_paths.contains(&path) {
+        seen_paths.insert(path.clone());
+        return Ok(Some(path));
+    }
+    let (path, warning) = match policy {
+        DuplicatePathPolicy::Error => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("duplicate path {}", path),
+            ))
+        }
+        DuplicatePathPolicy::Dedupe => (
+            None,
+            Warning::DuplicatePath {
+                path,
+                renamed: None,
+            },
+        ),
+        DuplicatePathPolicy::Rename => {
+            let renamed = (1..)
+                .map(|n| numbered_path(&path, n))
+                .find(|renamed| !seen_paths.contains(renamed))
+                .unwrap();
+            seen_paths.insert(renamed.clone());
+            let warning = Warning::DuplicatePath {
+                path,
+                renamed: Some(renamed.clone()),
+            };
+            (Some(renamed), warning)
+        }
+    };
+    report_warning(warnings, on_warning, warning);
+    Ok(path)
+}
+
+/// Whether the permissions of extracted listings are set, and what happens when setting them
+/// fails, e.g. on FAT or exFAT filesystems or on restrictive mounts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionPolicy {
+    /// set the permissions of every listing, stopping extraction if that fails
+    #[default]
+    Strict,
+    /// set the permissions of every listing, recording a warning in `ArchiveStats::warnings`
+    /// for every listing where that fails
+    Lenient,
+    /// don't set permissions, leaving listings with the defaults of new files and directories
+    Skip,
+}
+
+/// How much of an archive is verified against its checksums while reading and extracting it,
+/// trading speed for assurance; every level includes the checks of the levels before it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyLevel {
+    /// verify nothing; corruption goes unnoticed unless it breaks decompression
+    None,
+    /// verify the archive checksum and the checksum of every decompressed bundle
+    Bundles,
+    /// also verify the checksum of every file before it is written
+    #[default]
+    Files,
+    /// also read every file back after it is written and verify it again
+    Paranoid,
+}
+
+impl ExtractOptions {
+    /// Sets the permissions of the listing at `listing_path` to the normalized `mode` with `set`
+    /// as the permission policy dictates, pushing tolerated failures to `warnings`
+    fn set_permissions<F: FnOnce(Permissions) -> Result<(), io::Error>>(
+        &self,
+        listing_path: &Path,
+        mode: u32,
+        warnings: &Mutex<Vec<Warning>>,
+        set: F,
+    ) -> Result<(), io::Error> {
+        if self.permission_policy == PermissionPolicy::Skip {
+            return Ok(());
+        }
+        let Err(e) = set(Permissions::from_mode(self.extracted_mode(mode))) else {
+            return Ok(());
+        };
+        let warning = Warning::PermissionNotRestored {
+            path: listing_path.to_path_buf(),
+            error: e.to_string(),
+        };
+        if self.permission_policy == PermissionPolicy::Strict {
+            return Err(io::Error::new(e.kind(), warning.to_string()));
+        }
+        report_warning(
+            &mut warnings.lock().unwrap(),
+            self.on_warning.as_ref(),
+            warning,
+        );
+        Ok(())
+    }
+
+    /// Sets the file flags stored in `permissions` on the listing at `listing_path` if asked to,
+    /// failing like setting its permissions does
+    fn set_file_flags(
+        &self,
+        listing_path: &Path,
+        permissions: u32,
+        warnings: &Mutex<Vec<Warning>>,
+    ) -> Result<(), io::Error> {
+        if !self.file_flags || self.permission_policy == PermissionPolicy::Skip {
+            return Ok(());
+        }
+        let flags = FileFlags::from_permissions(permissions);
+        if flags.is_empty() {
+            return Ok(());
+        }
+        let Err(e) = flags.set(listing_path) else {
+            return Ok(());
+        };
+        let warning = Warning::PermissionNotRestored {
+            path: listing_path.to_path_buf(),
+            error: format!("could not set file flags: {}", e),
+        };
+        if self.permission_policy == PermissionPolicy::Strict {
+            return Err(io::Error::new(e.kind(), warning.to_string()));
+        }
+        report_warning(
+            &mut warnings.lock().unwrap(),
+            self.on_warning.as_ref(),
+            warning,
+        );
+        Ok(())
+    }
+
+    /// Returns whether the file at `listing_path` may be written as the overwrite policy dictates,
+    /// pushing a warning to `warnings` for every existing file which is kept
+    fn may_write(
+        &self,
+        listing_path: &Path,
+        warnings: &Mutex<Vec<Warning>>,
+    ) -> Result<bool, io::Error> {
+        if self.overwrite == OverwritePolicy::Overwrite
+            || fs::symlink_metadata(listing_path).is_err()
+        {
+            return Ok(true);
+        }
+        if self.overwrite == OverwritePolicy::Error {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", listing_path.display()),
+            ));
+        }
+        let warning = Warning::ExistingFileKept {
+            path: listing_path.to_path_buf(),
+        };
+        report_warning(
+            &mut warnings.lock().unwrap(),
+            self.on_warning.as_ref(),
+            warning,
+        );
+        Ok(false)
+    }
+
+    /// Checks that the options make sense together, returning an `InvalidInput` error naming the
+    /// first option that doesn't
+    pub fn validate(&self) -> Result<(), io::Error> {
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if self.threads == Some(0) {
+            return invalid("threads must be at least 1");
+        }
+        if self.rate_limit == Some(0) {
+            return invalid("rate limit must be at least 1 byte per second");
+        }
+        if self.max_memory == Some(0) {
+            return invalid("memory limit must be at least 1 byte");
+        }
+        if [self.file_mode, self.dir_mode, Some(self.mode_mask)]
+            .into_iter()
+            .flatten()
+            .any(|mode| mode > 0o7777)
+        {
+            return invalid("file mode, directory mode and mode mask must be permission bits");
+        }
+        if self
+            .path_remaps
+            .iter()
+            .any(|(from, _)| from.trim_matches('/').is_empty())
+        {
+            return invalid("path transforms must rewrite a non-empty prefix");
+        }
+        Ok(())
+    }
+
+    /// Returns the mode a listing with the full `mode` is extracted with, after `permissions`,
+    /// `file_mode` or `dir_mode`, `mode_mask` and `preserve_special_bits`, keeping its file type
+    /// bits
+    pub fn extracted_mode(&self, mode: u32) -> u32 {
+        let mode = self.permissions.apply(mode);
+        let file_type = mode & 0o170000;
+        let bits = match (file_type == 0o040000, self.dir_mode, self.file_mode) {
+            (true, Some(dir_mode), _) => dir_mode,
+            (false, _, Some(file_mode)) => file_mode,
+            _ => mode,
+        };
+        let special_bits = if self.preserve_special_bits {
+            0
+        } else {
+            0o7000
+        };
+        file_type | (bits & 0o7777 & !self.mode_mask & !special_bits)
+    }
+
+    /// Applies the path transforms to a listing path, returning `None` if the listing should be
+    /// skipped entirely
+    ///
+    /// Fails if the listing path or its transformed path has a `..` component, which would place
+    /// the listing outside of the output directory.
+    pub fn transform_path(&self, path: &str) -> Result<Option<String>, io::Error> {
+        if path.split('/').any(|component| component == "..") {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing path {} leaves the output directory",
+                path
+            ))
+            .into());
+        }
+        let stripped: Vec<&str> = path
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .skip(self.strip_components)
+            .collect();
+        if stripped.is_empty() {
+            return Ok(None);
+        }
+        let stripped = stripped.join("/");
+
+        for (from, to) in &self.path_remaps {
+            let from = from.trim_end_matches('/');
+            let remainder = match stripped.strip_prefix(from) {
+                Some(remainder) if remainder.is_empty() || remainder.starts_with('/') => remainder,
+                _ => continue,
+            };
+            let remapped = format!("{}{}", to.trim_end_matches('/'), remainder);
+            let remapped = remapped.trim_start_matches('/');
+            if remapped.is_empty() {
+                return Ok(None);
+            }
+            if remapped.split('/').any(|component| component == "..") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is transformed to {}, which leaves the output directory",
+                        path, remapped
+                    ),
+                ));
+            }
+            return Ok(Some(remapped.to_string()));
+        }
+
+        Ok(Some(stripped))
+    }
+}
+
+/// Describes why an archive could not be read or written; returned inside of an `io::Error` with
+/// kind `InvalidData`, where it can be recovered with `io::Error::get_ref` and `downcast_ref`
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// the archive is malformed or truncated
+    Corrupt(String),
+    /// a checksum stored in the archive did not match the data it covers
+    Verification(String),
+    /// the archive is larger than one of the configured `Limits`
+    LimitExceeded(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Corrupt(message)
+            | ArchiveError::Verification(message)
+            | ArchiveError::LimitExceeded(message) => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for ArchiveError {}
+
+impl From<ArchiveError> for io::Error {
+    fn from(error: ArchiveError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Converts a size or offset to a `usize` to address memory with, failing instead of truncating it
+/// on targets where it doesn't fit, such as sizes over 4 GiB on 32-bit targets
+fn addressable(value: u64, name: &str) -> Result<usize, ArchiveError> {
+    usize::try_from(value).map_err(|_| {
+        ArchiveError::LimitExceeded(format!(
+            "{} of {} does not fit in memory on this target",
+            name, value
+        ))
+    })
+}
+
+// TODO: use .map_err() for all the ?s
+
+// TODO: remove excessive buffering while writing archives; we can stitch data in whenever we want
+// by using Trait std::io::Seek
+
+// in general, we need to do way more pre-computation of buffer and file sizes etc etc
+
+// size of the chunks files are read and written in when their throughput is limited
+const RATE_LIMIT_CHUNK_SIZE: usize = 256 * 1024;
+
+// size of the chunks files are read in for archiving, each hashed while it is still in the cache
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Shares a bytes per second budget between every caller of `throttle`
+struct RateLimiter {
+    bytes_per_second: u64,
+    start: Instant,
+    total_bytes: Mutex<u64>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_second: Option<u64>) -> Option<RateLimiter> {
+        bytes_per_second.map(|bytes_per_second| RateLimiter {
+            bytes_per_second,
+            start: Instant::now(),
+            total_bytes: Mutex::new(0),
+        })
+    }
+
+    /// Accounts for `bytes` having been transferred, sleeping until they fit into the budget
+    fn throttle(&self, bytes: usize) {
+        let total_bytes = {
+            let mut total_bytes = self.total_bytes.lock().unwrap();
+            *total_bytes += bytes as u64;
+            *total_bytes
+        };
+        let due = Duration::from_secs_f64(total_bytes as f64 / self.bytes_per_second as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// The content of a listing as it is archived, with its checksum
+struct ListingContent {
+    bytes: Vec<u8>,
+    checksum: u64,
+}
+
+impl ListingContent {
+    fn new(bytes: Vec<u8>, hash: HashAlgorithm) -> ListingContent {
+        ListingContent {
+            checksum: hash.hash(&bytes),
+            bytes,
+        }
+    }
+}
+
+/// Reads the file at `path` in chunks, throttled if there is a `limiter`, hashing every chunk as
+/// soon as it is read instead of reading the whole content back afterwards
+fn read_checksummed(
+    path: &Path,
+    limiter: Option<&RateLimiter>,
+    hash: HashAlgorithm,
+) -> Result<ListingContent, io::Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+    let mut bytes = Vec::with_capacity(addressable(size, "file size")?);
+    let mut hasher = hash.hasher();
+    let chunk_size = match limiter {
+        Some(_) => RATE_LIMIT_CHUNK_SIZE,
+        None => READ_CHUNK_SIZE,
+    };
+    loop {
+        let start = bytes.len();
+        let read = (&mut file)
+            .take(chunk_size as u64)
+            .read_to_end(&mut bytes)?;
+        if read == 0 {
+            return Ok(ListingContent {
+                bytes,
+                checksum: hasher.digest(),
+            });
+        }
+        hasher.update(&bytes[start..]);
+        if let Some(limiter) = limiter {
+            limiter.throttle(read);
+        }
+    }
+}
+
+/// Writes `content` to `writer`, in throttled chunks if there is a `limiter`
+fn write_limited<W: Write>(
+    writer: &mut W,
+    content: &[u8],
+    limiter: Option<&RateLimiter>,
+) -> Result<(), io::Error> {
+    let Some(limiter) = limiter else {
+        return writer.write_all(content);
+    };
+
+    for chunk in content.chunks(RATE_LIMIT_CHUNK_SIZE) {
+        writer.write_all(chunk)?;
+        limiter.throttle(chunk.len());
+    }
+    Ok(())
+}
+
+/// Rewrites an archive of any readable format revision as a current revision archive
+pub fn upgrade<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<WrittenArchive, io::Error> {
+    repack(reader, writer, &ArchiveOptions::default())
+}
+
+fn relative_path_from<P: AsRef<Path>, B: AsRef<Path>>(path: P, base: B) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let base = base.as_ref();
+
+    if path.is_absolute() != base.is_absolute() {
+        if path.is_absolute() {
+            Some(PathBuf::from(path))
+        } else {
+            None
+        }
+    } else {
+        let mut ita = path.components();
+        let mut itb = base.components();
+        let mut comps: Vec<Component> = Vec::new();
+        loop {
+            match (ita.next(), itb.next()) {
+                (None, None) => break,
+                (Some(a), None) => {
+                    comps.push(a);
+                    comps.extend(ita.by_ref());
+                    break;
+                }
+                (None, _) => comps.push(Component::ParentDir),
+                (Some(a), Some(b)) if comps.is_empty() && a == b => (),
+                (Some(a), Some(Component::CurDir)) => comps.push(a),
+                (Some(_), Some(Component::ParentDir)) => return None,
+                (Some(a), Some(_)) => {
+                    comps.push(Component::ParentDir);
+                    for _ in itb {
+                        comps.push(Component::ParentDir);
+                    }
+                    comps.push(a);
+                    comps.extend(ita.by_ref());
+                    break;
+                }
+            }
+        }
+        Some(comps.iter().map(|c| c.as_os_str()).collect())
+    }
+}
+
+/// Returns the listing path of `relative_path`, with its components separated by forward slashes
+/// whatever the platform separator is
+fn listing_path_from(relative_path: &Path) -> Result<String, io::Error> {
+    let mut components = Vec::new();
+    for component in relative_path.components() {
+        match component {
+            Component::Normal(name) => components.push(
+                name.to_str()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid path"))?,
+            ),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a relative path", relative_path.display()),
+                ))
+            }
+        }
+    }
+    Ok(components.join("/"))
+}
+
+/// Returns `path` as it is stored in a listing: separated by forward slashes, without empty or
+/// `.` components
+fn normalize_listing_path(path: &str) -> String {
+    path.split(['/', MAIN_SEPARATOR])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Returns the size of the content of the `listings` which are extracted with `options`, for
+/// `Progress::total`
+fn extracted_size(listings: &[ExtractedListing], options: &ExtractOptions) -> u64 {
+    listings
+        .iter()
+        .filter(|listing| matches!(options.transform_path(&listing.path), Ok(Some(_))))
+        .map(|listing| listing.filesize)
+        .sum()
+}
+
+/// Returns the listing path `path` with the separators of the platform, relative to the directory
+/// it is extracted to
+fn native_path(path: &str) -> PathBuf {
+    path.split('/').collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivableListing {
+    pub relative_path: Box<str>, // relative file or directory path
+    pub permissions: u32,
+    pub file_size: u64,
+    pub literal_path: PathBuf,
+}
+
+impl ArchivableListing {
+    /// Reads the content the listing would be archived with, returning its size and its checksum
+    /// with `hash`, which is 0 without content like in a listing entry
+    fn read_checksum(&self, hash: HashAlgorithm) -> Result<(u64, u64), io::Error> {
+        let is_empty_file = is_regular_file(self.permissions) && self.file_size == 0;
+        if is_empty_file || self.literal_path.as_os_str().is_empty() {
+            return Ok((0, 0));
+        }
+        // hashed a chunk at a time, so that checking a tree never holds more than one chunk
+        let mut file = File::open(&self.literal_path)?;
+        let mut hasher = hash.hasher();
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        let mut size = 0;
+        loop {
+            let read = match file.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&chunk[..read]);
+            size += read as u64;
+        }
+        let checksum = if size == 0 { 0 } else { hasher.digest() };
+        Ok((size, checksum))
+    }
+}
+
+impl Ord for ArchivableListing {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // compare by content length
+        self.file_size
+            .cmp(&other.file_size)
+            // compare by path length
+            .then(self.relative_path.len().cmp(&other.relative_path.len()))
+            // compare by path bytes, so that the order is total and never depends on the order
+            // the listings were collected in
+            .then(self.relative_path.cmp(&other.relative_path))
+            // compare by permissions
+            .then(self.permissions.cmp(&other.permissions))
+    }
+}
+
+impl Eq for ArchivableListing {}
+
+impl PartialEq for ArchivableListing {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for ArchivableListing {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A problem that was worked around instead of failing, as collected in `ArchiveStats::warnings`
+/// and passed to an `on_warning` callback as soon as it happens
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// a symbolic link pointing outside of the archived tree was left out
+    SkippedSymlink { path: String },
+    /// a FIFO, socket or device was left out, as it has no content that could be archived
+    SkippedSpecialFile { path: String },
+    /// a file that couldn't be read was left out, or archived as empty if `archived_empty` is
+    /// set, as `ReadErrorPolicy` dictates
+    UnreadableFile {
+        path: String,
+        error: String,
+        archived_empty: bool,
+    },
+    /// the size of a file changed between indexing and reading it; it is archived as read
+    FileChangedDuringRead {
+        path: String,
+        indexed_size: u64,
+        read_size: u64,
+    },
+    /// a listing whose path was already taken was left out, or renamed to `renamed`
+    DuplicatePath {
+        path: String,
+        renamed: Option<String>,
+    },
+    /// a listing whose path differs only by case from an earlier one was extracted anyway, or
+    /// renamed to `renamed`
+    CaseCollision {
+        path: String,
+        renamed: Option<String>,
+    },
+    /// the permissions of an extracted listing couldn't be set under
+    /// `PermissionPolicy::Lenient`
+    PermissionNotRestored { path: PathBuf, error: String },
+    /// an existing file was kept instead of a listing under `OverwritePolicy::Skip`
+    ExistingFileKept { path: PathBuf },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::SkippedSymlink { path } => {
+                write!(f, "skipped {}: it links outside of the archived tree", path)
+            }
+            Warning::SkippedSpecialFile { path } => {
+                write!(
+                    f,
+                    "skipped {}: it is not a regular file, directory or link",
+                    path
+                )
+            }
+            Warning::UnreadableFile {
+                path,
+                error,
+                archived_empty: false,
+            } => write!(f, "skipped {}: {}", path, error),
+            Warning::UnreadableFile { path, error, .. } => {
+                write!(f, "archived {} as empty: {}", path, error)
+            }
+            Warning::FileChangedDuringRead {
+                path,
+                indexed_size,
+                read_size,
+            } => write!(
+                f,
+                "{} changed while it was archived, from {} to {} bytes",
+                path, indexed_size, read_size
+            ),
+            Warning::DuplicatePath {
+                path,
+                renamed: None,
+            } => write!(f, "left out duplicate path {}", path),
+            Warning::DuplicatePath {
+                path,
+                renamed: Some(renamed),
+            } => write!(f, "renamed duplicate path {} to {}", path, renamed),
+            Warning::CaseCollision {
+                path,
+                renamed: None,
+            } => write!(
+                f,
+                "{} differs only by case from an earlier path, which it overwrites",
+                path
+            ),
+            Warning::CaseCollision {
+                path,
+                renamed: Some(renamed),
+            } => write!(
+                f,
+                "renamed {} to {}, as it differs only by case from an earlier path",
+                path, renamed
+            ),
+            Warning::PermissionNotRestored { path, error } => write!(
+                f,
+                "Failed to set permissions for {}: {}",
+                path.display(),
+                error
+            ),
+            Warning::ExistingFileKept { path } => {
+                write!(f, "kept existing file {}", path.display())
+            }
+        }
+    }
+}
+
+/// A function called with every warning as soon as it happens, e.g. to log it or to abort
+/// automation on the kinds it can't tolerate; it is called from the threads doing the work
+#[derive(Clone)]
+pub struct WarningCallback(Arc<dyn Fn(&Warning) + Send + Sync>);
+
+impl WarningCallback {
+    pub fn new<F: Fn(&Warning) + Send + Sync + 'static>(callback: F) -> WarningCallback {
+        WarningCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for WarningCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningCallback")
+    }
+}
+
+/// A listing which was just archived or extracted, as passed to an `on_progress` callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress<'a> {
+    /// the path of the listing in the archive
+    pub path: &'a str,
+    pub permissions: u32,
+    /// the size of the content of the listing
+    pub size: u64,
+    /// the size of the content of every listing archived or extracted so far, including this one
+    pub done: u64,
+    /// the size of the content of every listing being archived or extracted, known before the
+    /// first is, e.g. to show a percentage or estimate the time left; `None` when extracting a
+    /// stream, whose listings are only known as it is read
+    pub total: Option<u64>,
+}
+
+/// A function called with every listing once it is archived or extracted, e.g. to print it like
+/// `tar -v`; during extraction it is called from the threads writing the listings, in no
+/// particular order
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(&Progress) + Send + Sync + 'static>(callback: F) -> ProgressCallback {
+        ProgressCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// A kind of archive that can be found as a file inside of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NestedArchiveKind {
+    /// a DeCAF archive of any revision
+    Decaf,
+    /// a DeCAF stream
+    DecafStream,
+    /// a POSIX tar archive
+    Tar,
+    /// a gzipped tar archive
+    TarGz,
+}
+
+impl NestedArchiveKind {
+    /// Returns the kind of archive the file at `path` with `content` is, recognized by its magic
+    /// number; gzipped files are only taken for tarballs if their name ends in `.tar.gz` or `.tgz`
+    pub fn detect(path: &str, content: &[u8]) -> Option<NestedArchiveKind> {
+        let magic = content.get(..8)?;
+        if magic == format::MAGIC_NUMBER || magic == format::LEGACY_MAGIC_NUMBER {
+            Some(NestedArchiveKind::Decaf)
+        } else if magic == format::STREAM_MAGIC_NUMBER {
+            Some(NestedArchiveKind::DecafStream)
+        } else if magic.starts_with(&[0x1f, 0x8b])
+            && (path.ends_with(".tar.gz") || path.ends_with(".tgz"))
+        {
+            Some(NestedArchiveKind::TarGz)
+        } else if content.get(257..262) == Some(b"ustar") {
+            Some(NestedArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// A file which is itself an archive, as passed to an `on_nested_archive` callback once it is
+/// extracted
+#[derive(Debug, Clone, Copy)]
+pub struct NestedArchive<'a> {
+    /// the path of the listing in the archive
+    pub path: &'a str,
+    /// where the file was extracted to
+    pub extracted_path: &'a Path,
+    pub kind: NestedArchiveKind,
+}
+
+/// A function called with every extracted file which is itself an archive, once the files of the
+/// bundles holding it are written; an error fails the extraction
+#[derive(Clone)]
+pub struct NestedArchiveCallback(Arc<NestedArchiveFn>);
+
+type NestedArchiveFn = dyn Fn(&NestedArchive) -> Result<(), io::Error> + Send + Sync;
+
+impl NestedArchiveCallback {
+    pub fn new<F>(callback: F) -> NestedArchiveCallback
+    where
+        F: Fn(&NestedArchive) -> Result<(), io::Error> + Send + Sync + 'static,
+    {
+        NestedArchiveCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for NestedArchiveCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NestedArchiveCallback")
+    }
+}
+
+/// Adds up the content of the listings passed to an `on_progress` callback, which the threads
+/// writing extracted files share
+struct ProgressCounter<'a> {
+    callback: Option<&'a ProgressCallback>,
+    total: Option<u64>,
+    done: AtomicU64,
+}
+
+impl ProgressCounter<'_> {
+    fn new(callback: Option<&ProgressCallback>, total: Option<u64>) -> ProgressCounter<'_> {
+        ProgressCounter {
+            callback,
+            total,
+            done: AtomicU64::new(0),
+        }
+    }
+
+    /// Passes the listing at `path` to the callback if there is one
+    fn report(&self, path: &str, permissions: u32, size: u64) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        let done = self.done.fetch_add(size, AtomicOrdering::Relaxed) + size;
+        (callback.0)(&Progress {
+            path,
+            permissions,
+            size,
+            done,
+            total: self.total,
+        });
+    }
+}
+
+/// Traces `warning`, passes it to `callback` if there is one and appends it to `warnings`
+fn report_warning(
+    warnings: &mut Vec<Warning>,
+    callback: Option<&WarningCallback>,
+    warning: Warning,
+) {
+    trace_event!(warn, "{}", warning);
+    if let Some(callback) = callback {
+        (callback.0)(&warning);
+    }
+    warnings.push(warning);
+}
+
+/// Describes an archive which was just written, so that callers can record its provenance
+/// without reading it back
+#[derive(Debug, Clone)]
+pub struct WrittenArchive {
+    /// total number of bytes written
+    pub bytes_written: usize,
+    /// the checksum stored in the archive header, or at the end of a stream
+    pub checksum: u64,
+    /// the bundles of the archive, in order
+    pub bundles: Vec<BundleInfo>,
+    pub stats: ArchiveStats,
+}
+
+/// Totals describing an archive that was written or extracted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveStats {
+    /// number of regular files
+    pub file_count: usize,
+    /// number of bare directories
+    pub dir_count: usize,
+    pub symlink_count: usize,
+    /// total size of the content of every listing
+    pub uncompressed_bytes: u64,
+    /// total size of the compressed bundles
+    pub compressed_bytes: u64,
+    pub bundle_count: usize,
+    /// how long the operation took
+    pub duration: Duration,
+    /// problems that were worked around instead of failing, such as listings skipped under
+    /// `ReadErrorPolicy::Skip`
+    pub warnings: Vec<Warning>,
+}
+
+impl ArchiveStats {
+    /// Counts a listing with the given full mode and content size
+    fn add_listing(&mut self, mode: u32, size: u64) {
+        match mode & 0o170000 {
+            0o040000 => self.dir_count += 1,
+            0o120000 => self.symlink_count += 1,
+            _ => self.file_count += 1,
+        }
+        self.uncompressed_bytes += size;
+    }
+
+    /// Returns the compression ratio, i.e. how many times smaller the bundles are compressed
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+/// What was found checking where an archive would be extracted, before anything is written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreflightReport {
+    /// paths that would be overwritten, or that are in the way of a directory to be created
+    pub collisions: Vec<PathBuf>,
+    /// existing directories that listings would be created in but that can't be written to
+    pub unwritable: Vec<PathBuf>,
+    /// total size of the content of the listings to be extracted
+    pub required_bytes: u64,
+    /// space available to unprivileged users on the filesystem extracted to
+    pub available_bytes: u64,
+}
+
+impl PreflightReport {
+    pub fn has_enough_space(&self) -> bool {
+        self.required_bytes <= self.available_bytes
+    }
+
+    /// Returns whether extraction is expected to succeed without overwriting anything
+    pub fn is_ok(&self) -> bool {
+        self.collisions.is_empty() && self.unwritable.is_empty() && self.has_enough_space()
+    }
+}
+
+/// Describes a single bundle of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BundleInfo {
+    /// offset of the compressed bundle from the start of the archive
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// checksum of the uncompressed bundle
+    pub checksum: u64,
+}
+
+/// How a bundle is compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// a single zstd frame
+    #[default]
+    Zstd,
+    /// independent zstd frames of `frame_size` uncompressed bytes followed by a seek table in the
+    /// zstd seekable format, as written with `ArchiveOptions::seekable_frame_size`
+    SeekableZstd { frame_size: usize },
+}
+
+/// The uncompressed content of a bundle, which is compressed and decompressed exactly like the
+/// bundles of an archive, e.g. to read or write single bundles of an archive without decaf doing
+/// the I/O
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bundle {
+    pub content: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn new(content: Vec<u8>) -> Bundle {
+        Bundle { content }
+    }
+
+    /// Returns the checksum of the content computed with `hash`, as stored in the bundle table of
+    /// an archive checksummed with `hash`
+    pub fn checksum(&self, hash: HashAlgorithm) -> u64 {
+        hash.hash(&self.content)
+    }
+
+    /// Compresses the bundle with `codec` at the zstd compression `level`
+    pub fn compress(&self, codec: Codec, level: i32) -> Result<Vec<u8>, io::Error> {
+        let mut context = zstd_safe::CCtx::create();
+        compress_with_context(&self.content, codec, level, false, &mut context)
+    }
+
+    /// Compresses the bundle like `compress`, storing the zstd checksum and the uncompressed size
+    /// in every zstd frame, as written with `ArchiveOptions::zstd_frame_checksums`
+    pub fn compress_with_frame_checksums(
+        &self,
+        codec: Codec,
+        level: i32,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut context = zstd_safe::CCtx::create();
+        compress_with_context(&self.content, codec, level, true, &mut context)
+    }
+
+    /// Decompresses a bundle compressed with any codec, failing unless it decompresses to
+    /// `expected_len` bytes with the `checksum` computed with `hash`
+    pub fn decompress(
+        compressed: &[u8],
+        expected_len: u64,
+        checksum: u64,
+        hash: HashAlgorithm,
+    ) -> Result<Bundle, io::Error> {
+        let corrupt = |e: io::Error| {
+            ArchiveError::Corrupt(format!("invalid bundle: could not decompress: {}", e))
+        };
+        let mut content = Vec::new();
+        zstd::read::Decoder::new(compressed)
+            .map_err(corrupt)?
+            .take(expected_len.saturating_add(1))
+            .read_to_end(&mut content)
+            .map_err(corrupt)?;
+        if content.len() as u64 != expected_len {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid bundle: does not decompress to the expected {} bytes",
+                expected_len
+            ))
+            .into());
+        }
+        let bundle = Bundle { content };
+        if bundle.checksum(hash) != checksum {
+            return Err(ArchiveError::Verification(
+                "invalid bundle: could not verify bundle integrity".to_string(),
+            )
+            .into());
+        }
+        Ok(bundle)
+    }
+}
+
+pub struct ArchivableArchive {
+    pub listings: Vec<ArchivableListing>,
+    /// warnings for what was left out while collecting the listings, reported again in the stats
+    /// of every archive written from them
+    pub skipped: Vec<Warning>,
+}
+
+impl ArchivableArchive {
+    /// Writes the listings as an archive, or as a stream if `stream` is set, compressing bundles
+    /// on `pool` if given
+    fn create_archive<W: Write>(
+        &self,
+        writer: &mut W,
+        options: &ArchiveOptions,
+        stream: bool,
+        pool: Option<&CompressionPool>,
+    ) -> Result<WrittenArchive, io::Error> {
+        options.check()?;
+        let limiter = RateLimiter::new(options.rate_limit);
+        let (mut plan, order) = listing_order(&self.listings, options);
+        if options.file_flags {
+            // bare directories have no path to read flags from; unreadable files fail or are
+            // reported once their content is read
+            for &listing_idx in &order {
+                let literal_path = &self.listings[listing_idx].literal_path;
+                if !literal_path.as_os_str().is_empty() {
+                    let flags = FileFlags::read(literal_path).unwrap_or_default();
+                    plan.permissions[listing_idx] |= flags.to_permissions();
+                }
+            }
+        }
+        let mut skipped = Vec::new();
+        for warning in &self.skipped {
+            report_warning(&mut skipped, options.on_warning.as_ref(), warning.clone());
+        }
+
+        // files are read on their own thread, up to a bounded number of listings ahead of bundle
+        // assembly, so that reading overlaps checksumming and compression
+        let read_ahead = match options.low_memory {
+            true => 1,
+            false => READ_AHEAD_LISTINGS,
+        };
+        thread::scope(|scope| {
+            let (content_sender, content_receiver) = mpsc::sync_channel(read_ahead);
+            scope.spawn(move || {
+                for listing_idx in order {
+                    // get file content for listing if necessary; empty regular files, and any
+                    // regular file of a structure-only archive, are not opened
+                    let listing = &self.listings[listing_idx];
+                    let is_empty_file = is_regular_file(listing.permissions)
+                        && (listing.file_size == 0 || options.structure_only);
+                    let content = if !is_empty_file && listing.literal_path.to_str().unwrap() != ""
+                    {
+                        read_checksummed(&listing.literal_path, limiter.as_ref(), options.hash)
+                    } else {
+                        Ok(ListingContent::new(Vec::new(), options.hash))
+                    };
+                    // archiving stopped early if nothing is receiving anymore
+                    if content_sender.send((listing_idx, content)).is_err() {
+                        return;
+                    }
+                }
+            });
+            let write = match stream {
+                true => write_ordered_stream,
+                false => write_ordered_archive,
+            };
+            let mut written = write(
+                &self.listings,
+                &plan,
+                content_receiver.into_iter(),
+                writer,
+                options,
+                pool,
+            )?;
+            written.stats.warnings.splice(0..0, skipped);
+            Ok(written)
+        })
+    }
+
+    pub fn archive_to_file<P: AsRef<Path>>(
+        &self,
+        output_archive_path: P,
+    ) -> Result<usize, io::Error> {
+        self.archive_to_file_with_options(output_archive_path, &ArchiveOptions::default())
+            .map(|written| written.bytes_written)
+    }
+
+    pub fn archive_to_file_with_options<P: AsRef<Path>>(
+        &self,
+        output_archive_path: P,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let output_archive_path = output_archive_path.as_ref();
+        if !options.atomic_write {
+            let mut writer = BufWriter::new(File::create(output_archive_path)?);
+            return self.create_archive(&mut writer, options, false, None);
+        }
+
+        let mut temporary_path = output_archive_path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let written = File::create(&temporary_path).and_then(|temporary_file| {
+            let mut writer = BufWriter::new(temporary_file);
+            let written = self.create_archive(&mut writer, options, false, None)?;
+            // the content has to be on disk before the rename is
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            Ok(written)
+        });
+        match written.and_then(|written| {
+            fs::rename(&temporary_path, output_archive_path)?;
+            Ok(written)
+        }) {
+            Ok(written) => Ok(written),
+            Err(e) => {
+                let _ = fs::remove_file(&temporary_path);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn archive_to_writer<W: Write>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        self.archive_to_writer_with_options(writer, &ArchiveOptions::default())
+            .map(|written| written.bytes_written)
+    }
+
+    pub fn archive_to_writer_with_options<W: Write>(
+        &self,
+        writer: &mut W,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let mut writer = BufWriter::new(writer);
+        self.create_archive(&mut writer, options, false, None)
+    }
+
+    /// Brings the listings of the tree under `directory_path`, which they were indexed from, up to
+    /// date after the paths in `changed_paths` changed, e.g. as reported by a filesystem watcher
+    ///
+    /// Files which were listed before and still are files only have their metadata read again.
+    /// Created paths are walked on their own and removed ones dropped along with everything under
+    /// them, and the bare listings of their parent directories are dropped or added to match; only
+    /// paths outside of the tree or that aren't UTF-8 walk the whole tree again. Returns whether
+    /// any listings were added, e.g. to filter them again.
+    pub fn refresh<P: AsRef<Path>, I: IntoIterator<Item = PathBuf>>(
+        &mut self,
+        directory_path: P,
+        changed_paths: I,
+    ) -> Result<bool, io::Error> {
+        let directory_path = directory_path.as_ref();
+        let index: HashMap<&str, usize> = self
+            .listings
+            .iter()
+            .enumerate()
+            .map(|(i, listing)| (&*listing.relative_path, i))
+            .collect();
+        let mut updates = Vec::new();
+        let mut reindexed = Vec::new();
+        for path in changed_paths {
+            let Some(relative_path) = path
+                .strip_prefix(directory_path)
+                .ok()
+                .and_then(Path::to_str)
+            else {
+                *self = create_archive_from_directory(directory_path)?;
+                return Ok(true);
+            };
+            let listed = index
+                .get(relative_path)
+                .filter(|&&i| is_regular_file(self.listings[i].permissions));
+            match (listed, fs::symlink_metadata(&path)) {
+                (Some(&i), Ok(metadata)) if metadata.is_file() => updates.push((i, metadata)),
+                // the root itself is never listed
+                _ if relative_path.is_empty() => {}
+                _ => reindexed.push(relative_path.to_string()),
+            }
+        }
+        for (i, metadata) in updates {
+            self.listings[i].permissions = metadata.permissions().mode();
+            self.listings[i].file_size = metadata.size();
+        }
+
+        let mut added = false;
+        for relative_path in reindexed {
+            let mut relative_path = relative_path.as_str();
+            // the metadata of a directory is only kept while it is bare, so a directory which
+            // still has listings under it has nothing to update; its changed entries are reported
+            // on their own
+            let is_dir = fs::symlink_metadata(directory_path.join(relative_path))
+                .is_ok_and(|metadata| metadata.is_dir());
+            if is_dir
+                && self.listings.iter().any(|listing| {
+                    &*listing.relative_path != relative_path
+                        && is_within(&listing.relative_path, relative_path)
+                })
+            {
+                continue;
+            }
+
+            // walks the path, and then its parents for as long as removing it leaves them bare
+            loop {
+                let path = directory_path.join(relative_path);
+                self.listings
+                    .retain(|listing| !is_within(&listing.relative_path, relative_path));
+                self.skipped.retain(|warning| match warning {
+                    Warning::SkippedSymlink { path } | Warning::SkippedSpecialFile { path } => {
+                        !is_within(path, relative_path)
+                    }
+                    _ => true,
+                });
+                let mut listings = Vec::new();
+                match fs::symlink_metadata(&path) {
+                    Ok(metadata) => walk_entry(
+                        &path,
+                        metadata,
+                        directory_path,
+                        &mut listings,
+                        &mut self.skipped,
+                    )?,
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                    Err(error) => return Err(error),
+                }
+                if !listings.is_empty() {
+                    // the parents aren't bare any more
+                    self.listings
+                        .retain(|listing| !is_within(relative_path, &listing.relative_path));
+                    self.listings.append(&mut listings);
+                    added = true;
+                    break;
+                }
+                let Some((parent, _)) = relative_path.rsplit_once('/') else {
+                    break;
+                };
+                if self
+                    .listings
+                    .iter()
+                    .any(|listing| is_within(&listing.relative_path, parent))
+                {
+                    break;
+                }
+                relative_path = parent;
+            }
+        }
+
+        // the listings are kept in the order of a fresh walk
+        self.listings.sort();
+        Ok(added)
+    }
+
+    /// Returns the listings as they would be read back from an archive of them, with the
+    /// checksums of their content computed with `hash` but without bundles, e.g. to compare a live
+    /// tree against an archive without archiving it
+    pub fn checksummed_listings(
+        &self,
+        hash: HashAlgorithm,
+    ) -> Result<Vec<ExtractedListing>, io::Error> {
+        self.listings
+            .iter()
+            .map(|listing| {
+                let (filesize, content_checksum) = listing.read_checksum(hash)?;
+                Ok(ExtractedListing {
+                    path: listing.relative_path.clone(),
+                    permissions: listing.permissions,
+                    content_checksum,
+                    filesize,
+                    bundle_idx: 0,
+                    bundle_offset: 0,
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the listings as a stream, which unlike an archive is written as it is compressed, so
+    /// that it can be piped to `extract_stream` without ever being held in memory as a whole
+    pub fn archive_to_stream<W: Write>(
+        &self,
+        writer: &mut W,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let mut writer = BufWriter::new(writer);
+        self.create_archive(&mut writer, options, true, None)
+    }
+
+    /// Returns an iterator over the archive of the listings in parts of `part_size` bytes, the
+    /// last of which may be shorter, e.g. for the parts of a multipart upload
+    ///
+    /// The archive is written on its own thread, which stays at most one part ahead of the
+    /// iterator. An archive can only be written once its bundles are compressed, so they are held
+    /// until then, in memory or spilled to a temporary file with `ArchiveOptions::low_memory`;
+    /// `into_stream_parts` holds nothing but the part being filled.
+    pub fn into_parts(
+        self,
+        part_size: usize,
+        options: ArchiveOptions,
+    ) -> Result<ArchivePartIterator, io::Error> {
+        ArchivePartIterator::spawn(self, part_size, options, false)
+    }
+
+    /// Returns an iterator over the listings written as a stream, in parts of `part_size` bytes
+    /// like `into_parts`
+    pub fn into_stream_parts(
+        self,
+        part_size: usize,
+        options: ArchiveOptions,
+    ) -> Result<ArchivePartIterator, io::Error> {
+        ArchivePartIterator::spawn(self, part_size, options, true)
+    }
+}
+
+/// Yields an archive in parts of a fixed size as it is written, as returned by
+/// `ArchivableArchive::into_parts`
+///
+/// If writing fails, the error is the last item. Dropping the iterator early stops writing.
+pub struct ArchivePartIterator {
+    parts: mpsc::Receiver<Vec<u8>>,
+    writer: Option<thread::JoinHandle<Result<WrittenArchive, io::Error>>>,
+    written: Option<WrittenArchive>,
+}
+
+impl ArchivePartIterator {
+    fn spawn(
+        archive: ArchivableArchive,
+        part_size: usize,
+        options: ArchiveOptions,
+        stream: bool,
+    ) -> Result<ArchivePartIterator, io::Error> {
+        if part_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "part size must be at least 1 byte",
+            ));
+        }
+        options.check()?;
+        let (sender, parts) = mpsc::sync_channel(1);
+        let writer = thread::spawn(move || {
+            let mut writer = PartWriter {
+                part: Vec::with_capacity(part_size),
+                part_size,
+                sender,
+            };
+            let written = archive.create_archive(&mut writer, &options, stream, None)?;
+            if !writer.part.is_empty() {
+                writer.send_part()?;
+            }
+            Ok(written)
+        });
+        Ok(ArchivePartIterator {
+            parts,
+            writer: Some(writer),
+            written: None,
+        })
+    }
+
+    /// Returns the description of the archive once every part has been yielded
+    pub fn written(&self) -> Option<&WrittenArchive> {
+        self.written.as_ref()
+    }
+}
+
+impl Iterator for ArchivePartIterator {
+    type Item = Result<Vec<u8>, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(part) = self.parts.recv() {
+            return Some(Ok(part));
+        }
+        // the writer is done once it has dropped its end of the channel
+        let written = self
+            .writer
+            .take()?
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload));
+        match written {
+            Ok(written) => {
+                self.written = Some(written);
+                None
+            }
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+/// Collects written bytes into parts of `part_size` bytes, sending every part once it is full
+struct PartWriter {
+    part: Vec<u8>,
+    part_size: usize,
+    sender: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl PartWriter {
+    fn send_part(&mut self) -> Result<(), io::Error> {
+        let part = std::mem::replace(&mut self.part, Vec::with_capacity(self.part_size));
+        self.sender.send(part).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "nothing is receiving the parts of the archive anymore",
+            )
+        })
+    }
+}
+
+impl Write for PartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.part_size - self.part.len());
+        self.part.extend_from_slice(&buf[..length]);
+        if self.part.len() == self.part_size {
+            self.send_part()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes `listings` as an archive, pulling the content of the listing at each index from
+/// `read_content`; the `literal_path` of each listing is not used
+pub fn write_archive<W: Write, F: FnMut(usize) -> Result<Vec<u8>, io::Error>>(
+    listings: &[ArchivableListing],
+    mut read_content: F,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    let (plan, order) = listing_order(listings, options);
+    write_ordered_archive(
+        listings,
+        &plan,
+        order.into_iter().map(|listing_idx| {
+            let content =
+                read_content(listing_idx).map(|bytes| ListingContent::new(bytes, options.hash));
+            (listing_idx, content)
+        }),
+        writer,
+        options,
+        None,
+    )
+}
+
+/// Serializes `listings` as a stream like `write_archive` serializes them as an archive
+pub fn write_stream<W: Write, F: FnMut(usize) -> Result<Vec<u8>, io::Error>>(
+    listings: &[ArchivableListing],
+    mut read_content: F,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    let (plan, order) = listing_order(listings, options);
+    write_ordered_stream(
+        listings,
+        &plan,
+        order.into_iter().map(|listing_idx| {
+            let content =
+                read_content(listing_idx).map(|bytes| ListingContent::new(bytes, options.hash));
+            (listing_idx, content)
+        }),
+        writer,
+        options,
+        None,
+    )
+}
+
+/// Writes and reads any number of archives in a row, keeping the compression threads and the zstd
+/// contexts of every thread alive from one archive to the next, e.g. for servers handling many
+/// archives per second, where starting them for every archive would add up
+///
+/// A session can be shared between threads; archives written at the same time share its
+/// compression threads.
+pub struct DecafSession {
+    compression: CompressionPool,
+    decompression_contexts: Mutex<Vec<zstd_safe::DCtx<'static>>>,
+}
+
+impl DecafSession {
+    /// Starts a session compressing bundles on `threads` threads, or one per available core, up
+    /// to 8
+    pub fn new(threads: Option<usize>) -> DecafSession {
+        DecafSession {
+            compression: CompressionPool::new(worker_count(threads, MAX_COMPRESSION_WORKERS)),
+            decompression_contexts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Writes `archive` like `ArchivableArchive::archive_to_writer_with_options`, compressing its
+    /// bundles on the threads of the session; `options.threads` only bounds how many bundles of
+    /// this archive are compressed at once
+    pub fn archive_to_writer<W: Write>(
+        &self,
+        archive: &ArchivableArchive,
+        writer: &mut W,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let mut writer = BufWriter::new(writer);
+        archive.create_archive(&mut writer, options, false, Some(&self.compression))
+    }
+
+    /// Writes `archive` as a stream like `ArchivableArchive::archive_to_stream`, compressing its
+    /// bundles on the threads of the session
+    pub fn archive_to_stream<W: Write>(
+        &self,
+        archive: &ArchivableArchive,
+        writer: &mut W,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let mut writer = BufWriter::new(writer);
+        archive.create_archive(&mut writer, options, true, Some(&self.compression))
+    }
+
+    /// Reads an archive like `ExtractedArchive::from_reader_with_options`, decompressing its
+    /// bundles with the zstd contexts of the session
+    pub fn extract_from_reader<R: Read>(
+        &self,
+        reader: &mut R,
+        limits: &Limits,
+        options: &ExtractOptions,
+    ) -> Result<ExtractedArchive, io::Error> {
+        ExtractedArchive::read_with_contexts(
+            reader,
+            limits,
+            options,
+            None,
+            &self.decompression_contexts,
+        )
+    }
+}
+
+/// What is written of the listings of an archive besides their paths and content
+struct ListingPlan {
+    /// the normalized permissions of every listing
+    permissions: Vec<u32>,
+    /// the size of the content of the listings which are written, for `Progress::total`
+    total_size: u64,
+}
+
+/// Returns the plan for `listings` and the order they are written in, leaving out excluded
+/// listings
+fn listing_order(
+    listings: &[ArchivableListing],
+    options: &ArchiveOptions,
+) -> (ListingPlan, Vec<usize>) {
+    // without their content, regular files are ordered like the empty files they are stored as
+    let listings: Cow<[ArchivableListing]> = if options.structure_only {
+        listings
+            .iter()
+            .map(|listing| ArchivableListing {
+                file_size: if is_regular_file(listing.permissions) {
+                    0
+                } else {
+                    listing.file_size
+                },
+                ..listing.clone()
+            })
+            .collect()
+    } else {
+        Cow::Borrowed(listings)
+    };
+
+    // permissions are normalized before ordering so that they can't reorder listings; the file
+    // flags above the mode are kept as they are
+    let permissions: Vec<u32> = listings
+        .iter()
+        .map(|listing| {
+            let mode = listing.permissions & format::ListingEntry::MODE_BITS;
+            options.permissions.apply(mode)
+                | (listing.permissions & !format::ListingEntry::MODE_BITS)
+        })
+        .collect();
+    let mut order: Vec<usize> = (0..listings.len())
+        .filter(|&i| {
+            !options
+                .excludes
+                .iter()
+                .any(|pattern| is_excluded(&listings[i].relative_path, pattern))
+        })
+        .collect();
+    order.sort_by(|&a, &b| {
+        options
+            .sort
+            .compare(&listings[a], permissions[a], &listings[b], permissions[b])
+    });
+
+    // every path of a file with several hard links counts, as each is archived with the content;
+    // links take the content of their target, whose size is only looked up for the progress
+    let total_size = match options.on_progress {
+        Some(_) => order
+            .iter()
+            .map(|&i| match permissions[i] & 0o170000 {
+                0o040000 => 0,
+                0o120000 => fs::metadata(&listings[i].literal_path)
+                    .map_or(listings[i].file_size, |metadata| metadata.len()),
+                _ => listings[i].file_size,
+            })
+            .sum(),
+        None => 0,
+    };
+    (
+        ListingPlan {
+            permissions,
+            total_size,
+        },
+        order,
+    )
+}
+
+/// A bundle compressed by a compression worker
+struct CompressedBundle {
+    index: usize,
+    uncompressed_size: u64,
+    checksum: u64,
+    content: Vec<u8>,
+}
+
+/// A bundle queued for compression by a `CompressionPool`, whose result is sent to `compressed`
+struct CompressionJob {
+    index: usize,
+    bundle: Vec<u8>,
+    level: i32,
+    codec: Codec,
+    frame_checksums: bool,
+    hash: HashAlgorithm,
+    compressed: mpsc::Sender<Result<CompressedBundle, io::Error>>,
+}
+
+/// Threads compressing bundles, each keeping its zstd context from one bundle to the next
+///
+/// Archiving starts a pool for every archive it writes; a `DecafSession` keeps one for all of them.
+struct CompressionPool {
+    jobs: Option<mpsc::Sender<CompressionJob>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl CompressionPool {
+    fn new(workers: usize) -> CompressionPool {
+        // the workers share the receiving end of the queue
+        let (jobs, job_receiver) = mpsc::channel::<CompressionJob>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let threads = (0..workers.max(1))
+            .map(|_| {
+                let job_receiver = Arc::clone(&job_receiver);
+                thread::spawn(move || {
+                    let mut context = zstd_safe::CCtx::create();
+                    loop {
+                        let next_job = job_receiver.lock().unwrap().recv();
+                        let Ok(job) = next_job else {
+                            return;
+                        };
+                        // a panicking job is reported like a failing one, so that every job is
+                        // answered and the worker lives on
+                        let compressed = panic::catch_unwind(AssertUnwindSafe(|| {
+                            compress_bundle(&job, &mut context)
+                        }))
+                        .unwrap_or_else(|_| {
+                            context = zstd_safe::CCtx::create();
+                            Err(io::Error::other("bundle compression worker panicked"))
+                        });
+                        // the archive stopped early if nothing is receiving anymore
+                        let _ = job.compressed.send(compressed);
+                    }
+                })
+            })
+            .collect();
+        CompressionPool {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    fn workers(&self) -> usize {
+        self.threads.len()
+    }
+
+    fn compress(&self, job: CompressionJob) -> Result<(), io::Error> {
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send(job).ok())
+            .ok_or_else(|| io::Error::other("bundle compression workers stopped unexpectedly"))
+    }
+}
+
+impl Drop for CompressionPool {
+    fn drop(&mut self) {
+        // the workers return once the queue is closed and empty
+        drop(self.jobs.take());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Compresses the bundle of `job`, reusing `context` as left behind by the previous bundle
+fn compress_bundle(
+    job: &CompressionJob,
+    context: &mut zstd_safe::CCtx<'static>,
+) -> Result<CompressedBundle, io::Error> {
+    Ok(CompressedBundle {
+        index: job.index,
+        uncompressed_size: job.bundle.len() as u64,
+        checksum: job.hash.hash(&job.bundle),
+        content: compress_with_context(
+            &job.bundle,
+            job.codec,
+            job.level,
+            job.frame_checksums,
+            context,
+        )?,
+    })
+}
+
+/// Compresses `bundle` with `codec` at `level`, reusing `context` as left behind by the previous
+/// bundle
+///
+/// A seekable bundle is a frame for every `frame_size` bytes, followed by a skippable frame holding
+/// the compressed and uncompressed size of every frame and a footer. Frames only carry a checksum
+/// and their uncompressed size with `frame_checksums`, which leaves the output of the default
+/// options as it always was.
+fn compress_with_context(
+    bundle: &[u8],
+    codec: Codec,
+    level: i32,
+    frame_checksums: bool,
+    context: &mut zstd_safe::CCtx<'static>,
+) -> Result<Vec<u8>, io::Error> {
+    let zstd_error = |code| io::Error::other(zstd_safe::get_error_name(code));
+    let frames: Vec<&[u8]> = match codec {
+        Codec::SeekableZstd { frame_size } => bundle
+            .chunks(frame_size.clamp(1, MAX_SEEKABLE_FRAME_SIZE))
+            .collect(),
+        Codec::Zstd => vec![bundle],
+    };
+    let mut content = Vec::new();
+    let mut seek_table = Vec::new();
+    for frame in &frames {
+        let start = content.len();
+        context
+            .reset(zstd_safe::ResetDirective::SessionOnly)
+            .map_err(zstd_error)?;
+        context
+            .set_parameter(zstd_safe::CParameter::CompressionLevel(level))
+            .map_err(zstd_error)?;
+        context
+            .set_parameter(zstd_safe::CParameter::ChecksumFlag(frame_checksums))
+            .map_err(zstd_error)?;
+        if frame_checksums {
+            // the size is only written to the frame header when it is known up front
+            context
+                .set_pledged_src_size(Some(frame.len() as u64))
+                .map_err(zstd_error)?;
+        }
+        let mut encoder = zstd::write::Encoder::with_context(&mut content, context);
+        io::copy(&mut &frame[..], &mut encoder)?;
+        encoder.finish()?;
+        seek_table.extend(((content.len() - start) as u32).to_le_bytes());
+        seek_table.extend((frame.len() as u32).to_le_bytes());
+    }
+    if let Codec::SeekableZstd { .. } = codec {
+        content.extend(SEEK_TABLE_FRAME_MAGIC.to_le_bytes());
+        content.extend(((seek_table.len() + 9) as u32).to_le_bytes());
+        content.extend(seek_table);
+        content.extend((frames.len() as u32).to_le_bytes());
+        // the seek table carries no checksums of its own, as the files in the frames have theirs
+        content.push(0);
+        content.extend(SEEKABLE_MAGIC_NUMBER.to_le_bytes());
+    }
+    Ok(content)
+}
+
+/// Puts the bundles coming back from the compression workers in any order back in the order they
+/// were queued in, along with the listings preceding each
+#[derive(Default)]
+struct BundleSequence {
+    /// for every queued bundle, where the entries of the listings up to the next bundle end in the
+    /// listing block, and the number of listings up to there
+    queued: Vec<(usize, u64)>,
+    compressed: BTreeMap<usize, CompressedBundle>,
+    emitted: usize,
+}
+
+impl BundleSequence {
+    /// Passes every bundle which is next in order to `emit` with the entries of its listings
+    fn emit_ready<E: FnMut(CompressedBundle, &[u8], u64) -> Result<(), io::Error>>(
+        &mut self,
+        listing_block: &[u8],
+        emit: &mut E,
+    ) -> Result<(), io::Error> {
+        while let Some(bundle) = self.compressed.remove(&self.emitted) {
+            let (start, start_count) = match self.emitted {
+                0 => (0, 0),
+                i => self.queued[i - 1],
+            };
+            let (end, count) = self.queued[self.emitted];
+            emit(bundle, &listing_block[start..end], count - start_count)?;
+            self.emitted += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Checksums and assembles the content of `listings` into bundles in the order it is read from
+/// `contents`, encoding the entries of the listings into a listing block
+///
+/// Archiving is a pipeline: the content is read by the caller of `contents`, checksummed and
+/// assembled into bundles here, and every bundle is handed to a pool of compression workers as soon
+/// as it is full, so that reading overlaps compression. The bundle queue is bounded, so assembly
+/// waits for the workers when they fall behind. Every compressed bundle is passed to `emit` in order
+/// as soon as it is available, along with the entries and the number of the listings encoded since
+/// the previous bundle's; the listings after the last bundle are left to the caller. Returns the
+/// number of listings and the listing block.
+fn assemble_bundles<I, E>(
+    listings: &[ArchivableListing],
+    plan: &ListingPlan,
+    contents: I,
+    options: &ArchiveOptions,
+    pool: Option<&CompressionPool>,
+    stats: &mut ArchiveStats,
+    mut emit: E,
+) -> Result<(u64, Vec<u8>), io::Error>
+where
+    I: Iterator<Item = (usize, Result<ListingContent, io::Error>)>,
+    E: FnMut(CompressedBundle, &[u8], u64) -> Result<(), io::Error>,
+{
+    options.limits.check_listing_count(listings.len() as u64)?;
+    let permissions = &plan.permissions;
+    let progress = ProgressCounter::new(options.on_progress.as_ref(), Some(plan.total_size));
+    let max_workers = match options.low_memory {
+        true => 1,
+        false => MAX_COMPRESSION_WORKERS,
+    };
+    // a session's pool is shared by every archive it writes, so only the bundles in flight are
+    // bounded by this archive's worker count
+    let owned_pool;
+    let (pool, workers) = match pool {
+        Some(pool) if options.low_memory => (pool, 1),
+        Some(pool) => (pool, options.threads.unwrap_or(pool.workers())),
+        None => {
+            owned_pool = CompressionPool::new(worker_count(options.threads, max_workers));
+            (&owned_pool, owned_pool.workers())
+        }
+    };
+    trace_span!("compress", workers);
+    // assembly waits for a bundle to come back once as many bundles are in flight as there
+    // are workers compressing and queued behind them
+    let (compressed_sender, compressed_receiver) = mpsc::channel();
+    let mut in_flight = 0;
+    let mut sequence = BundleSequence::default();
+    let mut compress = |index: usize,
+                        bundle: Vec<u8>,
+                        sequence: &mut BundleSequence,
+                        listing_block: &[u8],
+                        listing_count: u64| {
+        trace_event!(debug, bundle = index, "queued bundle");
+        sequence.queued.push((listing_block.len(), listing_count));
+        while in_flight >= 2 * workers.max(1) {
+            let compressed: Result<CompressedBundle, io::Error> = compressed_receiver
+                .recv()
+                .map_err(|_| io::Error::other("bundle compression workers stopped unexpectedly"))?;
+            let compressed = compressed?;
+            in_flight -= 1;
+            sequence.compressed.insert(compressed.index, compressed);
+        }
+        pool.compress(CompressionJob {
+            index,
+            bundle,
+            level: options.compression_level,
+            codec: options.codec(),
+            frame_checksums: options.zstd_frame_checksums,
+            hash: options.hash,
+            compressed: compressed_sender.clone(),
+        })?;
+        in_flight += 1;
+        for compressed in compressed_receiver.try_iter() {
+            let compressed = compressed?;
+            in_flight -= 1;
+            sequence.compressed.insert(compressed.index, compressed);
+        }
+        sequence.emit_ready(listing_block, &mut emit)
+    };
+
+    let mut listing_block = Vec::new();
+    let mut listing_count = 0;
+    let mut bundle_count = 0;
+    let mut current_bundle: Option<Vec<u8>> = None;
+    let mut seen_paths = HashSet::new();
+    for (listing_idx, listing_content) in contents {
+        // the checksum is only missing for content replaced after a read error
+        let (mut listing_content, checksum) = match listing_content {
+            Ok(ListingContent { bytes, checksum }) => (bytes, Some(checksum)),
+            Err(error) => {
+                if options.read_errors == ReadErrorPolicy::FailFast {
+                    return Err(error);
+                }
+                let warning = Warning::UnreadableFile {
+                    path: listings[listing_idx].relative_path.to_string(),
+                    error: error.to_string(),
+                    archived_empty: options.read_errors == ReadErrorPolicy::ReplaceWithEmpty,
+                };
+                report_warning(&mut stats.warnings, options.on_warning.as_ref(), warning);
+                if options.read_errors == ReadErrorPolicy::Skip {
+                    continue;
+                }
+                (Vec::new(), None)
+            }
+        };
+        // the content is archived as read, but a file that was written to meanwhile may be torn
+        let listing = &listings[listing_idx];
+        if checksum.is_some()
+            && is_regular_file(listing.permissions)
+            && !options.structure_only
+            && listing.file_size != 0
+            && listing.file_size != listing_content.len() as u64
+        {
+            let warning = Warning::FileChangedDuringRead {
+                path: listing.relative_path.to_string(),
+                indexed_size: listing.file_size,
+                read_size: listing_content.len() as u64,
+            };
+            report_warning(&mut stats.warnings, options.on_warning.as_ref(), warning);
+        }
+
+        let relative_path = &listings[listing_idx].relative_path;
+        let prefixed_path = match &options.path_prefix {
+            Some(prefix) => normalize_listing_path(&format!("{}/{}", prefix, relative_path)),
+            None => normalize_listing_path(relative_path),
+        };
+        // `..` would place the listing outside of the directory the archive is extracted to
+        if prefixed_path.is_empty() || prefixed_path.split('/').any(|component| component == "..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a path to archive a listing at", relative_path),
+            ));
+        }
+        let Some(prefixed_path) = resolve_duplicate_path(
+            prefixed_path,
+            &mut seen_paths,
+            options.duplicate_paths,
+            &mut stats.warnings,
+            options.on_warning.as_ref(),
+        )?
+        else {
+            continue;
+        };
+        options.limits.check_path(&prefixed_path)?;
+
+        if options.structure_only && is_regular_file(permissions[listing_idx]) {
+            listing_content.clear();
+        }
+        // bare directories and empty files are metadata-only; they have no content, so they
+        // take no space in a bundle, have no checksum, and refer to bundle 0 at offset 0
+        let is_metadata_only = listing_content.is_empty();
+
+        // bundles are only started for listings with content, so an archive without any has
+        // no bundles either; a bundle is compressed as soon as the next one is started
+        if !is_metadata_only
+            && current_bundle
+                .as_ref()
+                .is_none_or(|bundle| bundle.len() > options.bundle_size)
+        {
+            bundle_count += 1;
+            options.limits.check_bundle_count(bundle_count as u64)?;
+            if let Some(full_bundle) = current_bundle.replace(Vec::new()) {
+                compress(
+                    bundle_count - 2,
+                    full_bundle,
+                    &mut sequence,
+                    &listing_block,
+                    listing_count,
+                )?;
+            }
+        }
+
+        let listing_path: &[u8] = prefixed_path.as_bytes();
+        let listing_entry = format::ListingEntry {
+            total_length: (format::ListingEntry::SIZE + listing_path.len()) as u64,
+            bundle_index: 0,
+            bundle_offset: 0,
+            size: 0,
+            permissions: permissions[listing_idx],
+            checksum: 0,
+        };
+        let listing_entry = match &current_bundle {
+            Some(bundle) if !is_metadata_only => format::ListingEntry {
+                bundle_index: bundle_count as u64 - 1,
+                bundle_offset: bundle.len() as u64,
+                size: listing_content.len() as u64,
+                checksum: checksum.unwrap_or(0),
+                ..listing_entry
+            },
+            _ => listing_entry,
+        };
+        listing_entry.encode(listing_path, &mut listing_block);
+        listing_count += 1;
+        progress.report(
+            &prefixed_path,
+            permissions[listing_idx],
+            listing_content.len() as u64,
+        );
+        stats.add_listing(permissions[listing_idx], listing_content.len() as u64);
+        options
+            .limits
+            .check_uncompressed_size(stats.uncompressed_bytes)?;
+        // content starting a bundle becomes the bundle instead of being copied into it
+        match &mut current_bundle {
+            Some(bundle) if bundle.is_empty() => *bundle = listing_content,
+            Some(bundle) if !is_metadata_only => bundle.append(&mut listing_content),
+            _ => {}
+        }
+    }
+    if let Some(last_bundle) = current_bundle {
+        compress(
+            bundle_count - 1,
+            last_bundle,
+            &mut sequence,
+            &listing_block,
+            listing_count,
+        )?;
+    }
+
+    for compressed in compressed_receiver.iter().take(in_flight) {
+        let compressed = compressed?;
+        sequence.compressed.insert(compressed.index, compressed);
+    }
+    sequence.emit_ready(&listing_block, &mut emit)?;
+    if sequence.emitted != bundle_count {
+        return Err(io::Error::other(
+            "bundle compression workers stopped unexpectedly",
+        ));
+    }
+    Ok((listing_count, listing_block))
+}
+
+/// Serializes `listings` as an archive from their content in the order it is written in
+///
+/// The archive is written once every bundle is compressed, as the tables preceding the bundles
+/// depend on all of them. Until then, the compressed bundles are held in memory, or in a temporary
+/// file in low-memory mode.
+fn write_ordered_archive<
+    W: Write,
+    I: Iterator<Item = (usize, Result<ListingContent, io::Error>)>,
+>(
+    listings: &[ArchivableListing],
+    plan: &ListingPlan,
+    contents: I,
+    writer: &mut W,
+    options: &ArchiveOptions,
+    pool: Option<&CompressionPool>,
+) -> Result<WrittenArchive, io::Error> {
+    if options.footer {
+        return write_ordered_footer_archive(listings, plan, contents, writer, options, pool);
+    }
+    let start = Instant::now();
+    trace_span!("write_archive", listings = listings.len());
+    let mut stats = ArchiveStats::default();
+    let mut compressed_bundles = Vec::new();
+    let mut spill = match options.low_memory {
+        true => Some(BufWriter::new(match &options.temp_dir {
+            Some(directory) => {
+                tempfile::tempfile_in(directory).map_err(temp_dir_error(directory))?
+            }
+            None => tempfile::tempfile()?,
+        })),
+        false => None,
+    };
+    let (listing_count, mut listing_block) = assemble_bundles(
+        listings,
+        plan,
+        contents,
+        options,
+        pool,
+        &mut stats,
+        |mut bundle, _, _| {
+            let compressed_size = bundle.content.len() as u64;
+            if let Some(spill) = &mut spill {
+                spill.write_all(&bundle.content)?;
+                bundle.content = Vec::new();
+            }
+            compressed_bundles.push((bundle, compressed_size));
+            Ok(())
+        },
+    )?;
+
+    // --------------------------------------------
+    // generating the archive header data
+    // --------------------------------------------
+
+    // generate header info for bundles
+    // the listing block is compressed first, as its length determines the bundle offsets
+    let mut listing_block_length = listing_block.len() as u64;
+    if options.compress_listings {
+        let mut compressed_listing_block = Vec::new();
+        zstd::copy_encode(
+            listing_block.as_slice(),
+            &mut compressed_listing_block,
+            options.compression_level,
+        )?;
+        listing_block = compressed_listing_block;
+        listing_block_length = listing_block.len() as u64 | format::Header::COMPRESSED_LISTINGS;
+    }
+    let section_checksums_length = match options.section_checksums {
+        true => {
+            listing_block_length |= format::Header::SECTION_CHECKSUMS;
+            format::SectionChecksums::SIZE
+        }
+        false => 0,
+    };
+    let header_checksum_length = match options.header_checksum {
+        true => {
+            listing_block_length |= format::Header::HEADER_CHECKSUM;
+            format::HeaderChecksum::SIZE
+        }
+        false => 0,
+    };
+    if options.split_checksum {
+        listing_block_length |= format::Header::SPLIT_CHECKSUM;
+    }
+    listing_block_length |= (options.hash.id() as u64) << format::Header::HASH_ALGORITHM_SHIFT;
+
+    let mut bundle_section: Vec<u8> =
+        Vec::with_capacity(compressed_bundles.len() * format::BundleEntry::SIZE);
+    let mut bundle_infos: Vec<BundleInfo> = Vec::with_capacity(compressed_bundles.len());
+    let mut compressed_bundle_current_offset: u64 = (format::Header::SIZE
+        + section_checksums_length
+        + header_checksum_length
+        + listing_block.len()
+        + compressed_bundles.len() * format::BundleEntry::SIZE)
+        as u64;
+
+    for (bundle, compressed_bundle_size) in &compressed_bundles {
+        let compressed_bundle_offset = compressed_bundle_current_offset;
+        let compressed_bundle_size = *compressed_bundle_size;
+        trace_event!(
+            debug,
+            bundle = bundle.index,
+            uncompressed_size = bundle.uncompressed_size,
+            compressed_size = compressed_bundle_size,
+            "compressed bundle"
+        );
+
+        // increment offset
+        compressed_bundle_current_offset += compressed_bundle_size;
+
+        let bundle_entry = format::BundleEntry {
+            offset: compressed_bundle_offset,
+            compressed_size: compressed_bundle_size,
+            checksum: bundle.checksum,
+        };
+        bundle_section.extend_from_slice(&bundle_entry.encode());
+
+        stats.compressed_bytes += compressed_bundle_size;
+        bundle_infos.push(BundleInfo {
+            offset: compressed_bundle_offset,
+            compressed_size: compressed_bundle_size,
+            uncompressed_size: bundle.uncompressed_size,
+            checksum: bundle.checksum,
+        });
+    }
+
+    // --------------------------------------------
+    // writing the archive buffer
+    // --------------------------------------------
+
+    let mut header = format::Header {
+        magic: format::MAGIC_NUMBER,
+        checksum: 0,
+        listing_block_length,
+        listing_count,
+        bundle_count: bundle_infos.len() as u64,
+    };
+    let mut archive_buffer: Vec<u8> = header.encode().to_vec();
+
+    // write the section checksums, if any
+    if options.section_checksums {
+        let section_checksums = format::SectionChecksums {
+            listing_block: options.hash.hash(&listing_block),
+            bundle_table: options.hash.hash(&bundle_section),
+        };
+        archive_buffer.extend_from_slice(&section_checksums.encode());
+    }
+
+    // write the header checksum, if any; it can't cover the archive checksum, which covers it
+    if options.header_checksum {
+        let header_checksum = format::HeaderChecksum {
+            checksum: header_checksum(&header, options.hash, &bundle_section),
+        };
+        archive_buffer.extend_from_slice(&header_checksum.encode());
+    }
+
+    // write listing block
+    archive_buffer.append(&mut listing_block);
+
+    // write the bundle block
+    archive_buffer.append(&mut bundle_section);
+    let tables_end = archive_buffer.len();
+
+    // write compressed block
+    for (compressed_bundle, _) in compressed_bundles {
+        archive_buffer.write_all(&compressed_bundle.content)?;
+    }
+
+    // --------------------------------------------
+    // writing the actual archive
+    // --------------------------------------------
+
+    // fill in the checksum of everything following it, which takes reading a spilled compressed
+    // block twice: once to hash it and once to copy it after the tables; a split checksum ends
+    // with the tables instead
+    let checksummed_end = match options.split_checksum {
+        true => tables_end,
+        false => archive_buffer.len(),
+    };
+    let mut hasher = options.hash.hasher();
+    hasher.update(&archive_buffer[format::Header::CHECKSUMMED_FROM..checksummed_end]);
+    let spill = spill
+        .map(|spill| spill.into_inner().map_err(|e| e.into_error()))
+        .transpose()?;
+    let mut spilled_bytes = 0;
+    if let Some(mut spill) = spill.as_ref() {
+        spill.rewind()?;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = spill.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            if !options.split_checksum {
+                hasher.update(&buffer[..read]);
+            }
+            spilled_bytes += read;
+        }
+    }
+    let bytes_written = archive_buffer.len() + spilled_bytes;
+    trace_span!("write", bytes = bytes_written);
+    header.checksum = hasher.digest();
+    archive_buffer[..format::Header::SIZE].copy_from_slice(&header.encode());
+    writer.write_all(&archive_buffer)?;
+    if let Some(mut spill) = spill.as_ref() {
+        spill.rewind()?;
+        io::copy(&mut spill, writer)?;
+    }
+
+    stats.bundle_count = bundle_infos.len();
+    stats.duration = start.elapsed();
+    Ok(WrittenArchive {
+        bytes_written,
+        checksum: header.checksum,
+        bundles: bundle_infos,
+        stats,
+    })
+}
+
+/// Serializes `listings` as an archive with a footer from their content in the order it is written
+/// in
+///
+/// Like a stream, an archive with a footer is written as its bundles are compressed: the header
+/// starting it holds only its flags, and the tables and the footer completing the header follow
+/// the last bundle, so that nothing but the bundles being compressed is held in memory and the
+/// writer never seeks back.
+fn write_ordered_footer_archive<
+    W: Write,
+    I: Iterator<Item = (usize, Result<ListingContent, io::Error>)>,
+>(
+    listings: &[ArchivableListing],
+    plan: &ListingPlan,
+    contents: I,
+    writer: &mut W,
+    options: &ArchiveOptions,
+    pool: Option<&CompressionPool>,
+) -> Result<WrittenArchive, io::Error> {
+    let start = Instant::now();
+    trace_span!("write_footer_archive", listings = listings.len());
+    let mut stats = ArchiveStats::default();
+    let mut bundle_section: Vec<u8> = Vec::new();
+    let mut bundle_infos: Vec<BundleInfo> = Vec::new();
+    let mut flags =
+        format::Header::FOOTER | (options.hash.id() as u64) << format::Header::HASH_ALGORITHM_SHIFT;
+    if options.compress_listings {
+        flags |= format::Header::COMPRESSED_LISTINGS;
+    }
+    let mut header = format::Header {
+        magic: format::MAGIC_NUMBER,
+        checksum: 0,
+        listing_block_length: flags,
+        listing_count: 0,
+        bundle_count: 0,
+    };
+    let mut writer = StreamWriter {
+        writer,
+        hasher: options.hash.hasher(),
+        bytes_written: 0,
+    };
+    let encoded = header.encode();
+    writer.write_unhashed(&encoded[..format::Header::CHECKSUMMED_FROM])?;
+    writer.write(&[&encoded[format::Header::CHECKSUMMED_FROM..]])?;
+
+    let (listing_count, mut listing_block) = assemble_bundles(
+        listings,
+        plan,
+        contents,
+        options,
+        pool,
+        &mut stats,
+        |bundle, _, _| {
+            let bundle_entry = format::BundleEntry {
+                offset: writer.bytes_written as u64,
+                compressed_size: bundle.content.len() as u64,
+                checksum: bundle.checksum,
+            };
+            trace_event!(
+                debug,
+                bundle = bundle.index,
+                uncompressed_size = bundle.uncompressed_size,
+                compressed_size = bundle.content.len(),
+                "compressed bundle"
+            );
+            writer.write(&[&bundle.content])?;
+            bundle_section.extend_from_slice(&bundle_entry.encode());
+            bundle_infos.push(BundleInfo {
+                offset: bundle_entry.offset,
+                compressed_size: bundle_entry.compressed_size,
+                uncompressed_size: bundle.uncompressed_size,
+                checksum: bundle.checksum,
+            });
+            Ok(())
+        },
+    )?;
+
+    if options.compress_listings {
+        let mut compressed_listing_block = Vec::new();
+        zstd::copy_encode(
+            listing_block.as_slice(),
+            &mut compressed_listing_block,
+            options.compression_level,
+        )?;
+        listing_block = compressed_listing_block;
+    }
+    writer.write(&[&listing_block, &bundle_section])?;
+
+    // the footer is checksummed like the header, from its listing_block_length on
+    header.listing_block_length = listing_block.len() as u64 | flags;
+    header.listing_count = listing_count;
+    header.bundle_count = bundle_infos.len() as u64;
+    writer
+        .hasher
+        .update(&header.encode()[format::Header::CHECKSUMMED_FROM..]);
+    header.checksum = writer.hasher.digest();
+    writer.write_unhashed(&header.encode())?;
+    writer.writer.flush()?;
+    trace_span!("write", bytes = writer.bytes_written);
+
+    stats.compressed_bytes = bundle_infos.iter().map(|b| b.compressed_size).sum();
+    stats.bundle_count = bundle_infos.len();
+    stats.duration = start.elapsed();
+    Ok(WrittenArchive {
+        bytes_written: writer.bytes_written,
+        checksum: header.checksum,
+        bundles: bundle_infos,
+        stats,
+    })
+}
+
+/// Passes writes through while hashing them, so that the checksum ending a stream or an archive
+/// with a footer can be computed without holding it
+struct StreamWriter<'a, W: Write> {
+    writer: &'a mut W,
+    hasher: Box<dyn Hasher>,
+    bytes_written: usize,
+}
+
+impl<W: Write> StreamWriter<'_, W> {
+    fn write(&mut self, parts: &[&[u8]]) -> Result<(), io::Error> {
+        for part in parts {
+            self.hasher.update(part);
+            self.writer.write_all(part)?;
+            self.bytes_written += part.len();
+        }
+        Ok(())
+    }
+
+    fn write_unhashed(&mut self, part: &[u8]) -> Result<(), io::Error> {
+        self.writer.write_all(part)?;
+        self.bytes_written += part.len();
+        Ok(())
+    }
+}
+
+/// Serializes `listings` as a stream from their content in the order it is written in
+///
+/// Unlike an archive, a stream is written as its bundles are compressed: every bundle is written in
+/// a frame with the entries of its listings, so that nothing but the bundles being compressed is
+/// held in memory. The listing entries of frames are never compressed.
+fn write_ordered_stream<
+    W: Write,
+    I: Iterator<Item = (usize, Result<ListingContent, io::Error>)>,
+>(
+    listings: &[ArchivableListing],
+    plan: &ListingPlan,
+    contents: I,
+    writer: &mut W,
+    options: &ArchiveOptions,
+    pool: Option<&CompressionPool>,
+) -> Result<WrittenArchive, io::Error> {
+    // frame headers have no room to record another algorithm
+    if options.hash != HashAlgorithm::Xxh3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "streams are always checksummed with xxh3, not {}",
+                options.hash.name()
+            ),
+        ));
+    }
+    let start = Instant::now();
+    trace_span!("write_stream", listings = listings.len());
+    let mut stats = ArchiveStats::default();
+    let mut bundle_infos: Vec<BundleInfo> = Vec::new();
+    let mut writer = StreamWriter {
+        writer,
+        hasher: HashAlgorithm::Xxh3.hasher(),
+        bytes_written: 0,
+    };
+
+    writer.write(&[&format::STREAM_MAGIC_NUMBER])?;
+    let mut written_listings = (0, 0);
+    let (listing_count, listing_block) = assemble_bundles(
+        listings,
+        plan,
+        contents,
+        options,
+        pool,
+        &mut stats,
+        |bundle, entries, count| {
+            let frame = format::FrameHeader {
+                listing_block_length: entries.len() as u64,
+                listing_count: count,
+                compressed_size: bundle.content.len() as u64,
+                uncompressed_size: bundle.uncompressed_size,
+                checksum: bundle.checksum,
+            };
+            trace_event!(
+                debug,
+                bundle = bundle.index,
+                uncompressed_size = bundle.uncompressed_size,
+                compressed_size = bundle.content.len(),
+                "wrote frame"
+            );
+            let offset = writer.bytes_written + format::FrameHeader::SIZE + entries.len();
+            writer.write(&[&frame.encode(), entries, &bundle.content])?;
+            written_listings = (
+                written_listings.0 + entries.len(),
+                written_listings.1 + count,
+            );
+            bundle_infos.push(BundleInfo {
+                offset: offset as u64,
+                compressed_size: bundle.content.len() as u64,
+                uncompressed_size: bundle.uncompressed_size,
+                checksum: bundle.checksum,
+            });
+            Ok(())
+        },
+    )?;
+
+    // the listings after the last bundle, which are all of them if there are no bundles, go in a
+    // frame of their own
+    if listing_count > written_listings.1 {
+        let entries = &listing_block[written_listings.0..];
+        let frame = format::FrameHeader {
+            listing_block_length: entries.len() as u64,
+            listing_count: listing_count - written_listings.1,
+            ..Default::default()
+        };
+        writer.write(&[&frame.encode(), entries])?;
+    }
+    let checksum = writer.hasher.digest();
+    let end = format::FrameHeader {
+        checksum,
+        ..Default::default()
+    };
+    writer.write(&[&end.encode()])?;
+    writer.writer.flush()?;
+
+    stats.compressed_bytes = bundle_infos.iter().map(|b| b.compressed_size).sum();
+    stats.bundle_count = bundle_infos.len();
+    stats.duration = start.elapsed();
+    Ok(WrittenArchive {
+        bytes_written: writer.bytes_written,
+        checksum,
+        bundles: bundle_infos,
+        stats,
+    })
+}
+
+/// Rewrites the archive read from `reader` to `writer` using new archive options, without
+/// needing the original source files
+pub fn repack<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    ExtractedArchive::from_reader(reader)?.repack_to_writer(writer, options)
+}
+
+/// An entry of a tar or zip archive being converted into a DeCAF archive, before links are
+/// resolved
+#[cfg(any(feature = "tar", feature = "zip"))]
+enum ForeignEntry {
+    File {
+        mode: u32,
+        content: Vec<u8>,
+    },
+    Directory {
+        mode: u32,
+    },
+    /// a symbolic link, with its target resolved by `symlink_target`
+    Symlink {
+        mode: u32,
+        target: Option<String>,
+    },
+    /// zip archives have no hard links
+    #[cfg(feature = "tar")]
+    HardLink {
+        mode: u32,
+        target: String,
+    },
+}
+
+/// Returns the entry path `path` of a foreign archive relative to its root, or `None` if it
+/// escapes it
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn normalize_entry_path(path: &str) -> Option<String> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+/// Returns the path a symbolic link at `path` pointing at `target` resolves to relative to the
+/// root of its archive, or `None` if it points outside of it
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn symlink_target(path: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let directory = path.rsplit_once('/').map_or("", |(directory, _)| directory);
+    normalize_entry_path(&format!("{}/{}", directory, target))
+}
+
+/// The entries of a foreign archive, collected in order before they are written as listings
+#[cfg(any(feature = "tar", feature = "zip"))]
+#[derive(Default)]
+struct ForeignEntries {
+    paths: Vec<String>,
+    entries: HashMap<String, ForeignEntry>,
+    skipped: Vec<Warning>,
+}
+
+#[cfg(any(feature = "tar", feature = "zip"))]
+impl ForeignEntries {
+    /// Adds the entry at `path`, unless it is the root or an earlier entry has the same path
+    fn insert(&mut self, path: String, entry: ForeignEntry) {
+        if path.is_empty() || self.entries.contains_key(&path) {
+            return;
+        }
+        self.entries.insert(path.clone(), entry);
+        self.paths.push(path);
+    }
+
+    /// Returns the content of the file that the entry at `path` ends up at, following at most as
+    /// many symbolic links as Linux does
+    fn resolve<'a>(&'a self, mut path: &'a str) -> Option<&'a [u8]> {
+        for _ in 0..40 {
+            match self.entries.get(path)? {
+                ForeignEntry::File { content, .. } => return Some(content),
+                ForeignEntry::Symlink { target, .. } => path = target.as_deref()?,
+                #[cfg(feature = "tar")]
+                ForeignEntry::HardLink { target, .. } => path = target,
+                ForeignEntry::Directory { .. } => return None,
+            }
+        }
+        None
+    }
+
+    /// Writes the entries as an archive, or as a stream if `stream` is set, archiving links like
+    /// the directory walk archives them: symbolic and hard links to a file hold the content of that
+    /// file with the mode of the link, while symbolic links pointing anywhere else are left out
+    /// with a `SkippedSymlink` warning. Directories are only kept when no other entry is inside
+    /// them, as DeCAF only records bare directories.
+    fn write<W: Write>(
+        mut self,
+        writer: &mut W,
+        options: &ArchiveOptions,
+        stream: bool,
+    ) -> Result<WrittenArchive, io::Error> {
+        let mut skipped = std::mem::take(&mut self.skipped);
+        // directories holding other entries are implied by their paths
+        let parents: HashSet<&str> = self
+            .paths
+            .iter()
+            .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]))
+            .collect();
+        let mut listings = Vec::new();
+        let mut contents = Vec::new();
+        for path in &self.paths {
+            let (permissions, content) = match &self.entries[path] {
+                ForeignEntry::File { mode, content } => (0o100000 | mode, &content[..]),
+                ForeignEntry::Directory { .. } if parents.contains(&**path) => continue,
+                ForeignEntry::Directory { mode } => (0o040000 | mode, &[][..]),
+                ForeignEntry::Symlink { mode, .. } => match self.resolve(path) {
+                    Some(content) => (0o120000 | mode, content),
+                    None => {
+                        skipped.push(Warning::SkippedSymlink { path: path.clone() });
+                        continue;
+                    }
+                },
+                #[cfg(feature = "tar")]
+                ForeignEntry::HardLink { mode, target } => match self.resolve(target) {
+                    Some(content) => (0o100000 | mode, content),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("hard link {} points at no file, but {}", path, target),
+                        ))
+                    }
+                },
+            };
+            listings.push(ArchivableListing {
+                relative_path: path.as_str().into(),
+                permissions,
+                file_size: content.len() as u64,
+                literal_path: PathBuf::new(),
+            });
+            contents.push(content);
+        }
+
+        let mut warnings = Vec::new();
+        for warning in skipped {
+            report_warning(&mut warnings, options.on_warning.as_ref(), warning);
+        }
+        let read_content = |listing_idx: usize| Ok(contents[listing_idx].to_vec());
+        let mut written = match stream {
+            true => write_stream(
+                &listings,
+                read_content,
+                &mut BufWriter::new(writer),
+                options,
+            )?,
+            false => write_archive(
+                &listings,
+                read_content,
+                &mut BufWriter::new(writer),
+                options,
+            )?,
+        };
+        written.stats.warnings.splice(0..0, warnings);
+        Ok(written)
+    }
+}
+
+/// Converts a tar archive, gzipped or not, into a DeCAF archive without touching the filesystem
+///
+/// Entries keep their permissions, and links are archived like the directory walk archives them:
+/// symbolic and hard links to a file of the tar archive hold the content of that file, with the
+/// mode of the link, while symbolic links pointing anywhere else are left out with a
+/// `SkippedSymlink` warning and FIFOs and devices with a `SkippedSpecialFile` warning. Directories
+/// are only kept when no other entry is inside them, as DeCAF only records bare directories.
+///
+/// The content of every entry is held in memory until the archive is written, so the content of
+/// the tar archive as a whole is bounded by `options.limits.max_uncompressed_size` as it is read,
+/// whatever sizes its entries claim.
+#[cfg(feature = "tar")]
+pub fn from_tar<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    trace_span!("from_tar");
+    read_tar_entries(reader, &options.limits)?.write(writer, options, false)
+}
+
+/// Converts a tar archive, gzipped or not, into a DeCAF stream like `from_tar` converts it into an
+/// archive
+///
+/// The tar archive is decompressed and unpacked as it is read, so it can come straight from a
+/// download, e.g. the body of a response serving an upstream source tarball, without being saved
+/// first. Its entries are held in memory until the end of the tar archive, as the listings of a
+/// stream are written in their sorted order rather than in the order of the tar archive, so their
+/// content is bounded by `options.limits.max_uncompressed_size` like for `from_tar`.
+#[cfg(feature = "tar")]
+pub fn from_tar_to_stream<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    trace_span!("from_tar_to_stream");
+    read_tar_entries(reader, &options.limits)?.write(writer, options, true)
+}
+
+/// Reads the content of a tar or zip entry, adding its size to `total_size`, without reading more
+/// than `limits` leave for it
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn read_entry_content<R: Read>(
+    entry: R,
+    total_size: &mut u64,
+    limits: &Limits,
+) -> Result<Vec<u8>, io::Error> {
+    // one byte past what is left is enough to tell that the limit is exceeded
+    let remaining = limits
+        .max_uncompressed_size
+        .map_or(u64::MAX, |limit| limit.saturating_sub(*total_size));
+    let mut content = Vec::new();
+    entry
+        .take(remaining.saturating_add(1))
+        .read_to_end(&mut content)?;
+    *total_size += content.len() as u64;
+    limits.check_uncompressed_size(*total_size)?;
+    Ok(content)
+}
+
+/// Reads the entries of a tar archive, gzipped or not, as it arrives from `reader`, with the
+/// content of its entries bounded by `limits`
+#[cfg(feature = "tar")]
+fn read_tar_entries<R: Read>(reader: R, limits: &Limits) -> Result<ForeignEntries, io::Error> {
+    let mut reader = io::BufReader::new(reader);
+    let gzipped = io::BufRead::fill_buf(&mut reader)?.starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn Read> = match gzipped {
+        true => Box::new(flate2::read::GzDecoder::new(reader)),
+        false => Box::new(reader),
+    };
+
+    let mut entries = ForeignEntries::default();
+    let mut total_size = 0;
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?;
+        let entry_path = entry_path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "tar entry path is not UTF-8")
+        })?;
+        let path = normalize_entry_path(entry_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("tar entry path {} is outside of the archive", entry_path),
+            )
+        })?;
+        let link_target = |entry: &tar::Entry<_>| -> Result<String, io::Error> {
+            let target = entry.link_name()?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("tar link {} has no target", path),
+                )
+            })?;
+            target.to_str().map(str::to_string).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "tar link target is not UTF-8")
+            })
+        };
+        let mode = entry.header().mode()? & 0o7777;
+        let foreign_entry = match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let content = read_entry_content(&mut entry, &mut total_size, limits)?;
+                ForeignEntry::File { mode, content }
+            }
+            tar::EntryType::Directory => ForeignEntry::Directory { mode },
+            tar::EntryType::Symlink => ForeignEntry::Symlink {
+                mode,
+                target: symlink_target(&path, &link_target(&entry)?),
+            },
+            tar::EntryType::Link => ForeignEntry::HardLink {
+                mode,
+                target: normalize_entry_path(&link_target(&entry)?).unwrap_or_default(),
+            },
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                entries.skipped.push(Warning::SkippedSpecialFile { path });
+                continue;
+            }
+            tar::EntryType::XGlobalHeader => continue,
+            entry_type => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported tar entry type {:?} for {}", entry_type, path),
+                ))
+            }
+        };
+        entries.insert(path, foreign_entry);
+    }
+    Ok(entries)
+}
+
+/// Converts a zip archive into a DeCAF archive without touching the filesystem
+///
+/// Entries written on Unix keep their permissions, and links are archived like `from_tar` archives
+/// them. Entries written elsewhere, which carry no permissions, become directories with 0755 and
+/// files with 0644, or 0444 if they are read-only. Like for `from_tar`, the content of the zip
+/// archive as a whole is bounded by `options.limits.max_uncompressed_size` as it is read.
+#[cfg(feature = "zip")]
+pub fn from_zip<R: Read + Seek, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    options.check()?;
+    trace_span!("from_zip");
+    let mut zip_archive = zip::ZipArchive::new(reader)?;
+    let mut entries = ForeignEntries::default();
+    let mut total_size = 0;
+    for index in 0..zip_archive.len() {
+        let mut entry = zip_archive.by_index(index)?;
+        let path = normalize_entry_path(entry.name()?.as_ref()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "zip entry path {} is outside of the archive",
+                    entry.name_raw().escape_ascii()
+                ),
+            )
+        })?;
+        let mode = match (entry.system(), entry.unix_mode()) {
+            (zip::System::Unix, Some(mode)) if mode & 0o170000 != 0 => mode,
+            (_, Some(mode)) if mode & 0o170000 == 0o120000 => mode,
+            _ if entry.is_dir() => 0o040755,
+            // the read-only attribute of DOS
+            _ if entry.external_attributes() & 0x01 != 0 => 0o100444,
+            _ => 0o100644,
+        };
+        let content = read_entry_content(&mut entry, &mut total_size, &options.limits)?;
+        let foreign_entry = match mode & 0o170000 {
+            0o040000 => ForeignEntry::Directory {
+                mode: mode & 0o7777,
+            },
+            0o120000 => ForeignEntry::Symlink {
+                mode: mode & 0o7777,
+                target: from_utf8(&content)
+                    .ok()
+                    .and_then(|target| symlink_target(&path, target)),
+            },
+            0o100000 => ForeignEntry::File {
+                mode: mode & 0o7777,
+                content,
+            },
+            _ => {
+                entries.skipped.push(Warning::SkippedSpecialFile { path });
+                continue;
+            }
+        };
+        entries.insert(path, foreign_entry);
+    }
+    entries.write(writer, options, false)
+}
+
+/// Converts a DeCAF archive into a zip archive holding the same listings in the same order, with
+/// their permissions for Unix and the modification time of every entry at the earliest time zip
+/// can hold, 1980-01-01, so that it is the same wherever it is converted
+///
+/// Symbolic links become files, as the archive only holds the content they point at, with 0644.
+#[cfg(feature = "zip")]
+pub fn to_zip<R: Read, W: Write + Seek>(reader: &mut R, writer: W) -> Result<(), io::Error> {
+    trace_span!("to_zip");
+    let archive = extract_from_reader(reader)?;
+    let mut zip_writer = zip::ZipWriter::new(writer);
+    let file_options = zip::write::SimpleFileOptions::default()
+        .last_modified_time(zip::DateTime::DEFAULT)
+        .compression_method(zip::CompressionMethod::Deflated);
+    for listing in &archive.listings {
+        let permissions = listing.permissions & 0o7777;
+        match listing.permissions & 0o170000 {
+            0o040000 => zip_writer.add_directory(
+                format!("{}/", listing.path),
+                file_options.unix_permissions(permissions),
+            )?,
+            file_type => {
+                let permissions = match file_type {
+                    0o120000 => 0o644,
+                    _ => permissions,
+                };
+                let content = archive.listing_bytes(listing);
+                zip_writer.start_file(
+                    &*listing.path,
+                    file_options
+                        .unix_permissions(permissions)
+                        .large_file(content.len() as u64 >= u32::MAX as u64),
+                )?;
+                zip_writer.write_all(content)?;
+            }
+        }
+    }
+    zip_writer.finish()?;
+    Ok(())
+}
+
+pub fn create_archive_from_directory<P: AsRef<Path>>(
+    directory_path: P,
+) -> Result<ArchivableArchive, io::Error> {
+    trace_span!("walk", directory = %directory_path.as_ref().display());
+    let mut listings = Vec::new();
+    let mut skipped = Vec::new();
+    walk_directory(
+        directory_path.as_ref(),
+        directory_path.as_ref(),
+        &mut listings,
+        &mut skipped,
+    )?;
+    listings.sort();
+    Ok(ArchivableArchive { listings, skipped })
+}
+
+/// Creates an archive from an explicit list of files and directories instead of walking a
+/// directory tree; relative paths are resolved against `base_path`, and every path must be inside
+/// of it. Directories are only archived when they are empty, as bare directories.
+pub fn create_archive_from_paths<I, P, B>(
+    paths: I,
+    base_path: B,
+) -> Result<ArchivableArchive, io::Error>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    B: AsRef<Path>,
+{
+    let base_path = base_path.as_ref();
+    trace_span!("walk", base_path = %base_path.display());
+    let mut seen_paths = HashSet::new();
+    let mut local_listings = Vec::new();
+    let mut skipped = Vec::new();
+
+    for path in paths {
+        let path = base_path.join(path);
+        let metadata = fs::symlink_metadata(&path)?;
+
+        let relative_path = relative_path_from(&path, base_path)
+            .filter(|p| p.is_relative() && !p.starts_with(".."))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not inside of {}",
+                        path.display(),
+                        base_path.display()
+                    ),
+                )
+            })?;
+        let path_str = listing_path_from(&relative_path)?;
+        if path_str.is_empty() || !seen_paths.insert(path_str.clone()) {
+            continue;
+        }
+
+        if metadata.is_symlink() {
+            if resolve_link(&path, base_path)? {
+                local_listings.push(ArchivableListing {
+                    permissions: metadata.permissions().mode(),
+                    relative_path: path_str.into(),
+                    file_size: 0,
+                    literal_path: path.canonicalize()?,
+                });
+            } else {
+                skipped.push(Warning::SkippedSymlink { path: path_str });
+            }
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if fs::read_dir(&path)?.next().is_none() {
+                // bare directory
+                local_listings.push(ArchivableListing {
+                    permissions: metadata.permissions().mode(),
+                    relative_path: path_str.into(),
+                    file_size: 0,
+                    literal_path: "".into(),
+                });
+            }
+            continue;
+        }
+
+        if is_special_file(&metadata) {
+            skipped.push(Warning::SkippedSpecialFile { path: path_str });
+            continue;
+        }
+
+        let can_path = path.canonicalize()?;
+        local_listings.push(ArchivableListing {
+            permissions: metadata.permissions().mode(),
+            relative_path: path_str.into(),
+            file_size: fs::metadata(&can_path)?.size(),
+            literal_path: can_path,
+        });
+    }
+
+    // listings stay in the order they were given; the archive writer sorts them as configured
+    Ok(ArchivableArchive {
+        listings: local_listings,
+        skipped,
+    })
+}
+
+/// Creates an archive from several files and directories, like `tar -c a b c`: every file is
+/// archived under its own name and every directory is walked under its name, so that unarchiving
+/// recreates them side by side. No two inputs can have the same name.
+pub fn create_archive_from_inputs<I, P>(inputs: I) -> Result<ArchivableArchive, io::Error>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let mut listings = Vec::new();
+    let mut skipped = Vec::new();
+    let mut names: HashMap<String, PathBuf> = HashMap::new();
+    for input in inputs {
+        let input = input.as_ref();
+        trace_span!("walk", input = %input.display());
+        // a name like `.` is taken from the directory it stands for
+        let name = match input.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => top_level_directory_name(input)?,
+        };
+        if let Some(other) = names.insert(name.clone(), input.to_path_buf()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} and {} would both be archived as {}",
+                    other.display(),
+                    input.display(),
+                    name
+                ),
+            ));
+        }
+
+        let metadata = fs::metadata(input)?;
+        if metadata.is_dir() {
+            let mut walked = Vec::new();
+            let mut walk_skipped = Vec::new();
+            walk_directory(input, input, &mut walked, &mut walk_skipped)?;
+            if walked.is_empty() {
+                // bare directory
+                listings.push(ArchivableListing {
+                    permissions: metadata.permissions().mode(),
+                    relative_path: name.as_str().into(),
+                    file_size: 0,
+                    literal_path: "".into(),
+                });
+            }
+            listings.extend(walked.into_iter().map(|listing| ArchivableListing {
+                relative_path: format!("{}/{}", name, listing.relative_path).into(),
+                ..listing
+            }));
+            skipped.extend(walk_skipped.into_iter().map(|warning| match warning {
+                Warning::SkippedSymlink { path } => Warning::SkippedSymlink {
+                    path: format!("{}/{}", name, path),
+                },
+                Warning::SkippedSpecialFile { path } => Warning::SkippedSpecialFile {
+                    path: format!("{}/{}", name, path),
+                },
+                warning => warning,
+            }));
+        } else if is_special_file(&metadata) {
+            skipped.push(Warning::SkippedSpecialFile { path: name });
+        } else {
+            listings.push(ArchivableListing {
+                permissions: metadata.permissions().mode(),
+                relative_path: name.into(),
+                file_size: metadata.size(),
+                literal_path: input.canonicalize()?,
+            });
+        }
+    }
+    listings.sort();
+    Ok(ArchivableArchive { listings, skipped })
+}
+
+/// Returns the name of the directory at `directory_path` for use as an `ArchiveOptions::path_prefix`,
+/// resolving paths like `.` that have no name of their own
+pub fn top_level_directory_name<P: AsRef<Path>>(directory_path: P) -> Result<String, io::Error> {
+    directory_path
+        .as_ref()
+        .canonicalize()?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} has no directory name",
+                    directory_path.as_ref().display()
+                ),
+            )
+        })
+}
+
+/// Returns whether `metadata` is of a FIFO, socket or device, which have no content to archive
+fn is_special_file(metadata: &fs::Metadata) -> bool {
+    let file_type = metadata.file_type();
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+fn resolve_link<P: AsRef<Path>, B: AsRef<Path>>(
+    path: P,
+    parent_path: B,
+) -> Result<bool, io::Error> {
+    let resolved = read_link(path)?;
+    if !resolved.starts_with(&parent_path) {
+        return Ok(false);
+    }
+    if !resolved.metadata()?.is_symlink() {
+        return Ok(true);
+    }
+    resolve_link(resolved, parent_path)
+}
+
+/// Appends the listings for everything under `directory_path` to `local_listings`, reading every
+/// directory only once; directories which end up without listings of their own, because they are
+/// empty or all of their entries were skipped, are recorded as bare directories. Symbolic links out
+/// of the tree and special files are left out with a warning in `skipped`.
+fn walk_directory<P: AsRef<Path>, B: AsRef<Path>>(
+    directory_path: P,
+    parent_path: B,
+    local_listings: &mut Vec<ArchivableListing>,
+    skipped: &mut Vec<Warning>,
+) -> Result<(), io::Error> {
+    // the order of read_dir is up to the filesystem, so entries are walked by name instead
+    let mut entries = fs::read_dir(directory_path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        walk_entry(
+            entry.path(),
+            entry.metadata()?,
+            parent_path.as_ref(),
+            local_listings,
+            skipped,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Collects the listings for the single path `path` of the tree under `parent_path`, walking it
+/// if it is a directory; `metadata` is that of the path itself, not of what a link points at
+fn walk_entry<P: AsRef<Path>, B: AsRef<Path>>(
+    path: P,
+    metadata: fs::Metadata,
+    parent_path: B,
+    local_listings: &mut Vec<ArchivableListing>,
+    skipped: &mut Vec<Warning>,
+) -> Result<(), io::Error> {
+    let path = path.as_ref();
+
+    if metadata.is_symlink() {
+        if !resolve_link(path, &parent_path)? {
+            let relative_path = relative_path_from(path, &parent_path).unwrap();
+            skipped.push(Warning::SkippedSymlink {
+                path: relative_path.to_string_lossy().into_owned(),
+            });
+        } else {
+            let can_path = path.canonicalize()?;
+            let relative_path = relative_path_from(path, &parent_path).unwrap();
+            let path_str = listing_path_from(&relative_path)?;
+            let perms = metadata.permissions().mode();
+            local_listings.push(ArchivableListing {
+                permissions: perms,
+                relative_path: path_str.into(),
+                file_size: 0,
+                literal_path: can_path.clone(),
+            });
+        }
+        return Ok(());
+    }
+
+    // directory handling
+    if metadata.is_dir() {
+        let listing_count = local_listings.len();
+        walk_directory(path, parent_path.as_ref(), local_listings, skipped)?;
+        if local_listings.len() == listing_count {
+            // bare directory
+            let relative_path = relative_path_from(path, &parent_path).unwrap();
+            let path_str = listing_path_from(&relative_path)?;
+            local_listings.push(ArchivableListing {
+                permissions: metadata.permissions().mode(),
+                relative_path: path_str.into(),
+                file_size: 0,
+                literal_path: "".into(),
+            });
+        }
+        return Ok(());
+    }
+
+    // file handling
+    let perms = metadata.permissions().mode();
+    let relative_path = relative_path_from(path, parent_path.as_ref()).unwrap();
+    let path_str = listing_path_from(&relative_path)?;
+    if is_special_file(&metadata) {
+        skipped.push(Warning::SkippedSpecialFile { path: path_str });
+        return Ok(());
+    }
+
+    let can_path = &path.canonicalize()?;
+
+    let file_size = fs::metadata(can_path)?.size();
+
+    local_listings.push(ArchivableListing {
+        permissions: perms,
+        relative_path: path_str.into(),
+        file_size,
+        literal_path: can_path.clone(),
+    });
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtractedListing {
+    pub path: Box<str>, // relative file or directory path
+    pub permissions: u32,
+    pub content_checksum: u64, // checksum of `content`
+    pub filesize: u64,
+    pub bundle_idx: usize,
+    pub bundle_offset: usize, // binary content of file or empty if directory
+}
+
+impl ExtractedListing {
+    /// Returns whether the listing has no content and therefore no bundle, like bare directories
+    /// and empty files
+    pub fn is_metadata_only(&self) -> bool {
+        self.filesize == 0
+    }
+
+    /// Returns the file flags stored in the permissions of the listing
+    pub fn file_flags(&self) -> FileFlags {
+        FileFlags::from_permissions(self.permissions)
+    }
+
+    /// Passes the listing to the `on_progress` callback counted by `progress` once it is extracted
+    fn report_progress(&self, progress: &ProgressCounter) {
+        progress.report(&self.path, self.permissions, self.filesize);
+    }
+}
+
+/// Maps listing paths to their index in a list of listings, so that looking up a single listing
+/// doesn't scan all of them
+#[derive(Debug, Clone, Default)]
+struct PathIndex(HashMap<Box<str>, usize>);
+
+impl PathIndex {
+    fn new(listings: &[ExtractedListing]) -> PathIndex {
+        let mut index = HashMap::with_capacity(listings.len());
+        for (i, listing) in listings.iter().enumerate() {
+            // like a scan, the first of several listings with the same path is found
+            index.entry(listing.path.clone()).or_insert(i);
+        }
+        PathIndex(index)
+    }
+
+    /// Returns the listing with the given path from the `listings` the index was built from,
+    /// scanning them instead if they were changed since
+    fn find<'a>(
+        &self,
+        listings: &'a [ExtractedListing],
+        path: &str,
+    ) -> Option<&'a ExtractedListing> {
+        match self.0.get(path).and_then(|&i| listings.get(i)) {
+            Some(listing) if &*listing.path == path => Some(listing),
+            _ => listings.iter().find(|listing| &*listing.path == path),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExtractedArchive {
+    pub listings: Vec<ExtractedListing>,
+    bundles: Vec<Vec<u8>>,
+    bundle_infos: Vec<BundleInfo>,
+    revision: FormatRevision,
+    hash: HashAlgorithm,
+    path_index: PathIndex,
+}
+
+pub fn extract_from_file<P: AsRef<Path>>(archive_path: P) -> Result<ExtractedArchive, io::Error> {
+    let mut archive_file = File::open(archive_path)?;
+    extract_from_reader(&mut archive_file)
+}
+
+pub fn extract_from_reader<R: Read>(reader: &mut R) -> Result<ExtractedArchive, io::Error> {
+    ExtractedArchive::from_reader(reader)
+}
+
+/// Reads a stream in parts, hashing everything read so that the checksum ending it can be verified
+struct StreamReader<'a, R: Read> {
+    reader: &'a mut R,
+    xxh3: xxhash_rust::xxh3::Xxh3,
+    position: u64,
+}
+
+impl<R: Read> StreamReader<'_, R> {
+    /// Reads the next `length` bytes, failing if the stream ends before them
+    fn read_part(&mut self, length: u64) -> Result<Vec<u8>, io::Error> {
+        let mut part = Vec::new();
+        self.reader.by_ref().take(length).read_to_end(&mut part)?;
+        if (part.len() as u64) < length {
+            return Err(
+                ArchiveError::Corrupt("invalid stream: stream is truncated".to_string()).into(),
+            );
+        }
+        self.xxh3.update(&part);
+        self.position += length;
+        Ok(part)
+    }
+}
+
+/// Extracts the stream read from `reader` to `output_directory_path` as it is read, one frame at a
+/// time, so that only a single bundle is held in memory
+///
+/// The stream is checked against `limits` as it is read. Every bundle and, as configured by
+/// `options.verify`, every file is verified before it is written, but the checksum of the stream
+/// as a whole can only be verified at its end, so a stream failing it leaves files behind. Unlike
+/// for an archive, the space needed can't be checked before anything is written.
+pub fn extract_stream<R: Read, P: AsRef<Path>>(
+    reader: &mut R,
+    output_directory_path: P,
+    limits: &Limits,
+    options: &ExtractOptions,
+) -> Result<ArchiveStats, io::Error> {
+    options.validate()?;
+    let start = Instant::now();
+    let output_directory_path = output_directory_path.as_ref();
+    trace_span!("extract_stream", output = %output_directory_path.display());
+    let mut reader = StreamReader {
+        reader,
+        xxh3: xxhash_rust::xxh3::Xxh3::new(),
+        position: 0,
+    };
+    if reader.read_part(format::STREAM_MAGIC_NUMBER.len() as u64)? != format::STREAM_MAGIC_NUMBER {
+        return Err(ArchiveError::Corrupt(
+            "invalid stream: does not contain stream magic number".to_string(),
+        )
+        .into());
+    }
+
+    fs::create_dir_all(output_directory_path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to create output directory: {}", e),
+        )
+    })?;
+    let mut stats = ArchiveStats::default();
+    let mut warnings = Mutex::new(Vec::new());
+    let mut extraction_paths = ExtractionPaths::new(output_directory_path, options)?;
+    let progress = ProgressCounter::new(options.on_progress.as_ref(), None);
+    let mut listing_count: u64 = 0;
+    let mut bundle_infos: Vec<BundleInfo> = Vec::new();
+    let mut uncompressed_size: u64 = 0;
+    loop {
+        let checksum = reader.xxh3.digest();
+        let frame =
+            format::FrameHeader::decode(&reader.read_part(format::FrameHeader::SIZE as u64)?)?;
+        if frame.is_end() {
+            if options.verify >= VerifyLevel::Bundles && frame.checksum != checksum {
+                return Err(ArchiveError::Verification(
+                    "invalid stream: could not verify stream integrity".to_string(),
+                )
+                .into());
+            }
+            break;
+        }
+
+        listing_count = listing_count.saturating_add(frame.listing_count);
+        limits.check_listing_count(listing_count)?;
+        // paths are at most 65535 bytes long, as in the specification
+        let max_path_length = limits.max_path_length.unwrap_or(u16::MAX as u64);
+        let max_length = frame
+            .listing_count
+            .saturating_mul(max_path_length.saturating_add(format::ListingEntry::SIZE as u64));
+        if frame.listing_block_length > max_length {
+            let frame_listing_count = frame.listing_count;
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid stream: frame listing entries are longer than {} listings can take up",
+                frame_listing_count
+            ))
+            .into());
+        }
+        let listing_block = reader.read_part(frame.listing_block_length)?;
+        let mut listings = parse_listings(&listing_block, frame.listing_count, limits)?;
+
+        let mut bundles = Vec::new();
+        let mut frame_bundle_infos = Vec::new();
+        if frame.compressed_size > 0 {
+            let bundle_info = BundleInfo {
+                offset: reader.position,
+                compressed_size: frame.compressed_size,
+                uncompressed_size: frame.uncompressed_size,
+                checksum: frame.checksum,
+            };
+            limits.check_bundle_count(bundle_infos.len() as u64 + 1)?;
+            uncompressed_size = uncompressed_size.saturating_add(frame.uncompressed_size);
+            limits.check_uncompressed_size(uncompressed_size)?;
+            let compressed = reader.read_part(frame.compressed_size)?;
+            bundles.push(decompress_bundle(
+                bundle_infos.len(),
+                &compressed,
+                &bundle_info,
+                options.verify,
+                HashAlgorithm::Xxh3,
+            )?);
+            bundle_infos.push(bundle_info);
+            frame_bundle_infos.push(bundle_info);
+        }
+        // the listings with content refer to the bundle of their frame, which is the only bundle
+        // of the archive extracting it
+        for listing in &mut listings {
+            if listing.is_metadata_only() {
+                continue;
+            }
+            let in_bundle = !bundles.is_empty()
+                && listing.bundle_idx == bundle_infos.len() - 1
+                && (listing.bundle_offset as u64)
+                    .checked_add(listing.filesize)
+                    .is_some_and(|end| end <= frame.uncompressed_size);
+            if !in_bundle {
+                return Err(ArchiveError::Corrupt(format!(
+                    "invalid stream: listing {} is not in the bundle of its frame",
+                    listing.path
+                ))
+                .into());
+            }
+            listing.bundle_idx = 0;
+        }
+
+        let frame_archive = ExtractedArchive {
+            path_index: PathIndex::default(),
+            listings,
+            bundles,
+            bundle_infos: frame_bundle_infos,
+            revision: FormatRevision::Current,
+            hash: HashAlgorithm::Xxh3,
+        };
+        frame_archive.write_listings(
+            output_directory_path,
+            options,
+            &mut extraction_paths,
+            &mut stats,
+            &progress,
+            &mut warnings,
+        )?;
+    }
+
+    stats.compressed_bytes = bundle_infos.iter().map(|b| b.compressed_size).sum();
+    stats.bundle_count = bundle_infos.len();
+    stats.warnings = warnings.into_inner().unwrap();
+    stats.duration = start.elapsed();
+    Ok(stats)
+}
+
+/// Fails if the filesystem of `output_directory_path` has less space available than the listings
+/// being extracted take up
+fn check_space(
+    listings: &[ExtractedListing],
+    output_directory_path: &Path,
+    options: &ExtractOptions,
+) -> Result<(), io::Error> {
+    let required_bytes: u64 = listings
+        .iter()
+        .filter(|listing| matches!(options.transform_path(&listing.path), Ok(Some(_))))
+        .map(|listing| listing.filesize)
+        .sum();
+    let space_path = nearest_existing_ancestor(output_directory_path);
+    let available_bytes = available_space(space_path)?;
+    if required_bytes > available_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "not enough space to extract to {}: {} bytes are needed but only {} bytes are available",
+                space_path.display(),
+                required_bytes,
+                available_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns `path` itself if it exists, or else its closest ancestor which does
+fn nearest_existing_ancestor(path: &Path) -> &Path {
+    path.ancestors()
+        .find(|ancestor| fs::symlink_metadata(ancestor).is_ok())
+        .unwrap_or(Path::new("."))
+}
+
+fn is_writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Sizes a newly created `file` to `size` bytes before it is written, so that the filesystem can
+/// lay it out in one piece; the space is reserved with `fallocate` where it is supported
+fn preallocate(file: &File, size: u64) -> Result<(), io::Error> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let length = libc::off_t::try_from(size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if !matches!(
+            error.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL)
+        ) {
+            return Err(error);
+        }
+    }
+
+    file.set_len(size)
+}
+
+/// Copies the first `size` bytes of `source` into the empty `destination` within the filesystem,
+/// sharing their blocks with a reflink on filesystems like btrfs and XFS and otherwise with
+/// `copy_file_range`; returns `false` without copying anything where neither is supported
+fn clone_file(source: &File, destination: &File, size: u64) -> Result<bool, io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let (source, destination) = (source.as_raw_fd(), destination.as_raw_fd());
+        if unsafe { libc::ioctl(destination, libc::FICLONE, source) } == 0 {
+            return Ok(true);
+        }
+
+        let mut copied = 0;
+        while copied < size {
+            let remaining = (size - copied).min(isize::MAX as u64) as usize;
+            let result = unsafe {
+                libc::copy_file_range(
+                    source,
+                    std::ptr::null_mut(),
+                    destination,
+                    std::ptr::null_mut(),
+                    remaining,
+                    0,
+                )
+            };
+            if result < 0 {
+                let error = io::Error::last_os_error();
+                let unsupported = matches!(
+                    error.raw_os_error(),
+                    Some(libc::EXDEV | libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL)
+                );
+                if copied == 0 && unsupported {
+                    return Ok(false);
+                }
+                return Err(error);
+            }
+            if result == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("source ended after {} of {} bytes", copied, size),
+                ));
+            }
+            copied += result as u64;
+        }
+        Ok(true)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (source, destination, size);
+        Ok(false)
+    }
+}
+
+/// the flags of `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS`, as defined in `linux/fs.h`
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+#[cfg(target_os = "linux")]
+const FS_APPEND_FL: libc::c_int = 0x20;
+
+/// the flags of `chflags` settable by the owner of a file, and those only settable by root, which
+/// have the same values on every BSD and macOS
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+mod chflags {
+    pub const UF_IMMUTABLE: u32 = 0x2;
+    pub const UF_APPEND: u32 = 0x4;
+    pub const SF_IMMUTABLE: u32 = 0x20000;
+    pub const SF_APPEND: u32 = 0x40000;
+}
+
+/// Reads the flags of the file at `path`, which has none where the filesystem doesn't support them
+fn read_file_flags(path: &Path) -> Result<FileFlags, io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let mut flags: libc::c_int = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+            let error = io::Error::last_os_error();
+            if matches!(
+                error.raw_os_error(),
+                Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL)
+            ) {
+                return Ok(FileFlags::default());
+            }
+            return Err(error);
+        }
+        Ok(FileFlags {
+            immutable: flags & FS_IMMUTABLE_FL != 0,
+            append_only: flags & FS_APPEND_FL != 0,
+        })
+    }
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    {
+        use chflags::*;
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::stat(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = stat.st_flags as u32;
+        Ok(FileFlags {
+            immutable: flags & (UF_IMMUTABLE | SF_IMMUTABLE) != 0,
+            append_only: flags & (UF_APPEND | SF_APPEND) != 0,
+        })
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )))]
+    {
+        let _ = path;
+        Ok(FileFlags::default())
+    }
+}
+
+/// Sets or clears `flags` on the file at `path`; on the BSDs and macOS they are set as the flags of
+/// the owner, `uchg` and `uappnd`, which don't take root to set
+fn set_file_flags(path: &Path, flags: FileFlags) -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let mut current: libc::c_int = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut current) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        current &= !(FS_IMMUTABLE_FL | FS_APPEND_FL);
+        if flags.immutable {
+            current |= FS_IMMUTABLE_FL;
+        }
+        if flags.append_only {
+            current |= FS_APPEND_FL;
+        }
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &current) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    {
+        use chflags::*;
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::stat(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut current = stat.st_flags as u32 & !(UF_IMMUTABLE | UF_APPEND);
+        if flags.immutable {
+            current |= UF_IMMUTABLE;
+        }
+        if flags.append_only {
+            current |= UF_APPEND;
+        }
+        if unsafe { libc::chflags(c_path.as_ptr(), current as _) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )))]
+    {
+        let _ = (path, flags);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file flags are not supported on this platform",
+        ))
+    }
+}
+
+/// Returns the space available to unprivileged users on the filesystem holding `path`
+fn available_space(path: &Path) -> Result<u64, io::Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Where the listing block, the bundle table and the bundles of an archive lie
+struct TableBounds {
+    listing_block: Range<u64>,
+    bundle_table: Range<u64>,
+    bundles: Range<u64>,
+}
+
+/// Returns where the tables and the bundles of an archive of `archive_length` bytes lie, checking
+/// that the tables fit into it; the header of an archive with a footer is its footer
+fn table_bounds(header: &format::Header, archive_length: u64) -> Result<TableBounds, ArchiveError> {
+    let listing_block_length = header.stored_listing_block_length();
+    let bundle_count = header.bundle_count;
+    if header.has_footer() {
+        // the tables end where the footer starts, and the bundles where the tables do
+        let tables_end = footer_offset(archive_length)?;
+        let bundle_table_start = bundle_count
+            .checked_mul(format::BundleEntry::SIZE as u64)
+            .and_then(|length| tables_end.checked_sub(length))
+            .filter(|&start| start >= format::Header::SIZE as u64)
+            .ok_or_else(|| {
+                ArchiveError::Corrupt(format!(
+                    "invalid archive: bundle_count {} runs past the start of the archive",
+                    bundle_count
+                ))
+            })?;
+        let listing_block_start = bundle_table_start
+            .checked_sub(listing_block_length)
+            .filter(|&start| start >= format::Header::SIZE as u64)
+            .ok_or_else(|| {
+                ArchiveError::Corrupt(format!(
+                    "invalid archive: listing_block_length {} runs past the start of the archive",
+                    listing_block_length
+                ))
+            })?;
+        return Ok(TableBounds {
+            listing_block: listing_block_start..bundle_table_start,
+            bundle_table: bundle_table_start..tables_end,
+            bundles: format::Header::SIZE as u64..listing_block_start,
+        });
+    }
+    let listing_block_end = header
+        .listing_block_offset()
+        .checked_add(listing_block_length)
+        .filter(|&end| end <= archive_length)
+        .ok_or_else(|| {
+            ArchiveError::Corrupt(format!(
+                "invalid archive: listing_block_length {} runs past the end of the archive",
+                listing_block_length
+            ))
+        })?;
+    let bundle_table_end = bundle_count
+        .checked_mul(format::BundleEntry::SIZE as u64)
+        .and_then(|length| listing_block_end.checked_add(length))
+        .filter(|&end| end <= archive_length)
+        .ok_or_else(|| {
+            ArchiveError::Corrupt(format!(
+                "invalid archive: bundle_count {} runs past the end of the archive",
+                bundle_count
+            ))
+        })?;
+    Ok(TableBounds {
+        listing_block: header.listing_block_offset()..listing_block_end,
+        bundle_table: listing_block_end..bundle_table_end,
+        bundles: bundle_table_end..archive_length,
+    })
+}
+
+/// Returns the offset of the footer of an archive of `archive_length` bytes with one
+fn footer_offset(archive_length: u64) -> Result<u64, ArchiveError> {
+    archive_length
+        .checked_sub(format::Header::SIZE as u64)
+        .filter(|&offset| offset >= format::Header::SIZE as u64)
+        .ok_or_else(|| ArchiveError::Corrupt("invalid archive: archive too small".to_string()))
+}
+
+/// Decodes the footer completing `header`, checking that it is one
+fn decode_footer(header: &format::Header, bytes: &[u8]) -> Result<format::Header, ArchiveError> {
+    let footer = format::Header::decode(bytes)?;
+    let flags = |header: &format::Header| {
+        header.listing_block_length ^ header.stored_listing_block_length()
+    };
+    if header.magic != format::MAGIC_NUMBER
+        || footer.magic != format::MAGIC_NUMBER
+        || flags(&footer) != flags(header)
+        || footer.has_section_checksums()
+        || footer.has_header_checksum()
+        || footer.has_split_checksum()
+    {
+        return Err(ArchiveError::Corrupt(
+            "invalid archive: footer does not match the header".to_string(),
+        ));
+    }
+    Ok(footer)
+}
+
+/// Returns the checksum of the header from `listing_block_length` on followed by the bundle
+/// table, as stored in a `format::HeaderChecksum`
+fn header_checksum(header: &format::Header, hash: HashAlgorithm, bundle_table: &[u8]) -> u64 {
+    let mut hasher = hash.hasher();
+    hasher.update(&header.encode()[format::Header::CHECKSUMMED_FROM..]);
+    hasher.update(bundle_table);
+    hasher.digest()
+}
+
+/// Fills `bytes` from `reader`, reporting the archive as corrupt with `message` if it ends first
+fn read_exact_or_corrupt<R: Read>(
+    reader: &mut R,
+    bytes: &mut [u8],
+    message: &str,
+) -> Result<(), io::Error> {
+    reader.read_exact(bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => ArchiveError::Corrupt(message.to_string()).into(),
+        _ => e,
+    })
+}
+
+/// Parses the bundle table, checking that every bundle lies within `bundle_area`, and declares the
+/// uncompressed size of every bundle as the end of the last listing content in it, checking the
+/// total against `limits`
+///
+/// Bundles hold nothing but the content of their listings, so a bundle which decompresses to any
+/// other size is corrupt; knowing the size up front bounds decompression.
+fn parse_bundle_table(
+    bundle_table: &[u8],
+    bundle_count: u64,
+    bundle_area: Range<u64>,
+    listings: &[ExtractedListing],
+    limits: &Limits,
+) -> Result<Vec<BundleInfo>, io::Error> {
+    let bundle_count = addressable(bundle_count, "bundle count")?;
+    let mut bundle_infos = Vec::with_capacity(bundle_count);
+    for i in 0..bundle_count {
+        let entry = format::BundleEntry::decode(
+            bundle_table
+                .get(i * format::BundleEntry::SIZE..)
+                .unwrap_or_default(),
+        )?;
+        let bundle_info = BundleInfo {
+            offset: entry.offset,
+            compressed_size: entry.compressed_size,
+            uncompressed_size: 0,
+            checksum: entry.checksum,
+        };
+        if !bundle_area.contains(&bundle_info.offset) {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: bundle {} offset {} is outside of the bundles ({}..{})",
+                i, bundle_info.offset, bundle_area.start, bundle_area.end
+            ))
+            .into());
+        }
+        if bundle_info
+            .offset
+            .checked_add(bundle_info.compressed_size)
+            .is_none_or(|end| end > bundle_area.end)
+        {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: bundle {} compressed_size {} runs past the end of the archive",
+                i, bundle_info.compressed_size
+            ))
+            .into());
+        }
+        bundle_infos.push(bundle_info);
+    }
+
+    for listing in listings {
+        if listing.is_metadata_only() {
+            continue;
+        }
+        let Some(bundle_info) = bundle_infos.get_mut(listing.bundle_idx) else {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing {} bundle_index {} is out of range for {} bundles",
+                listing.path, listing.bundle_idx, bundle_count
+            ))
+            .into());
+        };
+        let Some(content_end) = (listing.bundle_offset as u64).checked_add(listing.filesize) else {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing {} bundle_offset {} and size {} overflow",
+                listing.path, listing.bundle_offset, listing.filesize
+            ))
+            .into());
+        };
+        bundle_info.uncompressed_size = bundle_info.uncompressed_size.max(content_end);
+    }
+
+    limits.check_uncompressed_size(bundle_infos.iter().fold(0u64, |total, bundle| {
+        total.saturating_add(bundle.uncompressed_size)
+    }))?;
+    Ok(bundle_infos)
+}
+
+/// Decompresses the bundle at `index` and verifies it against its declared size and, unless
+/// `verify` is `VerifyLevel::None`, its checksum computed with `hash`, failing as soon as more than
+/// the declared size comes out of it
+fn decompress_bundle(
+    index: usize,
+    compressed: &[u8],
+    bundle_info: &BundleInfo,
+    verify: VerifyLevel,
+    hash: HashAlgorithm,
+) -> Result<Vec<u8>, io::Error> {
+    let mut context = zstd_safe::DCtx::create();
+    decompress_bundle_with_context(index, compressed, bundle_info, verify, hash, &mut context)
+}
+
+/// Decompresses a bundle like `decompress_bundle`, reusing `context` as left behind by the
+/// previous bundle
+fn decompress_bundle_with_context(
+    index: usize,
+    compressed: &[u8],
+    bundle_info: &BundleInfo,
+    verify: VerifyLevel,
+    hash: HashAlgorithm,
+    context: &mut zstd_safe::DCtx<'static>,
+) -> Result<Vec<u8>, io::Error> {
+    let corrupt = |e: io::Error| {
+        ArchiveError::Corrupt(format!(
+            "invalid archive: could not decompress bundle {}: {}",
+            index, e
+        ))
+    };
+    // a bundle which failed to decompress leaves the context in the middle of a frame
+    context
+        .reset(zstd_safe::ResetDirective::SessionOnly)
+        .map_err(|code| io::Error::other(zstd_safe::get_error_name(code)))?;
+    let mut uncompressed_bundle_content = Vec::new();
+    zstd::read::Decoder::with_context(compressed, context)
+        .take(bundle_info.uncompressed_size.saturating_add(1))
+        .read_to_end(&mut uncompressed_bundle_content)
+        .map_err(corrupt)?;
+    if uncompressed_bundle_content.len() as u64 != bundle_info.uncompressed_size {
+        return Err(ArchiveError::Corrupt(format!(
+            "invalid archive: bundle {} does not decompress to the {} bytes its listings declare",
+            index, bundle_info.uncompressed_size
+        ))
+        .into());
+    }
+
+    trace_event!(
+        debug,
+        bundle = index,
+        compressed_size = compressed.len(),
+        uncompressed_size = uncompressed_bundle_content.len(),
+        "decompressed bundle"
+    );
+
+    // verify bundle checksum
+    if verify >= VerifyLevel::Bundles
+        && hash.hash(&uncompressed_bundle_content) != bundle_info.checksum
+    {
+        return Err(ArchiveError::Verification(format!(
+            "invalid archive: could not verify bundle integrity for bundle {}",
+            index
+        ))
+        .into());
+    }
+
+    Ok(uncompressed_bundle_content)
+}
+
+/// Returns the listing block as stored in the archive, decompressing it if the header says it is
+/// compressed; decompression is bounded by the largest block `listing_count` listings can take up
+fn decode_listing_block<'a>(
+    stored: &'a [u8],
+    header: &format::Header,
+    limits: &Limits,
+) -> Result<Cow<'a, [u8]>, io::Error> {
+    if !header.has_compressed_listings() {
+        return Ok(Cow::Borrowed(stored));
+    }
+
+    // paths are at most 65535 bytes long, as in the specification
+    let listing_count = header.listing_count;
+    let max_path_length = limits.max_path_length.unwrap_or(u16::MAX as u64);
+    let max_length = listing_count
+        .saturating_mul(max_path_length.saturating_add(format::ListingEntry::SIZE as u64));
+    let corrupt = |e: io::Error| {
+        ArchiveError::Corrupt(format!(
+            "invalid archive: could not decompress listing block: {}",
+            e
+        ))
+    };
+    let mut listing_block = Vec::new();
+    zstd::Decoder::new(stored)
+        .map_err(corrupt)?
+        .take(max_length.saturating_add(1))
+        .read_to_end(&mut listing_block)
+        .map_err(corrupt)?;
+    if listing_block.len() as u64 > max_length {
+        return Err(ArchiveError::Corrupt(format!(
+            "invalid archive: listing block decompresses to more than {} listings can take up",
+            listing_count
+        ))
+        .into());
+    }
+    Ok(Cow::Owned(listing_block))
+}
+
+/// Parses the listings of the listing block, checking their paths and total size against `limits`
+fn parse_listings(
+    listing_block: &[u8],
+    listing_count: u64,
+    limits: &Limits,
+) -> Result<Vec<ExtractedListing>, io::Error> {
+    parse_listings_filtered(listing_block, listing_count, limits, &|_| true)
+        .map(|(listings, _)| listings)
+}
+
+/// Parses the listings of the listing block like `parse_listings`, leaving out the metadata-only
+/// listings whose path `keep` rejects; the rejected listings with content still determine the
+/// size of their bundle, so they follow the listings that are kept without their path. Returns
+/// the listings and the number of them that are kept.
+fn parse_listings_filtered(
+    listing_block: &[u8],
+    listing_count: u64,
+    limits: &Limits,
+    keep: &dyn Fn(&str) -> bool,
+) -> Result<(Vec<ExtractedListing>, usize), io::Error> {
+    // every listing takes at least its entry, so a larger count can't be trusted to preallocate
+    if listing_count > (listing_block.len() / format::ListingEntry::SIZE) as u64 {
+        return Err(ArchiveError::Corrupt(format!(
+            "invalid archive: listing_count {} does not fit into a listing block of {} bytes",
+            listing_count,
+            listing_block.len()
+        ))
+        .into());
+    }
+    let mut uncompressed_size: u64 = 0;
+    let mut listings_vec: Vec<ExtractedListing> = Vec::with_capacity(listing_count as usize);
+    let mut rejected = Vec::new();
+
+    let mut current_offset = 0;
+    for _ in 0..listing_count {
+        let (entry, path) =
+            format::ListingEntry::decode(listing_block.get(current_offset..).unwrap_or_default())?;
+        let listing_path = from_utf8(path).map_err(|_| {
+            ArchiveError::Corrupt("invalid archive: listing path is not UTF-8".to_string())
+        })?;
+        current_offset += entry.total_length as usize;
+        limits.check_path(listing_path)?;
+        if listing_path.split('/').any(|component| component == "..") {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing path {} has a .. component",
+                listing_path
+            ))
+            .into());
+        }
+
+        // bare directories have no content, and neither bare directories nor empty files refer to
+        // a bundle, although archives written before empty files were metadata-only do
+        let is_metadata_only = entry.permissions & 0o040000 == 0o040000 || entry.size == 0;
+        let kept = keep(listing_path);
+        if is_metadata_only && !kept {
+            continue;
+        }
+        if is_metadata_only {
+            listings_vec.push(ExtractedListing {
+                path: listing_path.into(),
+                permissions: entry.permissions,
+                content_checksum: 0,
+                filesize: 0,
+                bundle_idx: 0,
+                bundle_offset: 0,
+            });
+            continue;
+        }
+
+        uncompressed_size = uncompressed_size.saturating_add(entry.size);
+        limits.check_uncompressed_size(uncompressed_size)?;
+        let listing = ExtractedListing {
+            path: Box::default(),
+            permissions: entry.permissions,
+            content_checksum: entry.checksum,
+            filesize: entry.size,
+            bundle_idx: addressable(entry.bundle_index, "bundle index")?,
+            bundle_offset: addressable(entry.bundle_offset, "bundle offset")?,
+        };
+        // content is sliced out of bundles in memory, so `bundle_offset + filesize` can be cast to
+        // a `usize` anywhere once it is checked here
+        addressable(
+            entry.bundle_offset.saturating_add(entry.size),
+            "listing content end",
+        )?;
+        match kept {
+            true => listings_vec.push(ExtractedListing {
+                path: listing_path.into(),
+                ..listing
+            }),
+            false => rejected.push(listing),
+        }
+    }
+
+    let kept = listings_vec.len();
+    listings_vec.append(&mut rejected);
+    Ok((listings_vec, kept))
+}
+
+/// Returns the number of worker threads to use: `threads` if set, otherwise one per available
+/// core up to `max`
+fn worker_count(threads: Option<usize>, max: usize) -> usize {
+    threads
+        .unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .min(max)
+        })
+        .max(1)
+}
+
+/// Calls `f` for every item from up to `workers` threads, stopping at the first error
+fn for_each_parallel<T: Sync, F: Fn(&T) -> Result<(), io::Error> + Sync>(
+    items: &[T],
+    workers: usize,
+    f: F,
+) -> Result<(), io::Error> {
+    let next_item = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.max(1).min(items.len()))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), io::Error> {
+                    loop {
+                        let i = next_item.fetch_add(1, AtomicOrdering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return Ok(());
+                        };
+                        if let Err(e) = f(item) {
+                            // stop the other workers from picking up more items
+                            next_item.store(items.len(), AtomicOrdering::Relaxed);
+                            return Err(e);
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())
+    })
+}
+
+impl ExtractedArchive {
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<ExtractedArchive, io::Error> {
+        ExtractedArchive::from_reader_with_limits(reader, &Limits::default())
+    }
+
+    /// Reads an archive, failing before anything is decompressed if its header or listings exceed
+    /// `limits`, and while decompressing if its bundles do
+    pub fn from_reader_with_limits<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<ExtractedArchive, io::Error> {
+        ExtractedArchive::from_reader_with_verify_level(reader, limits, VerifyLevel::default())
+    }
+
+    /// Reads an archive like `from_reader_with_limits`, verifying the archive and bundle checksums
+    /// only as far as `verify` asks for; files are verified when they are written, as configured
+    /// by `ExtractOptions::verify`
+    pub fn from_reader_with_verify_level<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+        verify: VerifyLevel,
+    ) -> Result<ExtractedArchive, io::Error> {
+        let options = ExtractOptions {
+            verify,
+            ..Default::default()
+        };
+        ExtractedArchive::from_reader_with_options(reader, limits, &options)
+    }
+
+    /// Reads an archive like `from_reader_with_verify_level` at the level of `options.verify`,
+    /// decompressing bundles on `options.threads` threads
+    pub fn from_reader_with_options<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+        options: &ExtractOptions,
+    ) -> Result<ExtractedArchive, io::Error> {
+        ExtractedArchive::read_with_contexts(reader, limits, options, None, &Mutex::new(Vec::new()))
+    }
+
+    /// Reads an archive like `from_reader`, keeping only the listings whose path `predicate`
+    /// accepts, e.g. to read a single subtree of a large archive
+    ///
+    /// The rejected listings are dropped as they are parsed, and only the bundles holding the
+    /// content of the listings that are kept are decompressed. The archive checksum is still
+    /// verified over the whole archive.
+    pub fn from_reader_filtered<R: Read, F: Fn(&str) -> bool>(
+        reader: &mut R,
+        predicate: F,
+    ) -> Result<ExtractedArchive, io::Error> {
+        ExtractedArchive::read_with_contexts(
+            reader,
+            &Limits::default(),
+            &ExtractOptions::default(),
+            Some(&predicate),
+            &Mutex::new(Vec::new()),
+        )
+    }
+
+    /// Reads an archive like `from_reader_with_options`, keeping only the listings `keep` accepts
+    /// if given, and decompressing bundles with the zstd contexts in `contexts`, which are returned
+    /// there for the next archive
+    fn read_with_contexts<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+        options: &ExtractOptions,
+        keep: Option<&dyn Fn(&str) -> bool>,
+        contexts: &Mutex<Vec<zstd_safe::DCtx<'static>>>,
+    ) -> Result<ExtractedArchive, io::Error> {
+        options.validate()?;
+        let verify = options.verify;
+        let mut input_buffer: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut input_buffer)?;
+        trace_span!("read_archive", bytes = input_buffer.len());
+
+        let header = format::Header::decode(&input_buffer)?;
+        let archive_length = input_buffer.len() as u64;
+        let hash = HashAlgorithm::of(&header)?;
+
+        // an archive with a footer is described by the footer, which is checksummed after
+        // everything preceding it
+        let (header, footer_offset) = match header.has_footer() {
+            true => {
+                let offset = footer_offset(archive_length)? as usize;
+                (decode_footer(&header, &input_buffer[offset..])?, offset)
+            }
+            false => (header, input_buffer.len()),
+        };
+
+        // verify magic number and archive checksum, detecting the format revision
+        let checksummed_end = match header.has_split_checksum() {
+            true => table_bounds(&header, archive_length)?.bundle_table.end as usize,
+            false => footer_offset,
+        };
+        let checksummed = &input_buffer[format::Header::CHECKSUMMED_FROM..checksummed_end];
+        let revision = if header.magic == format::MAGIC_NUMBER {
+            let mut hasher = hash.hasher();
+            hasher.update(checksummed);
+            if header.has_footer() {
+                hasher.update(&input_buffer[footer_offset + format::Header::CHECKSUMMED_FROM..]);
+            }
+            if verify >= VerifyLevel::Bundles && header.checksum != hasher.digest() {
+                return Err(ArchiveError::Verification(
+                    "invalid archive: could not verify archive integrity".to_string(),
+                )
+                .into());
+            }
+            FormatRevision::Current
+        } else if header.magic == format::LEGACY_MAGIC_NUMBER {
+            if header.checksum == xxh3(checksummed) {
+                FormatRevision::LegacyXxh3
+            } else if header.checksum == crc32fast::hash(checksummed) as u64 {
+                FormatRevision::LegacyCrc32
+            } else {
+                return Err(ArchiveError::Verification(
+                    "invalid archive: could not verify legacy archive integrity".to_string(),
+                )
+                .into());
+            }
+        } else {
+            return Err(ArchiveError::Corrupt(
+                "invalid archive: does not contain magic number".to_string(),
+            )
+            .into());
+        };
+
+        limits.check_listing_count(header.listing_count)?;
+        limits.check_bundle_count(header.bundle_count)?;
+
+        let bounds = table_bounds(&header, archive_length)?;
+        // the section checksums are covered by the archive checksum
+        let listing_block =
+            &input_buffer[bounds.listing_block.start as usize..bounds.listing_block.end as usize];
+        let bundle_table =
+            &input_buffer[bounds.bundle_table.start as usize..bounds.bundle_table.end as usize];
+
+        let listing_block = decode_listing_block(listing_block, &header, limits)?;
+        let (mut listings_vec, kept) = parse_listings_filtered(
+            &listing_block,
+            header.listing_count,
+            limits,
+            keep.unwrap_or(&|_| true),
+        )?;
+
+        let bundle_infos = parse_bundle_table(
+            bundle_table,
+            header.bundle_count,
+            bounds.bundles,
+            &listings_vec,
+            limits,
+        )?;
+        listings_vec.truncate(kept);
+
+        // bundles no listing that is kept refers to are left empty
+        let indices: Vec<usize> = match keep {
+            Some(_) => listings_vec
+                .iter()
+                .filter(|listing| !listing.is_metadata_only())
+                .map(|listing| listing.bundle_idx)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            None => (0..bundle_infos.len()).collect(),
+        };
+        Limits::check(
+            options.max_memory,
+            indices
+                .iter()
+                .map(|&i| bundle_infos[i].uncompressed_size)
+                .sum(),
+            "memory for decompressed bundles",
+        )?;
+        let workers = worker_count(options.threads, MAX_DECOMPRESSION_WORKERS);
+        trace_span!("decompress", workers);
+        let bundles_uncompressed = Mutex::new(vec![Vec::new(); bundle_infos.len()]);
+        for_each_parallel(&indices, workers, |&i| {
+            let bundle_info = &bundle_infos[i];
+            let start = bundle_info.offset as usize;
+            let end = start + bundle_info.compressed_size as usize;
+            let mut context = contexts
+                .lock()
+                .unwrap()
+                .pop()
+                .unwrap_or_else(zstd_safe::DCtx::create);
+            let bundle = decompress_bundle_with_context(
+                i,
+                &input_buffer[start..end],
+                bundle_info,
+                verify,
+                hash,
+                &mut context,
+            );
+            contexts.lock().unwrap().push(context);
+            bundles_uncompressed.lock().unwrap()[i] = bundle?;
+            Ok(())
+        })?;
+        let bundles_uncompressed = bundles_uncompressed.into_inner().unwrap();
+
+        Ok(ExtractedArchive {
+            path_index: PathIndex::new(&listings_vec),
+            listings: listings_vec,
+            bundles: bundles_uncompressed,
+            bundle_infos,
+            revision,
+            hash,
+        })
+    }
+
+    /// Returns the layout of the bundles the archive was read from
+    pub fn bundles(&self) -> &[BundleInfo] {
+        &self.bundle_infos
+    }
+
+    /// Returns the revision of the format the archive was read from
+    pub fn revision(&self) -> FormatRevision {
+        self.revision
+    }
+
+    /// Returns the hash algorithm the checksums of the archive are computed with
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// Returns the listing with the given path, looked up in an index built when the archive was
+    /// read rather than by scanning every listing
+    pub fn find(&self, path: &str) -> Option<&ExtractedListing> {
+        self.path_index.find(&self.listings, path)
+    }
+
+    /// Returns the content of the listing with the given path, borrowed from the decompressed
+    /// bundles without copying
+    pub fn file_bytes(&self, path: &str) -> Option<&[u8]> {
+        self.find(path).map(|listing| self.listing_bytes(listing))
+    }
+
+    /// Returns the content of `listing`, borrowed from the decompressed bundles without copying
+    pub fn listing_bytes(&self, listing: &ExtractedListing) -> &[u8] {
+        if listing.is_metadata_only() {
+            return &[];
+        }
+        &self.bundles[listing.bundle_idx]
+            [listing.bundle_offset..listing.bundle_offset + listing.filesize as usize]
+    }
+
+    /// Returns the permissions and content of every listing by path, e.g. to assert on the
+    /// contents of an archive without touching the filesystem; bare directories map to empty
+    /// content, and of several listings with the same path the first is kept
+    pub fn to_memory_map(&self) -> BTreeMap<PathBuf, (u32, Vec<u8>)> {
+        let mut map = BTreeMap::new();
+        for listing in &self.listings {
+            map.entry(PathBuf::from(&*listing.path))
+                .or_insert_with(|| (listing.permissions, self.listing_bytes(listing).to_vec()));
+        }
+        map
+    }
+
+    /// Writes the extracted listings back out as a new archive using `options`
+    pub fn repack_to_writer<W: Write>(
+        &self,
+        writer: &mut W,
+        options: &ArchiveOptions,
+    ) -> Result<WrittenArchive, io::Error> {
+        let listings: Vec<ArchivableListing> = self
+            .listings
+            .iter()
+            .map(|listing| ArchivableListing {
+                relative_path: listing.path.clone(),
+                permissions: listing.permissions,
+                file_size: listing.filesize,
+                literal_path: PathBuf::new(),
+            })
+            .collect();
+
+        let mut writer = BufWriter::new(writer);
+        write_archive(
+            &listings,
+            |listing_idx| {
+                let listing = &self.listings[listing_idx];
+                Ok(self.listing_bytes(listing).to_vec())
+            },
+            &mut writer,
+            options,
+        )
+    }
+
+    pub fn create_all_files<P: AsRef<Path>>(
+        &self,
+        output_directory_path: P,
+    ) -> Result<usize, io::Error> {
+        self.create_all_files_with_options(output_directory_path, &ExtractOptions::default())
+            .map(|stats| stats.uncompressed_bytes as usize)
+    }
+
+    pub fn create_all_files_with_options<P: AsRef<Path>>(
+        &self,
+        output_directory_path: P,
+        options: &ExtractOptions,
+    ) -> Result<ArchiveStats, io::Error> {
+        options.validate()?;
+        let start = Instant::now();
+        let output_directory_path = output_directory_path.as_ref();
+        trace_span!(
+            "extract",
+            listings = self.listings.len(),
+            output = %output_directory_path.display()
+        );
+        let mut stats = ArchiveStats {
+            compressed_bytes: self.bundle_infos.iter().map(|b| b.compressed_size).sum(),
+            bundle_count: self.bundle_infos.len(),
+            ..Default::default()
+        };
+
+        // fail before writing anything rather than leave a partial tree behind on ENOSPC
+        check_space(&self.listings, output_directory_path, options)?;
+
+        // the output directory exists afterwards even if the archive has no listings
+        fs::create_dir_all(output_directory_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to create output directory: {}", e),
+            )
+        })?;
+
+        let mut warnings = Mutex::new(Vec::new());
+        let mut extraction_paths = ExtractionPaths::new(output_directory_path, options)?;
+        let progress = ProgressCounter::new(
+            options.on_progress.as_ref(),
+            Some(extracted_size(&self.listings, options)),
+        );
+        self.write_listings(
+            output_directory_path,
+            options,
+            &mut extraction_paths,
+            &mut stats,
+            &progress,
+            &mut warnings,
+        )?;
+
+        stats.warnings = warnings.into_inner().unwrap();
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Writes the listings to the existing `output_directory_path`, adding them to `stats` and
+    /// reporting them to `progress`
+    fn write_listings(
+        &self,
+        output_directory_path: &Path,
+        options: &ExtractOptions,
+        extraction_paths: &mut ExtractionPaths,
+        stats: &mut ArchiveStats,
+        progress: &ProgressCounter,
+        warnings: &mut Mutex<Vec<Warning>>,
+    ) -> Result<(), io::Error> {
+        // bare directories are created up front so that workers only ever write files
+        let mut file_listings = Vec::with_capacity(self.listings.len());
+        let mut duplicates = Vec::new();
+        // files with the same content as an earlier file are copied from it once it is written,
+        // which shares their blocks on filesystems supporting reflinks; throttled extraction
+        // writes every file itself so that all of its writes are accounted for
+        let mut originals: HashMap<(u64, u64), (&ExtractedListing, PathBuf)> = HashMap::new();
+        for listing in &self.listings {
+            let Some(transformed_path) =
+                extraction_paths.resolve(listing, options, warnings.get_mut().unwrap())?
+            else {
+                continue;
+            };
+            let listing_path = output_directory_path.join(native_path(&transformed_path));
+            stats.add_listing(listing.permissions, listing.filesize);
+            if listing.permissions & 0o040000 == 0o040000 {
+                self.materialize(listing, &listing_path, options, None, None, warnings)?;
+                listing.report_progress(progress);
+                continue;
+            }
+            if options.rate_limit.is_none() && !listing.is_metadata_only() {
+                match originals.entry((listing.content_checksum, listing.filesize)) {
+                    Entry::Occupied(original)
+                        if self.listing_bytes(original.get().0) == self.listing_bytes(listing) =>
+                    {
+                        let original_path = original.get().1.clone();
+                        duplicates.push((listing, listing_path, original_path));
+                        continue;
+                    }
+                    Entry::Occupied(_) => {}
+                    Entry::Vacant(entry) => {
+                        entry.insert((listing, listing_path.clone()));
+                    }
+                }
+            }
+            file_listings.push((listing, listing_path));
+        }
+
+        let limiter = RateLimiter::new(options.rate_limit);
+        let workers = worker_count(options.threads, MAX_EXTRACTION_WORKERS);
+        for_each_parallel(&file_listings, workers, |(listing, listing_path)| {
+            self.materialize(
+                listing,
+                listing_path,
+                options,
+                limiter.as_ref(),
+                None,
+                warnings,
+            )?;
+            listing.report_progress(progress);
+            Ok(())
+        })?;
+        for_each_parallel(
+            &duplicates,
+            workers,
+            |(listing, listing_path, original_path)| {
+                self.materialize(
+                    listing,
+                    listing_path,
+                    options,
+                    None,
+                    Some(original_path),
+                    warnings,
+                )?;
+                listing.report_progress(progress);
+                Ok(())
+            },
+        )?;
+
+        let Some(callback) = &options.on_nested_archive else {
+            return Ok(());
+        };
+        let written = file_listings.iter().map(|(listing, path)| (listing, path));
+        let duplicated = duplicates.iter().map(|(listing, path, _)| (listing, path));
+        for (listing, listing_path) in written.chain(duplicated) {
+            if let Some(kind) =
+                NestedArchiveKind::detect(&listing.path, self.listing_bytes(listing))
+            {
+                (callback.0)(&NestedArchive {
+                    path: &listing.path,
+                    extracted_path: listing_path,
+                    kind,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the listings which are themselves archives, with their kind
+    pub fn nested_archives(&self) -> Vec<(&ExtractedListing, NestedArchiveKind)> {
+        self.listings
+            .iter()
+            .filter_map(|listing| {
+                NestedArchiveKind::detect(&listing.path, self.listing_bytes(listing))
+                    .map(|kind| (listing, kind))
+            })
+            .collect()
+    }
+
+    /// Extracts the archive into a new temporary directory, which is removed when the returned
+    /// `TempDir` is dropped
+    pub fn extract_to_temp(&self) -> Result<(TempDir, ArchiveStats), io::Error> {
+        self.extract_to_temp_with_options(&ExtractOptions::default())
+    }
+
+    pub fn extract_to_temp_with_options(
+        &self,
+        options: &ExtractOptions,
+    ) -> Result<(TempDir, ArchiveStats), io::Error> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("decaf-");
+        let temp_dir = match &options.temp_dir {
+            Some(directory) => builder
+                .tempdir_in(directory)
+                .map_err(temp_dir_error(directory))?,
+            None => builder.tempdir()?,
+        };
+        let stats = self.create_all_files_with_options(temp_dir.path(), options)?;
+        Ok((temp_dir, stats))
+    }
+
+    /// Checks where the archive would be extracted to for collisions with existing files,
+    /// unwritable directories and insufficient disk space, without creating anything
+    pub fn preflight<P: AsRef<Path>>(
+        &self,
+        output_directory_path: P,
+        options: &ExtractOptions,
+    ) -> Result<PreflightReport, io::Error> {
+        let output_directory_path = output_directory_path.as_ref();
+        let mut collisions = BTreeSet::new();
+        let mut unwritable = BTreeSet::new();
+        let mut required_bytes = 0;
+
+        for listing in &self.listings {
+            let Some(transformed_path) = options.transform_path(&listing.path)? else {
+                continue;
+            };
+            required_bytes += listing.filesize;
+
+            let listing_path = output_directory_path.join(native_path(&transformed_path));
+            let is_dir = listing.permissions & 0o040000 == 0o040000;
+            match fs::symlink_metadata(&listing_path) {
+                Ok(metadata) if !(is_dir && metadata.is_dir()) => {
+                    collisions.insert(listing_path);
+                    continue;
+                }
+                Ok(_) => continue,
+                // a file in place of an ancestor is reported below
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+
+            // the listing is created inside its nearest existing ancestor
+            let ancestor = nearest_existing_ancestor(&listing_path);
+            if !ancestor.is_dir() {
+                collisions.insert(ancestor.to_path_buf());
+            } else if !is_writable(ancestor) {
+                unwritable.insert(ancestor.to_path_buf());
+            }
+        }
+
+        Ok(PreflightReport {
+            collisions: collisions.into_iter().collect(),
+            unwritable: unwritable.into_iter().collect(),
+            required_bytes,
+            available_bytes: available_space(nearest_existing_ancestor(output_directory_path))?,
+        })
+    }
+
+    pub fn create_file<P: AsRef<Path>>(
+        &self,
+        listing: &ExtractedListing,
+        output_directory_path: P,
+    ) -> Result<usize, io::Error> {
+        self.create_file_with_options(listing, output_directory_path, &ExtractOptions::default())
+    }
+
+    pub fn create_file_with_options<P: AsRef<Path>>(
+        &self,
+        listing: &ExtractedListing,
+        output_directory_path: P,
+        options: &ExtractOptions,
+    ) -> Result<usize, io::Error> {
+        options.validate()?;
+        let Some(transformed_path) = options.transform_path(&listing.path)? else {
+            return Ok(0);
+        };
+        let limiter = RateLimiter::new(options.rate_limit);
+        // a single file has nowhere to report warnings but the trace
+        self.materialize(
+            listing,
+            &output_directory_path
+                .as_ref()
+                .join(native_path(&transformed_path)),
+            options,
+            limiter.as_ref(),
+            None,
+            &Mutex::new(Vec::new()),
+        )
+    }
+
+    /// Writes `listing` to `listing_path`, copying its content from the already extracted file at
+    /// `clone_from` if there is one and the filesystem can copy it by itself
+    fn materialize(
+        &self,
+        listing: &ExtractedListing,
+        listing_path: &Path,
+        options: &ExtractOptions,
+        limiter: Option<&RateLimiter>,
+        clone_from: Option<&Path>,
+        warnings: &Mutex<Vec<Warning>>,
+    ) -> Result<usize, io::Error> {
+        if listing.permissions & 0o040000 == 0o040000 {
+            // bare directories
+            fs::create_dir_all(listing_path).map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to create bare directory: {}", e))
+            })?;
+            options.set_permissions(listing_path, listing.permissions, warnings, |mode| {
+                fs::set_permissions(listing_path, mode)
+            })?;
+            options.set_file_flags(listing_path, listing.permissions, warnings)?;
+            return Ok(0);
+        }
+        if !options.may_write(listing_path, warnings)? {
+            return Ok(0);
+        }
+
+        fs::create_dir_all(listing_path.parent().unwrap()).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to create ancestor directory: {}", e),
+            )
+        })?;
+
+        File::create(listing_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to create file {}: {}", listing_path.display(), e),
+            )
+        })?;
+
+        let mut listing_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(listing_path)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to create/open file {} for writing: {}",
+                        listing_path.display(),
+                        e
+                    ),
+                )
+            })?;
+
+        let listing_content = self.listing_bytes(listing);
+
+        // verify listing content checksum; metadata-only listings have none
+        if options.verify >= VerifyLevel::Files && !listing.is_metadata_only() {
+            let computed_checksum = self.hash.hash(listing_content);
+            if computed_checksum != listing.content_checksum {
+                return Err(ArchiveError::Verification(format!(
+                    "invalid listing: could not verify file integrity for file {}, listing has {} but checksum was computed as {} (bundle {} with offset {}; size: {})",
+                    listing.path, listing.content_checksum, computed_checksum, listing.bundle_idx, listing.bundle_offset, listing.filesize,
+                ))
+                .into());
+            }
+        }
+
+        // an original whose permissions don't allow reading it back is not copied from
+        let cloned = match clone_from.map(|path| (path, File::open(path))) {
+            Some((source_path, Ok(source))) => clone_file(&source, &listing_file, listing.filesize)
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!(
+                            "Failed to copy {} to file {}: {}",
+                            source_path.display(),
+                            listing_path.display(),
+                            e
+                        ),
+                    )
+                })?,
+            Some((_, Err(_))) | None => false,
+        };
+        if cloned {
+            return self.finish_file(listing, &listing_file, listing_path, options, warnings);
+        }
+
+        preallocate(&listing_file, listing.filesize).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to allocate {} bytes for file {}: {}",
+                    listing.filesize,
+                    listing_path.display(),
+                    e
+                ),
+            )
+        })?;
+
+        write_limited(&mut listing_file, listing_content, limiter).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to write content to file {}: {}",
+                    listing_path.display(),
+                    e
+                ),
+            )
+        })?;
+
+        self.finish_file(listing, &listing_file, listing_path, options, warnings)
+    }
+
+    /// Verifies a file whose content was just written if asked to, and sets its permissions
+    fn finish_file(
+        &self,
+        listing: &ExtractedListing,
+        listing_file: &File,
+        listing_path: &Path,
+        options: &ExtractOptions,
+        warnings: &Mutex<Vec<Warning>>,
+    ) -> Result<usize, io::Error> {
+        // read the file back before its permissions might make it unreadable
+        if options.verify >= VerifyLevel::Paranoid {
+            let written = fs::read(listing_path)?;
+            if written.len() as u64 != listing.filesize
+                || (!listing.is_metadata_only()
+                    && self.hash.hash(&written) != listing.content_checksum)
+            {
+                return Err(ArchiveError::Verification(format!(
+                    "could not verify file {} after writing it to {}",
+                    listing.path,
+                    listing_path.display()
+                ))
+                .into());
+            }
+        }
+
+        options.set_permissions(listing_path, listing.permissions, warnings, |mode| {
+            listing_file.set_permissions(mode)
+        })?;
+        options.set_file_flags(listing_path, listing.permissions, warnings)?;
+        Ok(listing.filesize as usize)
+    }
+}
+
+/// Reads the header at the start of an archive, checking its magic number and hash algorithm
+fn read_header<R: Read>(reader: &mut R) -> Result<(format::Header, HashAlgorithm), io::Error> {
+    let mut header = [0u8; format::Header::SIZE];
+    read_exact_or_corrupt(reader, &mut header, "invalid archive: archive too small")?;
+    let header = format::Header::decode(&header)?;
+    if header.magic != format::MAGIC_NUMBER && header.magic != format::LEGACY_MAGIC_NUMBER {
+        return Err(ArchiveError::Corrupt(
+            "invalid archive: does not contain magic number".to_string(),
+        )
+        .into());
+    }
+    let hash = HashAlgorithm::of(&header)?;
+    Ok((header, hash))
+}
+
+/// Reads the footer of an archive of `archive_length` bytes starting with `header`, returning it
+/// with the hash algorithm it names
+fn read_footer<R: Read + Seek>(
+    reader: &mut R,
+    header: &format::Header,
+    archive_length: u64,
+) -> Result<(format::Header, HashAlgorithm), io::Error> {
+    reader.seek(SeekFrom::Start(footer_offset(archive_length)?))?;
+    let mut footer = [0u8; format::Header::SIZE];
+    read_exact_or_corrupt(reader, &mut footer, "invalid archive: footer is truncated")?;
+    let footer = decode_footer(header, &footer)?;
+    let hash = HashAlgorithm::of(&footer)?;
+    Ok((footer, hash))
+}
+
+/// Reads the listing block and the bundle table, which directly follows it, from `reader`
+/// positioned at the start of the listing block
+fn read_tables<R: Read>(reader: &mut R, bounds: &TableBounds) -> Result<Vec<u8>, io::Error> {
+    // the tables are read without allocating their declared length up front, as it can't be
+    // trusted
+    let tables_length = bounds.bundle_table.end - bounds.listing_block.start;
+    let mut tables = Vec::new();
+    reader.take(tables_length).read_to_end(&mut tables)?;
+    if (tables.len() as u64) < tables_length {
+        return Err(ArchiveError::Corrupt(
+            "invalid archive: listing block or bundle table is truncated".to_string(),
+        )
+        .into());
+    }
+    Ok(tables)
+}
+
+/// Reads the header checksum following the header and its section checksums, if the header says
+/// there is one
+fn read_header_checksum<R: Read>(
+    reader: &mut R,
+    header: &format::Header,
+) -> Result<Option<format::HeaderChecksum>, io::Error> {
+    if !header.has_header_checksum() {
+        return Ok(None);
+    }
+    let mut header_checksum = [0u8; format::HeaderChecksum::SIZE];
+    read_exact_or_corrupt(
+        reader,
+        &mut header_checksum,
+        "invalid archive: header checksum is truncated",
+    )?;
+    Ok(Some(format::HeaderChecksum::decode(&header_checksum)?))
+}
+
+fn verify_header_checksum(
+    header: &format::Header,
+    hash: HashAlgorithm,
+    bundle_table: &[u8],
+    expected: format::HeaderChecksum,
+) -> Result<(), ArchiveError> {
+    if header_checksum(header, hash, bundle_table) != expected.checksum {
+        return Err(ArchiveError::Verification(
+            "invalid archive: could not verify header integrity".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The counts and sizes of an archive, read from its header and bundle table alone, e.g. for UIs
+/// listing many archives
+///
+/// Neither the archive checksum nor the listing block is read, so nothing is verified unless the
+/// archive has a header checksum, as written with `ArchiveOptions::header_checksum`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSummary {
+    /// the hash algorithm the checksums of the archive are computed with, as named by the header
+    pub hash: HashAlgorithm,
+    pub listing_count: u64,
+    pub bundle_count: u64,
+    /// the length of the listing block as stored, which is compressed if the header says so
+    pub listing_block_length: u64,
+    /// the sum of the compressed sizes of the bundles
+    pub compressed_size: u64,
+    /// the length of the whole archive
+    pub archive_size: u64,
+    /// whether the header and the bundle table were verified against a header checksum
+    pub verified: bool,
+}
+
+impl ArchiveSummary {
+    /// Reads the summary of the archive `reader` holds, seeking past its listing block to read
+    /// the bundle table
+    pub fn peek<R: Read + Seek>(reader: &mut R) -> Result<ArchiveSummary, io::Error> {
+        let archive_size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let (header, hash) = read_header(reader)?;
+        let (header, hash) = match header.has_footer() {
+            true => read_footer(reader, &header, archive_size)?,
+            false => (header, hash),
+        };
+        let limits = Limits::default();
+        limits.check_listing_count(header.listing_count)?;
+        limits.check_bundle_count(header.bundle_count)?;
+        let bounds = table_bounds(&header, archive_size)?;
+        // every listing takes at least its entry, which only the stored length of an uncompressed
+        // listing block tells without reading it
+        let listing_count = header.listing_count;
+        let listing_block_length = header.stored_listing_block_length();
+        if !header.has_compressed_listings()
+            && listing_count > listing_block_length / format::ListingEntry::SIZE as u64
+        {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: listing_count {} does not fit into a listing block of {} bytes",
+                listing_count, listing_block_length
+            ))
+            .into());
+        }
+        if header.has_header_checksum() {
+            reader.seek(SeekFrom::Start(header.header_checksum_offset()))?;
+        }
+        let header_checksum = read_header_checksum(reader, &header)?;
+
+        // the bundle count is within the limits and the bundle table within the archive, so
+        // reading it allocates no more than the archive holds
+        let mut bundle_table =
+            vec![0u8; (bounds.bundle_table.end - bounds.bundle_table.start) as usize];
+        reader.seek(SeekFrom::Start(bounds.bundle_table.start))?;
+        read_exact_or_corrupt(
+            reader,
+            &mut bundle_table,
+            "invalid archive: bundle table is truncated",
+        )?;
+        if let Some(header_checksum) = header_checksum {
+            verify_header_checksum(&header, hash, &bundle_table, header_checksum)?;
+        }
+
+        // without the listings, the uncompressed sizes of the bundles are unknown
+        let bundles = parse_bundle_table(
+            &bundle_table,
+            header.bundle_count,
+            bounds.bundles,
+            &[],
+            &limits,
+        )?;
+        let compressed_size = bundles.iter().fold(0u64, |total, bundle| {
+            total.saturating_add(bundle.compressed_size)
+        });
+        Ok(ArchiveSummary {
+            hash,
+            listing_count: header.listing_count,
+            bundle_count: header.bundle_count,
+            listing_block_length: header.stored_listing_block_length(),
+            compressed_size,
+            archive_size,
+            verified: header_checksum.is_some(),
+        })
+    }
+}
+
+/// The layout of an archive as described by its header, listing block and bundle table, read
+/// without decompressing any bundles, e.g. to analyze compression or to plan ranged downloads
+///
+/// No bundle checksum is verified, and the archive checksum only if it is split, but the listing
+/// block and the bundle table are if the archive has section checksums, and the header and the
+/// bundle table are if it has a header checksum.
+#[derive(Debug, Clone)]
+pub struct ArchiveLayout {
+    /// the header, or the footer completing it in an archive with one
+    pub header: format::Header,
+    /// the hash algorithm the checksums of the archive are computed with, as named by the header
+    pub hash: HashAlgorithm,
+    /// the checksums of the listing block and bundle table, if the archive has them
+    pub section_checksums: Option<format::SectionChecksums>,
+    /// the checksum of the header and bundle table, if the archive has one
+    pub header_checksum: Option<format::HeaderChecksum>,
+    /// the listings, each naming the bundle holding its content and its offset in that bundle
+    pub listings: Vec<ExtractedListing>,
+    /// the bundles, with the uncompressed sizes declared by the listings
+    pub bundles: Vec<BundleInfo>,
+}
+
+impl ArchiveLayout {
+    /// Reads the layout from the start of an archive, consuming only the header and the tables;
+    /// archives with a footer take `read_seekable`
+    pub fn read<R: Read>(reader: &mut R) -> Result<ArchiveLayout, io::Error> {
+        // without knowing where the archive ends, bundles can only be checked to follow the tables
+        ArchiveLayout::read_bounded(reader, u64::MAX, &Limits::default())
+    }
+
+    /// Reads the layout of an archive by seeking to its tables, which also reads archives with a
+    /// footer
+    pub fn read_seekable<R: Read + Seek>(reader: &mut R) -> Result<ArchiveLayout, io::Error> {
+        ArchiveLayout::read_seeking(reader, &Limits::default())
+    }
+
+    fn read_seeking<R: Read + Seek>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<ArchiveLayout, io::Error> {
+        let archive_length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let (header, _) = read_header(reader)?;
+        if !header.has_footer() {
+            reader.seek(SeekFrom::Start(0))?;
+            return ArchiveLayout::read_bounded(reader, archive_length, limits);
+        }
+
+        let (header, hash) = read_footer(reader, &header, archive_length)?;
+        limits.check_listing_count(header.listing_count)?;
+        limits.check_bundle_count(header.bundle_count)?;
+        let bounds = table_bounds(&header, archive_length)?;
+        reader.seek(SeekFrom::Start(bounds.listing_block.start))?;
+        let tables = read_tables(reader, &bounds)?;
+        ArchiveLayout::from_tables(header, hash, None, None, &tables, bounds, limits)
+    }
+
+    fn read_bounded<R: Read>(
+        reader: &mut R,
+        archive_length: u64,
+        limits: &Limits,
+    ) -> Result<ArchiveLayout, io::Error> {
+        let (header, hash) = read_header(reader)?;
+        if header.has_footer() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "archives with a footer can only be read as a whole or by seeking to their tables",
+            ));
+        }
+
+        limits.check_listing_count(header.listing_count)?;
+        limits.check_bundle_count(header.bundle_count)?;
+        let bounds = table_bounds(&header, archive_length)?;
+        let section_checksums = match header.has_section_checksums() {
+            true => {
+                let mut section_checksums = [0u8; format::SectionChecksums::SIZE];
+                read_exact_or_corrupt(
+                    reader,
+                    &mut section_checksums,
+                    "invalid archive: section checksums are truncated",
+                )?;
+                Some(format::SectionChecksums::decode(&section_checksums)?)
+            }
+            false => None,
+        };
+        let header_checksum = read_header_checksum(reader, &header)?;
+
+        let tables = read_tables(reader, &bounds)?;
+        let (listing_block, bundle_table) =
+            tables.split_at((bounds.listing_block.end - bounds.listing_block.start) as usize);
+        if let Some(section_checksums) = section_checksums {
+            if hash.hash(listing_block) != section_checksums.listing_block {
+                return Err(ArchiveError::Verification(
+                    "invalid archive: could not verify listing block integrity".to_string(),
+                )
+                .into());
+            }
+            if hash.hash(bundle_table) != section_checksums.bundle_table {
+                return Err(ArchiveError::Verification(
+                    "invalid archive: could not verify bundle table integrity".to_string(),
+                )
+                .into());
+            }
+        }
+        if let Some(header_checksum) = header_checksum {
+            verify_header_checksum(&header, hash, bundle_table, header_checksum)?;
+        }
+        // a split archive checksum covers nothing but what was just read
+        if header.has_split_checksum() {
+            let mut hasher = hash.hasher();
+            hasher.update(&header.encode()[format::Header::CHECKSUMMED_FROM..]);
+            if let Some(section_checksums) = section_checksums {
+                hasher.update(&section_checksums.encode());
+            }
+            if let Some(header_checksum) = header_checksum {
+                hasher.update(&header_checksum.encode());
+            }
+            hasher.update(&tables);
+            if hasher.digest() != header.checksum {
+                return Err(ArchiveError::Verification(
+                    "invalid archive: could not verify archive integrity".to_string(),
+                )
+                .into());
+            }
+        }
+
+        ArchiveLayout::from_tables(
+            header,
+            hash,
+            section_checksums,
+            header_checksum,
+            &tables,
+            bounds,
+            limits,
+        )
+    }
+
+    fn from_tables(
+        header: format::Header,
+        hash: HashAlgorithm,
+        section_checksums: Option<format::SectionChecksums>,
+        header_checksum: Option<format::HeaderChecksum>,
+        tables: &[u8],
+        bounds: TableBounds,
+        limits: &Limits,
+    ) -> Result<ArchiveLayout, io::Error> {
+        let (listing_block, bundle_table) =
+            tables.split_at((bounds.listing_block.end - bounds.listing_block.start) as usize);
+        let listing_block = decode_listing_block(listing_block, &header, limits)?;
+        let listings = parse_listings(&listing_block, header.listing_count, limits)?;
+        let bundles = parse_bundle_table(
+            bundle_table,
+            header.bundle_count,
+            bounds.bundles,
+            &listings,
+            limits,
+        )?;
+        Ok(ArchiveLayout {
+            header,
+            hash,
+            section_checksums,
+            header_checksum,
+            listings,
+            bundles,
+        })
+    }
+
+    /// Returns the byte range of the compressed bundle at `index` within the archive
+    pub fn bundle_range(&self, index: usize) -> Option<Range<u64>> {
+        let bundle = self.bundles.get(index)?;
+        Some(bundle.offset..bundle.offset + bundle.compressed_size)
+    }
+
+    /// Returns an index of the listings ordered by path, to be stored apart from the archive
+    pub fn index(&self) -> ArchiveIndex {
+        let mut listings = self.listings.clone();
+        // the sort is stable, so the first of several listings with the same path is still found
+        listings.sort_by(|a, b| a.path.cmp(&b.path));
+        ArchiveIndex {
+            archive_checksum: self.header.checksum,
+            hash: self.hash,
+            listings,
+            bundles: self.bundles.clone(),
+        }
+    }
+
+    /// Breaks the compression of the archive down by bundle and by file extension, along with
+    /// the `largest` listings taking the most compressed space
+    pub fn compression_report(&self, largest: usize) -> CompressionReport {
+        let mut listing_totals = self
+            .listings
+            .iter()
+            .filter(|listing| !listing.is_metadata_only())
+            .filter_map(|listing| {
+                let bundle = self.bundles.get(listing.bundle_idx)?;
+                let compressed_bytes = (bundle.compressed_size as u128 * listing.filesize as u128)
+                    .checked_div(bundle.uncompressed_size as u128)
+                    .unwrap_or(0) as u64;
+                Some((
+                    listing,
+                    CompressionTotals {
+                        uncompressed_bytes: listing.filesize,
+                        compressed_bytes,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let mut extensions: HashMap<&str, CompressionTotals> = HashMap::new();
+        for (listing, totals) in &listing_totals {
+            extensions
+                .entry(extension_of(&listing.path))
+                .or_default()
+                .add(*totals);
+        }
+        let mut extensions = extensions
+            .into_iter()
+            .map(|(extension, totals)| (extension.to_string(), totals))
+            .collect::<Vec<_>>();
+        extensions.sort_by(|a, b| {
+            b.1.compressed_bytes
+                .cmp(&a.1.compressed_bytes)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+
+        listing_totals.sort_by(|a, b| {
+            b.1.compressed_bytes
+                .cmp(&a.1.compressed_bytes)
+                .then_with(|| a.0.path.cmp(&b.0.path))
+        });
+        listing_totals.truncate(largest);
+
+        CompressionReport {
+            bundles: self
+                .bundles
+                .iter()
+                .map(|bundle| CompressionTotals {
+                    uncompressed_bytes: bundle.uncompressed_size,
+                    compressed_bytes: bundle.compressed_size,
+                })
+                .collect(),
+            extensions,
+            largest: listing_totals
+                .into_iter()
+                .map(|(listing, totals)| (listing.path.clone(), totals))
+                .collect(),
+        }
+    }
+
+    /// Recreates the tree described by the listing block with every file created empty but with
+    /// its permissions, without decompressing any bundle, e.g. to quickly check the layout a
+    /// deployment would have
+    pub fn create_placeholders<P: AsRef<Path>>(
+        &self,
+        output_directory_path: P,
+        options: &ExtractOptions,
+    ) -> Result<ArchiveStats, io::Error> {
+        options.validate()?;
+        let start = Instant::now();
+        let output_directory_path = output_directory_path.as_ref();
+        trace_span!(
+            "create_placeholders",
+            listings = self.listings.len(),
+            output = %output_directory_path.display()
+        );
+        let mut stats = ArchiveStats::default();
+        let mut warnings = Mutex::new(Vec::new());
+
+        fs::create_dir_all(output_directory_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to create output directory: {}", e),
+            )
+        })?;
+        let mut extraction_paths = ExtractionPaths::new(output_directory_path, options)?;
+        // placeholders have no content to count
+        let progress = ProgressCounter::new(options.on_progress.as_ref(), Some(0));
+        for listing in &self.listings {
+            let Some(transformed_path) =
+                extraction_paths.resolve(listing, options, warnings.get_mut().unwrap())?
+            else {
+                continue;
+            };
+            let listing_path = output_directory_path.join(native_path(&transformed_path));
+            stats.add_listing(listing.permissions, 0);
+
+            if listing.permissions & 0o040000 == 0o040000 {
+                fs::create_dir_all(&listing_path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Failed to create bare directory: {}", e))
+                })?;
+            } else {
+                if !options.may_write(&listing_path, &warnings)? {
+                    continue;
+                }
+                fs::create_dir_all(listing_path.parent().unwrap()).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Failed to create ancestor directory: {}", e),
+                    )
+                })?;
+                File::create(&listing_path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Failed to create file {}: {}", listing_path.display(), e),
+                    )
+                })?;
+            }
+            options.set_permissions(&listing_path, listing.permissions, &warnings, |mode| {
+                fs::set_permissions(&listing_path, mode)
+            })?;
+            progress.report(&listing.path, listing.permissions, 0);
+        }
+
+        stats.warnings = warnings.into_inner().unwrap();
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Returns the listings whose content is stored in the bundle at `index`
+    pub fn listings_in_bundle(&self, index: usize) -> impl Iterator<Item = &ExtractedListing> {
+        self.listings
+            .iter()
+            .filter(move |listing| listing.bundle_idx == index && !listing.is_metadata_only())
+    }
+
+    /// Returns the listings whose path matches the glob `pattern` (see `glob_match`), each with
+    /// the index of the bundle holding its content, or `None` if it has no content
+    pub fn find_matching(&self, pattern: &str) -> Vec<(&ExtractedListing, Option<usize>)> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        self.listings
+            .iter()
+            .filter(|listing| glob_match_chars(&pattern, &listing.path.chars().collect::<Vec<_>>()))
+            .map(|listing| {
+                let bundle = (!listing.is_metadata_only()).then_some(listing.bundle_idx);
+                (listing, bundle)
+            })
+            .collect()
+    }
+}
+
+/// The listings of an archive ordered by path along with its bundle table, which is kept apart
+/// from the archive, e.g. by a package manager, to look up single files and fetch only the bundles
+/// holding them without reading the listing block of the archive again
+///
+/// The index records the checksum from the header of the archive it was made from, which tells
+/// whether it still describes an archive by reading just the archive header.
+#[derive(Debug, Clone)]
+pub struct ArchiveIndex {
+    pub archive_checksum: u64,
+    /// the hash algorithm the checksums of the indexed archive are computed with
+    pub hash: HashAlgorithm,
+    /// the listings, ordered by path
+    pub listings: Vec<ExtractedListing>,
+    /// the bundles, with the uncompressed sizes declared by the listings
+    pub bundles: Vec<BundleInfo>,
+}
+
+impl ArchiveIndex {
+    /// Encodes the index as described in `format`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut listing_block = Vec::new();
+        for listing in &self.listings {
+            format::ListingEntry {
+                total_length: (format::ListingEntry::SIZE + listing.path.len()) as u64,
+                bundle_index: listing.bundle_idx as u64,
+                bundle_offset: listing.bundle_offset as u64,
+                size: listing.filesize,
+                permissions: listing.permissions,
+                checksum: listing.content_checksum,
+            }
+            .encode(listing.path.as_bytes(), &mut listing_block);
+        }
+
+        let mut header = format::IndexHeader {
+            magic: format::INDEX_MAGIC_NUMBER,
+            checksum: 0,
+            archive_checksum: self.archive_checksum,
+            listing_block_length: listing_block.len() as u64
+                | (self.hash.id() as u64) << format::Header::HASH_ALGORITHM_SHIFT,
+            listing_count: self.listings.len() as u64,
+            bundle_count: self.bundles.len() as u64,
+        };
+        let mut index = header.encode().to_vec();
+        index.append(&mut listing_block);
+        for bundle in &self.bundles {
+            index.extend_from_slice(
+                &format::BundleEntry {
+                    offset: bundle.offset,
+                    compressed_size: bundle.compressed_size,
+                    checksum: bundle.checksum,
+                }
+                .encode(),
+            );
+        }
+        header.checksum = xxh3(&index[format::IndexHeader::CHECKSUMMED_FROM..]);
+        index[..format::IndexHeader::SIZE].copy_from_slice(&header.encode());
+        index
+    }
+
+    /// Decodes and verifies an index written by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<ArchiveIndex, io::Error> {
+        ArchiveIndex::decode_with_limits(bytes, &Limits::default())
+    }
+
+    /// Decodes an index like `decode`, failing if it exceeds `limits`
+    pub fn decode_with_limits(bytes: &[u8], limits: &Limits) -> Result<ArchiveIndex, io::Error> {
+        let header = format::IndexHeader::decode(bytes)?;
+        if header.magic != format::INDEX_MAGIC_NUMBER {
+            return Err(ArchiveError::Corrupt(
+                "invalid index: does not contain magic number".to_string(),
+            )
+            .into());
+        }
+        if xxh3(&bytes[format::IndexHeader::CHECKSUMMED_FROM..]) != header.checksum {
+            return Err(ArchiveError::Verification(
+                "invalid index: could not verify index integrity".to_string(),
+            )
+            .into());
+        }
+        let hash = HashAlgorithm::from_id(header.hash_algorithm_id())?;
+        limits.check_listing_count(header.listing_count)?;
+        limits.check_bundle_count(header.bundle_count)?;
+
+        let tables = &bytes[format::IndexHeader::SIZE..];
+        let listing_block_length =
+            addressable(header.stored_listing_block_length(), "listing block length")?;
+        if tables.len() < listing_block_length {
+            return Err(ArchiveError::Corrupt(
+                "invalid index: listing block is truncated".to_string(),
+            )
+            .into());
+        }
+        let (listing_block, bundle_table) = tables.split_at(listing_block_length);
+        let listings = parse_listings(listing_block, header.listing_count, limits)?;
+        if listings.windows(2).any(|pair| pair[0].path > pair[1].path) {
+            return Err(ArchiveError::Corrupt(
+                "invalid index: listings are not ordered by path".to_string(),
+            )
+            .into());
+        }
+        // the index doesn't know where the bundles of the archive end
+        let bundles = parse_bundle_table(
+            bundle_table,
+            header.bundle_count,
+            0..u64::MAX,
+            &listings,
+            limits,
+        )?;
+        Ok(ArchiveIndex {
+            archive_checksum: header.archive_checksum,
+            hash,
+            listings,
+            bundles,
+        })
+    }
+
+    /// Returns the listing with the given path, found by binary search
+    pub fn find(&self, path: &str) -> Option<&ExtractedListing> {
+        let i = self
+            .listings
+            .partition_point(|listing| &*listing.path < path);
+        self.listings
+            .get(i)
+            .filter(|listing| &*listing.path == path)
+    }
+
+    /// Returns the byte range of the compressed bundle at `index` within the archive
+    pub fn bundle_range(&self, index: usize) -> Option<Range<u64>> {
+        let bundle = self.bundles.get(index)?;
+        Some(bundle.offset..bundle.offset + bundle.compressed_size)
+    }
+
+    /// Returns the verified content of `listing` from its bundle as fetched from the archive,
+    /// the bytes in `bundle_range(listing.bundle_idx)`
+    pub fn read_content(
+        &self,
+        listing: &ExtractedListing,
+        compressed_bundle: &[u8],
+    ) -> Result<Vec<u8>, io::Error> {
+        if listing.is_metadata_only() {
+            return Ok(Vec::new());
+        }
+        let bundle_info = self.bundles.get(listing.bundle_idx).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("index has no bundle {}", listing.bundle_idx),
+            )
+        })?;
+        let mut bundle = decompress_bundle(
+            listing.bundle_idx,
+            compressed_bundle,
+            bundle_info,
+            VerifyLevel::default(),
+            self.hash,
+        )?;
+        bundle.truncate(listing.bundle_offset + listing.filesize as usize);
+        let content = bundle.split_off(listing.bundle_offset);
+        if self.hash.hash(&content) != listing.content_checksum {
+            return Err(ArchiveError::Verification(format!(
+                "invalid listing: could not verify file integrity for file {}",
+                listing.path
+            ))
+            .into());
+        }
+        Ok(content)
+    }
+}
+
+/// Returns whether `path` matches the glob `pattern` as a whole, where `?` matches any single
+/// character and `*` any run of characters except `/`, `**` matches any run of characters
+/// including `/`, and `**/` any number of leading directories, e.g. `**/*.so` matches both
+/// `libz.so` and `usr/lib/libz.so`
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    glob_match_chars(
+        &pattern.chars().collect::<Vec<_>>(),
+        &path.chars().collect::<Vec<_>>(),
+    )
+}
 
-            current_offset += 8 * 3;
+/// Returns whether `path` or one of its parent directories matches the exclude `pattern` (see
+/// `glob_match`); patterns without a `/` are matched against every path component, others
+/// against the whole path
+pub fn is_excluded(path: &str, pattern: &str) -> bool {
+    if pattern.contains('/') {
+        path.match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain([path])
+            .any(|prefix| glob_match(pattern, prefix))
+    } else {
+        path.split('/')
+            .any(|component| glob_match(pattern, component))
+    }
+}
 
-            let mut decompression_buffer = Vec::with_capacity(compressed_bundle_size as usize);
-            decompression_buffer.write_all(
-                &input_buffer[compressed_bundle_offset as usize
-                    ..compressed_bundle_offset as usize + compressed_bundle_size as usize],
-            )?;
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match (pattern, text.first()) {
+        ([], _) => text.is_empty(),
+        (['*', '*', '/', rest @ ..], _) => {
+            glob_match_chars(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && glob_match_chars(rest, &text[i + 1..]))
+        }
+        (['*', '*', rest @ ..], _) => (0..=text.len()).any(|i| glob_match_chars(rest, &text[i..])),
+        (['*', rest @ ..], _) => {
+            glob_match_chars(rest, text)
+                || (text.first().is_some_and(|&c| c != '/')
+                    && glob_match_chars(pattern, &text[1..]))
+        }
+        (['?', rest @ ..], Some(&c)) if c != '/' => glob_match_chars(rest, &text[1..]),
+        ([p, rest @ ..], Some(c)) if p == c => glob_match_chars(rest, &text[1..]),
+        _ => false,
+    }
+}
 
-            let mut uncompressed_bundle_content = Vec::new();
-            zstd::copy_decode(
-                decompression_buffer.as_slice(),
-                &mut uncompressed_bundle_content,
-            )?;
+/// Uncompressed and compressed sizes of part of an archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionTotals {
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
 
-            // verify bundle checksum
-            if xxh3(&uncompressed_bundle_content) != uncompressed_bundle_checksum {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "invalid archive: could not verify bundle integrity for bundle {}",
-                        i
-                    ),
-                ));
-            }
+impl CompressionTotals {
+    fn add(&mut self, other: CompressionTotals) {
+        self.uncompressed_bytes += other.uncompressed_bytes;
+        self.compressed_bytes += other.compressed_bytes;
+    }
 
-            bundles_uncompressed.push(uncompressed_bundle_content);
+    /// Returns the compression ratio, i.e. how many times smaller the compressed bytes are
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
         }
+        self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+    }
+}
 
-        // create listings vector
-        let mut listings_vec: Vec<ExtractedListing> = Vec::with_capacity(listing_count as usize);
+/// How well the content of an archive compressed, to help choose excludes and compression settings
+///
+/// Listings are compressed together in their bundles, so the compressed size of a listing is
+/// estimated as its share of the uncompressed size of its bundle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionReport {
+    /// every bundle, in order
+    pub bundles: Vec<CompressionTotals>,
+    /// every file extension, with an empty string for listings without one, taking the most
+    /// compressed space first
+    pub extensions: Vec<(String, CompressionTotals)>,
+    /// the listings taking the most compressed space, largest first
+    pub largest: Vec<(Box<str>, CompressionTotals)>,
+}
 
-        current_offset = 40;
-        for _ in 0..listing_count {
-            let listing_total_length = u64::from_le_bytes(
-                input_buffer[current_offset..current_offset + 8]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_bundle_index = u64::from_le_bytes(
-                input_buffer[current_offset + 8..current_offset + 16]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_offset_in_uncompressed_bundle = u64::from_le_bytes(
-                input_buffer[current_offset + 16..current_offset + 24]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_file_size = u64::from_le_bytes(
-                input_buffer[current_offset + 24..current_offset + 32]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_permissions = u32::from_le_bytes(
-                input_buffer[current_offset + 32..current_offset + 36]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_checksum = u64::from_le_bytes(
-                input_buffer[current_offset + 36..current_offset + 44]
-                    .try_into()
-                    .unwrap(),
-            );
-            let listing_path = from_utf8(
-                &input_buffer
-                    [current_offset + 44..current_offset + (listing_total_length as usize)],
-            )
-            .unwrap();
+/// What `verify_archive` found wrong with an archive
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// whether the checksum of the whole archive matches
+    pub archive_checksum_matches: bool,
+    /// bundles which could not be decompressed or whose checksum doesn't match, in order
+    pub corrupt_bundles: Vec<usize>,
+    /// listings stored in the corrupt bundles whose content is damaged, in the order of the
+    /// listing block
+    pub corrupt_listings: Vec<Box<str>>,
+}
 
-            current_offset += (listing_total_length) as usize;
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.archive_checksum_matches && self.corrupt_bundles.is_empty()
+    }
+}
 
-            if listing_permissions & 0o040000 == 0o040000 {
-                // bare directories
-                listings_vec.push(ExtractedListing {
-                    path: listing_path.into(),
-                    permissions: listing_permissions,
-                    content_checksum: 0,
+/// Passes reads through while hashing everything after the archive checksum, so that the archive
+/// checksum can be verified without holding the whole archive
+struct ChecksummingReader<'a, R: Read> {
+    reader: &'a mut R,
+    position: u64,
+    /// the header as read so far, until it is complete and names the hash algorithm
+    header: Vec<u8>,
+    /// hashers of the algorithm named by the header, or of both algorithms pre-release archives
+    /// are checksummed with
+    hashers: Vec<Box<dyn Hasher>>,
+    /// the last bytes read from an archive with a footer, which are hashed once they are known
+    /// not to be the footer
+    held_back: Option<Vec<u8>>,
+}
 
-                    bundle_idx: listing_bundle_index as usize,
-                    bundle_offset: 0,
-                    filesize: 0,
-                });
-                continue;
-            }
+impl<'a, R: Read> ChecksummingReader<'a, R> {
+    fn new(reader: &'a mut R) -> ChecksummingReader<'a, R> {
+        ChecksummingReader {
+            reader,
+            position: 0,
+            header: Vec::with_capacity(format::Header::SIZE),
+            hashers: Vec::new(),
+            held_back: None,
+        }
+    }
 
-            listings_vec.push(ExtractedListing {
-                path: listing_path.into(),
-                permissions: listing_permissions,
-                content_checksum: listing_checksum,
-                filesize: listing_file_size,
-                bundle_idx: listing_bundle_index as usize,
-                bundle_offset: listing_offset_in_uncompressed_bundle as usize,
-            })
+    /// Starts hashing with the algorithms the complete header calls for; an archive without a
+    /// magic number is left unhashed, to be rejected by the reader of the header, as is one with a
+    /// split checksum, which the reader of the tables verifies
+    fn start_hashing(&mut self) -> Result<(), io::Error> {
+        let header = format::Header::decode(&self.header)?;
+        let algorithms = if header.has_split_checksum() {
+            Vec::new()
+        } else if header.magic == format::MAGIC_NUMBER {
+            vec![HashAlgorithm::of(&header)?]
+        } else if header.magic == format::LEGACY_MAGIC_NUMBER {
+            vec![HashAlgorithm::Xxh3, HashAlgorithm::Crc32]
+        } else {
+            Vec::new()
+        };
+        self.hashers = algorithms.into_iter().map(HashAlgorithm::hasher).collect();
+        for hasher in &mut self.hashers {
+            hasher.update(&self.header[format::Header::CHECKSUMMED_FROM..]);
         }
+        if header.has_footer() {
+            self.held_back = Some(Vec::with_capacity(format::Header::SIZE));
+        }
+        Ok(())
+    }
 
-        Ok(ExtractedArchive {
-            listings: listings_vec,
-            bundles: bundles_uncompressed,
-        })
+    /// Reads the compressed bundle at `index`, which has to start at or after the current position
+    fn read_bundle(
+        &mut self,
+        index: usize,
+        bundle_info: &BundleInfo,
+    ) -> Result<Vec<u8>, io::Error> {
+        if bundle_info.offset < self.position {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: bundle {} overlaps the data before it",
+                index
+            ))
+            .into());
+        }
+        let gap = bundle_info.offset - self.position;
+        io::copy(&mut self.by_ref().take(gap), &mut io::sink())?;
+        let mut compressed = Vec::new();
+        self.by_ref()
+            .take(bundle_info.compressed_size)
+            .read_to_end(&mut compressed)?;
+        if (compressed.len() as u64) < bundle_info.compressed_size {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: bundle {} is truncated",
+                index
+            ))
+            .into());
+        }
+        Ok(compressed)
     }
 
-    pub fn create_all_files<P: AsRef<Path>>(
-        &self,
-        output_directory_path: P,
-    ) -> Result<usize, io::Error> {
-        let mut sum: usize = 0;
-        for listing in &self.listings {
-            sum += self.create_file(listing, &output_directory_path)?;
+    /// Reads the rest of the archive, returning whether the archive checksum in `header` matches
+    fn finish(&mut self, header: &format::Header) -> Result<bool, io::Error> {
+        // a split checksum was verified along with the tables it covers
+        if header.has_split_checksum() {
+            return Ok(true);
+        }
+        // anything after the last bundle is covered by the archive checksum too
+        io::copy(self, &mut io::sink())?;
+        // as is the footer, from its listing_block_length on
+        if let Some(footer) = &self.held_back {
+            for hasher in &mut self.hashers {
+                hasher.update(
+                    footer
+                        .get(format::Header::CHECKSUMMED_FROM..)
+                        .unwrap_or_default(),
+                );
+            }
         }
-        Ok(sum)
+        let checksum = header.checksum;
+        Ok(self
+            .hashers
+            .iter()
+            .any(|hasher| hasher.digest() == checksum))
     }
+}
 
-    pub fn create_file<P: AsRef<Path>>(
-        &self,
-        listing: &ExtractedListing,
-        output_directory_path: P,
-    ) -> Result<usize, io::Error> {
-        let output_directory_path = Path::new(output_directory_path.as_ref());
-        let mut listing_path = output_directory_path.to_path_buf();
-        listing_path.push(listing.path.to_string());
+impl<R: Read> Read for ChecksummingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        let mut unhashed = &buf[..read];
+        if self.header.len() < format::Header::SIZE {
+            let length = (format::Header::SIZE - self.header.len()).min(read);
+            self.header.extend_from_slice(&unhashed[..length]);
+            unhashed = &unhashed[length..];
+            if self.header.len() == format::Header::SIZE {
+                self.start_hashing()?;
+            }
+        }
+        match &mut self.held_back {
+            Some(held_back) => {
+                held_back.extend_from_slice(unhashed);
+                let hashed = held_back.len().saturating_sub(format::Header::SIZE);
+                for hasher in &mut self.hashers {
+                    hasher.update(&held_back[..hashed]);
+                }
+                held_back.drain(..hashed);
+            }
+            None => {
+                for hasher in &mut self.hashers {
+                    hasher.update(unhashed);
+                }
+            }
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
 
-        if listing.permissions & 0o040000 == 0o040000 {
-            // bare directories
-            fs::create_dir_all(listing_path).map_err(|e| {
-                io::Error::new(e.kind(), format!("Failed to create bare directory: {}", e))
-            })?;
-            return Ok(0);
+/// Verifies every checksum of the archive read from `reader` without extracting it, reading the
+/// archive once from start to end with only a few bundles in memory at a time
+///
+/// Bundles are decompressed and verified by up to `threads` threads (one per available core by
+/// default) while the next ones are read, so verification is bound by reading rather than by
+/// hashing. The checksums of single files are only checked in corrupt bundles, to tell which files
+/// are damaged. Errors are only returned if the archive can't be read or its tables are corrupt.
+///
+/// An archive with a footer is held in memory as a whole, since its tables follow its bundles.
+pub fn verify_archive<R: Read>(
+    reader: &mut R,
+    threads: Option<usize>,
+) -> Result<VerifyReport, io::Error> {
+    trace_span!("verify_archive");
+    let mut reader = ChecksummingReader::new(reader);
+    let mut header = [0u8; format::Header::SIZE];
+    read_exact_or_corrupt(
+        &mut reader,
+        &mut header,
+        "invalid archive: archive too small",
+    )?;
+    let mut archive = None;
+    let layout = match format::Header::decode(&header)?.has_footer() {
+        true => {
+            let mut bytes = header.to_vec();
+            reader.read_to_end(&mut bytes)?;
+            let layout =
+                ArchiveLayout::read_seeking(&mut io::Cursor::new(&bytes), &Limits::default())?;
+            archive = Some(bytes);
+            layout
         }
+        false => ArchiveLayout::read_bounded(
+            &mut (&header[..]).chain(&mut reader),
+            u64::MAX,
+            &Limits::default(),
+        )?,
+    };
 
-        fs::create_dir_all(listing_path.parent().unwrap()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("Failed to create ancestor directory: {}", e),
-            )
-        })?;
+    let workers = worker_count(threads, usize::MAX);
+    let corrupt = Mutex::new((Vec::new(), Vec::new()));
+    thread::scope(|scope| -> Result<(), io::Error> {
+        // as when archiving, the workers alone hold the receiving end of the queue
+        let (bundle_sender, bundle_receiver) = mpsc::sync_channel::<(usize, Vec<u8>)>(workers);
+        let bundle_receiver = Arc::new(Mutex::new(bundle_receiver));
+        for _ in 0..workers {
+            let bundle_receiver = Arc::clone(&bundle_receiver);
+            let (layout, corrupt) = (&layout, &corrupt);
+            scope.spawn(move || loop {
+                let next_bundle = bundle_receiver.lock().unwrap().recv();
+                let Ok((index, compressed)) = next_bundle else {
+                    return;
+                };
+                let bundle_info = &layout.bundles[index];
+                let hash = layout.hash;
+                if decompress_bundle(index, &compressed, bundle_info, VerifyLevel::Bundles, hash)
+                    .is_ok()
+                {
+                    continue;
+                }
 
-        File::create(listing_path.as_path()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("Failed to create file {}: {}", listing_path.display(), e),
-            )
-        })?;
+                // a bundle that can't be decompressed at all damages every listing it holds
+                let content =
+                    decompress_bundle(index, &compressed, bundle_info, VerifyLevel::None, hash)
+                        .unwrap_or_default();
+                let damaged: Vec<usize> = layout
+                    .listings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, listing)| {
+                        listing.bundle_idx == index && !listing.is_metadata_only()
+                    })
+                    .filter(|(_, listing)| {
+                        let start = listing.bundle_offset;
+                        content
+                            .get(start..start + listing.filesize as usize)
+                            .is_none_or(|bytes| hash.hash(bytes) != listing.content_checksum)
+                    })
+                    .map(|(i, _)| i)
+                    .collect();
+                let mut corrupt = corrupt.lock().unwrap();
+                corrupt.0.push(index);
+                corrupt.1.extend(damaged);
+            });
+        }
+        drop(bundle_receiver);
 
-        let mut listing_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&listing_path)
-            .map_err(|e| {
-                io::Error::new(
-                    e.kind(),
-                    format!(
-                        "Failed to create/open file {} for writing: {}",
-                        listing_path.display(),
-                        e
-                    ),
-                )
+        for (index, bundle_info) in layout.bundles.iter().enumerate() {
+            let compressed = match &archive {
+                // the bundle table was checked to point into the archive
+                Some(archive) => archive[bundle_info.offset as usize
+                    ..(bundle_info.offset + bundle_info.compressed_size) as usize]
+                    .to_vec(),
+                None => reader.read_bundle(index, bundle_info)?,
+            };
+            bundle_sender.send((index, compressed)).map_err(|_| {
+                io::Error::other("bundle verification workers stopped unexpectedly")
             })?;
+        }
+        Ok(())
+    })?;
+    let archive_checksum_matches = reader.finish(&layout.header)?;
+    let (mut corrupt_bundles, mut corrupt_listings) = corrupt.into_inner().unwrap();
+    corrupt_bundles.sort_unstable();
+    corrupt_listings.sort_unstable();
+    Ok(VerifyReport {
+        archive_checksum_matches,
+        corrupt_bundles,
+        corrupt_listings: corrupt_listings
+            .into_iter()
+            .map(|i| layout.listings[i].path.clone())
+            .collect(),
+    })
+}
+
+/// Extracts the archive read from `reader` to `output_directory_path` in a single pass from start
+/// to end, so that only one bundle is held in memory at a time, e.g. in memory-constrained
+/// containers
+///
+/// The listings are written in runs sharing a bundle, which is read and decompressed when its run
+/// is reached; archives whose listings don't refer to their bundles in order, which this crate
+/// never writes, are not supported. As for a stream, every bundle and, as configured by
+/// `options.verify`, every file is verified before it is written, but the archive checksum can
+/// only be verified once the whole archive has been read, so an archive failing it leaves files
+/// behind. Archives with a footer can't be extracted this way, as their tables follow their
+/// bundles.
+pub fn extract_sequentially<R: Read, P: AsRef<Path>>(
+    reader: &mut R,
+    output_directory_path: P,
+    limits: &Limits,
+    options: &ExtractOptions,
+) -> Result<ArchiveStats, io::Error> {
+    options.validate()?;
+    let start = Instant::now();
+    let output_directory_path = output_directory_path.as_ref();
+    trace_span!("extract_sequentially", output = %output_directory_path.display());
+    let mut reader = ChecksummingReader::new(reader);
+    let layout = ArchiveLayout::read_bounded(&mut reader, u64::MAX, limits)?;
+    check_space(&layout.listings, output_directory_path, options)?;
+    fs::create_dir_all(output_directory_path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to create output directory: {}", e),
+        )
+    })?;
+
+    let mut stats = ArchiveStats {
+        compressed_bytes: layout.bundles.iter().map(|b| b.compressed_size).sum(),
+        bundle_count: layout.bundles.len(),
+        ..Default::default()
+    };
+    let mut warnings = Mutex::new(Vec::new());
+    let mut extraction_paths = ExtractionPaths::new(output_directory_path, options)?;
+    let progress = ProgressCounter::new(
+        options.on_progress.as_ref(),
+        Some(extracted_size(&layout.listings, options)),
+    );
+    let mut listings = layout.listings.into_iter().peekable();
+    let mut next_bundle = 0;
+    while listings.peek().is_some() {
+        // a run is every listing up to the first one with content in another bundle
+        let mut run = Vec::new();
+        let mut run_bundle: Option<usize> = None;
+        while let Some(mut listing) = listings.next_if(|listing| {
+            listing.is_metadata_only() || run_bundle.is_none_or(|b| b == listing.bundle_idx)
+        }) {
+            if !listing.is_metadata_only() {
+                run_bundle = Some(listing.bundle_idx);
+                listing.bundle_idx = 0;
+            }
+            run.push(listing);
+        }
+
+        let mut bundles = Vec::new();
+        let mut bundle_infos = Vec::new();
+        if let Some(index) = run_bundle {
+            if index < next_bundle {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the listings of the archive refer to its bundles out of order, so it can't be extracted sequentially",
+                ));
+            }
+            // bundles no listing refers to are skipped over
+            let mut compressed = Vec::new();
+            while next_bundle <= index {
+                compressed = reader.read_bundle(next_bundle, &layout.bundles[next_bundle])?;
+                next_bundle += 1;
+            }
+            let bundle_info = layout.bundles[index];
+            Limits::check(
+                options.max_memory,
+                bundle_info.uncompressed_size,
+                "memory for decompressed bundles",
+            )?;
+            bundles.push(decompress_bundle(
+                index,
+                &compressed,
+                &bundle_info,
+                options.verify,
+                layout.hash,
+            )?);
+            bundle_infos.push(bundle_info);
+        }
+
+        let run_archive = ExtractedArchive {
+            path_index: PathIndex::default(),
+            listings: run,
+            bundles,
+            bundle_infos,
+            revision: FormatRevision::Current,
+            hash: layout.hash,
+        };
+        run_archive.write_listings(
+            output_directory_path,
+            options,
+            &mut extraction_paths,
+            &mut stats,
+            &progress,
+            &mut warnings,
+        )?;
+    }
+
+    if !reader.finish(&layout.header)? && options.verify >= VerifyLevel::Bundles {
+        return Err(ArchiveError::Verification(
+            "invalid archive: could not verify archive integrity".to_string(),
+        )
+        .into());
+    }
+    stats.warnings = warnings.into_inner().unwrap();
+    stats.duration = start.elapsed();
+    Ok(stats)
+}
+
+/// Calls `f` with every file of the archive read from `reader` and its content, in a single pass
+/// from start to end holding only one bundle in memory at a time, e.g. to search an archive
+/// without extracting it
+///
+/// Files are visited bundle by bundle; listings without content are skipped. Every bundle is
+/// verified before its files are visited, and the archive checksum once the whole archive has
+/// been read, so an archive failing it returns an error after every file was visited. Archives with
+/// a footer can't be read this way, as their tables follow their bundles.
+pub fn for_each_file<R: Read, F: FnMut(&ExtractedListing, &[u8]) -> io::Result<()>>(
+    reader: &mut R,
+    limits: &Limits,
+    mut f: F,
+) -> Result<(), io::Error> {
+    trace_span!("for_each_file");
+    let mut reader = ChecksummingReader::new(reader);
+    let layout = ArchiveLayout::read_bounded(&mut reader, u64::MAX, limits)?;
 
-        let mut listing_content = Vec::with_capacity(listing.filesize as usize);
-        listing_content.write_all(
-            &self.bundles[listing.bundle_idx]
-                [listing.bundle_offset..listing.bundle_offset + listing.filesize as usize],
+    let mut by_bundle = vec![Vec::new(); layout.bundles.len()];
+    for listing in layout.listings.iter().filter(|l| !l.is_metadata_only()) {
+        by_bundle[listing.bundle_idx].push(listing);
+    }
+    for (index, bundle_info) in layout.bundles.iter().enumerate() {
+        let compressed = reader.read_bundle(index, bundle_info)?;
+        if by_bundle[index].is_empty() {
+            continue;
+        }
+        let bundle = decompress_bundle(
+            index,
+            &compressed,
+            bundle_info,
+            VerifyLevel::Bundles,
+            layout.hash,
         )?;
+        for listing in &by_bundle[index] {
+            let start = listing.bundle_offset;
+            f(listing, &bundle[start..start + listing.filesize as usize])?;
+        }
+    }
+
+    if !reader.finish(&layout.header)? {
+        return Err(ArchiveError::Verification(
+            "invalid archive: could not verify archive integrity".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// How a directory tree differs from an archive, as found by `compare`; every list is sorted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Comparison {
+    /// paths whose type, permissions, size and content are the same in the tree
+    pub matched: Vec<Box<str>>,
+    /// paths whose type, permissions, size or content differ in the tree
+    pub modified: Vec<Box<str>>,
+    /// paths of the archive which are not in the tree
+    pub missing: Vec<Box<str>>,
+    /// paths of the tree which are not in the archive
+    pub extra: Vec<Box<str>>,
+}
+
+impl Comparison {
+    /// Returns whether the tree matches the archive exactly
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Compares the listings of an archive against the tree under `directory_path` as it would be
+/// archived, without extracting anything, e.g. to verify a deployment
+///
+/// Files are only read if their type, permissions and size match, to compare their checksums.
+/// Permissions are compared as stored, so an archive written with normalized permissions only
+/// matches a tree extracted with the same normalization.
+pub fn compare<P: AsRef<Path>>(
+    layout: &ArchiveLayout,
+    directory_path: P,
+) -> Result<Comparison, io::Error> {
+    trace_span!("compare", listings = layout.listings.len());
+    let live = create_archive_from_directory(directory_path)?;
+    let mut live_listings: HashMap<&str, &ArchivableListing> = live
+        .listings
+        .iter()
+        .map(|listing| (&*listing.relative_path, listing))
+        .collect();
+
+    let mut comparison = Comparison::default();
+    for listing in &layout.listings {
+        let Some(live_listing) = live_listings.remove(&*listing.path) else {
+            comparison.missing.push(listing.path.clone());
+            continue;
+        };
+        // regular files are sized without reading them, unlike links which are archived with the
+        // content of their target
+        // file flags aren't read from the tree, so only modes are compared
+        let matches = live_listing.permissions
+            == listing.permissions & format::ListingEntry::MODE_BITS
+            && (!is_regular_file(listing.permissions)
+                || live_listing.file_size == listing.filesize)
+            && live_listing.read_checksum(layout.hash)?
+                == (listing.filesize, listing.content_checksum);
+        match matches {
+            true => comparison.matched.push(listing.path.clone()),
+            false => comparison.modified.push(listing.path.clone()),
+        }
+    }
+    comparison.extra = live_listings.into_keys().map(Box::from).collect();
+
+    comparison.matched.sort();
+    comparison.modified.sort();
+    comparison.missing.sort();
+    comparison.extra.sort();
+    Ok(comparison)
+}
+
+/// A frame of a bundle in the zstd seekable format, as listed by its seek table
+#[derive(Debug, Clone)]
+struct SeekableFrame {
+    /// the compressed frame, relative to the start of the bundle
+    compressed: Range<u64>,
+    /// the part of the uncompressed bundle the frame holds
+    uncompressed: Range<u64>,
+}
+
+/// Reads an archive from a seekable source, reading only the header, the listing block and the
+/// bundle table up front and then seeking to and decompressing just the bundles that are needed
+///
+/// The archive checksum covers the whole archive and is therefore not verified; bundles and files
+/// are still verified against their own checksums as they are read.
+#[derive(Debug)]
+pub struct SeekableArchiveReader<R: Read + Seek> {
+    reader: R,
+    hash: HashAlgorithm,
+    listings: Vec<ExtractedListing>,
+    bundle_infos: Vec<BundleInfo>,
+    path_index: PathIndex,
+}
+
+impl<R: Read + Seek> SeekableArchiveReader<R> {
+    pub fn new(reader: R) -> Result<SeekableArchiveReader<R>, io::Error> {
+        SeekableArchiveReader::with_limits(reader, Limits::default())
+    }
+
+    /// Opens an archive, failing if its header or listings exceed `limits`
+    pub fn with_limits(
+        mut reader: R,
+        limits: Limits,
+    ) -> Result<SeekableArchiveReader<R>, io::Error> {
+        let layout = ArchiveLayout::read_seeking(&mut reader, &limits)?;
 
-        // verify listing content checksum
-        let computed_checksum = xxh3(&listing_content);
-        if computed_checksum != listing.content_checksum {
+        // telling pre-release revisions apart takes the archive checksum, so only current
+        // archives are read
+        if layout.header.magic == format::LEGACY_MAGIC_NUMBER {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "invalid listing: could not verify file integrity for file {}, listing has {} but checksum was computed as {} (bundle {} with offset {}; size: {})",
-                    listing.path, listing.content_checksum, computed_checksum, listing.bundle_idx, listing.bundle_offset, listing.filesize,
-                ),
+                io::ErrorKind::Unsupported,
+                "pre-release archives cannot be read by seeking; upgrade the archive first",
             ));
         }
+        Ok(SeekableArchiveReader {
+            reader,
+            hash: layout.hash,
+            path_index: PathIndex::new(&layout.listings),
+            listings: layout.listings,
+            bundle_infos: layout.bundles,
+        })
+    }
+
+    pub fn listings(&self) -> &[ExtractedListing] {
+        &self.listings
+    }
+
+    /// Returns the listing with the given path, looked up in an index built when the archive was
+    /// opened
+    pub fn find(&self, path: &str) -> Option<&ExtractedListing> {
+        self.path_index.find(&self.listings, path)
+    }
+
+    /// Returns the layout of the bundles, with the uncompressed sizes declared by the listings
+    pub fn bundles(&self) -> &[BundleInfo] {
+        &self.bundle_infos
+    }
+
+    /// Returns the hash algorithm the checksums of the archive are computed with
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
 
-        listing_file.write_all(&listing_content).map_err(|e| {
+    /// Seeks to the bundle at `index` and returns it decompressed and verified
+    pub fn read_bundle(&mut self, index: usize) -> Result<Vec<u8>, io::Error> {
+        let bundle_info = *self.bundle_infos.get(index).ok_or_else(|| {
             io::Error::new(
-                e.kind(),
-                format!(
-                    "Failed to write content to file {}: {}",
-                    listing_path.display(),
-                    e
-                ),
+                io::ErrorKind::InvalidInput,
+                format!("archive has no bundle {}", index),
             )
         })?;
 
-        listing_file
-            .set_permissions(Permissions::from_mode(listing.permissions))
-            .map_err(|e| {
-                io::Error::new(
-                    e.kind(),
-                    format!(
-                        "Failed to set permissions for file {}: {}",
-                        listing_path.display(),
-                        e
-                    ),
-                )
-            })?;
-        Ok(listing.filesize as usize)
+        self.reader.seek(SeekFrom::Start(bundle_info.offset))?;
+        let mut compressed =
+            vec![0u8; addressable(bundle_info.compressed_size, "bundle compressed size")?];
+        self.reader.read_exact(&mut compressed)?;
+
+        decompress_bundle(
+            index,
+            &compressed,
+            &bundle_info,
+            VerifyLevel::default(),
+            self.hash,
+        )
+    }
+
+    /// Returns the frames listed by the seek table of the bundle at `index`, or `None` if the
+    /// bundle isn't in the zstd seekable format
+    fn seek_table(&mut self, index: usize) -> Result<Option<Vec<SeekableFrame>>, io::Error> {
+        let bundle_info = self.bundle_infos[index];
+        // the header of the skippable frame and the footer
+        if bundle_info.compressed_size < 17 {
+            return Ok(None);
+        }
+        let bundle_end = bundle_info.offset + bundle_info.compressed_size;
+        let mut footer = [0u8; 9];
+        self.reader.seek(SeekFrom::Start(bundle_end - 9))?;
+        self.reader.read_exact(&mut footer)?;
+        let truncated = "invalid archive: seek table is truncated";
+        let mut footer = format::Reader::new(&footer, truncated);
+        let frame_count = footer.read_u32_le()? as u64;
+        let descriptor = footer.read_u8()?;
+        // the reserved bits of the descriptor are zero
+        if footer.read_u32_le()? != SEEKABLE_MAGIC_NUMBER || descriptor & 0x7c != 0 {
+            return Ok(None);
+        }
+        let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+        let table_size = frame_count * entry_size + 17;
+        if table_size > bundle_info.compressed_size {
+            return Ok(None);
+        }
+        let mut table = vec![0u8; table_size as usize];
+        self.reader.seek(SeekFrom::Start(bundle_end - table_size))?;
+        self.reader.read_exact(&mut table)?;
+        let mut table = format::Reader::new(&table, truncated);
+        if table.read_u32_le()? != SEEK_TABLE_FRAME_MAGIC
+            || table.read_u32_le()? as u64 != table_size - 8
+        {
+            return Ok(None);
+        }
+
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        let (mut compressed, mut uncompressed) = (0u64, 0u64);
+        for _ in 0..frame_count {
+            let compressed_size = table.read_u32_le()? as u64;
+            let uncompressed_size = table.read_u32_le()? as u64;
+            // the checksum of the frame, which the bundle checksum already covers
+            table.read_bytes(entry_size as usize - 8)?;
+            frames.push(SeekableFrame {
+                compressed: compressed..compressed + compressed_size,
+                uncompressed: uncompressed..uncompressed + uncompressed_size,
+            });
+            compressed += compressed_size;
+            uncompressed += uncompressed_size;
+        }
+        // a table which doesn't add up to the bundle is ignored in favour of the whole bundle
+        if compressed != bundle_info.compressed_size - table_size
+            || uncompressed != bundle_info.uncompressed_size
+        {
+            return Ok(None);
+        }
+        Ok(Some(frames))
+    }
+
+    /// Decompresses just the frames of the seekable bundle at `index` which overlap `range` of
+    /// its uncompressed content, returning that range
+    fn read_frames(
+        &mut self,
+        index: usize,
+        frames: &[SeekableFrame],
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let corrupt = |message: String| {
+            ArchiveError::Corrupt(format!(
+                "invalid archive: could not decompress bundle {}: {}",
+                index, message
+            ))
+        };
+        let mut needed = frames.iter().filter(|frame| {
+            frame.uncompressed.start < range.end && frame.uncompressed.end > range.start
+        });
+        let (Some(first), last) = (needed.next(), needed.next_back()) else {
+            return Err(corrupt("no frame holds the listing".to_string()).into());
+        };
+        let last = last.unwrap_or(first);
+
+        let compressed_size = last.compressed.end - first.compressed.start;
+        let mut compressed = vec![0u8; addressable(compressed_size, "frames compressed size")?];
+        self.reader.seek(SeekFrom::Start(
+            self.bundle_infos[index].offset + first.compressed.start,
+        ))?;
+        self.reader.read_exact(&mut compressed)?;
+        let expected = last.uncompressed.end - first.uncompressed.start;
+        let mut uncompressed = Vec::new();
+        zstd::read::Decoder::new(&compressed[..])?
+            .take(expected + 1)
+            .read_to_end(&mut uncompressed)
+            .map_err(|e| corrupt(e.to_string()))?;
+        if uncompressed.len() as u64 != expected {
+            return Err(corrupt("frames do not match the seek table".to_string()).into());
+        }
+        let start = (range.start - first.uncompressed.start) as usize;
+        uncompressed.truncate(start + (range.end - range.start) as usize);
+        Ok(uncompressed.split_off(start))
+    }
+
+    /// Returns the verified content of the listing with the given path, reading only the bundle
+    /// holding it, or only the frames holding it if the bundle is in the zstd seekable format
+    ///
+    /// The checksum of the bundle is only verified if the whole bundle is read; the checksum of
+    /// the file always is.
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        let Some(listing) = self.find(path).cloned() else {
+            return Ok(None);
+        };
+        if listing.is_metadata_only() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let start = listing.bundle_offset as u64;
+        let content = match self.seek_table(listing.bundle_idx)? {
+            Some(frames) => {
+                self.read_frames(listing.bundle_idx, &frames, start..start + listing.filesize)?
+            }
+            None => {
+                let mut bundle = self.read_bundle(listing.bundle_idx)?;
+                bundle.truncate(listing.bundle_offset + listing.filesize as usize);
+                bundle.split_off(listing.bundle_offset)
+            }
+        };
+        if self.hash.hash(&content) != listing.content_checksum {
+            return Err(ArchiveError::Verification(format!(
+                "invalid listing: could not verify file integrity for file {}",
+                listing.path
+            ))
+            .into());
+        }
+        Ok(Some(content))
+    }
+
+    /// Reads the bundles holding the listings for which `predicate` returns true, returning an
+    /// `ExtractedArchive` of just those listings
+    pub fn extract_matching<F: FnMut(&ExtractedListing) -> bool>(
+        &mut self,
+        mut predicate: F,
+    ) -> Result<ExtractedArchive, io::Error> {
+        let listings: Vec<ExtractedListing> = self
+            .listings
+            .iter()
+            .filter(|listing| predicate(listing))
+            .cloned()
+            .collect();
+
+        let mut bundles = vec![Vec::new(); self.bundle_infos.len()];
+        for listing in &listings {
+            if !listing.is_metadata_only() && bundles[listing.bundle_idx].is_empty() {
+                bundles[listing.bundle_idx] = self.read_bundle(listing.bundle_idx)?;
+            }
+        }
+
+        Ok(ExtractedArchive {
+            path_index: PathIndex::new(&listings),
+            listings,
+            bundles,
+            bundle_infos: self.bundle_infos.clone(),
+            revision: FormatRevision::Current,
+            hash: self.hash,
+        })
+    }
+}
+
+/// A read-only archive for repeated reads of single files, e.g. by a filesystem or a server, which
+/// keeps the most recently used decompressed bundles so that reading several files of a bundle
+/// decompresses it only once
+pub struct ArchiveHandle<R: Read + Seek> {
+    reader: SeekableArchiveReader<R>,
+    /// the cached bundles by index, most recently used first
+    cache: VecDeque<(usize, Arc<Vec<u8>>)>,
+    cached_bytes: u64,
+    max_bundles: usize,
+    max_bytes: u64,
+}
+
+impl<R: Read + Seek> ArchiveHandle<R> {
+    /// Keeps up to `DEFAULT_CACHED_BUNDLES` bundles of up to `DEFAULT_CACHE_BYTES` in total
+    pub fn new(reader: SeekableArchiveReader<R>) -> ArchiveHandle<R> {
+        ArchiveHandle::with_cache(reader, DEFAULT_CACHED_BUNDLES, DEFAULT_CACHE_BYTES)
+    }
+
+    /// Keeps up to `max_bundles` bundles of up to `max_bytes` in total; a bundle larger than
+    /// `max_bytes` is never kept
+    pub fn with_cache(
+        reader: SeekableArchiveReader<R>,
+        max_bundles: usize,
+        max_bytes: u64,
+    ) -> ArchiveHandle<R> {
+        ArchiveHandle {
+            reader,
+            cache: VecDeque::new(),
+            cached_bytes: 0,
+            max_bundles,
+            max_bytes,
+        }
+    }
+
+    pub fn listings(&self) -> &[ExtractedListing] {
+        self.reader.listings()
+    }
+
+    /// Returns the listing with the given path, like `SeekableArchiveReader::find`
+    pub fn find(&self, path: &str) -> Option<&ExtractedListing> {
+        self.reader.find(path)
+    }
+
+    /// Returns the bundle at `index` decompressed and verified, from the cache if it was read
+    /// recently
+    pub fn bundle(&mut self, index: usize) -> Result<Arc<Vec<u8>>, io::Error> {
+        if let Some(position) = self.cache.iter().position(|(i, _)| *i == index) {
+            let entry = self.cache.remove(position).unwrap();
+            self.cache.push_front(entry.clone());
+            return Ok(entry.1);
+        }
+
+        let bundle = Arc::new(self.reader.read_bundle(index)?);
+        self.cached_bytes += bundle.len() as u64;
+        self.cache.push_front((index, Arc::clone(&bundle)));
+        while self.cache.len() > self.max_bundles || self.cached_bytes > self.max_bytes {
+            let Some((_, evicted)) = self.cache.pop_back() else {
+                break;
+            };
+            self.cached_bytes -= evicted.len() as u64;
+        }
+        Ok(bundle)
+    }
+
+    /// Returns the verified content of the file with the given path, or `None` if the archive
+    /// has no such listing
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        let Some(listing) = self.find(path).cloned() else {
+            return Ok(None);
+        };
+        let content = self.read_range(&listing, 0..listing.filesize)?;
+        if !listing.is_metadata_only()
+            && self.reader.hash.hash(&content) != listing.content_checksum
+        {
+            return Err(ArchiveError::Verification(format!(
+                "invalid listing: could not verify file integrity for file {}",
+                listing.path
+            ))
+            .into());
+        }
+        Ok(Some(content))
+    }
+
+    /// Returns the part of the content of `listing` within `range`, which is cut off at the end
+    /// of the content; only the bundle holding it is verified
+    pub fn read_range(
+        &mut self,
+        listing: &ExtractedListing,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, io::Error> {
+        if listing.is_metadata_only() {
+            return Ok(Vec::new());
+        }
+        let bundle = self.bundle(listing.bundle_idx)?;
+        let end = range.end.min(listing.filesize);
+        let start = range.start.min(end) as usize;
+        let end = end as usize;
+        Ok(bundle[listing.bundle_offset + start..listing.bundle_offset + end].to_vec())
+    }
+
+    /// Returns the number of bytes of decompressed bundles held in the cache
+    pub fn cached_bytes(&self) -> u64 {
+        self.cached_bytes
+    }
+
+    pub fn into_inner(self) -> SeekableArchiveReader<R> {
+        self.reader
     }
 }
@@ -0,0 +1,606 @@
+//! The binary layout of DeCAF archives
+//!
+//! An archive is a [`Header`], then the listing block of `listing_count` [`ListingEntry`]s each
+//! followed by its path, then the bundle table of `bundle_count` [`BundleEntry`]s, and finally the
+//! zstd-compressed bundles. The listing block may itself be stored as a zstd frame, and the header
+//! may be followed by the [`SectionChecksums`] of the listing block and bundle table and then by a
+//! [`HeaderChecksum`], as flagged in the header. An archive with a footer instead starts with a
+//! header holding only its flags and ends with a second header completing it, the tables directly
+//! preceding that footer, so that it can be written in a single pass. All integers are
+//! little-endian. The structs are packed so that their size and field offsets match the encoded
+//! layout exactly.
+//!
+//! A stream is the framed variant of an archive, which is written and read in one pass, e.g.
+//! through a pipe: [`STREAM_MAGIC_NUMBER`], then one frame per bundle, each a [`FrameHeader`]
+//! followed by the uncompressed entries of the listings up to the next bundle and the compressed
+//! bundle. A frame without listings ends the stream.
+//!
+//! A path index is kept apart from the archive it indexes: [`INDEX_MAGIC_NUMBER`], the rest of an
+//! [`IndexHeader`], then the listing block with its listings ordered by path, and the bundle table
+//! of the archive.
+
+use std::mem::{offset_of, size_of};
+
+use crate::ArchiveError;
+
+pub const MAGIC_NUMBER: [u8; 8] = *b"iamdecaf";
+/// magic number of pre-release archives
+pub const LEGACY_MAGIC_NUMBER: [u8; 8] = *b"notdecaf";
+
+/// magic number of streams
+pub const STREAM_MAGIC_NUMBER: [u8; 8] = *b"decafstm";
+
+/// magic number of path indexes
+pub const INDEX_MAGIC_NUMBER: [u8; 8] = *b"decafidx";
+
+/// Describes one field of an encoded structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    /// offset from the start of the structure
+    pub offset: usize,
+    pub size: usize,
+    pub description: &'static str,
+}
+
+macro_rules! field {
+    ($type:ty, $name:ident: $field_type:ty, $description:expr) => {
+        Field {
+            name: stringify!($name),
+            offset: offset_of!($type, $name),
+            size: size_of::<$field_type>(),
+            description: $description,
+        }
+    };
+}
+
+/// Reads little-endian values from the front of a byte slice, keeping track of the offset and
+/// failing with `truncated` instead of reading past the end
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    truncated: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    /// Returns a reader at the start of `bytes`, whose reads past the end fail with a corrupt
+    /// archive error of `truncated`
+    pub fn new(bytes: &'a [u8], truncated: &'static str) -> Reader<'a> {
+        Reader {
+            bytes,
+            offset: 0,
+            truncated,
+        }
+    }
+
+    /// Returns the number of bytes read so far
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the bytes which haven't been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.offset..]
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], ArchiveError> {
+        let bytes = self
+            .offset
+            .checked_add(length)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| ArchiveError::Corrupt(self.truncated.to_string()))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ArchiveError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ArchiveError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32_le(&mut self) -> Result<u32, ArchiveError> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    pub fn read_u64_le(&mut self) -> Result<u64, ArchiveError> {
+        self.read_array().map(u64::from_le_bytes)
+    }
+}
+
+fn write_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The start of every archive
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub checksum: u64,
+    pub listing_block_length: u64,
+    pub listing_count: u64,
+    pub bundle_count: u64,
+}
+
+impl Header {
+    pub const SIZE: usize = size_of::<Header>();
+    /// everything after the checksum is covered by it, up to the end of the bundle table if the
+    /// header has `SPLIT_CHECKSUM` set
+    pub const CHECKSUMMED_FROM: usize = offset_of!(Header, listing_block_length);
+    /// set in `listing_block_length` when the listing block is stored as a zstd frame
+    pub const COMPRESSED_LISTINGS: u64 = 1 << 63;
+    /// set in `listing_block_length` when the header is followed by `SectionChecksums`
+    pub const SECTION_CHECKSUMS: u64 = 1 << 62;
+    /// the bits of `listing_block_length` holding the identifier of the hash algorithm every
+    /// checksum of the archive is computed with, 0 for xxh3
+    pub const HASH_ALGORITHM: u64 = 0b11 << 60;
+    /// the lowest bit of `HASH_ALGORITHM`
+    pub const HASH_ALGORITHM_SHIFT: u32 = 60;
+    /// set in `listing_block_length` when the header is followed by a `HeaderChecksum`, after the
+    /// section checksums if there are any
+    pub const HEADER_CHECKSUM: u64 = 1 << 59;
+    /// set in `listing_block_length` when the archive checksum ends with the bundle table, leaving
+    /// the bundles to their own checksums
+    pub const SPLIT_CHECKSUM: u64 = 1 << 58;
+    /// set in `listing_block_length` when the listing block and the bundle table follow the
+    /// bundles, followed in turn by a second header completing this one with the checksum, the
+    /// lengths and the counts
+    pub const FOOTER: u64 = 1 << 57;
+    pub const FIELDS: [Field; 5] = [
+        field!(Header, magic: [u8; 8], "magic number, `iamdecaf`"),
+        field!(Header, checksum: u64, "checksum of the rest of the archive, or of the rest of the header and the tables if the checksum is split"),
+        field!(Header, listing_block_length: u64, "length of the listing block in bytes, with the top bit set if it is zstd-compressed, the next if section checksums follow the header, the two after that identifying the hash algorithm, the next if a header checksum follows the header, the next if the checksum is split and the next if the tables and a footer follow the bundles"),
+        field!(Header, listing_count: u64, "number of listings"),
+        field!(Header, bundle_count: u64, "number of bundles"),
+    ];
+
+    pub fn encode(&self) -> [u8; Header::SIZE] {
+        let mut bytes = [0u8; Header::SIZE];
+        bytes[offset_of!(Header, magic)..][..8].copy_from_slice(&self.magic);
+        write_u64(&mut bytes, offset_of!(Header, checksum), self.checksum);
+        write_u64(
+            &mut bytes,
+            offset_of!(Header, listing_block_length),
+            self.listing_block_length,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(Header, listing_count),
+            self.listing_count,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(Header, bundle_count),
+            self.bundle_count,
+        );
+        bytes
+    }
+
+    /// Returns whether the listing block is stored as a zstd frame
+    pub fn has_compressed_listings(&self) -> bool {
+        self.listing_block_length & Header::COMPRESSED_LISTINGS != 0
+    }
+
+    /// Returns whether the header is followed by the checksums of the listing block and bundle
+    /// table
+    pub fn has_section_checksums(&self) -> bool {
+        self.listing_block_length & Header::SECTION_CHECKSUMS != 0
+    }
+
+    /// Returns whether the header is followed by a checksum of itself and the bundle table
+    pub fn has_header_checksum(&self) -> bool {
+        self.listing_block_length & Header::HEADER_CHECKSUM != 0
+    }
+
+    /// Returns whether the archive checksum ends with the bundle table rather than covering the
+    /// bundles too
+    pub fn has_split_checksum(&self) -> bool {
+        self.listing_block_length & Header::SPLIT_CHECKSUM != 0
+    }
+
+    /// Returns whether the tables follow the bundles, followed by a footer completing the header
+    pub fn has_footer(&self) -> bool {
+        self.listing_block_length & Header::FOOTER != 0
+    }
+
+    /// Returns the identifier of the hash algorithm the checksums of the archive are computed with
+    pub fn hash_algorithm_id(&self) -> u8 {
+        ((self.listing_block_length & Header::HASH_ALGORITHM) >> Header::HASH_ALGORITHM_SHIFT) as u8
+    }
+
+    /// Returns the number of bytes the listing block takes up in the archive, which is its
+    /// compressed length if it is compressed
+    pub fn stored_listing_block_length(&self) -> u64 {
+        self.listing_block_length
+            & !(Header::COMPRESSED_LISTINGS
+                | Header::SECTION_CHECKSUMS
+                | Header::HASH_ALGORITHM
+                | Header::HEADER_CHECKSUM
+                | Header::SPLIT_CHECKSUM
+                | Header::FOOTER)
+    }
+
+    /// Returns the offset of the header checksum, if there is one, which follows the section
+    /// checksums if there are any
+    pub fn header_checksum_offset(&self) -> u64 {
+        match self.has_section_checksums() {
+            true => (Header::SIZE + SectionChecksums::SIZE) as u64,
+            false => Header::SIZE as u64,
+        }
+    }
+
+    /// Returns the offset of the listing block, which follows the section checksums and the
+    /// header checksum if there are any
+    pub fn listing_block_offset(&self) -> u64 {
+        match self.has_header_checksum() {
+            true => self.header_checksum_offset() + HeaderChecksum::SIZE as u64,
+            false => self.header_checksum_offset(),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Header, ArchiveError> {
+        if bytes.len() < Header::SIZE {
+            return Err(ArchiveError::Corrupt(format!(
+                "invalid archive: archive too small with size {} bytes",
+                bytes.len()
+            )));
+        }
+        let mut reader = Reader::new(bytes, "invalid archive: header is truncated");
+        Ok(Header {
+            magic: reader.read_array()?,
+            checksum: reader.read_u64_le()?,
+            listing_block_length: reader.read_u64_le()?,
+            listing_count: reader.read_u64_le()?,
+            bundle_count: reader.read_u64_le()?,
+        })
+    }
+}
+
+/// The fixed-size start of a listing in the listing block, directly followed by its UTF-8 path
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ListingEntry {
+    pub total_length: u64,
+    pub bundle_index: u64,
+    pub bundle_offset: u64,
+    pub size: u64,
+    pub permissions: u32,
+    pub checksum: u64,
+}
+
+impl ListingEntry {
+    pub const SIZE: usize = size_of::<ListingEntry>();
+    /// the bits of `permissions` holding the mode; the file flags are stored above them
+    pub const MODE_BITS: u32 = 0o177777;
+    /// set in `permissions` when the listing is immutable, like `chattr +i` or `chflags schg`
+    pub const IMMUTABLE: u32 = 1 << 16;
+    /// set in `permissions` when the listing can only be appended to, like `chattr +a` or
+    /// `chflags sappnd`
+    pub const APPEND_ONLY: u32 = 1 << 17;
+    pub const FIELDS: [Field; 6] = [
+        field!(ListingEntry, total_length: u64, "length of the entry including its path"),
+        field!(ListingEntry, bundle_index: u64, "index of the bundle holding the content"),
+        field!(ListingEntry, bundle_offset: u64, "offset of the content in the uncompressed bundle"),
+        field!(ListingEntry, size: u64, "length of the content"),
+        field!(ListingEntry, permissions: u32, "full mode, including the file type bits, with the file flags above it"),
+        field!(ListingEntry, checksum: u64, "checksum of the content, 0 for listings without content"),
+    ];
+
+    /// Appends the entry followed by `path` to `out`
+    pub fn encode(&self, path: &[u8], out: &mut Vec<u8>) {
+        let mut bytes = [0u8; ListingEntry::SIZE];
+        write_u64(
+            &mut bytes,
+            offset_of!(ListingEntry, total_length),
+            self.total_length,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(ListingEntry, bundle_index),
+            self.bundle_index,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(ListingEntry, bundle_offset),
+            self.bundle_offset,
+        );
+        write_u64(&mut bytes, offset_of!(ListingEntry, size), self.size);
+        write_u32(
+            &mut bytes,
+            offset_of!(ListingEntry, permissions),
+            self.permissions,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(ListingEntry, checksum),
+            self.checksum,
+        );
+        out.extend_from_slice(&bytes);
+        out.extend_from_slice(path);
+    }
+
+    /// Decodes the entry at the start of `bytes`, returning it with its path
+    pub fn decode(bytes: &[u8]) -> Result<(ListingEntry, &[u8]), ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid archive: listing block is truncated");
+        let entry = ListingEntry {
+            total_length: reader.read_u64_le()?,
+            bundle_index: reader.read_u64_le()?,
+            bundle_offset: reader.read_u64_le()?,
+            size: reader.read_u64_le()?,
+            permissions: reader.read_u32_le()?,
+            checksum: reader.read_u64_le()?,
+        };
+        let path_length = usize::try_from(entry.total_length)
+            .ok()
+            .and_then(|total_length| total_length.checked_sub(ListingEntry::SIZE))
+            .ok_or_else(|| {
+                ArchiveError::Corrupt("invalid archive: listing block is truncated".to_string())
+            })?;
+        Ok((entry, reader.read_bytes(path_length)?))
+    }
+}
+
+/// An entry of the bundle table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BundleEntry {
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub checksum: u64,
+}
+
+impl BundleEntry {
+    pub const SIZE: usize = size_of::<BundleEntry>();
+    pub const FIELDS: [Field; 3] = [
+        field!(BundleEntry, offset: u64, "offset of the compressed bundle from the archive start"),
+        field!(BundleEntry, compressed_size: u64, "length of the compressed bundle"),
+        field!(BundleEntry, checksum: u64, "checksum of the uncompressed bundle"),
+    ];
+
+    pub fn encode(&self) -> [u8; BundleEntry::SIZE] {
+        let mut bytes = [0u8; BundleEntry::SIZE];
+        write_u64(&mut bytes, offset_of!(BundleEntry, offset), self.offset);
+        write_u64(
+            &mut bytes,
+            offset_of!(BundleEntry, compressed_size),
+            self.compressed_size,
+        );
+        write_u64(&mut bytes, offset_of!(BundleEntry, checksum), self.checksum);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<BundleEntry, ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid archive: bundle table is truncated");
+        Ok(BundleEntry {
+            offset: reader.read_u64_le()?,
+            compressed_size: reader.read_u64_le()?,
+            checksum: reader.read_u64_le()?,
+        })
+    }
+}
+
+/// Checksums of the listing block and the bundle table, following the header if it says so, which
+/// let readers that never see the whole archive verify the tables they use
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SectionChecksums {
+    pub listing_block: u64,
+    pub bundle_table: u64,
+}
+
+impl SectionChecksums {
+    pub const SIZE: usize = size_of::<SectionChecksums>();
+    pub const FIELDS: [Field; 2] = [
+        field!(SectionChecksums, listing_block: u64, "checksum of the listing block as stored"),
+        field!(SectionChecksums, bundle_table: u64, "checksum of the bundle table"),
+    ];
+
+    pub fn encode(&self) -> [u8; SectionChecksums::SIZE] {
+        let mut bytes = [0u8; SectionChecksums::SIZE];
+        write_u64(
+            &mut bytes,
+            offset_of!(SectionChecksums, listing_block),
+            self.listing_block,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(SectionChecksums, bundle_table),
+            self.bundle_table,
+        );
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SectionChecksums, ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid archive: section checksums are truncated");
+        Ok(SectionChecksums {
+            listing_block: reader.read_u64_le()?,
+            bundle_table: reader.read_u64_le()?,
+        })
+    }
+}
+
+/// A checksum of the header after the archive checksum followed by the bundle table, following
+/// the header and its section checksums if the header says so, which lets readers that only need
+/// the counts and sizes of an archive, like `ArchiveSummary::peek`, trust them without reading the
+/// listing block
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeaderChecksum {
+    pub checksum: u64,
+}
+
+impl HeaderChecksum {
+    pub const SIZE: usize = size_of::<HeaderChecksum>();
+    pub const FIELDS: [Field; 1] = [field!(
+        HeaderChecksum,
+        checksum: u64,
+        "checksum of the header from listing_block_length on, followed by the bundle table"
+    )];
+
+    pub fn encode(&self) -> [u8; HeaderChecksum::SIZE] {
+        let mut bytes = [0u8; HeaderChecksum::SIZE];
+        write_u64(
+            &mut bytes,
+            offset_of!(HeaderChecksum, checksum),
+            self.checksum,
+        );
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<HeaderChecksum, ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid archive: header checksum is truncated");
+        Ok(HeaderChecksum {
+            checksum: reader.read_u64_le()?,
+        })
+    }
+}
+
+/// The start of every frame of a stream
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameHeader {
+    pub listing_block_length: u64,
+    pub listing_count: u64,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub checksum: u64,
+}
+
+impl FrameHeader {
+    pub const SIZE: usize = size_of::<FrameHeader>();
+    pub const FIELDS: [Field; 5] = [
+        field!(FrameHeader, listing_block_length: u64, "length of the listing entries of the frame"),
+        field!(FrameHeader, listing_count: u64, "number of listings of the frame, 0 at the end of the stream"),
+        field!(FrameHeader, compressed_size: u64, "length of the compressed bundle, 0 if the frame has none"),
+        field!(FrameHeader, uncompressed_size: u64, "length of the uncompressed bundle"),
+        field!(FrameHeader, checksum: u64, "xxh3 of the uncompressed bundle, or at the end of the stream of everything before it"),
+    ];
+
+    /// Returns whether the frame ends the stream
+    pub fn is_end(&self) -> bool {
+        self.listing_count == 0
+    }
+
+    pub fn encode(&self) -> [u8; FrameHeader::SIZE] {
+        let mut bytes = [0u8; FrameHeader::SIZE];
+        write_u64(
+            &mut bytes,
+            offset_of!(FrameHeader, listing_block_length),
+            self.listing_block_length,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(FrameHeader, listing_count),
+            self.listing_count,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(FrameHeader, compressed_size),
+            self.compressed_size,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(FrameHeader, uncompressed_size),
+            self.uncompressed_size,
+        );
+        write_u64(&mut bytes, offset_of!(FrameHeader, checksum), self.checksum);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<FrameHeader, ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid stream: frame header is truncated");
+        Ok(FrameHeader {
+            listing_block_length: reader.read_u64_le()?,
+            listing_count: reader.read_u64_le()?,
+            compressed_size: reader.read_u64_le()?,
+            uncompressed_size: reader.read_u64_le()?,
+            checksum: reader.read_u64_le()?,
+        })
+    }
+}
+
+/// The start of every path index
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexHeader {
+    pub magic: [u8; 8],
+    pub checksum: u64,
+    pub archive_checksum: u64,
+    pub listing_block_length: u64,
+    pub listing_count: u64,
+    pub bundle_count: u64,
+}
+
+impl IndexHeader {
+    pub const SIZE: usize = size_of::<IndexHeader>();
+    /// everything after the checksum is covered by it
+    pub const CHECKSUMMED_FROM: usize = offset_of!(IndexHeader, archive_checksum);
+    pub const FIELDS: [Field; 6] = [
+        field!(IndexHeader, magic: [u8; 8], "magic number, `decafidx`"),
+        field!(IndexHeader, checksum: u64, "xxh3 of the rest of the index"),
+        field!(IndexHeader, archive_checksum: u64, "checksum from the header of the indexed archive"),
+        field!(IndexHeader, listing_block_length: u64, "length of the listing block in bytes, uncompressed, with the hash algorithm of the archive in the bits it takes up in the archive header"),
+        field!(IndexHeader, listing_count: u64, "number of listings, ordered by path"),
+        field!(IndexHeader, bundle_count: u64, "number of bundles"),
+    ];
+
+    pub fn encode(&self) -> [u8; IndexHeader::SIZE] {
+        let mut bytes = [0u8; IndexHeader::SIZE];
+        bytes[offset_of!(IndexHeader, magic)..][..8].copy_from_slice(&self.magic);
+        write_u64(&mut bytes, offset_of!(IndexHeader, checksum), self.checksum);
+        write_u64(
+            &mut bytes,
+            offset_of!(IndexHeader, archive_checksum),
+            self.archive_checksum,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(IndexHeader, listing_block_length),
+            self.listing_block_length,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(IndexHeader, listing_count),
+            self.listing_count,
+        );
+        write_u64(
+            &mut bytes,
+            offset_of!(IndexHeader, bundle_count),
+            self.bundle_count,
+        );
+        bytes
+    }
+
+    /// Returns the identifier of the hash algorithm the checksums of the indexed archive are
+    /// computed with; the checksum of the index itself is always xxh3
+    pub fn hash_algorithm_id(&self) -> u8 {
+        ((self.listing_block_length & Header::HASH_ALGORITHM) >> Header::HASH_ALGORITHM_SHIFT) as u8
+    }
+
+    /// Returns the length of the listing block
+    pub fn stored_listing_block_length(&self) -> u64 {
+        self.listing_block_length & !Header::HASH_ALGORITHM
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<IndexHeader, ArchiveError> {
+        let mut reader = Reader::new(bytes, "invalid index: header is truncated");
+        Ok(IndexHeader {
+            magic: reader.read_array()?,
+            checksum: reader.read_u64_le()?,
+            archive_checksum: reader.read_u64_le()?,
+            listing_block_length: reader.read_u64_le()?,
+            listing_count: reader.read_u64_le()?,
+            bundle_count: reader.read_u64_le()?,
+        })
+    }
+}
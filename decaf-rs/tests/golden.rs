@@ -0,0 +1,198 @@
+//! Golden-file tests guarding the stability of the format
+//!
+//! `tests/fixtures` holds archives of the canonical tree written by `write_canonical_tree`: one
+//! for every format revision, plus a stream and an archive using the optional parts of the format.
+//! Today's reader has to read all of them, and today's writer has to reproduce the ones it writes
+//! byte for byte, as archives of the same tree are the same archive whenever and wherever they are
+//! written. A deliberate change to the output, e.g. by a new zstd release, is committed by running
+//! the tests with `DECAF_UPDATE_FIXTURES=1` and explained in the commit.
+
+use decaf::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Writes the canonical tree, setting every mode explicitly so that the umask doesn't matter
+fn write_canonical_tree(root: &Path) {
+    let files: [(&str, Vec<u8>, u32); 7] = [
+        (
+            "README.md",
+            b"the canonical tree of the golden archives\n".to_vec(),
+            0o644,
+        ),
+        ("bin/tool.sh", b"#!/bin/sh\necho decaf\n".to_vec(), 0o755),
+        (
+            "data/lipsum.txt",
+            "lorem ipsum ".repeat(2048).into_bytes(),
+            0o644,
+        ),
+        ("data/zeros.bin", vec![0u8; 16 * 1024], 0o600),
+        ("data/nested/deep/note.txt", b"deep\n".to_vec(), 0o640),
+        (
+            "data/\u{fc}n\u{ef}c\u{f6}d\u{e9}.txt",
+            "\u{2615}\n".into(),
+            0o644,
+        ),
+        ("empty-file", Vec::new(), 0o644),
+    ];
+    for (path, content, mode) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+    fs::create_dir(root.join("empty")).unwrap();
+    fs::set_permissions(root.join("empty"), fs::Permissions::from_mode(0o750)).unwrap();
+}
+
+/// What is at every path of a tree: the content and mode of files, and the mode of directories
+/// without entries, which are the only directories an archive stores
+fn read_tree(root: &Path) -> BTreeMap<String, (Option<Vec<u8>>, u32)> {
+    fn walk(root: &Path, directory: &Path, tree: &mut BTreeMap<String, (Option<Vec<u8>>, u32)>) {
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let relative = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+            if path.is_dir() {
+                if fs::read_dir(&path).unwrap().next().is_none() {
+                    tree.insert(relative, (None, mode));
+                }
+                walk(root, &path, tree);
+            } else {
+                tree.insert(relative, (Some(fs::read(&path).unwrap()), mode));
+            }
+        }
+    }
+    let mut tree = BTreeMap::new();
+    walk(root, root, &mut tree);
+    tree
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// The fixtures written by today's writer, with the options they are written with
+fn written_fixtures() -> [(&'static str, bool, ArchiveOptions); 3] {
+    [
+        ("current.df", false, ArchiveOptions::default()),
+        (
+            "current-optional.df",
+            false,
+            ArchiveOptions {
+                bundle_size: 8 * 1024,
+                compress_listings: true,
+                section_checksums: true,
+                seekable_frame_size: Some(4096),
+                ..Default::default()
+            },
+        ),
+        (
+            "current.dfs",
+            true,
+            ArchiveOptions {
+                bundle_size: 8 * 1024,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+/// Rewrites `archive` as a pre-release archive, which shares the current layout after the archive
+/// checksum, with the `notdecaf` magic number and the archive checksum computed by `checksum`
+fn legacy(archive: &[u8], checksum: fn(&[u8]) -> u64) -> Vec<u8> {
+    let mut legacy = archive.to_vec();
+    legacy[0..8].copy_from_slice(b"notdecaf");
+    let checksum = checksum(&legacy[16..]);
+    legacy[8..16].copy_from_slice(&checksum.to_le_bytes());
+    legacy
+}
+
+#[test]
+fn writer_reproduces_golden_archives() {
+    let source = tempfile::tempdir().unwrap();
+    write_canonical_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+
+    let update = std::env::var_os("DECAF_UPDATE_FIXTURES").is_some();
+    for (name, stream, options) in written_fixtures() {
+        let mut written = Vec::new();
+        match stream {
+            true => archivable.archive_to_stream(&mut written, &options),
+            false => archivable.archive_to_writer_with_options(&mut written, &options),
+        }
+        .unwrap();
+
+        if update {
+            fs::create_dir_all(fixture("")).unwrap();
+            fs::write(fixture(name), &written).unwrap();
+            if name == "current.df" {
+                let crc32 = |bytes: &[u8]| crc32fast::hash(bytes) as u64;
+                let xxh3 = xxhash_rust::xxh3::xxh3_64;
+                fs::write(fixture("legacy-crc32.df"), legacy(&written, crc32)).unwrap();
+                fs::write(fixture("legacy-xxh3.df"), legacy(&written, xxh3)).unwrap();
+            }
+        }
+        assert!(
+            fs::read(fixture(name)).unwrap() == written,
+            "{} is no longer written byte for byte the same",
+            name
+        );
+    }
+}
+
+#[test]
+fn reader_reads_golden_archives() {
+    let source = tempfile::tempdir().unwrap();
+    write_canonical_tree(source.path());
+    let expected = read_tree(source.path());
+
+    let archives = [
+        ("legacy-crc32.df", FormatRevision::LegacyCrc32),
+        ("legacy-xxh3.df", FormatRevision::LegacyXxh3),
+        ("current.df", FormatRevision::Current),
+        ("current-optional.df", FormatRevision::Current),
+    ];
+    for (name, revision) in archives {
+        let archive = fs::read(fixture(name)).unwrap();
+        let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+        assert_eq!(extracted.revision(), revision, "{}", name);
+        let destination = tempfile::tempdir().unwrap();
+        let output = destination.path().join("tree");
+        extracted
+            .create_all_files_with_options(&output, &ExtractOptions::default())
+            .unwrap();
+        assert_eq!(read_tree(&output), expected, "{}", name);
+
+        // pre-release archives upgrade to exactly the current archive
+        let mut upgraded = Vec::new();
+        upgrade(&mut archive.as_slice(), &mut upgraded).unwrap();
+        if name != "current-optional.df" {
+            assert_eq!(
+                upgraded,
+                fs::read(fixture("current.df")).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    let stream = fs::read(fixture("current.dfs")).unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(read_tree(destination.path()), expected);
+}
@@ -0,0 +1,209 @@
+use decaf::*;
+use proptest::prelude::*;
+use proptest::sample::Index;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+/// A generated directory tree
+#[derive(Debug, Clone)]
+enum Node {
+    File {
+        content: Vec<u8>,
+        mode: u32,
+    },
+    Directory {
+        children: BTreeMap<String, Node>,
+        mode: u32,
+    },
+}
+
+/// A symlink in the root of a generated tree pointing at one of its files
+#[derive(Debug, Clone)]
+struct Link {
+    name: String,
+    target: Index,
+    /// only absolute links resolve inside of the archived directory; relative ones are skipped
+    absolute: bool,
+}
+
+/// What is expected at a path of an extracted tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    File {
+        content: Vec<u8>,
+        mode: u32,
+    },
+    /// a directory, with its mode if it is bare and so stored as its own listing
+    Directory {
+        mode: Option<u32>,
+    },
+}
+
+fn name() -> impl Strategy<Value = String> {
+    "[a-z0-9_é][a-zA-Z0-9_.é-]{0,11}"
+}
+
+fn content() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..2048),
+        // long runs compress well, so bundles fill up with more than their target size
+        (any::<u8>(), 0..64 * 1024usize).prop_map(|(byte, length)| vec![byte; length]),
+    ]
+}
+
+fn node() -> impl Strategy<Value = Node> {
+    // the owner can always read files and enter directories, so the tree can be archived
+    let file =
+        (content(), 0o600..=0o777u32).prop_map(|(content, mode)| Node::File { content, mode });
+    file.prop_recursive(4, 48, 6, |inner| {
+        (
+            prop::collection::btree_map(name(), inner, 0..6),
+            0o700..=0o777u32,
+        )
+            .prop_map(|(children, mode)| Node::Directory { children, mode })
+    })
+}
+
+fn tree() -> impl Strategy<Value = (BTreeMap<String, Node>, Vec<Link>)> {
+    let link = (name(), any::<Index>(), any::<bool>()).prop_map(|(name, target, absolute)| Link {
+        name: format!("link-{}", name),
+        target,
+        absolute,
+    });
+    (
+        prop::collection::btree_map(name(), node(), 0..8),
+        prop::collection::vec(link, 0..3),
+    )
+}
+
+fn options() -> impl Strategy<Value = ArchiveOptions> {
+    (
+        prop_oneof![Just(1024usize), Just(64 * 1024), Just(10 * 1024 * 1024)],
+        prop_oneof![
+            Just(SortStrategy::Size),
+            Just(SortStrategy::Path),
+            Just(SortStrategy::Extension),
+            Just(SortStrategy::WalkOrder),
+        ],
+    )
+        .prop_map(|(bundle_size, sort)| ArchiveOptions {
+            bundle_size,
+            sort,
+            ..ArchiveOptions::default()
+        })
+}
+
+/// Writes `children` to `directory`, recording what extracting them is expected to produce
+fn write_tree(
+    directory: &Path,
+    relative: &str,
+    children: &BTreeMap<String, Node>,
+    expected: &mut BTreeMap<String, Entry>,
+) {
+    for (name, node) in children {
+        let path = directory.join(name);
+        let relative = match relative {
+            "" => name.clone(),
+            _ => format!("{}/{}", relative, name),
+        };
+        match node {
+            Node::File { content, mode } => {
+                fs::write(&path, content).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+                expected.insert(
+                    relative,
+                    Entry::File {
+                        content: content.clone(),
+                        mode: *mode,
+                    },
+                );
+            }
+            Node::Directory { children, mode } => {
+                fs::create_dir(&path).unwrap();
+                write_tree(&path, &relative, children, expected);
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+                let mode = children.is_empty().then_some(*mode);
+                expected.insert(relative, Entry::Directory { mode });
+            }
+        }
+    }
+}
+
+/// Reads the tree under `directory` in the same shape as `write_tree` records it
+fn read_tree(directory: &Path, relative: &str, tree: &mut BTreeMap<String, Entry>) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().into_string().unwrap();
+        let relative = match relative {
+            "" => name,
+            _ => format!("{}/{}", relative, name),
+        };
+        let metadata = fs::symlink_metadata(entry.path()).unwrap();
+        let mode = metadata.permissions().mode() & 0o7777;
+        if metadata.is_dir() {
+            read_tree(&entry.path(), &relative, tree);
+            let is_bare = fs::read_dir(entry.path()).unwrap().next().is_none();
+            let mode = is_bare.then_some(mode);
+            tree.insert(relative, Entry::Directory { mode });
+        } else {
+            assert!(metadata.is_file(), "{} is not a file", relative);
+            let content = fs::read(entry.path()).unwrap();
+            tree.insert(relative, Entry::File { content, mode });
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn archive_extract_round_trip((children, links) in tree(), options in options()) {
+        let source = tempfile::tempdir().unwrap();
+        let mut expected = BTreeMap::new();
+        write_tree(source.path(), "", &children, &mut expected);
+
+        // links are archived as the content of the file they point at
+        let files: Vec<(String, Vec<u8>)> = expected
+            .iter()
+            .filter_map(|(path, entry)| match entry {
+                Entry::File { content, .. } => Some((path.clone(), content.clone())),
+                Entry::Directory { .. } => None,
+            })
+            .collect();
+        for link in &links {
+            if files.is_empty() || expected.contains_key(&link.name) {
+                continue;
+            }
+            let (target, content) = link.target.get(&files);
+            let link_path = source.path().join(&link.name);
+            if link.absolute {
+                symlink(source.path().join(target), &link_path).unwrap();
+                let mode = fs::symlink_metadata(&link_path).unwrap().permissions().mode() & 0o7777;
+                expected.insert(link.name.clone(), Entry::File { content: content.clone(), mode });
+            } else {
+                symlink(target, &link_path).unwrap();
+            }
+        }
+
+        let mut archive = Vec::new();
+        create_archive_from_directory(source.path())
+            .unwrap()
+            .archive_to_writer_with_options(&mut archive, &options)
+            .unwrap();
+
+        let destination = tempfile::tempdir().unwrap();
+        let output = destination.path().join("output");
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .create_all_files(&output)
+            .unwrap();
+
+        let mut extracted = BTreeMap::new();
+        if output.exists() {
+            read_tree(&output, "", &mut extracted);
+        }
+        prop_assert_eq!(extracted, expected);
+    }
+}
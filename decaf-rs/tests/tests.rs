@@ -0,0 +1,3898 @@
+use decaf::*;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+fn write_test_tree<P: AsRef<Path>>(root: P) {
+    let root = root.as_ref();
+    fs::create_dir_all(root.join("dir/subdir")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    fs::write(root.join("small.txt"), b"hello decaf\n").unwrap();
+    fs::write(root.join("dir/lipsum.txt"), "lorem ipsum ".repeat(4096)).unwrap();
+    fs::write(root.join("dir/subdir/zeros.bin"), vec![0u8; 64 * 1024]).unwrap();
+}
+
+/// Returns the `ArchiveError` an io error wraps, if it wraps one
+fn archive_error(error: &std::io::Error) -> Option<&ArchiveError> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<ArchiveError>())
+}
+
+#[test]
+fn repack_round_trip() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut original = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut original)
+        .unwrap();
+
+    let options = ArchiveOptions {
+        compression_level: 19,
+        bundle_size: 1024,
+        ..Default::default()
+    };
+    let mut repacked = Vec::new();
+    let written = repack(&mut original.as_slice(), &mut repacked, &options).unwrap();
+    assert_eq!(written.bytes_written, repacked.len());
+    assert_eq!(written.checksum.to_le_bytes(), repacked[8..16]);
+    assert!(written.bundles.len() > 1);
+    assert_eq!(
+        written
+            .bundles
+            .iter()
+            .map(|bundle| bundle.uncompressed_size)
+            .sum::<u64>(),
+        12 + 12 * 4096 + 64 * 1024
+    );
+
+    let original = extract_from_reader(&mut original.as_slice()).unwrap();
+    let repacked = extract_from_reader(&mut repacked.as_slice()).unwrap();
+    assert_eq!(original.listings.len(), repacked.listings.len());
+
+    let original_dir = tempfile::tempdir().unwrap();
+    let repacked_dir = tempfile::tempdir().unwrap();
+    original.create_all_files(original_dir.path()).unwrap();
+    let stats = repacked
+        .create_all_files_with_options(repacked_dir.path(), &ExtractOptions::default())
+        .unwrap();
+    assert_eq!(stats.file_count, 3);
+    assert_eq!(stats.dir_count, 1);
+    assert_eq!(stats.bundle_count, written.bundles.len());
+    assert_eq!(stats.uncompressed_bytes, written.stats.uncompressed_bytes);
+    assert_eq!(stats.compressed_bytes, written.stats.compressed_bytes);
+    for listing in &repacked.listings {
+        let original_path = original_dir.path().join(&*listing.path);
+        let repacked_path = repacked_dir.path().join(&*listing.path);
+        if original_path.is_file() {
+            assert_eq!(
+                fs::read(original_path).unwrap(),
+                fs::read(repacked_path).unwrap()
+            );
+        } else {
+            assert!(repacked_path.is_dir());
+        }
+    }
+}
+
+#[test]
+fn temporary_extraction() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    let (temp_dir, stats) = extracted.extract_to_temp().unwrap();
+    assert_eq!(stats.file_count, 3);
+    assert_eq!(
+        fs::read(temp_dir.path().join("small.txt")).unwrap(),
+        b"hello decaf\n"
+    );
+    // the directory is removed with everything in it once it is dropped
+    let temp_path = temp_dir.path().to_path_buf();
+    drop(temp_dir);
+    assert!(!temp_path.exists());
+}
+
+#[test]
+fn extract_path_transforms() {
+    let options = ExtractOptions {
+        strip_components: 1,
+        path_remaps: vec![
+            ("output".to_string(), "".to_string()),
+            ("docs".to_string(), "share/doc".to_string()),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(options.transform_path("build").unwrap(), None);
+    assert_eq!(options.transform_path("build/output").unwrap(), None);
+    assert_eq!(
+        options.transform_path("build/output/bin/app").unwrap(),
+        Some("bin/app".to_string())
+    );
+    assert_eq!(
+        options.transform_path("build/docs/readme.md").unwrap(),
+        Some("share/doc/readme.md".to_string())
+    );
+    assert_eq!(
+        options.transform_path("build/outputs/log.txt").unwrap(),
+        Some("outputs/log.txt".to_string())
+    );
+
+    // `..` never makes it past the transforms, whether stripped, remapped or introduced
+    assert!(options.transform_path("build/../../etc/passwd").is_err());
+    assert!(options.transform_path("../build/bin").is_err());
+    let escaping = ExtractOptions {
+        path_remaps: vec![("docs".to_string(), "../share".to_string())],
+        ..Default::default()
+    };
+    assert!(escaping.transform_path("docs/readme.md").is_err());
+    assert_eq!(
+        escaping.transform_path("src/main.rs").unwrap().as_deref(),
+        Some("src/main.rs")
+    );
+}
+
+#[test]
+fn escaping_listing_paths() {
+    let listing = |path: &str| ArchivableListing {
+        relative_path: path.into(),
+        permissions: 0o100644,
+        file_size: 7,
+        literal_path: Default::default(),
+    };
+    let content = |_| Ok(b"escaped".to_vec());
+    let options = ArchiveOptions::default();
+    for path in ["../escaped.txt", "dir/../../escaped.txt", "./.."] {
+        let error =
+            write_archive(&[listing(path)], content, &mut Vec::new(), &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = write_stream(&[listing(path)], content, &mut Vec::new(), &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // a crafted archive with such a path is corrupt rather than extracted outside of its directory
+    let escape = |written: &mut Vec<u8>| {
+        let at = written
+            .windows(3)
+            .position(|window| window == b"xx/")
+            .unwrap();
+        written[at..at + 3].copy_from_slice(b"../");
+    };
+    let parent = tempfile::tempdir().unwrap();
+    let destination = parent.path().join("destination");
+
+    let mut archive = Vec::new();
+    write_archive(
+        &[listing("xx/escaped.txt")],
+        content,
+        &mut archive,
+        &options,
+    )
+    .unwrap();
+    escape(&mut archive);
+    let mut header = format::Header::decode(&archive).unwrap();
+    header.checksum = xxhash_rust::xxh3::xxh3_64(&archive[format::Header::CHECKSUMMED_FROM..]);
+    archive[..format::Header::SIZE].copy_from_slice(&header.encode());
+    assert!(matches!(
+        archive_error(&extract_from_reader(&mut archive.as_slice()).unwrap_err()),
+        Some(ArchiveError::Corrupt(_))
+    ));
+    assert!(matches!(
+        archive_error(
+            &extract_sequentially(
+                &mut archive.as_slice(),
+                &destination,
+                &Limits::default(),
+                &ExtractOptions::default(),
+            )
+            .unwrap_err()
+        ),
+        Some(ArchiveError::Corrupt(_))
+    ));
+
+    let mut stream = Vec::new();
+    write_stream(&[listing("xx/escaped.txt")], content, &mut stream, &options).unwrap();
+    escape(&mut stream);
+    assert!(matches!(
+        archive_error(
+            &extract_stream(
+                &mut stream.as_slice(),
+                &destination,
+                &Limits::default(),
+                &ExtractOptions::default(),
+            )
+            .unwrap_err()
+        ),
+        Some(ArchiveError::Corrupt(_))
+    ));
+    assert!(!parent.path().join("escaped.txt").exists());
+}
+
+#[test]
+fn archive_from_paths() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let archive = create_archive_from_paths(
+        [
+            "small.txt",
+            "./dir/lipsum.txt",
+            "dir/lipsum.txt",
+            "dir",
+            "empty",
+        ],
+        source.path(),
+    )
+    .unwrap();
+    let mut paths: Vec<&str> = archive
+        .listings
+        .iter()
+        .map(|listing| &*listing.relative_path)
+        .collect();
+    paths.sort();
+    assert_eq!(paths, ["dir/lipsum.txt", "empty", "small.txt"]);
+
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("outside.txt"), b"outside").unwrap();
+    assert!(
+        create_archive_from_paths([outside.path().join("outside.txt")], source.path()).is_err()
+    );
+}
+
+#[test]
+fn archive_from_inputs() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let other = tempfile::tempdir().unwrap();
+    fs::create_dir(other.path().join("empty")).unwrap();
+    fs::create_dir(other.path().join("bare")).unwrap();
+
+    let archive = create_archive_from_inputs([
+        source.path().join("dir"),
+        source.path().join("small.txt"),
+        other.path().join("bare"),
+    ])
+    .unwrap();
+    let mut paths: Vec<&str> = archive
+        .listings
+        .iter()
+        .map(|listing| &*listing.relative_path)
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "bare",
+            "dir/lipsum.txt",
+            "dir/subdir/zeros.bin",
+            "small.txt"
+        ]
+    );
+
+    let mut written = Vec::new();
+    archive.archive_to_writer(&mut written).unwrap();
+    let extracted = extract_from_reader(&mut written.as_slice()).unwrap();
+    assert_eq!(
+        extracted.file_bytes("small.txt"),
+        Some(&b"hello decaf\n"[..])
+    );
+
+    // inputs with the same name would be merged into one
+    let error =
+        create_archive_from_inputs([source.path().join("empty"), other.path().join("empty")])
+            .err()
+            .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn permission_normalization() {
+    assert_eq!(PermissionMode::ExecutableOnly.apply(0o100600), 0o100644);
+    assert_eq!(PermissionMode::ExecutableOnly.apply(0o100700), 0o100755);
+    assert_eq!(PermissionMode::ExecutableOnly.apply(0o040700), 0o040755);
+    let fixed = PermissionMode::Fixed {
+        file: 0o600,
+        directory: 0o700,
+    };
+    assert_eq!(fixed.apply(0o100755), 0o100600);
+    assert_eq!(fixed.apply(0o040755), 0o040700);
+
+    // the same tree under different umasks produces the same archive
+    let options = ArchiveOptions {
+        permissions: PermissionMode::ExecutableOnly,
+        ..Default::default()
+    };
+    let mut archives = Vec::new();
+    for mode in [0o600, 0o664] {
+        let source = tempfile::tempdir().unwrap();
+        write_test_tree(source.path());
+        fs::set_permissions(
+            source.path().join("small.txt"),
+            fs::Permissions::from_mode(mode),
+        )
+        .unwrap();
+        let mut archive = Vec::new();
+        create_archive_from_directory(source.path())
+            .unwrap()
+            .archive_to_writer_with_options(&mut archive, &options)
+            .unwrap();
+        archives.push(archive);
+    }
+    assert_eq!(archives[0], archives[1]);
+}
+
+#[test]
+fn top_level_directory_prefix() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let options = ArchiveOptions {
+        path_prefix: Some(top_level_directory_name(source.path()).unwrap()),
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+
+    let prefix = format!("{}/", options.path_prefix.unwrap());
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert!(extracted
+        .listings
+        .iter()
+        .all(|listing| listing.path.starts_with(&prefix)));
+}
+
+#[test]
+fn sort_strategies() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("b.txt"), b"bb").unwrap();
+    fs::write(source.path().join("a.rs"), b"aaaa").unwrap();
+    fs::write(source.path().join("c.rs"), b"c").unwrap();
+
+    let paths_for = |sort: SortStrategy| {
+        let mut archive = Vec::new();
+        create_archive_from_paths(["b.txt", "a.rs", "c.rs"], source.path())
+            .unwrap()
+            .archive_to_writer_with_options(
+                &mut archive,
+                &ArchiveOptions {
+                    sort,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .listings
+            .iter()
+            .map(|listing| listing.path.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(paths_for(SortStrategy::Size), ["c.rs", "b.txt", "a.rs"]);
+    assert_eq!(paths_for(SortStrategy::Path), ["a.rs", "b.txt", "c.rs"]);
+    assert_eq!(
+        paths_for(SortStrategy::Extension),
+        ["c.rs", "a.rs", "b.txt"]
+    );
+    assert_eq!(
+        paths_for(SortStrategy::WalkOrder),
+        ["b.txt", "a.rs", "c.rs"]
+    );
+}
+
+#[test]
+fn borrowed_file_bytes() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    assert_eq!(
+        extracted.file_bytes("small.txt"),
+        Some(&b"hello decaf\n"[..])
+    );
+    assert_eq!(
+        extracted.file_bytes("dir/subdir/zeros.bin"),
+        Some(&[0u8; 64 * 1024][..])
+    );
+    assert_eq!(extracted.file_bytes("missing.txt"), None);
+}
+
+#[test]
+fn legacy_revisions() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut current = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut current)
+        .unwrap();
+    assert_eq!(
+        extract_from_reader(&mut current.as_slice())
+            .unwrap()
+            .revision(),
+        FormatRevision::Current
+    );
+
+    // a legacy archive has the `notdecaf` magic number and a CRC-32 archive checksum
+    let mut legacy = current.clone();
+    legacy[0..8].copy_from_slice(b"notdecaf");
+    let checksum = crc32fast::hash(&legacy[16..]) as u64;
+    legacy[8..16].copy_from_slice(&checksum.to_le_bytes());
+
+    let extracted = extract_from_reader(&mut legacy.as_slice()).unwrap();
+    assert_eq!(extracted.revision(), FormatRevision::LegacyCrc32);
+    assert_eq!(
+        extracted.file_bytes("small.txt"),
+        Some(&b"hello decaf\n"[..])
+    );
+
+    let mut upgraded = Vec::new();
+    upgrade(&mut legacy.as_slice(), &mut upgraded).unwrap();
+    assert_eq!(upgraded, current);
+}
+
+#[test]
+fn rate_limited_archiving() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    // the test tree holds a little over 100 KiB of content
+    let options = ArchiveOptions {
+        rate_limit: Some(512 * 1024),
+        ..Default::default()
+    };
+    let start = std::time::Instant::now();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut Vec::new(), &options)
+        .unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(180));
+}
+
+#[test]
+fn seekable_reader() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert!(reader.bundles().len() > 1);
+    assert_eq!(
+        reader.read_file("small.txt").unwrap(),
+        Some(b"hello decaf\n".to_vec())
+    );
+    assert_eq!(reader.read_file("missing.txt").unwrap(), None);
+
+    let subset = reader
+        .extract_matching(|listing| listing.path.starts_with("dir/"))
+        .unwrap();
+    let output = tempfile::tempdir().unwrap();
+    subset.create_all_files(output.path()).unwrap();
+    assert_eq!(
+        fs::read(output.path().join("dir/subdir/zeros.bin")).unwrap(),
+        vec![0u8; 64 * 1024]
+    );
+    assert!(!output.path().join("small.txt").exists());
+
+    // a corrupted bundle is only noticed when it is read
+    let small_bundle = reader.bundles()[reader
+        .listings()
+        .iter()
+        .find(|listing| &*listing.path == "small.txt")
+        .unwrap()
+        .bundle_idx];
+    archive[small_bundle.offset as usize + small_bundle.compressed_size as usize - 1] ^= 0xff;
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert!(reader.read_file("small.txt").is_err());
+}
+
+#[test]
+fn archive_handle() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    let bundle_count = reader.bundles().len();
+    let mut handle = ArchiveHandle::with_cache(reader, 2, 64 * 1024);
+    assert_eq!(
+        handle.read_file("small.txt").unwrap().unwrap(),
+        b"hello decaf\n"
+    );
+    assert_eq!(handle.read_file("empty").unwrap().unwrap(), b"");
+    assert!(handle.read_file("missing").unwrap().is_none());
+    let lipsum = handle.find("dir/lipsum.txt").unwrap().clone();
+    assert_eq!(handle.read_range(&lipsum, 6..11).unwrap(), b"ipsum");
+    assert_eq!(
+        handle
+            .read_range(&lipsum, lipsum.filesize - 6..u64::MAX)
+            .unwrap(),
+        b"ipsum "
+    );
+
+    // a cached bundle is the same one as before, and the cache stays within its budget
+    let first = handle.bundle(lipsum.bundle_idx).unwrap();
+    assert!(std::sync::Arc::ptr_eq(
+        &first,
+        &handle.bundle(lipsum.bundle_idx).unwrap()
+    ));
+    for index in 0..bundle_count {
+        handle.bundle(index).unwrap();
+        assert!(handle.cached_bytes() <= 64 * 1024);
+    }
+    assert!(handle.bundle(bundle_count).is_err());
+}
+
+#[test]
+fn archive_layout() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    // a corrupted bundle doesn't matter, as no bundle is decompressed
+    let last = archive.len() - 1;
+    archive[last] ^= 0xff;
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    assert_eq!(layout.bundles, written.bundles);
+    let listing_count = layout.header.listing_count;
+    assert_eq!(layout.listings.len(), listing_count as usize);
+    let first = layout.bundle_range(0).unwrap();
+    assert_eq!(
+        &archive[first.start as usize..first.start as usize + 4],
+        b"\x28\xb5\x2f\xfd"
+    );
+    assert!(layout.bundle_range(layout.bundles.len()).is_none());
+    for (index, bundle) in layout.bundles.iter().enumerate() {
+        let contained: Vec<_> = layout.listings_in_bundle(index).collect();
+        assert!(!contained.is_empty());
+        assert!(contained
+            .iter()
+            .all(|listing| listing.bundle_offset as u64 + listing.filesize
+                <= bundle.uncompressed_size));
+    }
+
+    assert!(ArchiveLayout::read(&mut &archive[..60]).is_err());
+}
+
+#[test]
+fn bundle_primitives() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("small.txt"), b"hello decaf\n").unwrap();
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    // a bundle compresses to exactly the bytes an archive holds, with the checksum of its hash
+    let bundle = Bundle::new(b"hello decaf\n".to_vec());
+    let info = ArchiveLayout::read(&mut archive.as_slice())
+        .unwrap()
+        .bundles[0];
+    assert_eq!(bundle.checksum(HashAlgorithm::Xxh3), info.checksum);
+    let compressed = bundle.compress(Codec::Zstd, 3).unwrap();
+    assert_eq!(
+        compressed,
+        &archive[info.offset as usize..(info.offset + info.compressed_size) as usize]
+    );
+    let mut crc32_archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut crc32_archive,
+            &ArchiveOptions {
+                hash: HashAlgorithm::Crc32,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let info = ArchiveLayout::read(&mut crc32_archive.as_slice())
+        .unwrap()
+        .bundles[0];
+    assert_eq!(bundle.checksum(HashAlgorithm::Crc32), info.checksum);
+    assert_ne!(info.checksum, bundle.checksum(HashAlgorithm::Xxh3));
+    let compressed = &crc32_archive[info.offset as usize..][..info.compressed_size as usize];
+    assert_eq!(
+        Bundle::decompress(compressed, 12, info.checksum, HashAlgorithm::Crc32).unwrap(),
+        bundle
+    );
+    assert!(Bundle::decompress(compressed, 12, info.checksum, HashAlgorithm::Xxh3).is_err());
+
+    let content = "lorem ipsum ".repeat(4096).into_bytes();
+    let bundle = Bundle::new(content.clone());
+    let seekable = bundle
+        .compress(Codec::SeekableZstd { frame_size: 1024 }, 3)
+        .unwrap();
+    let length = content.len() as u64;
+    for compressed in [bundle.compress(Codec::Zstd, 19).unwrap(), seekable] {
+        let checksum = bundle.checksum(HashAlgorithm::Xxh3);
+        let decompressed =
+            Bundle::decompress(&compressed, length, checksum, HashAlgorithm::Xxh3).unwrap();
+        assert_eq!(decompressed, bundle);
+        assert!(
+            Bundle::decompress(&compressed, length - 1, checksum, HashAlgorithm::Xxh3).is_err()
+        );
+        assert!(
+            Bundle::decompress(&compressed, length, checksum ^ 1, HashAlgorithm::Xxh3).is_err()
+        );
+    }
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn tar_ingestion() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut append = |path: &str, entry_type: tar::EntryType, mode: u32, content: &[u8]| {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content).unwrap();
+    };
+    append("./dir/", tar::EntryType::Directory, 0o755, b"");
+    append(
+        "dir/small.txt",
+        tar::EntryType::Regular,
+        0o640,
+        b"hello decaf\n",
+    );
+    append("empty/", tar::EntryType::Directory, 0o700, b"");
+    append("pipe", tar::EntryType::Fifo, 0o644, b"");
+    let mut link = |path: &str, entry_type: tar::EntryType, target: &str| {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    };
+    link("dir/link.txt", tar::EntryType::Symlink, "small.txt");
+    link("chained.txt", tar::EntryType::Symlink, "dir/link.txt");
+    link("hard.txt", tar::EntryType::Link, "dir/small.txt");
+    link("outside", tar::EntryType::Symlink, "../small.txt");
+    link("absolute", tar::EntryType::Symlink, "/etc/hostname");
+    let tarball = builder.into_inner().unwrap();
+
+    let mut archive = Vec::new();
+    let written = from_tar(tarball.as_slice(), &mut archive, &ArchiveOptions::default()).unwrap();
+    let warnings: Vec<String> = written
+        .stats
+        .warnings
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            "skipped pipe: it is not a regular file, directory or link",
+            "skipped outside: it links outside of the archived tree",
+            "skipped absolute: it links outside of the archived tree",
+        ]
+    );
+
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let mut listings: Vec<(&str, u32)> = extracted
+        .listings
+        .iter()
+        .map(|listing| (&*listing.path, listing.permissions))
+        .collect();
+    listings.sort();
+    assert_eq!(
+        listings,
+        [
+            ("chained.txt", 0o120777),
+            ("dir/link.txt", 0o120777),
+            ("dir/small.txt", 0o100640),
+            ("empty", 0o040700),
+            ("hard.txt", 0o100777),
+        ]
+    );
+    for path in ["chained.txt", "dir/link.txt", "dir/small.txt", "hard.txt"] {
+        assert_eq!(extracted.file_bytes(path), Some(&b"hello decaf\n"[..]));
+    }
+
+    // gzipped tarballs are recognized by their magic number
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut encoder, &tarball).unwrap();
+    let tar_gz = encoder.finish().unwrap();
+    let mut from_gzip = Vec::new();
+    from_tar(
+        tar_gz.as_slice(),
+        &mut from_gzip,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(from_gzip, archive);
+
+    // entries can't escape the archive
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_mode(0o644);
+    header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+    header.set_cksum();
+    builder.append(&header, &[][..]).unwrap();
+    let tarball = builder.into_inner().unwrap();
+    assert!(from_tar(
+        tarball.as_slice(),
+        &mut Vec::new(),
+        &ArchiveOptions::default()
+    )
+    .is_err());
+
+    // the content of every entry counts against the limits, however large its entry claims to be
+    let limited = ArchiveOptions {
+        limits: Limits {
+            max_uncompressed_size: Some(11),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    for to_stream in [false, true] {
+        let convert = if to_stream {
+            from_tar_to_stream
+        } else {
+            from_tar
+        };
+        let error = convert(tar_gz.as_slice(), &mut Vec::new(), &limited).unwrap_err();
+        assert!(matches!(
+            archive_error(&error),
+            Some(ArchiveError::LimitExceeded(_))
+        ));
+    }
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(1 << 60);
+    header.set_mode(0o644);
+    header.set_path("huge").unwrap();
+    header.set_cksum();
+    builder.append(&header, &b"not quite"[..]).unwrap();
+    let tarball = builder.into_inner().unwrap();
+    assert!(from_tar(tarball.as_slice(), &mut Vec::new(), &limited).is_err());
+    assert!(from_tar(
+        tarball.as_slice(),
+        &mut Vec::new(),
+        &ArchiveOptions::default()
+    )
+    .is_err());
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn zip_conversions() {
+    use zip::write::SimpleFileOptions;
+
+    let mut zip_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let unix = SimpleFileOptions::default();
+    zip_writer
+        .start_file("bin/run.sh", unix.unix_permissions(0o750))
+        .unwrap();
+    std::io::Write::write_all(&mut zip_writer, b"#!/bin/sh\n").unwrap();
+    zip_writer.add_symlink("bin/run", "run.sh", unix).unwrap();
+    zip_writer.add_symlink("escape", "../run.sh", unix).unwrap();
+    // entries written on Windows carry no permissions, only whether they are read-only
+    let dos = SimpleFileOptions::default()
+        .system(zip::System::Dos)
+        .external_attributes(0x01);
+    zip_writer.start_file("readme.txt", dos).unwrap();
+    std::io::Write::write_all(&mut zip_writer, b"read only\n").unwrap();
+    zip_writer
+        .add_directory("empty/", dos.external_attributes(0x10))
+        .unwrap();
+    let zipped = zip_writer.finish().unwrap().into_inner();
+
+    let mut archive = Vec::new();
+    let written = from_zip(
+        std::io::Cursor::new(&zipped),
+        &mut archive,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        written.stats.warnings,
+        [Warning::SkippedSymlink {
+            path: "escape".to_string()
+        }]
+    );
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let mut listings: Vec<(&str, u32)> = extracted
+        .listings
+        .iter()
+        .map(|listing| (&*listing.path, listing.permissions))
+        .collect();
+    listings.sort();
+    assert_eq!(
+        listings,
+        [
+            ("bin/run", 0o120777),
+            ("bin/run.sh", 0o100750),
+            ("empty", 0o040755),
+            ("readme.txt", 0o100444),
+        ]
+    );
+    assert_eq!(extracted.file_bytes("bin/run"), Some(&b"#!/bin/sh\n"[..]));
+
+    // archives without links survive a round trip through zip
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut original = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut original)
+        .unwrap();
+    let mut zipped = std::io::Cursor::new(Vec::new());
+    to_zip(&mut original.as_slice(), &mut zipped).unwrap();
+    let mut again = std::io::Cursor::new(Vec::new());
+    to_zip(&mut original.as_slice(), &mut again).unwrap();
+    assert_eq!(zipped, again);
+    let mut round_tripped = Vec::new();
+    zipped.set_position(0);
+    from_zip(zipped, &mut round_tripped, &ArchiveOptions::default()).unwrap();
+    assert_eq!(round_tripped, original);
+}
+
+#[test]
+fn zstd_frame_checksums() {
+    let content = "lorem ipsum ".repeat(4096).into_bytes();
+    let bundle = Bundle::new(content.clone());
+    let length = content.len() as u64;
+
+    // plain frames know neither their size nor their checksum
+    let plain = bundle.compress(Codec::Zstd, 3).unwrap();
+    assert_eq!(zstd_safe::get_frame_content_size(&plain).ok(), Some(None));
+    assert_eq!(plain[4] & 0b100, 0);
+
+    let checked = bundle
+        .compress_with_frame_checksums(Codec::Zstd, 3)
+        .unwrap();
+    assert_eq!(
+        zstd_safe::get_frame_content_size(&checked).ok(),
+        Some(Some(length))
+    );
+    assert_eq!(checked[4] & 0b100, 0b100);
+    assert_eq!(zstd::decode_all(checked.as_slice()).unwrap(), content);
+    let checksum = bundle.checksum(HashAlgorithm::Xxh3);
+    let decompressed = Bundle::decompress(&checked, length, checksum, HashAlgorithm::Xxh3).unwrap();
+    assert_eq!(decompressed, bundle);
+    // zstd alone catches a mismatching checksum
+    let mut corrupt = checked.clone();
+    *corrupt.last_mut().unwrap() ^= 1;
+    assert!(zstd::decode_all(corrupt.as_slice()).is_err());
+    assert!(Bundle::decompress(&corrupt, length, checksum, HashAlgorithm::Xxh3).is_err());
+
+    // every frame of a seekable bundle is checked on its own
+    let seekable = bundle
+        .compress_with_frame_checksums(Codec::SeekableZstd { frame_size: 1024 }, 3)
+        .unwrap();
+    assert_eq!(
+        zstd_safe::get_frame_content_size(&seekable).ok(),
+        Some(Some(1024))
+    );
+    assert_eq!(zstd::decode_all(seekable.as_slice()).unwrap(), content);
+
+    // archives written with the option extract like any other
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let options = ArchiveOptions::builder()
+        .zstd_frame_checksums(true)
+        .build()
+        .unwrap();
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    for info in ArchiveLayout::read(&mut archive.as_slice())
+        .unwrap()
+        .bundles
+    {
+        let compressed =
+            &archive[info.offset as usize..(info.offset + info.compressed_size) as usize];
+        assert_eq!(
+            zstd_safe::get_frame_content_size(compressed).ok(),
+            Some(Some(info.uncompressed_size))
+        );
+    }
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(
+        extracted.file_bytes("small.txt"),
+        Some(&b"hello decaf\n"[..])
+    );
+}
+
+#[test]
+fn path_index() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    let encoded = layout.index().encode();
+    let index = ArchiveIndex::decode(&encoded).unwrap();
+    let archive_checksum = layout.header.checksum;
+    assert_eq!(index.archive_checksum, archive_checksum);
+    assert_eq!(index.bundles, layout.bundles);
+    assert_eq!(index.listings.len(), layout.listings.len());
+    assert!(index
+        .listings
+        .windows(2)
+        .all(|pair| pair[0].path <= pair[1].path));
+
+    // a file is read from its bundle alone
+    let listing = index.find("dir/lipsum.txt").unwrap();
+    let range = index.bundle_range(listing.bundle_idx).unwrap();
+    let bundle = &archive[range.start as usize..range.end as usize];
+    assert_eq!(
+        index.read_content(listing, bundle).unwrap(),
+        "lorem ipsum ".repeat(4096).as_bytes()
+    );
+    assert!(index.find("empty").unwrap().is_metadata_only());
+    assert!(index.find("dir/missing.txt").is_none());
+
+    let mut corrupted = encoded.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    assert!(ArchiveIndex::decode(&corrupted).is_err());
+    assert!(ArchiveIndex::decode(&encoded[..20]).is_err());
+    assert!(ArchiveIndex::decode(&archive).is_err());
+}
+
+#[test]
+fn empty_archive() {
+    let source = tempfile::tempdir().unwrap();
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &ArchiveOptions::default())
+        .unwrap();
+    assert_eq!(written.bytes_written, 40);
+    assert!(written.bundles.is_empty());
+
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert!(extracted.listings.is_empty());
+    let output = tempfile::tempdir().unwrap();
+    let output_path = output.path().join("extracted");
+    let stats = extracted
+        .create_all_files_with_options(&output_path, &ExtractOptions::default())
+        .unwrap();
+    assert_eq!(stats.file_count, 0);
+    assert_eq!(fs::read_dir(output_path).unwrap().count(), 0);
+
+    let reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert!(reader.listings().is_empty());
+}
+
+#[test]
+fn extraction_preflight() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    let output = tempfile::tempdir().unwrap();
+    let report = extracted
+        .preflight(output.path(), &ExtractOptions::default())
+        .unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.required_bytes, 12 + 12 * 4096 + 64 * 1024);
+
+    // an existing file and a file in place of a directory to be created
+    fs::write(output.path().join("small.txt"), b"existing").unwrap();
+    fs::write(output.path().join("dir"), b"not a directory").unwrap();
+    let report = extracted
+        .preflight(output.path(), &ExtractOptions::default())
+        .unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(
+        report.collisions,
+        [output.path().join("dir"), output.path().join("small.txt")]
+    );
+    assert_eq!(
+        fs::read(output.path().join("small.txt")).unwrap(),
+        b"existing"
+    );
+}
+
+#[test]
+fn format_layout() {
+    // the sizes are fixed by the specification
+    assert_eq!(format::Header::SIZE, 40);
+    assert_eq!(format::ListingEntry::SIZE, 44);
+    assert_eq!(format::BundleEntry::SIZE, 24);
+    assert_eq!(format::FrameHeader::SIZE, 40);
+
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    let header = format::Header::decode(&archive).unwrap();
+    assert_eq!(header.magic, format::MAGIC_NUMBER);
+    assert_eq!(header.encode(), archive[..format::Header::SIZE]);
+
+    let listing_block = &archive[format::Header::SIZE..];
+    let (entry, path) = format::ListingEntry::decode(listing_block).unwrap();
+    let mut encoded = Vec::new();
+    entry.encode(path, &mut encoded);
+    assert_eq!(encoded, listing_block[..encoded.len()]);
+
+    let bundle_table = &archive[format::Header::SIZE + header.listing_block_length as usize..];
+    let bundle = format::BundleEntry::decode(bundle_table).unwrap();
+    assert_eq!(bundle.encode(), bundle_table[..format::BundleEntry::SIZE]);
+    assert!(format::BundleEntry::decode(&bundle_table[..8]).is_err());
+
+    // a listing whose length runs past the listing block is corrupt rather than a panic
+    let mut overlong = encoded.clone();
+    overlong[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(format::ListingEntry::decode(&overlong).is_err());
+    assert!(format::ListingEntry::decode(&encoded[..format::ListingEntry::SIZE - 1]).is_err());
+
+    let mut reader = format::Reader::new(&archive[..12], "truncated");
+    assert_eq!(reader.read_array().unwrap(), format::MAGIC_NUMBER);
+    assert_eq!(reader.offset(), 8);
+    assert!(reader.read_u64_le().is_err());
+    assert_eq!(reader.offset(), 8);
+    assert_eq!(reader.read_u32_le().unwrap(), header.checksum as u32);
+    assert!(reader.remaining().is_empty());
+}
+
+#[test]
+fn deterministic_tie_breaking() {
+    // same sizes, path lengths and permissions, so only the paths tell the listings apart
+    let source = tempfile::tempdir().unwrap();
+    for name in ["b.txt", "a.txt", "c.txt"] {
+        fs::write(source.path().join(name), b"same").unwrap();
+    }
+
+    let archive_of = |paths: [&str; 3]| {
+        let mut archive = Vec::new();
+        create_archive_from_paths(paths, source.path())
+            .unwrap()
+            .archive_to_writer(&mut archive)
+            .unwrap();
+        archive
+    };
+    let archive = archive_of(["b.txt", "a.txt", "c.txt"]);
+    assert_eq!(archive, archive_of(["c.txt", "b.txt", "a.txt"]));
+    assert_eq!(
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .listings
+            .iter()
+            .map(|listing| listing.path.to_string())
+            .collect::<Vec<_>>(),
+        ["a.txt", "b.txt", "c.txt"]
+    );
+
+    let mut walked = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut walked)
+        .unwrap();
+    assert_eq!(walked, archive);
+}
+
+#[test]
+fn directories_without_archived_entries_are_bare() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    // relative links are skipped, which leaves nothing to archive in `links`
+    fs::create_dir(source.path().join("links")).unwrap();
+    std::os::unix::fs::symlink("../small.txt", source.path().join("links/small.txt")).unwrap();
+
+    let archive = create_archive_from_directory(source.path()).unwrap();
+    let bare_directories: Vec<&str> = archive
+        .listings
+        .iter()
+        .filter(|listing| listing.permissions & 0o040000 == 0o040000)
+        .map(|listing| &*listing.relative_path)
+        .collect();
+    assert_eq!(bare_directories, ["empty", "links"]);
+}
+
+#[test]
+fn read_error_policies() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    // removing a file after indexing makes reading it fail, like a file without read permission
+    fs::remove_file(source.path().join("small.txt")).unwrap();
+
+    assert!(archivable.archive_to_writer(&mut Vec::new()).is_err());
+
+    let archive_with = |read_errors: ReadErrorPolicy| {
+        let mut archive = Vec::new();
+        let written = archivable
+            .archive_to_writer_with_options(
+                &mut archive,
+                &ArchiveOptions {
+                    read_errors,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(written.stats.warnings.len(), 1);
+        assert!(matches!(
+            &written.stats.warnings[0],
+            Warning::UnreadableFile { path, .. } if path == "small.txt"
+        ));
+        extract_from_reader(&mut archive.as_slice()).unwrap()
+    };
+
+    let skipped = archive_with(ReadErrorPolicy::Skip);
+    assert_eq!(skipped.file_bytes("small.txt"), None);
+    assert_eq!(skipped.listings.len(), archivable.listings.len() - 1);
+
+    let emptied = archive_with(ReadErrorPolicy::ReplaceWithEmpty);
+    assert_eq!(emptied.file_bytes("small.txt"), Some(&b""[..]));
+    assert_eq!(emptied.listings.len(), archivable.listings.len());
+}
+
+#[test]
+fn archive_limits() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut archive = Vec::new();
+    let written = archivable
+        .archive_to_writer_with_options(&mut archive, &ArchiveOptions::default())
+        .unwrap();
+    let uncompressed_size = written.stats.uncompressed_bytes;
+
+    let exceeded = [
+        Limits {
+            max_uncompressed_size: Some(uncompressed_size - 1),
+            ..Default::default()
+        },
+        Limits {
+            max_listing_count: Some(archivable.listings.len() as u64 - 1),
+            ..Default::default()
+        },
+        Limits {
+            max_path_length: Some("dir/subdir/zeros.bin".len() as u64 - 1),
+            ..Default::default()
+        },
+    ];
+    for limits in exceeded {
+        let error = archivable
+            .archive_to_writer_with_options(
+                &mut Vec::new(),
+                &ArchiveOptions {
+                    limits,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert!(
+            matches!(archive_error(&error), Some(ArchiveError::LimitExceeded(_))),
+            "{:?}",
+            limits
+        );
+
+        let error = ExtractedArchive::from_reader_with_limits(&mut archive.as_slice(), &limits)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(archive_error(&error), Some(ArchiveError::LimitExceeded(_))),
+            "{:?}",
+            limits
+        );
+        let error = SeekableArchiveReader::with_limits(std::io::Cursor::new(&archive), limits)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(archive_error(&error), Some(ArchiveError::LimitExceeded(_))),
+            "{:?}",
+            limits
+        );
+    }
+
+    let exact = Limits {
+        max_uncompressed_size: Some(uncompressed_size),
+        max_listing_count: Some(archivable.listings.len() as u64),
+        max_bundle_count: Some(1),
+        max_path_length: Some("dir/subdir/zeros.bin".len() as u64),
+    };
+    ExtractedArchive::from_reader_with_limits(&mut archive.as_slice(), &exact).unwrap();
+}
+
+#[test]
+fn decompression_bombs() {
+    let mut bomb = Vec::new();
+    write_archive(
+        &[ArchivableListing {
+            relative_path: "bomb".into(),
+            permissions: 0o100644,
+            file_size: 0,
+            literal_path: "".into(),
+        }],
+        |_| Ok(vec![0u8; 1024 * 1024]),
+        &mut bomb,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+
+    // claim that the bundle only holds a single byte, keeping the archive checksum valid
+    let size_offset = format::Header::SIZE + format::ListingEntry::FIELDS[3].offset;
+    bomb[size_offset..size_offset + 8].copy_from_slice(&1u64.to_le_bytes());
+    let mut header = format::Header::decode(&bomb).unwrap();
+    header.checksum = xxhash_rust::xxh3::xxh3_64(&bomb[format::Header::CHECKSUMMED_FROM..]);
+    bomb[..format::Header::SIZE].copy_from_slice(&header.encode());
+
+    assert!(matches!(
+        archive_error(&extract_from_reader(&mut bomb.as_slice()).err().unwrap()),
+        Some(ArchiveError::Corrupt(_))
+    ));
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&bomb)).unwrap();
+    assert_eq!(reader.bundles()[0].uncompressed_size, 1);
+    assert!(matches!(
+        archive_error(&reader.read_bundle(0).unwrap_err()),
+        Some(ArchiveError::Corrupt(_))
+    ));
+
+    // listings pointing outside of the bundles are rejected before anything is decompressed
+    let index_offset = format::Header::SIZE + format::ListingEntry::FIELDS[1].offset;
+    bomb[index_offset..index_offset + 8].copy_from_slice(&7u64.to_le_bytes());
+    assert!(matches!(
+        archive_error(
+            &SeekableArchiveReader::new(std::io::Cursor::new(&bomb))
+                .err()
+                .unwrap()
+        ),
+        Some(ArchiveError::Corrupt(_))
+    ));
+}
+
+#[test]
+fn out_of_bounds_fields() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let header = format::Header::decode(&archive).unwrap();
+    let bundle_table = format::Header::SIZE + header.listing_block_length as usize;
+    // the first listing with content, as the bundle fields of bare directories are ignored
+    let mut file_listing = format::Header::SIZE;
+    loop {
+        let (entry, _) = format::ListingEntry::decode(&archive[file_listing..]).unwrap();
+        if entry.permissions & 0o040000 == 0 {
+            break;
+        }
+        file_listing += entry.total_length as usize;
+    }
+
+    // (offset of the patched field, field name expected in the error)
+    let patches = [
+        (format::Header::FIELDS[2].offset, "listing_block_length"),
+        (format::Header::FIELDS[3].offset, "listing_count"),
+        (format::Header::FIELDS[4].offset, "bundle_count"),
+        (
+            bundle_table + format::BundleEntry::FIELDS[0].offset,
+            "offset",
+        ),
+        (
+            bundle_table + format::BundleEntry::FIELDS[1].offset,
+            "compressed_size",
+        ),
+        (
+            file_listing + format::ListingEntry::FIELDS[1].offset,
+            "bundle_index",
+        ),
+    ];
+    for (offset, field) in patches {
+        let mut patched = archive.clone();
+        patched[offset..offset + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        let mut header = format::Header::decode(&patched).unwrap();
+        header.checksum = xxhash_rust::xxh3::xxh3_64(&patched[format::Header::CHECKSUMMED_FROM..]);
+        patched[..format::Header::SIZE].copy_from_slice(&header.encode());
+
+        let errors = [
+            extract_from_reader(&mut patched.as_slice()).err().unwrap(),
+            SeekableArchiveReader::new(std::io::Cursor::new(&patched))
+                .err()
+                .unwrap(),
+        ];
+        for error in errors {
+            let message = error.to_string();
+            assert!(
+                matches!(archive_error(&error), Some(ArchiveError::Corrupt(_))),
+                "{}",
+                message
+            );
+            assert!(message.contains(field), "{}", message);
+        }
+    }
+}
+
+#[test]
+fn metadata_only_listings() {
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("empty")).unwrap();
+    fs::write(source.path().join("marker"), b"").unwrap();
+    fs::write(source.path().join(".keep"), b"").unwrap();
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &ArchiveOptions::default())
+        .unwrap();
+    assert!(written.bundles.is_empty());
+    assert_eq!(written.stats.file_count, 2);
+
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    assert!(layout
+        .listings
+        .iter()
+        .all(|listing| listing.is_metadata_only()
+            && listing.bundle_idx == 0
+            && listing.content_checksum == 0));
+
+    // empty files next to content neither take bundle space nor need a bundle to be read
+    fs::write(source.path().join("small.txt"), b"hello decaf\n").unwrap();
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    assert_eq!(written, archive.len());
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(extracted.bundles().len(), 1);
+    assert_eq!(extracted.bundles()[0].uncompressed_size, 12);
+    assert_eq!(extracted.file_bytes("marker"), Some(&b""[..]));
+
+    let (output, stats) = extracted.extract_to_temp().unwrap();
+    assert_eq!(stats.file_count, 3);
+    assert_eq!(fs::read(output.path().join(".keep")).unwrap(), b"");
+    assert!(output.path().join("empty").is_dir());
+
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(reader.read_file("marker").unwrap(), Some(Vec::new()));
+}
+
+#[test]
+fn verify_levels() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    // break the checksum of the first listing with content, keeping the archive checksum valid
+    let mut file_listing = format::Header::SIZE;
+    loop {
+        let (entry, _) = format::ListingEntry::decode(&archive[file_listing..]).unwrap();
+        if entry.size > 0 {
+            break;
+        }
+        file_listing += entry.total_length as usize;
+    }
+    let checksum_offset = file_listing + format::ListingEntry::FIELDS[5].offset;
+    archive[checksum_offset] ^= 0xff;
+    let mut header = format::Header::decode(&archive).unwrap();
+    header.checksum = xxhash_rust::xxh3::xxh3_64(&archive[format::Header::CHECKSUMMED_FROM..]);
+    archive[..format::Header::SIZE].copy_from_slice(&header.encode());
+
+    let extract_with = |mut archive: &[u8], verify: VerifyLevel| {
+        let extracted = ExtractedArchive::from_reader_with_verify_level(
+            &mut archive,
+            &Limits::default(),
+            verify,
+        )?;
+        let output = tempfile::tempdir().unwrap();
+        extracted.create_all_files_with_options(
+            output.path(),
+            &ExtractOptions {
+                verify,
+                ..Default::default()
+            },
+        )
+    };
+    assert!(extract_with(&archive, VerifyLevel::None).is_ok());
+    assert!(extract_with(&archive, VerifyLevel::Bundles).is_ok());
+    assert!(extract_with(&archive, VerifyLevel::Files).is_err());
+    assert!(extract_with(&archive, VerifyLevel::Paranoid).is_err());
+
+    // a corrupt archive checksum is only ignored without verification
+    archive[8] ^= 0xff;
+    assert!(extract_with(&archive, VerifyLevel::None).is_ok());
+    assert!(extract_with(&archive, VerifyLevel::Bundles).is_err());
+}
+
+#[test]
+fn extraction_replaces_longer_files() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    // files are sized before they are written, which must not keep anything of what was there
+    let output = tempfile::tempdir().unwrap();
+    fs::write(output.path().join("small.txt"), vec![b'x'; 4096]).unwrap();
+    extract_from_reader(&mut archive.as_slice())
+        .unwrap()
+        .create_all_files(output.path())
+        .unwrap();
+    assert_eq!(
+        fs::read(output.path().join("small.txt")).unwrap(),
+        b"hello decaf\n"
+    );
+    assert_eq!(
+        fs::metadata(output.path().join("dir/lipsum.txt"))
+            .unwrap()
+            .len(),
+        12 * 4096
+    );
+}
+
+#[test]
+fn duplicate_files_are_copied() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::write(source.path().join("copy.txt"), b"hello decaf\n").unwrap();
+    fs::write(source.path().join("dir/copy.bin"), vec![0u8; 64 * 1024]).unwrap();
+    fs::set_permissions(
+        source.path().join("copy.txt"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    for verify in [VerifyLevel::Files, VerifyLevel::Paranoid] {
+        let output = tempfile::tempdir().unwrap();
+        let stats = extracted
+            .create_all_files_with_options(
+                output.path(),
+                &ExtractOptions {
+                    verify,
+                    threads: Some(1),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(stats.file_count, 5);
+        assert_eq!(
+            fs::read(output.path().join("copy.txt")).unwrap(),
+            b"hello decaf\n"
+        );
+        assert_eq!(
+            fs::metadata(output.path().join("copy.txt"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::read(output.path().join("dir/copy.bin")).unwrap(),
+            vec![0u8; 64 * 1024]
+        );
+    }
+}
+
+#[test]
+fn compressed_listings() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    for i in 0..200 {
+        fs::write(
+            source.path().join(format!("dir/subdir/file-{:03}.txt", i)),
+            format!("{}", i),
+        )
+        .unwrap();
+    }
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+    let mut compressed = Vec::new();
+    archivable
+        .archive_to_writer_with_options(
+            &mut compressed,
+            &ArchiveOptions {
+                compress_listings: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let plain_header = format::Header::decode(&plain).unwrap();
+    let header = format::Header::decode(&compressed).unwrap();
+    assert!(!plain_header.has_compressed_listings());
+    assert!(header.has_compressed_listings());
+    assert!(header.stored_listing_block_length() < plain_header.listing_block_length / 4);
+
+    let extracted = extract_from_reader(&mut compressed.as_slice()).unwrap();
+    let expected = extract_from_reader(&mut plain.as_slice()).unwrap();
+    assert_eq!(extracted.listings.len(), expected.listings.len());
+    assert_eq!(
+        extracted.file_bytes("dir/subdir/file-042.txt"),
+        Some(&b"42"[..])
+    );
+
+    let layout = ArchiveLayout::read(&mut compressed.as_slice()).unwrap();
+    assert_eq!(layout.listings.len(), expected.listings.len());
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&compressed)).unwrap();
+    assert_eq!(
+        reader.read_file("small.txt").unwrap(),
+        Some(b"hello decaf\n".to_vec())
+    );
+
+    // repacking without the option writes the archive as if it had never been compressed
+    let mut repacked = Vec::new();
+    repack(
+        &mut compressed.as_slice(),
+        &mut repacked,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(repacked, plain);
+}
+
+#[test]
+fn path_lookup() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    let mut extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(
+        extracted.find("dir/lipsum.txt").unwrap().filesize,
+        12 * 4096
+    );
+    assert!(extracted.find("empty").unwrap().is_metadata_only());
+    assert!(extracted.find("dir").is_none());
+
+    // lookups keep working after the listings are changed
+    extracted.listings.reverse();
+    extracted
+        .listings
+        .retain(|listing| &*listing.path != "empty");
+    assert_eq!(
+        extracted.find("dir/lipsum.txt").unwrap().filesize,
+        12 * 4096
+    );
+    assert!(extracted.find("empty").is_none());
+
+    let reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(reader.find("small.txt").unwrap().filesize, 12);
+}
+
+#[test]
+fn compression_report() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::write(source.path().join("random.bin"), {
+        let mut state = 1u64;
+        (0..16 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<_>>()
+    })
+    .unwrap();
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let report = ArchiveLayout::read(&mut archive.as_slice())
+        .unwrap()
+        .compression_report(2);
+
+    assert_eq!(report.bundles.len(), written.bundles.len());
+    assert_eq!(
+        report
+            .bundles
+            .iter()
+            .map(|bundle| bundle.compressed_bytes)
+            .sum::<u64>(),
+        written.stats.compressed_bytes
+    );
+    let extensions: Vec<&str> = report
+        .extensions
+        .iter()
+        .map(|(extension, _)| extension.as_str())
+        .collect();
+    assert_eq!(extensions, ["bin", "txt"]);
+    assert_eq!(
+        report
+            .extensions
+            .iter()
+            .map(|(_, totals)| totals.uncompressed_bytes)
+            .sum::<u64>(),
+        written.stats.uncompressed_bytes
+    );
+    // the random file barely compresses, so it takes the most space
+    assert_eq!(report.largest.len(), 2);
+    assert_eq!(&*report.largest[0].0, "random.bin");
+    assert!(report.largest[0].1.ratio() < 1.1);
+    assert!(report.largest[0].1.compressed_bytes >= report.largest[1].1.compressed_bytes);
+}
+
+#[test]
+fn compression_threads() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    for i in 0..32 {
+        fs::write(
+            source.path().join(format!("file{}.txt", i)),
+            format!("file {} ", i).repeat(512 * i),
+        )
+        .unwrap();
+    }
+
+    let archive_with_threads = |threads| {
+        let mut archive = Vec::new();
+        let written = create_archive_from_directory(source.path())
+            .unwrap()
+            .archive_to_writer_with_options(
+                &mut archive,
+                &ArchiveOptions {
+                    bundle_size: 1024,
+                    threads: Some(threads),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(written.bundles.len() > 8);
+        archive
+    };
+
+    // bundles are compressed out of order but written in order
+    let archive = archive_with_threads(1);
+    assert_eq!(archive_with_threads(4), archive);
+    assert_eq!(archive_with_threads(16), archive);
+    extract_from_reader(&mut archive.as_slice()).unwrap();
+}
+
+#[test]
+fn structure_only() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::set_permissions(
+        source.path().join("small.txt"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                structure_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(written.stats.file_count, 3);
+    assert_eq!(written.stats.uncompressed_bytes, 0);
+    assert!(written.bundles.is_empty());
+
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert!(extracted
+        .listings
+        .iter()
+        .all(|listing| listing.is_metadata_only()));
+    let destination = tempfile::tempdir().unwrap();
+    extracted.create_all_files(destination.path()).unwrap();
+    for path in ["small.txt", "dir/lipsum.txt", "dir/subdir/zeros.bin"] {
+        assert_eq!(
+            fs::metadata(destination.path().join(path)).unwrap().len(),
+            0
+        );
+    }
+    assert!(destination.path().join("empty").is_dir());
+    assert_eq!(
+        fs::metadata(destination.path().join("small.txt"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn placeholders() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::set_permissions(
+        source.path().join("small.txt"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    // no bundle is decompressed, so a corrupted one doesn't matter
+    let last = archive.len() - 1;
+    archive[last] ^= 0xff;
+
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let stats = layout
+        .create_placeholders(destination.path(), &ExtractOptions::default())
+        .unwrap();
+    assert_eq!((stats.file_count, stats.dir_count), (3, 1));
+    assert_eq!(stats.uncompressed_bytes, 0);
+    for path in ["small.txt", "dir/lipsum.txt", "dir/subdir/zeros.bin"] {
+        assert_eq!(
+            fs::metadata(destination.path().join(path)).unwrap().len(),
+            0
+        );
+    }
+    assert!(destination.path().join("empty").is_dir());
+    assert_eq!(
+        fs::metadata(destination.path().join("small.txt"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn permission_policies() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::set_permissions(
+        source.path().join("small.txt"),
+        fs::Permissions::from_mode(0o700),
+    )
+    .unwrap();
+
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let mode_with_policy = |permission_policy| {
+        let destination = tempfile::tempdir().unwrap();
+        let stats = extracted
+            .create_all_files_with_options(
+                destination.path(),
+                &ExtractOptions {
+                    permission_policy,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(stats.warnings.is_empty());
+        fs::metadata(destination.path().join("small.txt"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+
+    assert_eq!(mode_with_policy(PermissionPolicy::Strict), 0o700);
+    assert_eq!(mode_with_policy(PermissionPolicy::Lenient), 0o700);
+    // new files are never created executable
+    assert_eq!(mode_with_policy(PermissionPolicy::Skip) & 0o111, 0);
+}
+
+#[test]
+fn verify_without_extracting() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+
+    let mut archive = Vec::new();
+    let written = create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert!(written.bundles.len() > 1);
+    let report = verify_archive(&mut archive.as_slice(), None).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(
+        report,
+        VerifyReport {
+            archive_checksum_matches: true,
+            ..Default::default()
+        }
+    );
+
+    // damage the middle of the last bundle, which holds the largest file
+    let last = written.bundles.last().unwrap();
+    archive[(last.offset + last.compressed_size / 2) as usize] ^= 0xff;
+    for threads in [Some(1), None] {
+        let report = verify_archive(&mut archive.as_slice(), threads).unwrap();
+        assert!(!report.is_ok());
+        assert!(!report.archive_checksum_matches);
+        assert_eq!(report.corrupt_bundles, [written.bundles.len() - 1]);
+        assert_eq!(report.corrupt_listings, ["dir/subdir/zeros.bin".into()]);
+    }
+
+    assert!(verify_archive(&mut &archive[..archive.len() - 1], None).is_err());
+}
+
+#[test]
+fn atomic_archive_files() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let destination = tempfile::tempdir().unwrap();
+    let output = destination.path().join("out.df");
+    fs::write(&output, b"previous archive").unwrap();
+
+    // a failed write leaves the previous archive alone
+    let pre_archive = create_archive_from_directory(source.path()).unwrap();
+    let failing = ArchiveOptions {
+        limits: Limits {
+            max_uncompressed_size: Some(1024),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(pre_archive
+        .archive_to_file_with_options(&output, &failing)
+        .is_err());
+    assert_eq!(fs::read(&output).unwrap(), b"previous archive");
+    assert!(!destination.path().join("out.df.tmp").exists());
+
+    pre_archive.archive_to_file(&output).unwrap();
+    assert!(!destination.path().join("out.df.tmp").exists());
+    extract_from_file(&output).unwrap();
+
+    // without atomic writes, the archive is truncated right away
+    let in_place = ArchiveOptions {
+        atomic_write: false,
+        ..failing
+    };
+    assert!(pre_archive
+        .archive_to_file_with_options(&output, &in_place)
+        .is_err());
+    assert!(fs::read(&output).unwrap().is_empty());
+}
+
+#[test]
+fn duplicate_paths() {
+    let listings: Vec<ArchivableListing> = ["x/a.txt", "x/a.txt", "y/a.txt"]
+        .iter()
+        .map(|path| ArchivableListing {
+            relative_path: (*path).into(),
+            permissions: 0o100644,
+            file_size: 1,
+            literal_path: Default::default(),
+        })
+        .collect();
+    let write = |duplicate_paths| {
+        let mut archive = Vec::new();
+        write_archive(
+            &listings,
+            |i| Ok(vec![b'0' + i as u8]),
+            &mut archive,
+            &ArchiveOptions {
+                duplicate_paths,
+                sort: SortStrategy::WalkOrder,
+                ..Default::default()
+            },
+        )
+        .map(|written| (archive, written.stats.warnings))
+    };
+
+    let error = write(DuplicatePathPolicy::Error).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    let (deduped, warnings) = write(DuplicatePathPolicy::Dedupe).unwrap();
+    assert_eq!(warnings.len(), 1);
+    let deduped = extract_from_reader(&mut deduped.as_slice()).unwrap();
+    assert_eq!(deduped.listings.len(), 2);
+    assert_eq!(deduped.file_bytes("x/a.txt").unwrap(), b"0");
+    let (renamed, _) = write(DuplicatePathPolicy::Rename).unwrap();
+    let renamed = extract_from_reader(&mut renamed.as_slice()).unwrap();
+    assert_eq!(renamed.file_bytes("x/a~1.txt").unwrap(), b"1");
+
+    // stripping the leading directory makes x/a.txt and y/a.txt collide on extraction
+    let extract = |duplicate_paths| {
+        let destination = tempfile::tempdir().unwrap();
+        renamed
+            .create_all_files_with_options(
+                destination.path(),
+                &ExtractOptions {
+                    strip_components: 1,
+                    duplicate_paths,
+                    ..Default::default()
+                },
+            )
+            .map(|stats| {
+                let mut names: Vec<_> = fs::read_dir(destination.path())
+                    .unwrap()
+                    .map(|entry| {
+                        let path = entry.unwrap().path();
+                        let content = fs::read_to_string(&path).unwrap();
+                        (
+                            path.file_name().unwrap().to_str().unwrap().to_string(),
+                            content,
+                        )
+                    })
+                    .collect();
+                names.sort();
+                (names, stats.warnings.len())
+            })
+    };
+    assert!(extract(DuplicatePathPolicy::Error).is_err());
+    let pair = |name: &str, content: &str| (name.to_string(), content.to_string());
+    assert_eq!(
+        extract(DuplicatePathPolicy::Dedupe).unwrap(),
+        (vec![pair("a.txt", "0"), pair("a~1.txt", "1")], 1)
+    );
+    assert_eq!(
+        extract(DuplicatePathPolicy::Rename).unwrap(),
+        (
+            vec![
+                pair("a.txt", "0"),
+                pair("a~1.txt", "1"),
+                pair("a~2.txt", "2")
+            ],
+            1
+        )
+    );
+}
+
+#[test]
+fn listing_path_normalization() {
+    let listings: Vec<ArchivableListing> = ["./a.txt", "dir//./b.txt", "dir/c.txt/"]
+        .iter()
+        .map(|path| ArchivableListing {
+            relative_path: (*path).into(),
+            permissions: 0o100644,
+            file_size: 1,
+            literal_path: Default::default(),
+        })
+        .collect();
+    let mut archive = Vec::new();
+    write_archive(
+        &listings,
+        |i| Ok(vec![b'0' + i as u8]),
+        &mut archive,
+        &ArchiveOptions {
+            path_prefix: Some("./top/".to_string()),
+            sort: SortStrategy::WalkOrder,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let paths: Vec<&str> = extracted.listings.iter().map(|l| &*l.path).collect();
+    assert_eq!(paths, ["top/a.txt", "top/dir/b.txt", "top/dir/c.txt"]);
+
+    // walked paths are stored the same, however they were given
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable =
+        create_archive_from_paths(["./dir//subdir/./zeros.bin"], source.path()).unwrap();
+    assert_eq!(
+        &*archivable.listings[0].relative_path,
+        "dir/subdir/zeros.bin"
+    );
+
+    // a path without any components has nowhere to be archived
+    let empty = ArchivableListing {
+        relative_path: "./".into(),
+        ..listings[0].clone()
+    };
+    assert!(write_archive(
+        &[empty],
+        |_| Ok(vec![b'0']),
+        &mut Vec::new(),
+        &Default::default()
+    )
+    .is_err());
+
+    // older archives with `.` components extract without them
+    let options = ExtractOptions::default();
+    assert_eq!(
+        options.transform_path("./x/./y").unwrap().as_deref(),
+        Some("x/y")
+    );
+}
+
+#[test]
+fn case_collisions() {
+    let listings: Vec<ArchivableListing> = ["README.md", "dir/x", "readme.md", "readme~1.md"]
+        .iter()
+        .map(|path| ArchivableListing {
+            relative_path: (*path).into(),
+            permissions: 0o100644,
+            file_size: 1,
+            literal_path: Default::default(),
+        })
+        .collect();
+    let mut archive = Vec::new();
+    write_archive(
+        &listings,
+        |i| Ok(vec![b'0' + i as u8]),
+        &mut archive,
+        &ArchiveOptions {
+            sort: SortStrategy::WalkOrder,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let archive = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    let extract = |case_collisions, assume_case_insensitive| {
+        let destination = tempfile::tempdir().unwrap();
+        archive
+            .create_all_files_with_options(
+                destination.path(),
+                &ExtractOptions {
+                    case_collisions,
+                    assume_case_insensitive,
+                    ..Default::default()
+                },
+            )
+            .map(|stats| {
+                let mut names: Vec<_> = fs::read_dir(destination.path())
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .collect();
+                names.sort();
+                (names, stats.warnings.len())
+            })
+    };
+
+    // the temporary directory is on a case-sensitive filesystem, so nothing collides
+    let (names, warnings) = extract(CaseCollisionPolicy::Error, false).unwrap();
+    assert_eq!(names, ["README.md", "dir", "readme.md", "readme~1.md"]);
+    assert_eq!(warnings, 0);
+
+    let error = extract(CaseCollisionPolicy::Error, true).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    let (names, warnings) = extract(CaseCollisionPolicy::Warn, true).unwrap();
+    assert_eq!(names, ["README.md", "dir", "readme.md", "readme~1.md"]);
+    assert_eq!(warnings, 1);
+    // readme.md takes readme~1.md's place, which in turn moves to readme~1~1.md
+    let (names, warnings) = extract(CaseCollisionPolicy::Rename, true).unwrap();
+    assert_eq!(names, ["README.md", "dir", "readme~1.md", "readme~1~1.md"]);
+    assert_eq!(warnings, 2);
+    let (_, warnings) = extract(CaseCollisionPolicy::Ignore, true).unwrap();
+    assert_eq!(warnings, 0);
+}
+
+#[test]
+fn streams() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let options = ArchiveOptions {
+        bundle_size: 1024,
+        ..Default::default()
+    };
+
+    let mut stream = Vec::new();
+    let written = archivable.archive_to_stream(&mut stream, &options).unwrap();
+    assert_eq!(written.bytes_written, stream.len());
+    assert!(written.bundles.len() > 1);
+    assert_eq!(&stream[..8], &format::STREAM_MAGIC_NUMBER);
+    let frame = format::FrameHeader::decode(&stream[8..]).unwrap();
+    assert_eq!(frame.encode(), stream[8..8 + format::FrameHeader::SIZE]);
+    let destination = tempfile::tempdir().unwrap();
+    let stats = extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(stats.bundle_count, written.bundles.len());
+    assert_eq!(stats.uncompressed_bytes, written.stats.uncompressed_bytes);
+    for path in ["small.txt", "dir/lipsum.txt", "dir/subdir/zeros.bin"] {
+        assert_eq!(
+            fs::read(destination.path().join(path)).unwrap(),
+            fs::read(source.path().join(path)).unwrap()
+        );
+    }
+    assert!(destination.path().join("empty").is_dir());
+
+    // a stream ends with a frame of its own, without which it is truncated
+    let truncated = &stream[..stream.len() - format::FrameHeader::SIZE];
+    let error = extract_stream(
+        &mut &truncated[..],
+        tempfile::tempdir().unwrap().path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("truncated"));
+    let mut corrupt = stream.clone();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 1;
+    let error = extract_stream(
+        &mut corrupt.as_slice(),
+        tempfile::tempdir().unwrap().path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("integrity"));
+
+    // listings without content are streamed in a frame without a bundle
+    let mut stream = Vec::new();
+    let written = archivable
+        .archive_to_stream(
+            &mut stream,
+            &ArchiveOptions {
+                structure_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert!(written.bundles.is_empty());
+    let destination = tempfile::tempdir().unwrap();
+    let stats = extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(stats.file_count, 3);
+    assert_eq!(
+        fs::metadata(destination.path().join("dir/lipsum.txt"))
+            .unwrap()
+            .len(),
+        0
+    );
+}
+
+#[test]
+fn memory_map() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    let map = extract_from_reader(&mut archive.as_slice())
+        .unwrap()
+        .to_memory_map();
+    let paths: Vec<_> = map.keys().map(|path| path.to_str().unwrap()).collect();
+    assert_eq!(
+        paths,
+        [
+            "dir/lipsum.txt",
+            "dir/subdir/zeros.bin",
+            "empty",
+            "small.txt"
+        ]
+    );
+    let (permissions, content) = &map[Path::new("small.txt")];
+    assert_eq!(permissions & 0o170000, 0o100000);
+    assert_eq!(content, b"hello decaf\n");
+    assert_eq!(
+        map[Path::new("dir/subdir/zeros.bin")].1,
+        vec![0u8; 64 * 1024]
+    );
+    let (permissions, content) = &map[Path::new("empty")];
+    assert_eq!(permissions & 0o040000, 0o040000);
+    assert!(content.is_empty());
+}
+
+#[test]
+fn hash_algorithms() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let algorithms = [
+        HashAlgorithm::Xxh3,
+        HashAlgorithm::Crc32,
+        #[cfg(feature = "blake3")]
+        HashAlgorithm::Blake3,
+    ];
+
+    for hash in algorithms {
+        let mut archive = Vec::new();
+        let options = ArchiveOptions {
+            hash,
+            section_checksums: true,
+            bundle_size: MIN_BUNDLE_SIZE,
+            ..Default::default()
+        };
+        archivable
+            .archive_to_writer_with_options(&mut archive, &options)
+            .unwrap();
+        let header = format::Header::decode(&archive).unwrap();
+        assert_eq!(header.hash_algorithm_id(), hash.id());
+        let checksum = header.checksum;
+        assert_eq!(
+            checksum,
+            hash.hash(&archive[format::Header::CHECKSUMMED_FROM..])
+        );
+
+        // every checksum of the archive is computed with the algorithm
+        let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+        assert_eq!(extracted.hash_algorithm(), hash);
+        for listing in &extracted.listings {
+            if !listing.is_metadata_only() {
+                let content = extracted.listing_bytes(listing);
+                assert_eq!(listing.content_checksum, hash.hash(content));
+            }
+        }
+        let live = archivable.checksummed_listings(hash).unwrap();
+        assert!(live.iter().all(|listing| extracted
+            .find(&listing.path)
+            .is_some_and(|archived| archived.content_checksum == listing.content_checksum)));
+        let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+        assert_eq!(layout.hash, hash);
+        assert!(compare(&layout, source.path()).unwrap().is_clean());
+        let index = ArchiveIndex::decode(&layout.index().encode()).unwrap();
+        assert_eq!(index.hash, hash);
+        assert!(verify_archive(&mut archive.as_slice(), None)
+            .unwrap()
+            .is_ok());
+        let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+        assert_eq!(
+            reader.read_file("small.txt").unwrap().unwrap(),
+            b"hello decaf\n"
+        );
+
+        // a bundle checksummed with another algorithm doesn't verify
+        let mut corrupt = archive.clone();
+        let bundle = &layout.bundles[0];
+        let end = (bundle.offset + bundle.compressed_size) as usize;
+        corrupt[end - 1] ^= 1;
+        assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+        assert!(!verify_archive(&mut corrupt.as_slice(), None)
+            .unwrap()
+            .is_ok());
+    }
+
+    // streams have no room to record the algorithm
+    let options = ArchiveOptions {
+        hash: HashAlgorithm::Crc32,
+        ..Default::default()
+    };
+    let error = archivable
+        .archive_to_stream(&mut Vec::new(), &options)
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    // the fourth identifier is reserved
+    let mut archive = Vec::new();
+    archivable.archive_to_writer(&mut archive).unwrap();
+    let mut header = format::Header::decode(&archive).unwrap();
+    header.listing_block_length |= format::Header::HASH_ALGORITHM;
+    archive[..format::Header::SIZE].copy_from_slice(&header.encode());
+    assert!(extract_from_reader(&mut archive.as_slice()).is_err());
+    assert!(ArchiveLayout::read(&mut archive.as_slice()).is_err());
+}
+
+#[test]
+fn checksummed_listings() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    // hashed over several chunks rather than read whole
+    let large: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source.path().join("large.bin"), large).unwrap();
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut archive = Vec::new();
+    archivable.archive_to_writer(&mut archive).unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    let live = archivable
+        .checksummed_listings(HashAlgorithm::Xxh3)
+        .unwrap();
+    assert_eq!(live.len(), extracted.listings.len());
+    for listing in &live {
+        let archived = extracted.find(&listing.path).unwrap();
+        assert_eq!(
+            (
+                listing.permissions,
+                listing.filesize,
+                listing.content_checksum
+            ),
+            (
+                archived.permissions,
+                archived.filesize,
+                archived.content_checksum
+            )
+        );
+    }
+
+    // source paths don't have to be UTF-8
+    let name = <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(b"caf\xe9");
+    fs::write(source.path().join(name), b"hello decaf\n").unwrap();
+    let listing = ArchivableListing {
+        relative_path: "cafe".into(),
+        permissions: 0o100644,
+        file_size: 12,
+        literal_path: source.path().join(name),
+    };
+    let live = ArchivableArchive {
+        listings: vec![listing],
+        ..archivable
+    }
+    .checksummed_listings(HashAlgorithm::Xxh3)
+    .unwrap();
+    assert_eq!(live[0].filesize, 12);
+    assert_eq!(
+        Some(live[0].content_checksum),
+        extracted
+            .find("small.txt")
+            .map(|listing| listing.content_checksum)
+    );
+}
+
+#[test]
+fn compare_against_tree() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+
+    let comparison = compare(&layout, source.path()).unwrap();
+    assert!(comparison.is_clean());
+    assert_eq!(comparison.matched.len(), 4);
+
+    // same size, different content
+    fs::write(source.path().join("small.txt"), b"hello DECAF\n").unwrap();
+    fs::remove_file(source.path().join("dir/subdir/zeros.bin")).unwrap();
+    fs::write(source.path().join("new.txt"), b"new").unwrap();
+    fs::set_permissions(
+        source.path().join("dir/lipsum.txt"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    let comparison = compare(&layout, source.path()).unwrap();
+    assert!(!comparison.is_clean());
+    let paths = |paths: &[Box<str>]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    assert_eq!(paths(&comparison.matched), ["empty"]);
+    assert_eq!(paths(&comparison.modified), ["dir/lipsum.txt", "small.txt"]);
+    assert_eq!(paths(&comparison.missing), ["dir/subdir/zeros.bin"]);
+    // the emptied directory is now bare, so it is new to the archive as well
+    assert_eq!(paths(&comparison.extra), ["dir/subdir", "new.txt"]);
+}
+
+#[test]
+fn refresh_listings() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archivable = create_archive_from_directory(source.path()).unwrap();
+    let root = source.path().canonicalize().unwrap();
+
+    // a changed file is updated in place
+    fs::write(root.join("small.txt"), b"hello again, decaf\n").unwrap();
+    let walked = archivable
+        .refresh(source.path(), [source.path().join("small.txt")])
+        .unwrap();
+    assert!(!walked);
+    let fresh = create_archive_from_directory(source.path()).unwrap();
+    assert_eq!(archivable.listings, fresh.listings);
+
+    // a new file is listed in place of the bare directory it was created in, and removing it
+    // makes the directory bare again
+    fs::write(root.join("empty/new.txt"), b"new").unwrap();
+    let added = archivable
+        .refresh(source.path(), [source.path().join("empty/new.txt")])
+        .unwrap();
+    assert!(added);
+    let fresh = create_archive_from_directory(source.path()).unwrap();
+    assert_eq!(archivable.listings, fresh.listings);
+    assert!(archivable
+        .listings
+        .iter()
+        .any(|listing| &*listing.relative_path == "empty/new.txt"));
+    fs::remove_file(root.join("empty/new.txt")).unwrap();
+    archivable
+        .refresh(source.path(), [source.path().join("empty/new.txt")])
+        .unwrap();
+    let fresh = create_archive_from_directory(source.path()).unwrap();
+    assert_eq!(archivable.listings, fresh.listings);
+
+    // a new directory is walked on its own, and a removed one takes everything under it along
+    fs::create_dir_all(root.join("new/nested")).unwrap();
+    fs::write(root.join("new/nested/file.txt"), b"nested").unwrap();
+    fs::create_dir(root.join("new/bare")).unwrap();
+    fs::remove_dir_all(root.join("dir")).unwrap();
+    let added = archivable
+        .refresh(
+            source.path(),
+            [source.path().join("new"), source.path().join("dir")],
+        )
+        .unwrap();
+    assert!(added);
+    let fresh = create_archive_from_directory(source.path()).unwrap();
+    assert_eq!(archivable.listings, fresh.listings);
+    assert!(!archivable
+        .listings
+        .iter()
+        .any(|listing| listing.relative_path.starts_with("dir")));
+}
+
+#[test]
+fn decompression_threads() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                bundle_size: 1024,
+                threads: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let read = |threads| {
+        let options = ExtractOptions {
+            threads: Some(threads),
+            ..Default::default()
+        };
+        ExtractedArchive::from_reader_with_options(
+            &mut archive.as_slice(),
+            &Limits::default(),
+            &options,
+        )
+        .unwrap()
+    };
+    let sequential = read(1);
+    let parallel = read(4);
+    assert!(parallel.bundles().len() > 1);
+    assert_eq!(sequential.to_memory_map(), parallel.to_memory_map());
+
+    // a corrupt bundle fails however many threads decompress it, keeping the archive checksum valid
+    let last_bundle = parallel.bundles().last().unwrap().offset as usize;
+    archive[last_bundle + 4] ^= 0xff;
+    let mut header = format::Header::decode(&archive).unwrap();
+    header.checksum = xxhash_rust::xxh3::xxh3_64(&archive[format::Header::CHECKSUMMED_FROM..]);
+    archive[..format::Header::SIZE].copy_from_slice(&header.encode());
+    let options = ExtractOptions {
+        threads: Some(4),
+        ..Default::default()
+    };
+    assert!(ExtractedArchive::from_reader_with_options(
+        &mut archive.as_slice(),
+        &Limits::default(),
+        &options,
+    )
+    .is_err());
+}
+
+#[test]
+fn low_memory() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let options = ArchiveOptions {
+        bundle_size: 1024,
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    let written = archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+
+    // spilling bundles to a temporary file writes the same archive
+    let mut spilled = Vec::new();
+    let spilled_written = archivable
+        .archive_to_writer_with_options(
+            &mut spilled,
+            &ArchiveOptions {
+                low_memory: true,
+                ..options
+            },
+        )
+        .unwrap();
+    assert_eq!(spilled, archive);
+    assert_eq!(spilled_written.bytes_written, written.bytes_written);
+    assert_eq!(spilled_written.checksum, written.checksum);
+    assert_eq!(spilled_written.bundles, written.bundles);
+
+    let destination = tempfile::tempdir().unwrap();
+    let stats = extract_sequentially(
+        &mut archive.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    assert!(stats.bundle_count > 1);
+    assert_eq!(stats.uncompressed_bytes, written.stats.uncompressed_bytes);
+    for path in ["small.txt", "dir/lipsum.txt", "dir/subdir/zeros.bin"] {
+        assert_eq!(
+            fs::read(destination.path().join(path)).unwrap(),
+            fs::read(source.path().join(path)).unwrap()
+        );
+    }
+    assert!(destination.path().join("empty").is_dir());
+
+    // the archive checksum is only verified once the archive has been read
+    let mut corrupt = archive.clone();
+    corrupt[8] ^= 1;
+    let error = extract_sequentially(
+        &mut corrupt.as_slice(),
+        tempfile::tempdir().unwrap().path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("integrity"));
+}
+
+#[test]
+fn temp_dirs() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut expected = Vec::new();
+    archivable.archive_to_writer(&mut expected).unwrap();
+
+    // low-memory archiving spills its bundles to the temporary directory
+    let scratch = tempfile::tempdir().unwrap();
+    let spilling = |temp_dir: &Path| {
+        ArchiveOptions::builder()
+            .low_memory(true)
+            .temp_dir(temp_dir)
+            .build()
+            .unwrap()
+    };
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(&mut archive, &spilling(scratch.path()))
+        .unwrap();
+    assert_eq!(archive, expected);
+    let missing = scratch.path().join("missing");
+    let error = archivable
+        .archive_to_writer_with_options(&mut Vec::new(), &spilling(&missing))
+        .unwrap_err();
+    assert!(error.to_string().contains("missing"));
+
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let options = ExtractOptions::builder()
+        .temp_dir(scratch.path())
+        .build()
+        .unwrap();
+    let (temp_dir, _) = extracted.extract_to_temp_with_options(&options).unwrap();
+    assert!(temp_dir.path().starts_with(scratch.path()));
+    assert_eq!(
+        fs::read(temp_dir.path().join("small.txt")).unwrap(),
+        b"hello decaf\n"
+    );
+}
+
+#[test]
+fn sessions() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let options = ArchiveOptions {
+        bundle_size: 1024,
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+
+    // the threads and contexts of a session write the same archives, also side by side
+    let session = DecafSession::new(Some(2));
+    std::thread::scope(|scope| {
+        for level in [3, 19, 3] {
+            let (session, archivable, archive) = (&session, &archivable, &archive);
+            scope.spawn(move || {
+                let options = ArchiveOptions {
+                    compression_level: level,
+                    bundle_size: 1024,
+                    ..Default::default()
+                };
+                let mut written = Vec::new();
+                session
+                    .archive_to_writer(archivable, &mut written, &options)
+                    .unwrap();
+                if level == 3 {
+                    assert_eq!(&written, archive);
+                }
+                let extracted = session
+                    .extract_from_reader(
+                        &mut written.as_slice(),
+                        &Limits::default(),
+                        &ExtractOptions::default(),
+                    )
+                    .unwrap();
+                assert_eq!(
+                    extracted.to_memory_map(),
+                    extract_from_reader(&mut archive.as_slice())
+                        .unwrap()
+                        .to_memory_map()
+                );
+            });
+        }
+    });
+
+    // a corrupt bundle doesn't leave a context unusable for the next archive
+    let mut corrupt = archive.clone();
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    let first_bundle = layout.bundles[0].offset as usize;
+    corrupt[first_bundle + 4] ^= 0xff;
+    let options = ExtractOptions {
+        verify: VerifyLevel::None,
+        ..Default::default()
+    };
+    let _ = session.extract_from_reader(&mut corrupt.as_slice(), &Limits::default(), &options);
+    session
+        .extract_from_reader(&mut archive.as_slice(), &Limits::default(), &options)
+        .unwrap();
+}
+
+#[test]
+fn section_checksums() {
+    assert_eq!(format::SectionChecksums::SIZE, 16);
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                section_checksums: true,
+                compress_listings: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+
+    let header = format::Header::decode(&archive).unwrap();
+    assert!(header.has_section_checksums() && header.has_compressed_listings());
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    assert!(layout.section_checksums.is_some());
+    assert_eq!(
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .to_memory_map(),
+        extract_from_reader(&mut plain.as_slice())
+            .unwrap()
+            .to_memory_map()
+    );
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(
+        reader.read_file("small.txt").unwrap(),
+        Some(b"hello decaf\n".to_vec())
+    );
+
+    // partial readers catch a damaged bundle table without the archive checksum
+    let bundle_table = layout.bundles[0].offset as usize - format::BundleEntry::SIZE;
+    let mut corrupt = archive.clone();
+    corrupt[bundle_table] ^= 1;
+    let error = ArchiveLayout::read(&mut corrupt.as_slice()).unwrap_err();
+    assert!(error.to_string().contains("bundle table integrity"));
+    assert!(SeekableArchiveReader::new(std::io::Cursor::new(&corrupt)).is_err());
+}
+
+#[test]
+fn archive_summary() {
+    assert_eq!(format::HeaderChecksum::SIZE, 8);
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                header_checksum: true,
+                section_checksums: true,
+                bundle_size: 1024,
+                hash: HashAlgorithm::Crc32,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let header = format::Header::decode(&archive).unwrap();
+    assert!(header.has_header_checksum() && header.has_section_checksums());
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    assert!(layout.header_checksum.is_some());
+    let summary = ArchiveSummary::peek(&mut std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(
+        summary,
+        ArchiveSummary {
+            hash: HashAlgorithm::Crc32,
+            listing_count: layout.listings.len() as u64,
+            bundle_count: layout.bundles.len() as u64,
+            listing_block_length: header.stored_listing_block_length(),
+            compressed_size: layout.bundles.iter().map(|b| b.compressed_size).sum(),
+            archive_size: archive.len() as u64,
+            verified: true,
+        }
+    );
+    assert!(summary.bundle_count > 1);
+    verify_archive(&mut archive.as_slice(), None).unwrap();
+    assert_eq!(
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .to_memory_map()
+            .len(),
+        layout.listings.len()
+    );
+
+    // archives without a header checksum are summarized all the same, just not verified
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+    let summary = ArchiveSummary::peek(&mut std::io::Cursor::new(&plain)).unwrap();
+    assert!(!summary.verified);
+    assert_eq!(summary.archive_size, plain.len() as u64);
+    let mut inflated = plain.clone();
+    let listing_count = format::Header::FIELDS[3].offset;
+    inflated[listing_count..listing_count + 8].copy_from_slice(&(1u64 << 60).to_le_bytes());
+    let error = ArchiveSummary::peek(&mut std::io::Cursor::new(&inflated)).unwrap_err();
+    assert!(error.to_string().contains("listing_count"));
+
+    // a damaged count or bundle entry fails the header checksum
+    let bundle_table = layout.bundles[0].offset as usize - format::BundleEntry::SIZE;
+    let mut corrupt = archive.clone();
+    corrupt[bundle_table] ^= 1;
+    let error = ArchiveSummary::peek(&mut std::io::Cursor::new(&corrupt)).unwrap_err();
+    assert!(error.to_string().contains("header integrity"));
+    let mut corrupt = archive.clone();
+    corrupt[format::Header::CHECKSUMMED_FROM + 8] ^= 1;
+    assert!(ArchiveSummary::peek(&mut std::io::Cursor::new(&corrupt)).is_err());
+    assert!(ArchiveSummary::peek(&mut std::io::Cursor::new(&archive[..20])).is_err());
+}
+
+#[test]
+fn split_checksum() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut archive = Vec::new();
+    let written = archivable
+        .archive_to_writer_with_options(
+            &mut archive,
+            &ArchiveOptions {
+                split_checksum: true,
+                header_checksum: true,
+                bundle_size: 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let mut low_memory = Vec::new();
+    archivable
+        .archive_to_writer_with_options(
+            &mut low_memory,
+            &ArchiveOptions {
+                split_checksum: true,
+                header_checksum: true,
+                bundle_size: 1024,
+                low_memory: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(archive, low_memory);
+
+    let header = format::Header::decode(&archive).unwrap();
+    assert!(header.has_split_checksum());
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    let tables_end = layout.bundles[0].offset as usize;
+    assert_eq!(
+        written.checksum,
+        xxhash_rust::xxh3::xxh3_64(&archive[format::Header::CHECKSUMMED_FROM..tables_end])
+    );
+    assert!(verify_archive(&mut archive.as_slice(), None)
+        .unwrap()
+        .is_ok());
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+    assert_eq!(
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .to_memory_map(),
+        extract_from_reader(&mut plain.as_slice())
+            .unwrap()
+            .to_memory_map()
+    );
+
+    // lazy readers catch damaged tables through the archive checksum alone
+    let mut corrupt = archive.clone();
+    corrupt[header.listing_block_offset() as usize] ^= 1;
+    let error = ArchiveLayout::read(&mut corrupt.as_slice()).unwrap_err();
+    assert!(error.to_string().contains("archive integrity"));
+    assert!(SeekableArchiveReader::new(std::io::Cursor::new(&corrupt)).is_err());
+    assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+
+    // while damaged bundles are left to their own checksums
+    let mut corrupt = archive.clone();
+    corrupt[tables_end + 4] ^= 1;
+    ArchiveLayout::read(&mut corrupt.as_slice()).unwrap();
+    let report = verify_archive(&mut corrupt.as_slice(), None).unwrap();
+    assert!(report.archive_checksum_matches);
+    assert_eq!(report.corrupt_bundles, vec![0]);
+    assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+}
+
+#[test]
+fn footer_archives() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let options = ArchiveOptions {
+        footer: true,
+        compress_listings: true,
+        bundle_size: 1024,
+        hash: HashAlgorithm::Crc32,
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    let written = archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    assert_eq!(written.bytes_written, archive.len());
+
+    // the header says nothing but where to look, and the footer completes it
+    let header = format::Header::decode(&archive).unwrap();
+    assert!(header.has_footer() && header.has_compressed_listings());
+    let listing_count = header.listing_count;
+    assert_eq!(listing_count, 0);
+    let footer = format::Header::decode(&archive[archive.len() - format::Header::SIZE..]).unwrap();
+    let checksum = footer.checksum;
+    assert_eq!(checksum, written.checksum);
+    assert_eq!(written.bundles[0].offset, format::Header::SIZE as u64);
+
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+    let expected = extract_from_reader(&mut plain.as_slice())
+        .unwrap()
+        .to_memory_map();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(extracted.hash_algorithm(), HashAlgorithm::Crc32);
+    assert_eq!(extracted.to_memory_map(), expected);
+
+    // readers that seek find the tables through the footer
+    let layout = ArchiveLayout::read_seekable(&mut std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(layout.header, footer);
+    assert_eq!(layout.bundles.len(), written.bundles.len());
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(
+        reader.read_file("small.txt").unwrap(),
+        Some(b"hello decaf\n".to_vec())
+    );
+    let summary = ArchiveSummary::peek(&mut std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(summary.listing_count, layout.listings.len() as u64);
+    assert_eq!(summary.bundle_count, written.bundles.len() as u64);
+    assert!(verify_archive(&mut archive.as_slice(), None)
+        .unwrap()
+        .is_ok());
+
+    // readers that can't seek past the bundles don't mistake the header for an empty archive
+    let error = ArchiveLayout::read(&mut archive.as_slice()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+
+    // damage anywhere is caught, in the footer included
+    let last_bundle = written.bundles.last().unwrap();
+    let mut corrupt = archive.clone();
+    corrupt[(last_bundle.offset + last_bundle.compressed_size) as usize - 1] ^= 1;
+    assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+    let report = verify_archive(&mut corrupt.as_slice(), None).unwrap();
+    assert!(!report.archive_checksum_matches);
+    assert_eq!(report.corrupt_bundles, vec![written.bundles.len() - 1]);
+    let mut corrupt = archive.clone();
+    let footer_count = archive.len() - format::Header::SIZE + format::Header::CHECKSUMMED_FROM + 8;
+    corrupt[footer_count] ^= 1;
+    assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+    assert!(verify_archive(&mut corrupt.as_slice(), None).is_err());
+    let mut corrupt = archive.clone();
+    let footer_magic = archive.len() - format::Header::SIZE;
+    corrupt[footer_magic] ^= 1;
+    let error = extract_from_reader(&mut corrupt.as_slice()).unwrap_err();
+    assert!(error.to_string().contains("footer"));
+
+    // section checksums would have to be found before the footer is read
+    let error = archivable
+        .archive_to_writer_with_options(
+            &mut Vec::new(),
+            &ArchiveOptions {
+                section_checksums: true,
+                ..options
+            },
+        )
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn filtered_reading() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let options = ArchiveOptions {
+        bundle_size: 1024,
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+
+    let in_dir = |path: &str| path == "dir" || path.starts_with("dir/");
+    let filtered = ExtractedArchive::from_reader_filtered(&mut archive.as_slice(), in_dir)
+        .unwrap()
+        .to_memory_map();
+    let mut expected = extract_from_reader(&mut archive.as_slice())
+        .unwrap()
+        .to_memory_map();
+    expected.retain(|path, _| path.starts_with("dir"));
+    assert_eq!(filtered.len(), 2);
+    assert_eq!(filtered, expected);
+
+    // a predicate rejecting everything leaves an empty archive
+    let none = ExtractedArchive::from_reader_filtered(&mut archive.as_slice(), |_| false).unwrap();
+    assert!(none.to_memory_map().is_empty());
+}
+
+#[test]
+fn glob_search() {
+    assert!(glob_match("**/*.so", "libz.so"));
+    assert!(glob_match("**/*.so", "usr/lib/libz.so"));
+    assert!(!glob_match("**/*.so", "usr/lib/libz.so.1"));
+    assert!(!glob_match("*.so", "usr/libz.so"));
+    assert!(glob_match("usr/**", "usr/lib/libz.so"));
+    assert!(!glob_match("**/b", "ab"));
+    assert!(glob_match("d?r/*/zeros.*", "dir/subdir/zeros.bin"));
+
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+
+    let matches = layout.find_matching("**/*.txt");
+    let mut paths: Vec<&str> = matches.iter().map(|(listing, _)| &*listing.path).collect();
+    paths.sort();
+    assert_eq!(paths, ["dir/lipsum.txt", "small.txt"]);
+    for (listing, bundle) in &matches {
+        assert_eq!(*bundle, Some(listing.bundle_idx));
+    }
+    let empty = layout.find_matching("empty");
+    assert_eq!(empty.len(), 1);
+    assert_eq!(empty[0].1, None);
+}
+
+#[test]
+fn visiting_files() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let options = ArchiveOptions {
+        bundle_size: 1024,
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+
+    let mut visited = std::collections::BTreeMap::new();
+    for_each_file(
+        &mut archive.as_slice(),
+        &Limits::default(),
+        |listing, content| {
+            visited.insert(listing.path.to_string(), content.to_vec());
+            Ok(())
+        },
+    )
+    .unwrap();
+    let expected: std::collections::BTreeMap<String, Vec<u8>> =
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .to_memory_map()
+            .into_iter()
+            .filter(|(_, (_, content))| !content.is_empty())
+            .map(|(path, (_, content))| (path.to_string_lossy().into_owned(), content))
+            .collect();
+    assert_eq!(visited, expected);
+
+    // errors from `f` stop the pass, and a corrupt archive fails after every file was visited
+    let error = for_each_file(&mut archive.as_slice(), &Limits::default(), |_, _| {
+        Err(std::io::Error::other("stop"))
+    })
+    .unwrap_err();
+    assert_eq!(error.to_string(), "stop");
+    let mut corrupt = archive.clone();
+    corrupt.push(0);
+    let mut count = 0;
+    let error = for_each_file(&mut corrupt.as_slice(), &Limits::default(), |_, _| {
+        count += 1;
+        Ok(())
+    })
+    .unwrap_err();
+    assert_eq!(count, expected.len());
+    assert!(error.to_string().contains("archive integrity"));
+}
+
+#[test]
+fn extraction_modes() {
+    let options = ExtractOptions {
+        dir_mode: Some(0o750),
+        mode_mask: 0o7022,
+        ..Default::default()
+    };
+    assert_eq!(options.extracted_mode(0o104777), 0o100755);
+    assert_eq!(options.extracted_mode(0o040777), 0o040750);
+    assert_eq!(ExtractOptions::default().extracted_mode(0o043777), 0o040777);
+
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    fs::set_permissions(
+        source.path().join("small.txt"),
+        fs::Permissions::from_mode(0o4777),
+    )
+    .unwrap();
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    let mode = |options: &ExtractOptions, path: &str| {
+        let destination = tempfile::tempdir().unwrap();
+        extracted
+            .create_all_files_with_options(destination.path(), options)
+            .unwrap();
+        fs::metadata(destination.path().join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(mode(&ExtractOptions::default(), "small.txt"), 0o777);
+    let preserving = ExtractOptions {
+        preserve_special_bits: true,
+        ..Default::default()
+    };
+    assert_eq!(mode(&preserving, "small.txt"), 0o4777);
+    assert_eq!(mode(&options, "small.txt"), 0o755);
+    assert_eq!(mode(&options, "empty"), 0o750);
+    let overridden = ExtractOptions {
+        file_mode: Some(0o640),
+        ..Default::default()
+    };
+    assert_eq!(mode(&overridden, "small.txt"), 0o640);
+    assert_eq!(mode(&overridden, "dir/lipsum.txt"), 0o640);
+}
+
+#[test]
+fn seekable_bundles() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let mut plain = Vec::new();
+    archivable.archive_to_writer(&mut plain).unwrap();
+    let options = ArchiveOptions {
+        seekable_frame_size: Some(4096),
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    assert_ne!(archive, plain);
+    assert_eq!(
+        extract_from_reader(&mut archive.as_slice())
+            .unwrap()
+            .to_memory_map(),
+        extract_from_reader(&mut plain.as_slice())
+            .unwrap()
+            .to_memory_map()
+    );
+
+    // damage to the last frame only affects the files in it
+    let layout = ArchiveLayout::read(&mut archive.as_slice()).unwrap();
+    let bundle = layout.bundles[0];
+    let frames = bundle.uncompressed_size.div_ceil(4096);
+    let last_frame_end = bundle.offset + bundle.compressed_size - (17 + 8 * frames);
+    let mut corrupt = archive.clone();
+    corrupt[last_frame_end as usize - 2] ^= 0xff;
+    assert!(extract_from_reader(&mut corrupt.as_slice()).is_err());
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&corrupt)).unwrap();
+    assert_eq!(
+        reader.read_file("small.txt").unwrap().unwrap(),
+        b"hello decaf\n"
+    );
+    assert!(reader.read_file("dir/subdir/zeros.bin").is_err());
+
+    let mut reader = SeekableArchiveReader::new(std::io::Cursor::new(&archive)).unwrap();
+    assert_eq!(
+        reader.read_file("dir/lipsum.txt").unwrap().unwrap(),
+        "lorem ipsum ".repeat(4096).as_bytes()
+    );
+}
+
+#[test]
+fn options_builder() {
+    let options = ArchiveOptions::builder()
+        .compression_level(19)
+        .bundle_size(MIN_BUNDLE_SIZE)
+        .sort(SortStrategy::Path)
+        .threads(2)
+        .exclude("*.bin")
+        .build()
+        .unwrap();
+    assert_eq!(options.compression_level, 19);
+    assert_eq!(options.bundle_size, MIN_BUNDLE_SIZE);
+    assert_eq!(options.threads, Some(2));
+    assert!(ArchiveOptions::default().validate().is_ok());
+    assert_eq!(
+        ArchiveOptions::default().bundle_size,
+        ArchiveOptions::builder().build().unwrap().bundle_size
+    );
+
+    for invalid in [
+        ArchiveOptions::builder().bundle_size(MIN_BUNDLE_SIZE - 1),
+        ArchiveOptions::builder().compression_level(1000),
+        ArchiveOptions::builder().threads(0),
+        ArchiveOptions::builder().seekable_frame_size(0),
+        ArchiveOptions::builder().path_prefix("../outside"),
+        ArchiveOptions::builder().path_prefix("/"),
+        ArchiveOptions::builder()
+            .footer(true)
+            .section_checksums(true),
+    ] {
+        let error = invalid.build().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // options set without the builder are validated when they are used
+    let listings = [ArchivableListing {
+        relative_path: "file".into(),
+        permissions: 0o100644,
+        file_size: 4,
+        literal_path: Default::default(),
+    }];
+    for invalid in [
+        ArchiveOptions {
+            rate_limit: Some(0),
+            ..Default::default()
+        },
+        ArchiveOptions {
+            seekable_frame_size: Some(0),
+            ..Default::default()
+        },
+        ArchiveOptions {
+            path_prefix: Some("../outside".to_string()),
+            ..Default::default()
+        },
+    ] {
+        let error = write_archive(
+            &listings,
+            |_| Ok(b"file".to_vec()),
+            &mut Vec::new(),
+            &invalid,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = write_stream(
+            &listings,
+            |_| Ok(b"file".to_vec()),
+            &mut Vec::new(),
+            &invalid,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // excluded listings are left out of the archive
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    let paths: Vec<&str> = extracted
+        .listings
+        .iter()
+        .map(|listing| &*listing.path)
+        .collect();
+    assert_eq!(paths, ["dir/lipsum.txt", "empty", "small.txt"]);
+    assert!(is_excluded("dir/subdir/zeros.bin", "subdir"));
+    assert!(is_excluded("dir/subdir/zeros.bin", "dir/subdir"));
+    assert!(!is_excluded("dir/subdir/zeros.bin", "sub"));
+}
+
+#[test]
+fn extract_options_builder() {
+    let options = ExtractOptions::builder()
+        .strip_components(1)
+        .verify(VerifyLevel::Paranoid)
+        .threads(2)
+        .overwrite(OverwritePolicy::Skip)
+        .build()
+        .unwrap();
+    assert_eq!(options.strip_components, 1);
+    assert_eq!(options.threads, Some(2));
+    for invalid in [
+        ExtractOptions::builder().threads(0),
+        ExtractOptions::builder().file_mode(0o10000),
+        ExtractOptions::builder().path_remap("/", "dir"),
+        ExtractOptions::builder().max_memory(0),
+    ] {
+        let error = invalid.build().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+
+    // options set without the builder are validated when they are used
+    let invalid = ExtractOptions {
+        rate_limit: Some(0),
+        ..Default::default()
+    };
+    let destination = tempfile::tempdir().unwrap();
+    let error = extracted
+        .create_all_files_with_options(destination.path(), &invalid)
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    let error = extract_sequentially(
+        &mut archive.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &invalid,
+    )
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    // existing files are overwritten, kept, or stop extraction
+    let destination = tempfile::tempdir().unwrap();
+    fs::write(destination.path().join("small.txt"), b"existing").unwrap();
+    let skipping = ExtractOptions::builder()
+        .overwrite(OverwritePolicy::Skip)
+        .build()
+        .unwrap();
+    let stats = extracted
+        .create_all_files_with_options(destination.path(), &skipping)
+        .unwrap();
+    assert_eq!(stats.warnings.len(), 1);
+    assert_eq!(
+        fs::read(destination.path().join("small.txt")).unwrap(),
+        b"existing"
+    );
+    assert!(destination.path().join("dir/lipsum.txt").exists());
+    let failing = ExtractOptions::builder()
+        .overwrite(OverwritePolicy::Error)
+        .build()
+        .unwrap();
+    let error = extracted
+        .create_all_files_with_options(destination.path(), &failing)
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    extracted
+        .create_all_files_with_options(destination.path(), &ExtractOptions::default())
+        .unwrap();
+    assert_eq!(
+        fs::read(destination.path().join("small.txt")).unwrap(),
+        b"hello decaf\n"
+    );
+
+    // the memory limit bounds the decompressed bundles held at once
+    let limited = ExtractOptions::builder().max_memory(1024).build().unwrap();
+    let error = ExtractedArchive::from_reader_with_options(
+        &mut archive.as_slice(),
+        &Limits::default(),
+        &limited,
+    )
+    .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("memory for decompressed bundles"));
+}
+
+#[test]
+fn structured_warnings() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let fifo = std::ffi::CString::new(source.path().join("fifo").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    std::os::unix::fs::symlink("/etc/hostname", source.path().join("outside")).unwrap();
+
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    assert_eq!(
+        archivable.skipped,
+        [
+            Warning::SkippedSpecialFile {
+                path: "fifo".to_string()
+            },
+            Warning::SkippedSymlink {
+                path: "outside".to_string()
+            },
+        ]
+    );
+    // a file that grows after indexing is archived as read, with a warning
+    fs::write(source.path().join("small.txt"), b"hello decaf, again\n").unwrap();
+
+    let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = ArchiveOptions::builder()
+        .on_warning({
+            let reported = reported.clone();
+            move |warning| reported.lock().unwrap().push(warning.clone())
+        })
+        .build()
+        .unwrap();
+    let mut archive = Vec::new();
+    let written = archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    assert_eq!(written.stats.warnings.len(), 3);
+    assert_eq!(
+        written.stats.warnings[2],
+        Warning::FileChangedDuringRead {
+            path: "small.txt".to_string(),
+            indexed_size: 12,
+            read_size: 19,
+        }
+    );
+    assert_eq!(*reported.lock().unwrap(), written.stats.warnings);
+
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(
+        extracted.file_bytes("small.txt"),
+        Some(&b"hello decaf, again\n"[..])
+    );
+    let destination = tempfile::tempdir().unwrap();
+    fs::write(destination.path().join("small.txt"), b"existing").unwrap();
+    reported.lock().unwrap().clear();
+    let options = ExtractOptions::builder()
+        .overwrite(OverwritePolicy::Skip)
+        .on_warning({
+            let reported = reported.clone();
+            move |warning| reported.lock().unwrap().push(warning.clone())
+        })
+        .build()
+        .unwrap();
+    let stats = extracted
+        .create_all_files_with_options(destination.path(), &options)
+        .unwrap();
+    assert_eq!(
+        stats.warnings,
+        [Warning::ExistingFileKept {
+            path: destination.path().join("small.txt")
+        }]
+    );
+    assert_eq!(*reported.lock().unwrap(), stats.warnings);
+}
+
+#[test]
+fn archive_parts() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let mut archive = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut archive)
+        .unwrap();
+
+    let mut parts = create_archive_from_directory(source.path())
+        .unwrap()
+        .into_parts(1000, ArchiveOptions::default())
+        .unwrap();
+    let collected: Vec<Vec<u8>> = parts.by_ref().map(Result::unwrap).collect();
+    assert_eq!(collected.len(), archive.len().div_ceil(1000));
+    assert!(collected[..collected.len() - 1]
+        .iter()
+        .all(|part| part.len() == 1000));
+    assert_eq!(collected.concat(), archive);
+    assert_eq!(parts.written().unwrap().bytes_written, archive.len());
+
+    let stream: Vec<u8> = create_archive_from_directory(source.path())
+        .unwrap()
+        .into_stream_parts(64, ArchiveOptions::default())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .concat();
+    let destination = tempfile::tempdir().unwrap();
+    extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read(destination.path().join("small.txt")).unwrap(),
+        b"hello decaf\n"
+    );
+
+    let error = create_archive_from_directory(source.path())
+        .unwrap()
+        .into_parts(0, ArchiveOptions::default())
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn chunked_reading() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    // larger than a few read chunks, and not a multiple of their size
+    let large: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source.path().join("large.bin"), &large).unwrap();
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+
+    for rate_limit in [None, Some(1 << 30)] {
+        let mut archive = Vec::new();
+        archivable
+            .archive_to_writer_with_options(
+                &mut archive,
+                &ArchiveOptions {
+                    rate_limit,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+        assert_eq!(extracted.file_bytes("large.bin"), Some(&large[..]));
+        let checksums = |listings: &[ExtractedListing]| {
+            listings
+                .iter()
+                .map(|listing| (listing.path.clone(), listing.content_checksum))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        assert_eq!(
+            checksums(&extracted.listings),
+            checksums(
+                &archivable
+                    .checksummed_listings(extracted.hash_algorithm())
+                    .unwrap()
+            )
+        );
+    }
+}
+
+// needs about 10 GiB of memory and a few minutes, so it is only run with `cargo test -- --ignored`
+#[test]
+#[ignore]
+fn files_over_4_gib() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let source = tempfile::tempdir().unwrap();
+    let size = (4 << 30) + 4099;
+    let mut file = fs::File::create(source.path().join("a.bin")).unwrap();
+    file.set_len(size).unwrap();
+    file.write_all(b"start").unwrap();
+    file.seek(SeekFrom::Start(size - 3)).unwrap();
+    file.write_all(b"end").unwrap();
+    drop(file);
+    fs::write(source.path().join("b.txt"), b"after the large file\n").unwrap();
+
+    // both files in one bundle, so that the second one starts past 4 GiB into it
+    let archive_path = source.path().join("archive.df");
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_file_with_options(
+            &archive_path,
+            &ArchiveOptions {
+                sort: SortStrategy::Path,
+                bundle_size: 8 << 30,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+    let mut reader = fs::File::open(&archive_path).unwrap();
+    let mut visited = Vec::new();
+    for_each_file(&mut reader, &Limits::default(), |listing, content| {
+        match &*listing.path {
+            "a.bin" => {
+                assert_eq!(content.len() as u64, size);
+                assert_eq!(&content[..5], b"start");
+                assert_eq!(&content[content.len() - 3..], b"end");
+                assert!(content[5..content.len() - 3].iter().all(|&byte| byte == 0));
+            }
+            "b.txt" => {
+                assert!(listing.bundle_offset as u64 > u32::MAX as u64);
+                assert_eq!(content, b"after the large file\n");
+            }
+            path => panic!("unexpected listing {}", path),
+        }
+        visited.push(listing.path.clone());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(visited.len(), 2);
+
+    let mut seekable = SeekableArchiveReader::new(fs::File::open(&archive_path).unwrap()).unwrap();
+    assert_eq!(
+        seekable.read_file("b.txt").unwrap().unwrap(),
+        b"after the large file\n"
+    );
+}
+
+#[test]
+fn progress_callbacks() {
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+
+    let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let counted = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let record = {
+        let reported = reported.clone();
+        let counted = counted.clone();
+        move |progress: &Progress| {
+            reported
+                .lock()
+                .unwrap()
+                .push((progress.path.to_string(), progress.size));
+            counted
+                .lock()
+                .unwrap()
+                .push((progress.done, progress.total))
+        }
+    };
+    let total = 12 + 12 * 4096 + 64 * 1024;
+    let options = ArchiveOptions::builder()
+        .on_progress(record.clone())
+        .build()
+        .unwrap();
+    let mut archive = Vec::new();
+    archivable
+        .archive_to_writer_with_options(&mut archive, &options)
+        .unwrap();
+    let expected: Vec<(String, u64)> = vec![
+        ("empty".to_string(), 0),
+        ("small.txt".to_string(), 12),
+        ("dir/lipsum.txt".to_string(), 12 * 4096),
+        ("dir/subdir/zeros.bin".to_string(), 64 * 1024),
+    ];
+    assert_eq!(*reported.lock().unwrap(), expected);
+    // the total is known before the first listing, and the listings add up to it
+    let done: Vec<(u64, Option<u64>)> = [0, 12, 12 + 12 * 4096, total]
+        .into_iter()
+        .map(|done| (done, Some(total)))
+        .collect();
+    assert_eq!(*counted.lock().unwrap(), done);
+
+    // extraction reports listings from the threads writing them, so in any order
+    reported.lock().unwrap().clear();
+    let options = ExtractOptions::builder()
+        .on_progress(record)
+        .build()
+        .unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    extract_from_reader(&mut archive.as_slice())
+        .unwrap()
+        .create_all_files_with_options(destination.path(), &options)
+        .unwrap();
+    let mut extracted = reported.lock().unwrap().clone();
+    extracted.sort();
+    let mut expected = expected;
+    expected.sort();
+    assert_eq!(extracted, expected);
+    let counted_done = counted.lock().unwrap().iter().map(|&(done, _)| done).max();
+    assert_eq!(counted_done, Some(total));
+    assert!(counted
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|&(_, counted_total)| counted_total == Some(total)));
+
+    // the listings of a stream are only known as it is read
+    counted.lock().unwrap().clear();
+    let mut stream = Vec::new();
+    archivable
+        .archive_to_stream(&mut stream, &ArchiveOptions::default())
+        .unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &options,
+    )
+    .unwrap();
+    let counted = counted.lock().unwrap();
+    assert!(counted.iter().all(|&(_, total)| total.is_none()));
+    assert_eq!(counted.iter().map(|&(done, _)| done).max(), Some(total));
+}
+
+#[test]
+fn nested_archives() {
+    let inner = tempfile::tempdir().unwrap();
+    write_test_tree(inner.path());
+    let archivable = create_archive_from_directory(inner.path()).unwrap();
+
+    let source = tempfile::tempdir().unwrap();
+    let mut archive = Vec::new();
+    archivable.archive_to_writer(&mut archive).unwrap();
+    fs::write(source.path().join("inner.df"), &archive).unwrap();
+    let mut stream = Vec::new();
+    archivable
+        .archive_to_stream(&mut stream, &ArchiveOptions::default())
+        .unwrap();
+    fs::write(source.path().join("inner.dfs"), &stream).unwrap();
+    let mut tar = vec![0u8; 1024];
+    tar[257..263].copy_from_slice(b"ustar\0");
+    fs::write(source.path().join("inner.tar"), &tar).unwrap();
+    // gzip alone doesn't make a tarball
+    fs::write(
+        source.path().join("notes.gz"),
+        [0x1f, 0x8b, 8, 0, 0, 0, 0, 0],
+    )
+    .unwrap();
+    fs::write(source.path().join("small.txt"), b"hello decaf\n").unwrap();
+
+    let mut outer = Vec::new();
+    create_archive_from_directory(source.path())
+        .unwrap()
+        .archive_to_writer(&mut outer)
+        .unwrap();
+    let extracted = extract_from_reader(&mut outer.as_slice()).unwrap();
+    let mut nested: Vec<(&str, NestedArchiveKind)> = extracted
+        .nested_archives()
+        .into_iter()
+        .map(|(listing, kind)| (&*listing.path, kind))
+        .collect();
+    nested.sort_by_key(|(path, _)| *path);
+    let expected = [
+        ("inner.df", NestedArchiveKind::Decaf),
+        ("inner.dfs", NestedArchiveKind::DecafStream),
+        ("inner.tar", NestedArchiveKind::Tar),
+    ];
+    assert_eq!(nested, expected);
+
+    // the callback finds every nested archive already written where it says
+    let found = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = ExtractOptions::builder()
+        .on_nested_archive({
+            let found = found.clone();
+            move |nested| {
+                assert!(nested.extracted_path.is_file());
+                let name = nested.extracted_path.file_name().unwrap();
+                assert_eq!(name.to_str(), Some(nested.path));
+                found
+                    .lock()
+                    .unwrap()
+                    .push((nested.path.to_string(), nested.kind));
+                Ok(())
+            }
+        })
+        .build()
+        .unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    extracted
+        .create_all_files_with_options(destination.path(), &options)
+        .unwrap();
+    let mut found = found.lock().unwrap().clone();
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    let expected: Vec<(String, NestedArchiveKind)> = expected
+        .iter()
+        .map(|(path, kind)| (path.to_string(), *kind))
+        .collect();
+    assert_eq!(found, expected);
+
+    // and its error fails the extraction
+    let options = ExtractOptions::builder()
+        .on_nested_archive(|_| Err(std::io::Error::other("nested")))
+        .build()
+        .unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    assert!(extracted
+        .create_all_files_with_options(destination.path(), &options)
+        .is_err());
+}
+
+#[test]
+fn file_flags() {
+    let flags = FileFlags {
+        immutable: false,
+        append_only: true,
+    };
+    assert_eq!(FileFlags::from_permissions(0o100644), FileFlags::default());
+    assert_eq!(
+        FileFlags::from_permissions(0o100644 | flags.to_permissions()),
+        flags
+    );
+
+    // flags stored in the listings survive normalizing the permissions, and are ignored unless
+    // they are asked for on extraction
+    let listings = [ArchivableListing {
+        relative_path: "log.txt".into(),
+        permissions: 0o100640 | flags.to_permissions(),
+        file_size: 1,
+        literal_path: Default::default(),
+    }];
+    let mut archive = Vec::new();
+    write_archive(
+        &listings,
+        |_| Ok(b"x".to_vec()),
+        &mut archive,
+        &ArchiveOptions {
+            permissions: PermissionMode::ExecutableOnly,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let extracted = extract_from_reader(&mut archive.as_slice()).unwrap();
+    assert_eq!(extracted.listings[0].file_flags(), flags);
+    assert_eq!(
+        extracted.listings[0].permissions & format::ListingEntry::MODE_BITS,
+        0o100644
+    );
+    let destination = tempfile::tempdir().unwrap();
+    extracted.create_all_files(destination.path()).unwrap();
+    let log = destination.path().join("log.txt");
+    assert_eq!(FileFlags::read(&log).unwrap(), FileFlags::default());
+
+    // the rest needs a filesystem with flags and the privilege to set them
+    if flags.set(&log).is_err() {
+        return;
+    }
+    let unflagged = FileFlags::default();
+    unflagged.set(&log).unwrap();
+
+    let source = tempfile::tempdir().unwrap();
+    write_test_tree(source.path());
+    let small = source.path().join("small.txt");
+    flags.set(&small).unwrap();
+    let archivable = create_archive_from_directory(source.path()).unwrap();
+    let archive_with = |file_flags| {
+        let mut archive = Vec::new();
+        archivable
+            .archive_to_writer_with_options(
+                &mut archive,
+                &ArchiveOptions {
+                    file_flags,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        extract_from_reader(&mut archive.as_slice()).unwrap()
+    };
+    let flags_of = |archive: &ExtractedArchive, path: &str| {
+        let listing = archive.listings.iter().find(|l| &*l.path == path);
+        listing.unwrap().file_flags()
+    };
+    assert_eq!(flags_of(&archive_with(false), "small.txt"), unflagged);
+    let flagged = archive_with(true);
+    assert_eq!(flags_of(&flagged, "small.txt"), flags);
+    assert_eq!(flags_of(&flagged, "dir/lipsum.txt"), unflagged);
+    unflagged.set(&small).unwrap();
+
+    let destination = tempfile::tempdir().unwrap();
+    flagged
+        .create_all_files_with_options(
+            destination.path(),
+            &ExtractOptions {
+                file_flags: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let small = destination.path().join("small.txt");
+    assert_eq!(fs::read(&small).unwrap(), b"hello decaf\n");
+    assert_eq!(FileFlags::read(&small).unwrap(), flags);
+    unflagged.set(&small).unwrap();
+}
@@ -1,27 +1,65 @@
 use std::{
+    collections::{hash_map::Entry, HashMap},
     ffi::OsStr,
     fs::{self, File},
-    io::{self, Write},
-    os::unix::fs::MetadataExt,
+    io::{self, BufWriter, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::Path,
 };
 
 use decaf::*;
 use flate2::Compression;
 
+/// Options controlling how tar archives are written
+#[derive(Debug, Clone)]
+pub struct TarOptions {
+    /// modification time of every entry and of the gzip header in seconds since the Unix epoch,
+    /// clamped to the largest time each header can hold
+    pub mtime: u64,
+}
+
+impl Default for TarOptions {
+    /// Uses `SOURCE_DATE_EPOCH` as the modification time if it is set to a valid time, as in a
+    /// reproducible build, and 0 otherwise
+    fn default() -> Self {
+        TarOptions {
+            mtime: source_date_epoch().unwrap_or(0),
+        }
+    }
+}
+
+/// Returns the `SOURCE_DATE_EPOCH` environment variable, if it is set to a number of seconds
+fn source_date_epoch() -> Option<u64> {
+    std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+/// The largest modification time a ustar header can hold in its 11 octal digits
+const MAX_TAR_MTIME: u64 = 0o77777777777;
+
 /// Writes a deterministically gzipped deterministic POSIX tar (ustar) archive of the passed directory to the writer
 pub fn create_tar_gz<P: AsRef<Path>, W: Write>(
     directory_path: P,
     writer: &mut W,
 ) -> Result<(), io::Error> {
-    create_tar(
+    create_tar_gz_with_options(directory_path, writer, &TarOptions::default())
+}
+
+/// Writes a deterministically gzipped deterministic POSIX tar (ustar) archive of the passed
+/// directory to the writer, with `options.mtime` also used as the gzip modification time
+pub fn create_tar_gz_with_options<P: AsRef<Path>, W: Write>(
+    directory_path: P,
+    writer: &mut W,
+    options: &TarOptions,
+) -> Result<(), io::Error> {
+    create_tar_with_options(
         &directory_path,
         &mut flate2::GzBuilder::new()
             .extra("")
             .filename("")
             .operating_system(0)
-            .mtime(0)
+            .mtime(options.mtime.min(u32::MAX as u64) as u32)
             .write(writer, Compression::fast()),
+        options,
     )
 }
 
@@ -30,8 +68,149 @@ pub fn create_tar<P: AsRef<Path>, W: Write>(
     directory_path: P,
     writer: &mut W,
 ) -> Result<(), io::Error> {
-    let dir_path_as_path = Path::new(directory_path.as_ref());
-    let top_level_directory = dir_path_as_path
+    create_tar_with_options(directory_path, writer, &TarOptions::default())
+}
+
+/// Writes a deterministic POSIX tar (ustar) archive of the passed directory to the writer, with
+/// symbolic links and hard links written as links
+pub fn create_tar_with_options<P: AsRef<Path>, W: Write>(
+    directory_path: P,
+    writer: &mut W,
+    options: &TarOptions,
+) -> Result<(), io::Error> {
+    let directory_path = directory_path.as_ref();
+    let top_level_directory = write_top_level_directory(directory_path, options, writer)?;
+    let mut hard_links = HashMap::new();
+    for listing in create_archive_from_directory(directory_path)?.listings {
+        let link = link_of(
+            directory_path,
+            &top_level_directory,
+            &listing,
+            &mut hard_links,
+        )?;
+        let content = match link {
+            Link::None => read_listing_content(&listing)?,
+            _ => Vec::new(),
+        };
+        write_prefixed_entry(
+            &top_level_directory,
+            &listing,
+            &link,
+            &content,
+            options,
+            writer,
+        )?;
+    }
+    write_end_of_archive(writer)
+}
+
+/// Writes both a deterministic POSIX tar (ustar) archive and a DeCAF archive of the passed
+/// directory, walking it and reading each file only once
+pub fn create_tar_to_decaf<P: AsRef<Path>, T: Write, D: Write>(
+    directory_path: P,
+    tar_writer: &mut T,
+    decaf_writer: &mut D,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    let directory_path = directory_path.as_ref();
+    let tar_options = TarOptions::default();
+    let top_level_directory = write_top_level_directory(directory_path, &tar_options, tar_writer)?;
+    let listings = create_archive_from_directory(directory_path)?.listings;
+
+    // the archive holds the content of every listing, even where the tarball only holds a link
+    let mut hard_links = HashMap::new();
+    let mut contents = Vec::with_capacity(listings.len());
+    for listing in &listings {
+        let content = read_listing_content(listing)?;
+        let link = link_of(
+            directory_path,
+            &top_level_directory,
+            listing,
+            &mut hard_links,
+        )?;
+        let tar_content = match link {
+            Link::None => &content[..],
+            _ => &[],
+        };
+        write_prefixed_entry(
+            &top_level_directory,
+            listing,
+            &link,
+            tar_content,
+            &tar_options,
+            tar_writer,
+        )?;
+        contents.push(content);
+    }
+    write_end_of_archive(tar_writer)?;
+
+    write_archive(
+        &listings,
+        |listing_idx| Ok(std::mem::take(&mut contents[listing_idx])),
+        &mut BufWriter::new(decaf_writer),
+        options,
+    )
+}
+
+/// Converts a DeCAF archive into a deterministic POSIX tar (ustar) archive holding the same
+/// listings in the same order, without touching the filesystem
+pub fn decaf_to_tar<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<(), io::Error> {
+    let archive = extract_from_reader(reader)?;
+    for listing in &archive.listings {
+        // tar has no place for the file flags stored above the mode
+        write_entry(
+            listing.path.as_bytes(),
+            listing.permissions & format::ListingEntry::MODE_BITS,
+            &Link::None,
+            archive.listing_bytes(listing),
+            &TarOptions::default(),
+            writer,
+        )?;
+    }
+    write_end_of_archive(writer)
+}
+
+/// Converts a tar archive into a DeCAF archive without touching the filesystem, as
+/// `decaf::from_tar` does
+pub fn tar_to_decaf<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    from_tar(reader, writer, options)
+}
+
+/// Converts a gzipped tar archive into a DeCAF archive like `tar_to_decaf`
+pub fn tar_gz_to_decaf<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    from_tar(flate2::read::GzDecoder::new(reader), writer, options)
+}
+
+/// Converts a gzipped tar archive into a DeCAF stream as `decaf::from_tar_to_stream` does, reading
+/// it as it arrives, e.g. to mirror an upstream source tarball in DeCAF format straight from the
+/// body of its download
+///
+/// The entries are held in memory until the end of the tarball, so the download is only as large
+/// as `options.limits.max_uncompressed_size` allows once it is decompressed.
+pub fn tar_gz_to_decaf_stream<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    options: &ArchiveOptions,
+) -> Result<WrittenArchive, io::Error> {
+    from_tar_to_stream(flate2::read::GzDecoder::new(reader), writer, options)
+}
+
+/// Writes the header of the directory the tarball's contents are placed under, returning its
+/// name including the trailing slash
+fn write_top_level_directory<W: Write>(
+    directory_path: &Path,
+    options: &TarOptions,
+    writer: &mut W,
+) -> Result<String, io::Error> {
+    let top_level_directory = directory_path
         .file_name()
         .and_then(OsStr::to_str)
         .map(|s| {
@@ -41,118 +220,243 @@ pub fn create_tar<P: AsRef<Path>, W: Write>(
         })
         .unwrap_or_else(|| "./".to_string());
 
-    let top_level_directory_perms = File::open(dir_path_as_path)?.metadata()?.mode();
-
-    write_header(
-        ArchivableListing {
-            relative_path: top_level_directory.clone().into_boxed_str(),
-            permissions: top_level_directory_perms,
-            file_size: 0,
-            literal_path: Default::default(),
-        },
+    let top_level_directory_perms = File::open(directory_path)?.metadata()?.mode();
+    write_entry(
+        top_level_directory.as_bytes(),
+        top_level_directory_perms,
+        &Link::None,
+        &[],
+        options,
         writer,
     )?;
+    Ok(top_level_directory)
+}
 
-    for mut listing in create_archive_from_directory(&directory_path)?.listings {
-        listing.relative_path = {
-            let mut path_string = listing.relative_path.to_string();
-            path_string.insert_str(0, top_level_directory.as_str());
-            path_string.into_boxed_str()
-        };
-        write_header(listing, writer)?;
+/// How a listing is written to a tarball besides as a regular file or directory
+enum Link {
+    None,
+    /// a symbolic link to the given target
+    Symbolic(Vec<u8>),
+    /// a hard link to the given earlier entry of the tarball
+    Hard(Vec<u8>),
+}
+
+/// Returns how `listing` is written to a tarball placing it under `top_level_directory`,
+/// remembering files with several hard links in `hard_links` so that their later paths link to
+/// the first one
+fn link_of(
+    directory_path: &Path,
+    top_level_directory: &str,
+    listing: &ArchivableListing,
+    hard_links: &mut HashMap<(u64, u64), Vec<u8>>,
+) -> Result<Link, io::Error> {
+    match listing.permissions & 0o170000 {
+        0o120000 => {
+            let target = fs::read_link(directory_path.join(&*listing.relative_path))?;
+            Ok(Link::Symbolic(target.as_os_str().as_bytes().to_vec()))
+        }
+        0o100000 => {
+            let metadata = fs::metadata(&listing.literal_path)?;
+            if metadata.nlink() < 2 {
+                return Ok(Link::None);
+            }
+            let path = format!("{}{}", top_level_directory, listing.relative_path).into_bytes();
+            match hard_links.entry((metadata.dev(), metadata.ino())) {
+                Entry::Occupied(first) => Ok(Link::Hard(first.get().clone())),
+                Entry::Vacant(entry) => {
+                    entry.insert(path);
+                    Ok(Link::None)
+                }
+            }
+        }
+        _ => Ok(Link::None),
     }
+}
 
-    // write two blocks of zeros to mark the end of the tarball
-    writer.write_all(&[0u8; 1024])?;
+fn read_listing_content(listing: &ArchivableListing) -> Result<Vec<u8>, io::Error> {
+    if listing.literal_path.as_os_str().is_empty() {
+        return Ok(Vec::new());
+    }
+    fs::read(&listing.literal_path)
+}
 
-    Ok(())
+fn write_prefixed_entry<W: Write>(
+    top_level_directory: &str,
+    listing: &ArchivableListing,
+    link: &Link,
+    content: &[u8],
+    options: &TarOptions,
+    writer: &mut W,
+) -> Result<(), io::Error> {
+    let mut path = top_level_directory.to_string();
+    path.push_str(&listing.relative_path);
+    write_entry(
+        path.as_bytes(),
+        listing.permissions,
+        link,
+        content,
+        options,
+        writer,
+    )
 }
 
-fn write_header<W: Write>(listing: ArchivableListing, writer: &mut W) -> Result<(), io::Error> {
-    let mut header_buffer = [0u8; 512];
+fn write_end_of_archive<W: Write>(writer: &mut W) -> Result<(), io::Error> {
+    // write two blocks of zeros to mark the end of the tarball
+    writer.write_all(&[0u8; 1024])
+}
 
-    // get file content for listing if necessary
-    let mut listing_content = Vec::with_capacity(listing.file_size as usize);
+fn write_entry<W: Write>(
+    path_bytes: &[u8],
+    permissions: u32,
+    link: &Link,
+    content: &[u8],
+    options: &TarOptions,
+    writer: &mut W,
+) -> Result<(), io::Error> {
+    let mut link_name = match link {
+        Link::Symbolic(target) | Link::Hard(target) => &target[..],
+        Link::None => &[],
+    };
 
-    if &listing.literal_path.to_str().unwrap() != &"" {
-        listing_content = fs::read(&listing.literal_path)?;
+    // paths and link targets which don't fit into the ustar header are written to a PAX extended
+    // header preceding it, and truncated in the ustar header for readers without PAX support
+    let mut pax_records = Vec::new();
+    let (name, prefix) = match split_path(path_bytes) {
+        Some(split) => split,
+        None => {
+            push_pax_record(&mut pax_records, "path", path_bytes);
+            (&path_bytes[..100], &[][..])
+        }
+    };
+    if link_name.len() > 100 {
+        push_pax_record(&mut pax_records, "linkpath", link_name);
+        link_name = &link_name[..100];
+    }
+    if !pax_records.is_empty() {
+        UstarHeader {
+            name: b"././@PaxHeader",
+            prefix: &[],
+            mode: 0o644,
+            size: pax_records.len() as u64,
+            mtime: options.mtime,
+            typeflag: b'x',
+            link_name: &[],
+        }
+        .write(writer)?;
+        write_content(&pax_records, writer)?;
     }
 
-    // TODO: prefix paths with top level directory
-    let path_bytes = listing.relative_path.as_bytes();
-    let (name, prefix) = if path_bytes.len() <= 100 {
-        (path_bytes, &[][..])
-    } else {
-        split_path(path_bytes)?
-    };
+    UstarHeader {
+        name,
+        prefix,
+        mode: permissions,
+        size: content.len() as u64,
+        mtime: options.mtime,
+        typeflag: match link {
+            Link::Hard(_) => b'1',
+            Link::Symbolic(_) => b'2',
+            Link::None if (permissions & 0o040000) == 0o040000 => b'5', // directory
+            Link::None => b'0',                                         // regular file
+        },
+        link_name,
+    }
+    .write(writer)?;
+    write_content(content, writer)
+}
 
-    // name (100 bytes)
-    header_buffer[..name.len()].copy_from_slice(name);
+/// The fields of a ustar header block
+struct UstarHeader<'a> {
+    name: &'a [u8],
+    prefix: &'a [u8],
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    typeflag: u8,
+    link_name: &'a [u8],
+}
 
-    // mode (8 bytes)
-    write_octal(&mut header_buffer[100..108], listing.permissions as u64, 7);
+impl UstarHeader<'_> {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        let mut header_buffer = [0u8; 512];
 
-    // uid (8 bytes) and gid (8 bytes) are null
+        // name (100 bytes)
+        header_buffer[..self.name.len()].copy_from_slice(self.name);
 
-    // file size (12 bytes)
-    write_octal(
-        &mut header_buffer[124..136],
-        listing_content.len() as u64,
-        11,
-    );
+        // mode (8 bytes)
+        write_octal(&mut header_buffer[100..108], self.mode as u64, 7);
 
-    // mtime (12 bytes) is null
+        // uid (8 bytes) and gid (8 bytes) are null
 
-    // typeflag (1 byte)
-    header_buffer[156] = if (listing.permissions & 0o040000) == 0o040000 {
-        b'5' // directory
-    } else {
-        b'0' // regular file
-    };
+        // file size (12 bytes)
+        write_octal(&mut header_buffer[124..136], self.size, 11);
+
+        // mtime (12 bytes)
+        write_octal(
+            &mut header_buffer[136..148],
+            self.mtime.min(MAX_TAR_MTIME),
+            12,
+        );
+
+        // typeflag (1 byte)
+        header_buffer[156] = self.typeflag;
 
-    // magic number (6 bytes)
-    header_buffer[257..263].copy_from_slice(b"ustar\0");
+        // linkname (100 bytes)
+        header_buffer[157..157 + self.link_name.len()].copy_from_slice(self.link_name);
 
-    // version (2 bytes)
-    header_buffer[263..265].copy_from_slice(b"00");
+        // magic number (6 bytes)
+        header_buffer[257..263].copy_from_slice(b"ustar\0");
 
-    // prefix (155 bytes)
-    header_buffer[345..345 + prefix.len()].copy_from_slice(prefix);
+        // version (2 bytes)
+        header_buffer[263..265].copy_from_slice(b"00");
 
-    // calculate and write checksum
-    let checksum = calculate_checksum(&header_buffer);
-    write_octal(&mut header_buffer[148..156], checksum, 6);
-    header_buffer[154] = b'\0';
-    header_buffer[155] = b' ';
+        // prefix (155 bytes)
+        header_buffer[345..345 + self.prefix.len()].copy_from_slice(self.prefix);
 
-    writer.write_all(&header_buffer)?;
-    writer.write_all(&listing_content)?;
+        // calculate and write checksum
+        let checksum = calculate_checksum(&header_buffer);
+        write_octal(&mut header_buffer[148..156], checksum, 6);
+        header_buffer[154] = b'\0';
+        header_buffer[155] = b' ';
 
-    // pad file content to a multiple of 512 bytes
-    let padding = (512 - (listing_content.len() % 512)) % 512;
-    writer.write_all(&vec![0u8; padding])?;
+        writer.write_all(&header_buffer)
+    }
+}
 
-    Ok(())
+/// Writes the content of an entry, padded to a multiple of 512 bytes
+fn write_content<W: Write>(content: &[u8], writer: &mut W) -> Result<(), io::Error> {
+    writer.write_all(content)?;
+    let padding = (512 - (content.len() % 512)) % 512;
+    writer.write_all(&vec![0u8; padding])
 }
 
-fn split_path(path: &[u8]) -> io::Result<(&[u8], &[u8])> {
-    if path.len() > 255 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Path is too long: {} bytes", path.len()),
-        ));
+/// Appends a PAX extended header record, which starts with its own length in decimal
+fn push_pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // " key=value\n" and then as many digits as the whole record's length has
+    let unprefixed_length = 1 + key.len() + 1 + value.len() + 1;
+    let mut length = unprefixed_length;
+    while length != unprefixed_length + length.to_string().len() {
+        length = unprefixed_length + length.to_string().len();
     }
+    records.extend_from_slice(format!("{} {}=", length, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
 
-    let split_index = path.len() - 100;
-    let (prefix, _) = path.split_at(split_index);
+/// Splits `path` into the name and prefix fields of a ustar header at a `/`, returning `None` if
+/// it doesn't fit into them
+fn split_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Some((path, &[]));
+    }
 
-    let adjusted_split = prefix
+    // the name holds at most 100 bytes after the slash, and the prefix 155 bytes before it
+    let earliest_split = path.len() - 101;
+    let split = path[earliest_split..path.len().min(156)]
         .iter()
-        .rposition(|&b| b == b'/')
-        .map(|i| i + 1)
-        .unwrap_or(0);
-
-    Ok((&path[adjusted_split..], &path[..adjusted_split]))
+        .position(|&b| b == b'/')
+        .map(|i| earliest_split + i)
+        .filter(|&i| i > 0 && i + 1 < path.len())?;
+    Some((&path[split + 1..], &path[..split]))
 }
 
 fn write_octal(buffer: &mut [u8], value: u64, field_size: usize) {
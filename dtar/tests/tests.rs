@@ -1,3 +1,4 @@
+use decaf::{extract_stream, repack, ArchiveOptions, ExtractOptions, Limits};
 use dtar::*;
 use std::fs;
 use std::fs::File;
@@ -55,8 +56,8 @@ fn gzip_determinism() {
     {
         let mut outfilea = File::create(file_a_path).unwrap();
         let mut outfileb = File::create(file_b_path).unwrap();
-        create_tar_gz(Path::new("../decaf"), &mut outfilea).unwrap();
-        create_tar_gz(Path::new("../decaf"), &mut outfileb).unwrap();
+        create_tar_gz(Path::new("../decaf-rs"), &mut outfilea).unwrap();
+        create_tar_gz(Path::new("../decaf-rs"), &mut outfileb).unwrap();
     }
 
     let mut filea = File::open(file_a_path).unwrap();
@@ -72,3 +73,237 @@ fn gzip_determinism() {
     std::fs::remove_file(file_a_path).unwrap();
     std::fs::remove_file(file_b_path).unwrap();
 }
+
+#[test]
+fn decaf_conversions() {
+    let mut tarball = Vec::new();
+    let mut archive = Vec::new();
+    create_tar_to_decaf(
+        Path::new("../decaf-rs/src"),
+        &mut tarball,
+        &mut archive,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+
+    let mut standalone_tarball = Vec::new();
+    create_tar(Path::new("../decaf-rs/src"), &mut standalone_tarball).unwrap();
+    assert_eq!(tarball, standalone_tarball);
+
+    // the tarball places everything under `src/`
+    let mut converted = Vec::new();
+    tar_to_decaf(
+        tarball.as_slice(),
+        &mut converted,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    let mut expected = Vec::new();
+    repack(
+        &mut archive.as_slice(),
+        &mut expected,
+        &ArchiveOptions {
+            path_prefix: Some("src".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(converted, expected);
+
+    let mut tar_gz = Vec::new();
+    create_tar_gz(Path::new("../decaf-rs/src"), &mut tar_gz).unwrap();
+    let mut converted = Vec::new();
+    tar_gz_to_decaf(
+        tar_gz.as_slice(),
+        &mut converted,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(converted, expected);
+
+    // a stream is written from the tarball as it is read, like a download of it
+    let mut stream = Vec::new();
+    tar_gz_to_decaf_stream(tar_gz.as_slice(), &mut stream, &ArchiveOptions::default()).unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    extract_stream(
+        &mut stream.as_slice(),
+        destination.path(),
+        &Limits::default(),
+        &ExtractOptions::default(),
+    )
+    .unwrap();
+    for entry in fs::read_dir("../decaf-rs/src").unwrap() {
+        let path = entry.unwrap().path();
+        assert_eq!(
+            fs::read(
+                destination
+                    .path()
+                    .join("src")
+                    .join(path.file_name().unwrap())
+            )
+            .unwrap(),
+            fs::read(&path).unwrap()
+        );
+    }
+
+    let mut round_tripped_tarball = Vec::new();
+    decaf_to_tar(&mut archive.as_slice(), &mut round_tripped_tarball).unwrap();
+    let mut round_tripped = Vec::new();
+    tar_to_decaf(
+        round_tripped_tarball.as_slice(),
+        &mut round_tripped,
+        &ArchiveOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(round_tripped, archive);
+}
+
+#[test]
+fn links_and_mtime() {
+    let source = tempfile::tempdir().unwrap();
+    let root = source.path().join("tree");
+    fs::create_dir(&root).unwrap();
+    fs::write(root.join("file.txt"), b"linked content").unwrap();
+    fs::hard_link(root.join("file.txt"), root.join("hard.txt")).unwrap();
+    // only links pointing into the tree by absolute path are archived
+    std::os::unix::fs::symlink(root.join("file.txt"), root.join("symlink.txt")).unwrap();
+
+    let mut tarball = Vec::new();
+    create_tar_with_options(
+        &root,
+        &mut tarball,
+        &TarOptions {
+            mtime: 1_700_000_000,
+        },
+    )
+    .unwrap();
+
+    let mut entries = Vec::new();
+    for entry in tar::Archive::new(tarball.as_slice()).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        entries.push((
+            entry.path().unwrap().to_str().unwrap().to_string(),
+            entry.header().entry_type(),
+            entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.to_str().unwrap().to_string()),
+            content,
+        ));
+    }
+    assert_eq!(entries[0].0, "tree/");
+    assert_eq!(
+        entries[1..],
+        [
+            (
+                "tree/symlink.txt".to_string(),
+                tar::EntryType::Symlink,
+                Some(root.join("file.txt").to_str().unwrap().to_string()),
+                Vec::new()
+            ),
+            (
+                "tree/file.txt".to_string(),
+                tar::EntryType::Regular,
+                None,
+                b"linked content".to_vec()
+            ),
+            (
+                "tree/hard.txt".to_string(),
+                tar::EntryType::Link,
+                Some("tree/file.txt".to_string()),
+                Vec::new()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn long_paths() {
+    let source = tempfile::tempdir().unwrap();
+    let root = source.path().join("tree");
+    let split_directory = root.join("s".repeat(130));
+    let deep_directory = root.join("d".repeat(120)).join("e".repeat(120));
+    fs::create_dir_all(&split_directory).unwrap();
+    fs::create_dir_all(&deep_directory).unwrap();
+    // fits into the prefix and name fields
+    fs::write(split_directory.join("file.txt"), b"split").unwrap();
+    // longer than 255 bytes
+    fs::write(deep_directory.join("file.txt"), b"deep").unwrap();
+    // a single component longer than the name field
+    fs::write(root.join("n".repeat(110)), b"long name").unwrap();
+    std::os::unix::fs::symlink(deep_directory.join("file.txt"), root.join("symlink")).unwrap();
+
+    let mut tarball = Vec::new();
+    create_tar(&root, &mut tarball).unwrap();
+
+    let mut entries = Vec::new();
+    for entry in tar::Archive::new(tarball.as_slice()).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        entries.push((
+            entry.path().unwrap().to_str().unwrap().to_string(),
+            entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.to_str().unwrap().to_string()),
+            content,
+        ));
+    }
+    let find = |path: String| {
+        entries
+            .iter()
+            .find(|entry| entry.0 == path)
+            .unwrap_or_else(|| panic!("{} is missing", path))
+    };
+    assert_eq!(
+        find(format!("tree/{}/file.txt", "s".repeat(130))).2,
+        b"split"
+    );
+    assert_eq!(
+        find(format!(
+            "tree/{}/{}/file.txt",
+            "d".repeat(120),
+            "e".repeat(120)
+        ))
+        .2,
+        b"deep"
+    );
+    assert_eq!(find(format!("tree/{}", "n".repeat(110))).2, b"long name");
+    assert_eq!(
+        find("tree/symlink".to_string()).1.as_deref(),
+        deep_directory.join("file.txt").to_str()
+    );
+}
+
+#[test]
+fn gzip_mtime() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("file.txt"), b"content").unwrap();
+
+    let tar_gz = |mtime| {
+        let mut tar_gz = Vec::new();
+        create_tar_gz_with_options(source.path(), &mut tar_gz, &TarOptions { mtime }).unwrap();
+        let mut tarball = Vec::new();
+        flate2::read::GzDecoder::new(tar_gz.as_slice())
+            .read_to_end(&mut tarball)
+            .unwrap();
+        let mtimes = tar::Archive::new(tarball.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().header().mtime().unwrap())
+            .collect::<Vec<_>>();
+        // the gzip modification time is stored little-endian at offset 4
+        (u32::from_le_bytes(tar_gz[4..8].try_into().unwrap()), mtimes)
+    };
+
+    assert_eq!(
+        tar_gz(1_700_000_000),
+        (1_700_000_000, vec![1_700_000_000; 2])
+    );
+    // times beyond what the headers can hold are clamped
+    assert_eq!(tar_gz(u64::MAX), (u32::MAX, vec![0o77777777777; 2]));
+}